@@ -1,17 +1,30 @@
 use mongodb::{Client, ThreadedClient};
 use mongodb::db::{Database, ThreadedDatabase};
+use mongodb::coll::options::FindOptions;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::ops::Deref;
+use std::time::Duration;
 use rocket::request::{self, FromRequest};
 use rocket::{Request, State, Outcome};
 use rocket::http::Status;
 use r2d2;
 use r2d2_redis::RedisConnectionManager;
-use redis::{self, Connection, PipelineCommands, Commands};
+use redis::{self, Connection, ConnectionLike, PipelineCommands, Commands};
 use setting;
-use service::{ServiceError, Result};
+use metrics;
+use pii;
+use policy;
+use util;
+use service::{Service, ServiceError, Result};
 use entity;
+use store::{TripStore, OrderStore, WalletStore, TokenStore, AuditStore, EventStore, SeatStore, SeatUpdates, WaitlistStore, TemplateStore, RideRequestStore, MatchStore, CouponStore, ReferralStore, MessageStore, MessageUpdates, NotificationStore, LimitStore, AccountStore};
+use event::{self, Event};
+use serde_json;
 use serde::ser::Serialize;
-use serde::de::Deserialize;
+use serde::de::{Deserialize, DeserializeOwned};
 use serde_redis::RedisDeserialize;
 use bson::{self, Document, Bson};
 
@@ -21,6 +34,12 @@ pub type Pool = r2d2::Pool<RedisConnectionManager>;
 pub struct DbConn(pub Database);
 pub struct CacheConn(pub r2d2::PooledConnection<RedisConnectionManager>);
 
+//线上使用的存储：mongodb + redis
+pub struct Storage {
+    pub conn: DbConn,
+    pub cache: CacheConn,
+}
+
 //获取mongodb中的name
 pub trait GetName {
     fn get_name() -> &'static str;
@@ -44,11 +63,10 @@ impl Deref for DbConn {
 }
 
 pub fn init_db_conn() -> Database {
-    Client::connect(
-        &setting::get_str("app.dburl"),
-        setting::get_int64("app.dbport") as u16,
-    ).expect("can't connect db")
-        .db("test")
+    let config = setting::get();
+    Client::connect(&config.app.dburl, config.app.dbport as u16)
+        .expect("can't connect db")
+        .db(&config.app.dbname)
 }
 
 
@@ -56,7 +74,7 @@ pub fn init_db_conn() -> Database {
 
 pub fn init_redis() -> Pool {
     let config = Default::default();
-    let manager = RedisConnectionManager::new(setting::get_str("app.redis").as_str())
+    let manager = RedisConnectionManager::new(setting::get().app.redis.as_str())
         .expect("can't open redis!!");
     r2d2::Pool::new(config, manager).expect("can't pooled redis conection!!")
 }
@@ -68,7 +86,10 @@ impl<'a, 'r> FromRequest<'a, 'r> for CacheConn {
         let pool = request.guard::<State<Pool>>()?;
         match pool.get() {
             Ok(conn) => Outcome::Success(CacheConn(conn)),
-            Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
+            Err(_) => {
+                metrics::inc("redis_pool_timeouts_total", &[]);
+                Outcome::Failure((Status::ServiceUnavailable, ()))
+            }
         }
     }
 }
@@ -81,6 +102,34 @@ impl Deref for CacheConn {
     }
 }
 
+//打包后的命令格式为 *参数个数\r\n$长度\r\n命令名\r\n...
+fn command_name(cmd: &[u8]) -> String {
+    cmd.split(|&b| b == b'\n')
+        .nth(2)
+        .map(|name| String::from_utf8_lossy(name).trim_right().to_uppercase())
+        .unwrap_or_default()
+}
+
+//所有redis命令都经过这里，按命令名记录耗时和错误，pipeline和事务记为一次PIPELINE
+impl ConnectionLike for CacheConn {
+    fn req_packed_command(&self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        let name = command_name(cmd);
+        metrics::time("redis_command_duration_seconds", "redis_errors_total", &[("command", &name)], || {
+            (**self).req_packed_command(cmd)
+        })
+    }
+
+    fn req_packed_commands(&self, cmd: &[u8], offset: usize, count: usize) -> redis::RedisResult<Vec<redis::Value>> {
+        metrics::time("redis_command_duration_seconds", "redis_errors_total", &[("command", "PIPELINE")], || {
+            (**self).req_packed_commands(cmd, offset, count)
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        (**self).get_db()
+    }
+}
+
 impl GetName for entity::Order {
     fn get_name() -> &'static str {
         "Order"
@@ -99,6 +148,131 @@ impl GetName for entity::Complain {
     }
 }
 
+impl GetName for entity::Notification {
+    fn get_name() -> &'static str {
+        "Notification"
+    }
+}
+
+impl GetName for entity::Withdraw {
+    fn get_name() -> &'static str {
+        "Withdraw"
+    }
+}
+
+impl GetName for entity::AuditLog {
+    fn get_name() -> &'static str {
+        "AuditLog"
+    }
+}
+
+impl GetName for entity::TripTemplate {
+    fn get_name() -> &'static str {
+        "TripTemplate"
+    }
+}
+
+impl GetName for entity::RideRequest {
+    fn get_name() -> &'static str {
+        "RideRequest"
+    }
+}
+
+impl GetName for entity::Waiter {
+    fn get_name() -> &'static str {
+        "Waiter"
+    }
+}
+
+impl GetName for entity::Coupon {
+    fn get_name() -> &'static str {
+        "Coupon"
+    }
+}
+
+impl GetName for entity::Redemption {
+    fn get_name() -> &'static str {
+        "Redemption"
+    }
+}
+
+impl GetName for entity::Referral {
+    fn get_name() -> &'static str {
+        "Referral"
+    }
+}
+
+impl GetName for entity::WalletLog {
+    fn get_name() -> &'static str {
+        "WalletLog"
+    }
+}
+
+impl GetName for entity::Conversation {
+    fn get_name() -> &'static str {
+        "Conversation"
+    }
+}
+
+impl GetName for entity::Message {
+    fn get_name() -> &'static str {
+        "Message"
+    }
+}
+
+//列表中的单个对象读取失败时跳过，记录日志以便发现无法解密的数据
+fn skip_broken<T>(id: &str, result: Result<T>) -> Option<T> {
+    result.map_err(|err| println!("skip {}: {:?}", id, err)).ok()
+}
+
+//每次检查的未确认事件数，超过的部分下次再检查
+const BATCH_SCAN: usize = 100;
+
+//事件在进入事务之前序列化，事务中只追加XADD
+fn encode_events(events: &[Event]) -> Result<Vec<String>> {
+    events
+        .iter()
+        .map(|event| serde_json::to_string(event).map_err(|err| ServiceError::String(format!("{:?}", err))))
+        .collect()
+}
+
+//事件和状态变化放在同一个MULTI里，状态变化提交后事件不会丢失
+fn add_events(pipe: &mut redis::Pipeline, events: &[String]) {
+    for data in events {
+        pipe.cmd("XADD")
+            .arg("Events")
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(100000)
+            .arg("*")
+            .arg("event")
+            .arg(data)
+            .ignore();
+    }
+}
+
+//钱包流水和余额变动放在同一个事务里，保证每次变动都有记录
+fn log_wallet(pipe: &mut redis::Pipeline, log: &entity::WalletLog) {
+    let log_key = format!("{}:{}", entity::WalletLog::get_name(), log.id);
+    pipe.hset_multiple(
+        &log_key,
+        &[
+            ("_id", &log.id),
+            ("openid", &log.openid),
+            ("reason", &log.reason),
+            ("ref_id", &log.ref_id),
+        ],
+    ).hset_multiple(
+            &log_key,
+            &[
+                ("change", log.change),
+                ("balance", log.balance),
+                ("time", log.time),
+            ],
+        )
+        .lpush(format!("WalletLogs:{}", log.openid), &log.id);
+}
+
 fn to_doc<T>(t: &T) -> Result<Document>
 where
     T: Serialize,
@@ -112,23 +286,40 @@ where
         })
 }
 
+//按操作和集合记录mongodb的耗时和错误
+fn timed<T, F>(op: &str, collection: &str, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    metrics::time(
+        "mongo_operation_duration_seconds",
+        "mongo_errors_total",
+        &[("op", op), ("collection", collection)],
+        f,
+    )
+}
+
 impl DbConn {
     pub fn add<T>(&self, t: &T) -> Result<Bson>
     where
         T: GetName + Serialize,
+    {
+        to_doc(t).and_then(|doc| self.add_document::<T>(doc))
+    }
+
+    //写入已经处理过的文档，例如加密了个人信息字段
+    pub fn add_document<T>(&self, doc: Document) -> Result<Bson>
+    where
+        T: GetName,
     {
         let coll = self.collection(T::get_name());
-        to_doc(t)
-            .and_then(|doc| {
-                coll.insert_one(doc, None).map_err(|err| {
-                    ServiceError::MongodbError(err)
-                })
-            })
-            .and_then(|r| {
-                r.inserted_id.ok_or(ServiceError::String(
-                    "ObjectId null".to_owned(),
-                ))
-            })
+        timed("insert", T::get_name(), || {
+            coll.insert_one(doc, None).map_err(|err| ServiceError::MongodbError(err))
+        }).and_then(|r| {
+            r.inserted_id.ok_or(ServiceError::String(
+                "ObjectId null".to_owned(),
+            ))
+        })
     }
 
     pub fn delete<T>(&self, id: &str) -> Result<()>
@@ -138,9 +329,56 @@ impl DbConn {
         let coll = self.collection(T::get_name());
         let mut doc = Document::new();
         doc.insert("_id", id);
-        coll.delete_one(doc, None)
-            .map_err(|err| ServiceError::MongodbError(err))
-            .map(|_| ())
+        timed("delete", T::get_name(), || {
+            coll.delete_one(doc, None)
+                .map_err(|err| ServiceError::MongodbError(err))
+                .map(|_| ())
+        })
+    }
+
+    pub fn delete_many<T>(&self, filter: Document) -> Result<()>
+    where
+        T: GetName,
+    {
+        let coll = self.collection(T::get_name());
+        timed("delete_many", T::get_name(), || {
+            coll.delete_many(filter, None)
+                .map_err(|err| ServiceError::MongodbError(err))
+                .map(|_| ())
+        })
+    }
+
+    pub fn replace<T>(&self, id: &str, t: &T) -> Result<()>
+    where
+        T: GetName + Serialize,
+    {
+        to_doc(t).and_then(|doc| self.replace_document::<T>(id, doc))
+    }
+
+    pub fn replace_document<T>(&self, id: &str, doc: Document) -> Result<()>
+    where
+        T: GetName,
+    {
+        let coll = self.collection(T::get_name());
+        let mut filter = Document::new();
+        filter.insert("_id", id);
+        timed("replace", T::get_name(), || {
+            coll.replace_one(filter, doc, None)
+                .map_err(|err| ServiceError::MongodbError(err))
+                .map(|_| ())
+        })
+    }
+
+    pub fn update_many<T>(&self, filter: Document, update: Document) -> Result<()>
+    where
+        T: GetName,
+    {
+        let coll = self.collection(T::get_name());
+        timed("update_many", T::get_name(), || {
+            coll.update_many(filter, update, None)
+                .map_err(|err| ServiceError::MongodbError(err))
+                .map(|_| ())
+        })
     }
 
     pub fn get_one<'de, T>(&self, id: &str) -> Result<T>
@@ -150,13 +388,47 @@ impl DbConn {
         let coll = self.collection(T::get_name());
         let mut doc = Document::new();
         doc.insert("_id", id);
-        let doc = coll.find_one(Some(doc), None)??;
+        let doc = timed("find_one", T::get_name(), || Ok(coll.find_one(Some(doc), None)?))?;
+        let doc = pii::open_document(doc?)?;
         bson::from_bson::<T>(Bson::Document(doc)).map_err(|err| ServiceError::BsonDecoderError(err))
     }
+
+    pub fn find<T>(&self, filter: Document, sort: Document) -> Result<Vec<T>>
+    where
+        T: GetName + DeserializeOwned,
+    {
+        self.find_limit(filter, sort, None)
+    }
+
+    //limit为None时不限制返回的条数，游标读取也计入耗时
+    pub fn find_limit<T>(&self, filter: Document, sort: Document, limit: Option<usize>) -> Result<Vec<T>>
+    where
+        T: GetName + DeserializeOwned,
+    {
+        let coll = self.collection(T::get_name());
+        let mut options = FindOptions::new();
+        options.sort = Some(sort);
+        options.limit = limit.map(|limit| limit as i64);
+        let docs = timed("find", T::get_name(), || {
+            coll.find(Some(filter), Some(options))?
+                .map(|doc| doc.map_err(|err| ServiceError::MongodbError(err)))
+                .collect::<Result<Vec<Document>>>()
+        })?;
+        docs.into_iter()
+            .map(|doc| {
+                pii::open_document(doc).and_then(|doc| {
+                    bson::from_bson::<T>(Bson::Document(doc))
+                        .map_err(|err| ServiceError::BsonDecoderError(err))
+                })
+            })
+            .collect()
+    }
 }
 
 impl CacheConn {
-    pub fn add_trip(&self, t: &entity::Trip) -> Result<()> {
+    pub fn add_trip(&self, t: &entity::Trip, events: &[Event]) -> Result<()> {
+        let tel = pii::seal(&t.tel)?;
+        let events = encode_events(events)?;
         let mut pipe = redis::pipe();
         let trip_key = format!("{}:{}", entity::Trip::get_name(), t.id);
         pipe.atomic()
@@ -170,7 +442,7 @@ impl CacheConn {
                     ("venue", &t.venue),
                     ("plate_number", &t.plate_number),
                     ("car_type", &t.car_type),
-                    ("tel", &t.tel),
+                    ("tel", &tel),
                 ],
             )
             .hset_multiple(
@@ -188,23 +460,68 @@ impl CacheConn {
         if let Some(ref msg) = t.message {
             pipe.hset(&trip_key, "message", msg);
         }
-        pipe.query(&**self)
-            .map(|result: Vec<i32>| {
-                println!("redis add trip result is {:?}", result)
-            })
+        if !t.stops.is_empty() {
+            pipe.rpush(format!("TripStops:{}", t.id), &t.stops)
+                .rpush(format!("TripFares:{}", t.id), &t.fares);
+        }
+        if !t.free_seats.is_empty() {
+            pipe.rpush(format!("TripSeats:{}", t.id), &t.free_seats);
+        }
+        add_events(&mut pipe, &events);
+        pipe.query(self)
             .map_err(|err| ServiceError::RedisError(err))
     }
 
-    pub fn add_order(&self, order: &entity::Order) -> Result<()> {
+    pub fn get_trip(&self, id: &str) -> Result<entity::Trip> {
+        let mut trip: entity::Trip = self.get_object(id)?;
+        trip.stops = self.lrange(format!("TripStops:{}", id), 0, -1)?;
+        trip.fares = self.lrange(format!("TripFares:{}", id), 0, -1)?;
+        trip.free_seats = self.lrange(format!("TripSeats:{}", id), 0, -1)?;
+        Ok(trip)
+    }
+
+    //读取座位信息，只用于事务中计算，不含行程的其他字段
+    fn trip_seats(&self, trip_id: &str) -> redis::RedisResult<entity::Trip> {
+        let current_seat: i64 = self.hget(format!("Trip:{}", trip_id), "current_seat")?;
+        let free_seats: Vec<i64> = self.lrange(format!("TripSeats:{}", trip_id), 0, -1)?;
+        Ok(entity::Trip {
+            current_seat,
+            free_seats,
+            ..Default::default()
+        })
+    }
+
+    fn save_seats(pipe: &mut redis::Pipeline, trip_id: &str, trip: &entity::Trip) {
+        let seats_key = format!("TripSeats:{}", trip_id);
+        pipe.hset(format!("Trip:{}", trip_id), "current_seat", trip.current_seat)
+            .del(&seats_key)
+            .rpush(&seats_key, &trip.free_seats);
+    }
+
+    pub fn add_order(&self, order: &entity::Order, events: &[Event]) -> Result<()> {
         let trip_key = format!("{}:{}", entity::Trip::get_name(), order.trip_id);
+        let seats_key = format!("TripSeats:{}", order.trip_id);
         let order_key = format!("{}:{}", entity::Order::get_name(), order.id);
-        redis::transaction(&**self, &[&trip_key], |pipe| {
-            let count: i64 = self.hget(&trip_key, "current_seat")?;
-            if count < order.count {
-                return pipe.query(&**self).map(|_: Vec<i32>| Some(false));
+        let tel = pii::seal_option(&order.tel)?;
+        let events = encode_events(events)?;
+        let user_key = format!("UserOrders:{}", order.openid);
+        redis::transaction(self, &[&trip_key, &seats_key, &user_key], |pipe| {
+            //同一用户并发下单时UserOrders变化会让事务重试
+            let caps = self.get_user_orders(&order.openid)
+                .and_then(|orders| policy::check_caps(&orders, &order.trip_id, order.count));
+            if let Err(err) = caps {
+                return pipe.query(self).map(|_: ()| Some(Err(err)));
             }
-            pipe.hincr(&trip_key, "current_seat", -order.count)
-                .hset_multiple(
+            let mut trip = self.trip_seats(&order.trip_id)?;
+            if !trip.take_seats(order.from, order.to, order.count) {
+                return pipe.query(self).map(|_: ()| Some(Err(ServiceError::DontHaveEnoughSeats)));
+            }
+            CacheConn::save_seats(pipe, &order.trip_id, &trip);
+            //TelOrders:{盲索引}用于客服按手机号查找订单，过期删除的订单在查找时跳过
+            if let Some(ref tel) = order.tel {
+                pipe.sadd(format!("TelOrders:{}", pii::blind_index(tel)), &order_key);
+            }
+            pipe.hset_multiple(
                     &order_key,
                     &[
                         ("_id", &order.id),
@@ -218,7 +535,8 @@ impl CacheConn {
                     &[
                         ("order_id", order.order_id.as_ref()),
                         ("transaction_id", order.transaction_id.as_ref()),
-                        ("tel", order.tel.as_ref()),
+                        ("tel", tel.as_ref()),
+                        ("coupon_id", order.coupon_id.as_ref()),
                     ],
                 )
                 .hset_multiple(
@@ -227,143 +545,1706 @@ impl CacheConn {
                         ("price", order.price),
                         ("count", order.count),
                         ("start_time", order.start_time),
+                        ("from", order.from),
+                        ("to", order.to),
+                        ("discount", order.discount),
+                        ("refunded", order.refunded),
                     ],
                 )
                 .hset(&order_key, "status", &order.status)
-                .expire(&order_key, 3 * 60)
+                .expire(&order_key, setting::get().business.pay_timeout as usize)
                 .hset(format!("OrderEx:{}", order.id), "count",order.count)
+                .hset(format!("OrderEx:{}", order.id), "from",order.from)
+                .hset(format!("OrderEx:{}", order.id), "to",order.to)
                 .hset(format!("OrderEx:{}", order.id),"trip_id",&order.trip_id)  //用于未支付时恢复物品数量
+                .hset(format!("OrderEx:{}", order.id),"openid",&order.openid)  //用于统计未支付过期次数
                 .sadd(format!("TripOrders:{}",&order.trip_id),&order_key)
-                .sadd(format!("UserOrders:{}",&order.openid),&order_key)
-                .query(&**self)
-                .map(|_: Vec<i32>| Some(true))
+                .sadd(&user_key,&order_key);
+            add_events(pipe, &events);
+            pipe.query(self).map(|_: ()| Some(Ok(())))
         }).map_err(|err| ServiceError::RedisError(err))
-            .and_then(|buy| if buy {
-                Ok(())
-            } else {
-                Err(ServiceError::DontHaveEnoughSeats)
-            })
+            .and_then(|result| result)
     }
 
-    pub fn pay_order(&self, id: String) -> Result<()> {
-        let order_key = format!("{}:{}", entity::Order::get_name(), &id);
-        redis::pipe()
-            .atomic()
-            .hset(&order_key, "status", &entity::OrderStatus::Paid)
-            .del(format!("OrderEx:{}", &id))
-            .persist(&order_key)
-            .query(&**self)
-            .map(|_: Vec<i32>| ())
-            .map_err(|err| ServiceError::RedisError(err))
+    //订单已过期时返回错误，重复通知直接忽略
+    pub fn pay_order(&self, id: &str, transaction_id: &str, events: &[Event]) -> Result<bool> {
+        let order_key = format!("{}:{}", entity::Order::get_name(), id);
+        let events = encode_events(events)?;
+        redis::transaction(self, &[&order_key], |pipe| {
+            let status: Option<entity::OrderStatus> = self.hget(&order_key, "status")?;
+            match status {
+                Some(entity::OrderStatus::Unpaid) => (),
+                Some(_) => return pipe.query(self).map(|_: ()| Some(Some(false))),
+                None => return pipe.query(self).map(|_: ()| Some(None)),
+            }
+            pipe.hset(&order_key, "status", &entity::OrderStatus::Paid)
+                .hset(&order_key, "transaction_id", transaction_id)
+                .del(format!("OrderEx:{}", id))
+                .persist(&order_key);
+            add_events(pipe, &events);
+            pipe.query(self).map(|_: ()| Some(Some(true)))
+        }).map_err(|err| ServiceError::RedisError(err))
+            .and_then(|paid| {
+                paid.ok_or(ServiceError::NotFound(entity::Order::get_name().to_owned()))
+            })
     }
 
-    //返回transaction_id用于微信退款
-    pub fn change_order_price(&self, order_id: &str, openid: &str, change: i64) -> Result<String> {
+    //退款成功后累计退款金额，refunded与记录的不一致时不修改
+    pub fn add_refund(&self, order_id: &str, refunded: i64, fee: i64) -> Result<()> {
         let order_key = format!("{}:{}", entity::Order::get_name(), order_id);
-        let trip_owner: String = self.hget(&order_key, "trip_owner")?;
-        let transaction_id: String = self.hget(&order_key, "transaction_id")?;
-        if openid != trip_owner {
-            Err(ServiceError::TripNotYours)
-        } else {
-            self.hincr(&order_key, "price", change)
-                .map(|_: i64| transaction_id)
-                .map_err(|err| ServiceError::RedisError(err))
-        }
+        redis::transaction(self, &[&order_key], |pipe| {
+            let current: Option<i64> = self.hget(&order_key, "refunded")?;
+            if current.unwrap_or(0) != refunded {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            pipe.hset(&order_key, "refunded", refunded + fee)
+                .query(self)
+                .map(|_: ()| Some(true))
+        }).map_err(|err| ServiceError::RedisError(err))
+            .and_then(|added| if added {
+                Ok(())
+            } else {
+                Err(ServiceError::RefundConflict)
+            })
     }
 
-    pub fn submit_order(&self, id: &str) -> Result<String> {
-        let order_key = format!("{}:{}", entity::Order::get_name(), id);
-        redis::transaction(&**self, &[&order_key], |pipe| {
+    //订单确认后把司机收入记入钱包
+    pub fn submit_order(&self, order: &entity::Order, income: i64, events: &[Event]) -> Result<String> {
+        let order_key = format!("{}:{}", entity::Order::get_name(), order.id);
+        let events = encode_events(events)?;
+        let wallet_key = format!("Wallet:{}", order.trip_owner);
+        redis::transaction(self, &[&order_key, &wallet_key], |pipe| {
             let old_status: entity::OrderStatus = self.hget(&order_key, "status")?;
             if old_status != entity::OrderStatus::Paid {
-                return pipe.query(&**self).map(|_: Vec<i32>| Some(false));
+                return pipe.query(self).map(|_: ()| Some(false));
             }
+            let balance: Option<i64> = self.hget(&wallet_key, "balance")?;
+            let log = entity::WalletLog::new(
+                &order.trip_owner,
+                income,
+                balance.unwrap_or(0) + income,
+                "order",
+                &order.id,
+            );
             pipe.hset(&order_key, "status", &entity::OrderStatus::Submit)
-                .query(&**self)
-                .map(|_: Vec<i32>| Some(true))
+                .hincr(&wallet_key, "balance", income);
+            log_wallet(pipe, &log);
+            add_events(pipe, &events);
+            pipe.query(self).map(|_: ()| Some(true))
         }).map_err(|err| ServiceError::RedisError(err))
             .and_then(|success| if success {
-                Ok(self.hget(&order_key, "trip_id")?)
+                Ok(order.trip_id.clone())
             } else {
                 Err(ServiceError::NoPay)
             })
     }
 
-    pub fn check_trip_finish(&self, id: &str) -> Result<()> {
-        let finish = self.sscan(format!("TripOrders:{}", id))?.all(
-            |key: String| {
-                let result: redis::RedisResult<entity::OrderStatus> = self.hget(key, "status");
-                if let Ok(status) = result {
-                    status == entity::OrderStatus::Submit
-                } else {
-                    false
-                }
-            },
-        );
-        if finish {
-            self.hset(
-                format!("Trip:{}", id),
-                "status",
-                &entity::TripStatus::Finish,
-            ).map(|_: i32| ())
-                .map_err(|err| ServiceError::RedisError(err))
-        } else {
-            Ok(())
-        }
+    pub fn check_trip_finish(&self, id: &str, events: &[Event]) -> Result<bool> {
+        let trip_key = format!("Trip:{}", id);
+        let events = encode_events(events)?;
+        redis::transaction(self, &[&trip_key], |pipe| {
+            let status: Option<String> = self.hget(&trip_key, "status")?;
+            if status == Some(entity::TripStatus::Finish.to_string()) {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            let finish = self.sscan(format!("TripOrders:{}", id))?.all(
+                |key: String| {
+                    let result: redis::RedisResult<entity::OrderStatus> = self.hget(key, "status");
+                    if let Ok(status) = result {
+                        status == entity::OrderStatus::Submit
+                    } else {
+                        false
+                    }
+                },
+            );
+            if !finish {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            pipe.hset(&trip_key, "status", &entity::TripStatus::Finish);
+            add_events(pipe, &events);
+            pipe.query(self).map(|_: ()| Some(true))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn remove_trip(&self, id: &str) -> Result<bool> {
+        let trip_key = format!("{}:{}", entity::Trip::get_name(), id);
+        let orders_key = format!("TripOrders:{}", id);
+        redis::transaction(self, &[&trip_key, &orders_key], |pipe| {
+            let booked: bool = self.exists(&orders_key)?;
+            let openid: Option<String> = self.hget(&trip_key, "openid")?;
+            if booked {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            let openid = match openid {
+                Some(openid) => openid,
+                None => return pipe.query(self).map(|_: ()| Some(true)), //已删除
+            };
+            pipe.del(&trip_key)
+                .del(format!("TripStops:{}", id))
+                .del(format!("TripFares:{}", id))
+                .del(format!("TripSeats:{}", id))
+                .lrem("TripList", 1, &trip_key)
+                .srem(format!("UserTrips:{}", openid), &trip_key)
+                .query(self)
+                .map(|_: ()| Some(true))
+        }).map_err(|err| ServiceError::RedisError(err))
     }
 
     pub fn get_trips(&self, start: isize, end: isize) -> Result<Vec<entity::Trip>> {
         let keys: Vec<String> = self.lrange("TripList", start, end)?;
         Ok(
             keys.iter()
-                .map(|s| self.get_object::<entity::Trip>(s))
-                .filter(|result| result.is_ok())
-                .map(|t| t.unwrap())
+                .filter_map(|key| key.splitn(2, ':').nth(1))
+                .filter_map(|id| skip_broken(id, self.get_trip(id)))
                 .collect(),
         )
     }
 
-    pub fn get_object<'de, T>(&self, id: &str) -> Result<T>
-    where
-        T: GetName + Deserialize<'de>,
-    {
-        let value: redis::Value = self.hgetall(format!("{}:{}", T::get_name(), id))?;
-        value.deserialize().map_err(
-            |err| ServiceError::RedisDecodeError(err),
+    //未支付订单过期，归还座位
+    pub fn expire_order(&self, id: &str) -> Result<Option<String>> {
+        let ex_key = format!("OrderEx:{}", id);
+        let trip_id: Option<String> = self.hget(&ex_key, "trip_id")?;
+        let trip_id = match trip_id {
+            Some(trip_id) => trip_id,
+            None => return Ok(None), //已支付
+        };
+        let trip_key = format!("Trip:{}", trip_id);
+        let seats_key = format!("TripSeats:{}", trip_id);
+        let events = encode_events(&[Event::OrderExpired {
+            order_id: id.to_owned(),
+            trip_id: trip_id.clone(),
+        }])?;
+        redis::transaction(self, &[&ex_key, &trip_key, &seats_key], |pipe| {
+            let count: Option<i64> = self.hget(&ex_key, "count")?;
+            let count = match count {
+                Some(count) => count,
+                None => return pipe.query(self).map(|_: ()| Some(None)), //已处理
+            };
+            let openid: Option<String> = self.hget(&ex_key, "openid")?;
+            let mut trip = self.trip_seats(&trip_id)?;
+            //旧订单没有路段信息，按全程归还
+            let from: Option<i64> = self.hget(&ex_key, "from")?;
+            let to: Option<i64> = self.hget(&ex_key, "to")?;
+            let last_stop = cmp::max(trip.free_seats.len() as i64, 1);
+            trip.release_seats(from.unwrap_or(0), to.unwrap_or(last_stop), count);
+            CacheConn::save_seats(pipe, &trip_id, &trip);
+            pipe.del(&ex_key)
+                .srem(
+                    format!("TripOrders:{}", &trip_id),
+                    format!("Order:{}", id),
+                );
+            if let Some(openid) = openid {
+                let expired_key = format!("OrderExpired:{}", openid);
+                pipe.srem(format!("UserOrders:{}", openid), format!("Order:{}", id))
+                    .incr(&expired_key, 1)
+                    .expire(&expired_key, setting::get().booking.block_time as usize);
+            }
+            add_events(pipe, &events);
+            pipe.query(self).map(|_: ()| Some(Some(trip_id.clone())))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //用户当前的订单，未支付过期的订单已删除
+    pub fn get_user_orders(&self, openid: &str) -> Result<Vec<entity::Order>> {
+        let keys: Vec<String> = self.smembers(format!("UserOrders:{}", openid))?;
+        Ok(
+            keys.iter()
+                .filter_map(|key| key.splitn(2, ':').nth(1))
+                .filter_map(|id| skip_broken(id, self.get_object::<entity::Order>(id)))
+                .collect(),
         )
     }
-}
 
-pub fn check_expire() -> Result<()> {
-    let client = redis::Client::open(setting::get_str("app.redis").as_str())?;
-    let mut pubsub = client.get_pubsub()?;
-    pubsub.subscribe("__keyevent@0__:expired")?;
-    loop {
-        if let Err(err) = deal_expire(&mut pubsub, &client) {
-            println!("{:?}", err);
+    //行程当前的订单，未支付过期的订单已删除
+    pub fn get_trip_orders(&self, trip_id: &str) -> Result<Vec<entity::Order>> {
+        let keys: Vec<String> = self.smembers(format!("TripOrders:{}", trip_id))?;
+        Ok(
+            keys.iter()
+                .filter_map(|key| key.splitn(2, ':').nth(1))
+                .filter_map(|id| skip_broken(id, self.get_object::<entity::Order>(id)))
+                .collect(),
+        )
+    }
+
+    pub fn get_expired_count(&self, openid: &str) -> Result<i64> {
+        let count: Option<i64> = self.get(format!("OrderExpired:{}", openid))?;
+        Ok(count.unwrap_or(0))
+    }
+
+    pub fn get_wallet(&self, openid: &str) -> Result<entity::Wallet> {
+        let wallet_key = format!("Wallet:{}", openid);
+        let balance: Option<i64> = self.hget(&wallet_key, "balance")?;
+        let frozen: Option<i64> = self.hget(&wallet_key, "frozen")?;
+        Ok(entity::Wallet {
+            openid: openid.to_owned(),
+            balance: balance.unwrap_or(0),
+            frozen: frozen.unwrap_or(0),
+        })
+    }
+
+    //申请提现，余额转入冻结金额，等待管理员审核
+    pub fn add_withdraw(&self, w: &entity::Withdraw, day_count: i64, day_amount: i64) -> Result<()> {
+        let wallet_key = format!("Wallet:{}", w.openid);
+        let daily_key = format!("WithdrawDaily:{}:{}", w.openid, util::today());
+        let withdraw_key = format!("{}:{}", entity::Withdraw::get_name(), w.id);
+        redis::transaction(self, &[&wallet_key, &daily_key], |pipe| {
+            let balance: Option<i64> = self.hget(&wallet_key, "balance")?;
+            let balance = balance.unwrap_or(0);
+            if balance < w.amount {
+                return pipe.query(self).map(|_: ()| {
+                    Some(Err(ServiceError::BalanceNotEnough))
+                });
+            }
+            let count: Option<i64> = self.hget(&daily_key, "count")?;
+            let amount: Option<i64> = self.hget(&daily_key, "amount")?;
+            if count.unwrap_or(0) + 1 > day_count || amount.unwrap_or(0) + w.amount > day_amount {
+                return pipe.query(self).map(|_: ()| Some(Err(ServiceError::WithdrawLimit)));
+            }
+            let log = entity::WalletLog::new(&w.openid, -w.amount, balance - w.amount, "withdraw", &w.id);
+            pipe.hincr(&wallet_key, "balance", -w.amount)
+                .hincr(&wallet_key, "frozen", w.amount)
+                .hset_multiple(&withdraw_key, &[("_id", &w.id), ("openid", &w.openid)])
+                .hset_multiple(
+                    &withdraw_key,
+                    &[("amount", w.amount), ("create_time", w.create_time)],
+                )
+                .hset(&withdraw_key, "status", &w.status)
+                .lpush(format!("Withdraws:{}", w.openid), &w.id)
+                .rpush("WithdrawQueue", &w.id)
+                .hincr(&daily_key, "count", 1)
+                .hincr(&daily_key, "amount", w.amount)
+                .expire(&daily_key, 2 * 24 * 3600);
+            log_wallet(pipe, &log);
+            pipe.query(self).map(|_: ()| Some(Ok(())))
+        }).map_err(|err| ServiceError::RedisError(err))
+            .and_then(|result| result)
+    }
+
+    //管理员开始处理提现，防止重复打款
+    pub fn claim_withdraw(&self, id: &str) -> Result<entity::Withdraw> {
+        let withdraw_key = format!("{}:{}", entity::Withdraw::get_name(), id);
+        redis::transaction(self, &[&withdraw_key], |pipe| {
+            let status: Option<entity::WithdrawStatus> = self.hget(&withdraw_key, "status")?;
+            match status {
+                Some(entity::WithdrawStatus::Pending) => (),
+                Some(_) => {
+                    return pipe.query(self).map(|_: ()| Some(Err(ServiceError::WithdrawHandled)))
+                }
+                None => {
+                    return pipe.query(self).map(|_: ()| {
+                        Some(Err(ServiceError::NotFound(entity::Withdraw::get_name().to_owned())))
+                    })
+                }
+            }
+            //WithdrawProcessing记录开始打款的时间，长时间未完成的由补单任务处理
+            pipe.hset(&withdraw_key, "status", &entity::WithdrawStatus::Processing)
+                .lrem("WithdrawQueue", 0, id)
+                .zadd("WithdrawProcessing", id, util::now())
+                .query(self)
+                .map(|_: ()| Some(Ok(())))
+        }).map_err(|err| ServiceError::RedisError(err))
+            .and_then(|result| result)
+            .and_then(|_| self.get_object(id))
+    }
+
+    //打款失败，放回审核队列；只处理仍在打款中的提现，已完成的不能再放回
+    pub fn release_withdraw(&self, id: &str) -> Result<()> {
+        let withdraw_key = format!("{}:{}", entity::Withdraw::get_name(), id);
+        redis::transaction(self, &[&withdraw_key], |pipe| {
+            let status: Option<entity::WithdrawStatus> = self.hget(&withdraw_key, "status")?;
+            if status != Some(entity::WithdrawStatus::Processing) {
+                return pipe.query(self).map(|_: ()| Some(Err(ServiceError::WithdrawHandled)));
+            }
+            pipe.hset(&withdraw_key, "status", &entity::WithdrawStatus::Pending)
+                .lpush("WithdrawQueue", id)
+                .zrem("WithdrawProcessing", id)
+                .query(self)
+                .map(|_: ()| Some(Ok(())))
+        }).map_err(|err| ServiceError::RedisError(err))
+            .and_then(|result| result)
+    }
+
+    //完成提现：通过则扣除冻结金额，拒绝则退回余额；只处理打款中的提现，重复完成时返回WithdrawHandled
+    pub fn finish_withdraw(&self, w: &entity::Withdraw, approved: bool) -> Result<()> {
+        let wallet_key = format!("Wallet:{}", w.openid);
+        let withdraw_key = format!("{}:{}", entity::Withdraw::get_name(), w.id);
+        redis::transaction(self, &[&wallet_key, &withdraw_key], |pipe| {
+            let status: Option<entity::WithdrawStatus> = self.hget(&withdraw_key, "status")?;
+            if status != Some(entity::WithdrawStatus::Processing) {
+                return pipe.query(self).map(|_: ()| Some(Err(ServiceError::WithdrawHandled)));
+            }
+            pipe.hincr(&wallet_key, "frozen", -w.amount)
+                .hset(&withdraw_key, "handle_time", util::now())
+                .zrem("WithdrawProcessing", &w.id);
+            if approved {
+                pipe.hset(&withdraw_key, "status", &entity::WithdrawStatus::Approved);
+            } else {
+                let balance: Option<i64> = self.hget(&wallet_key, "balance")?;
+                let log = entity::WalletLog::new(
+                    &w.openid,
+                    w.amount,
+                    balance.unwrap_or(0) + w.amount,
+                    "withdraw_rejected",
+                    &w.id,
+                );
+                pipe.hincr(&wallet_key, "balance", w.amount)
+                    .hset(&withdraw_key, "status", &entity::WithdrawStatus::Rejected);
+                log_wallet(pipe, &log);
+            }
+            pipe.query(self).map(|_: ()| Some(Ok(())))
+        }).map_err(|err| ServiceError::RedisError(err))
+            .and_then(|result| result)
+    }
+
+    //开始打款的时间不晚于before仍未完成的提现
+    pub fn get_processing_withdraws(&self, before: i64) -> Result<Vec<entity::Withdraw>> {
+        let ids: Vec<String> = self.zrangebyscore("WithdrawProcessing", "-inf", before)?;
+        ids.iter().map(|id| self.get_object(id)).collect()
+    }
+
+    pub fn get_withdraws(&self, openid: &str, start: isize, end: isize) -> Result<Vec<entity::Withdraw>> {
+        self.get_list(&format!("Withdraws:{}", openid), start, end)
+    }
+
+    pub fn get_pending_withdraws(&self, start: isize, end: isize) -> Result<Vec<entity::Withdraw>> {
+        self.get_list("WithdrawQueue", start, end)
+    }
+
+    pub fn get_wallet_logs(&self, openid: &str, start: isize, end: isize) -> Result<Vec<entity::WalletLog>> {
+        self.get_list(&format!("WalletLogs:{}", openid), start, end)
+    }
+
+    pub fn credit_wallet(&self, openid: &str, amount: i64, reason: &str, ref_id: &str) -> Result<()> {
+        let wallet_key = format!("Wallet:{}", openid);
+        redis::transaction(self, &[&wallet_key], |pipe| {
+            let balance: Option<i64> = self.hget(&wallet_key, "balance")?;
+            let log = entity::WalletLog::new(openid, amount, balance.unwrap_or(0) + amount, reason, ref_id);
+            pipe.hincr(&wallet_key, "balance", amount);
+            log_wallet(pipe, &log);
+            pipe.query(self).map(|_: ()| Some(()))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn add_refresh_token(&self, token: &str, openid: &str, ttl: i64) -> Result<()> {
+        let token_key = format!("RefreshToken:{}", token);
+        let user_key = format!("UserRefreshTokens:{}", openid);
+        redis::pipe()
+            .atomic()
+            .set_ex(&token_key, openid, ttl as usize)
+            .sadd(&user_key, token)
+            .expire(&user_key, ttl as usize)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn take_refresh_token(&self, token: &str) -> Result<Option<String>> {
+        let token_key = format!("RefreshToken:{}", token);
+        let (openid, _): (Option<String>, i32) = redis::pipe()
+            .atomic()
+            .get(&token_key)
+            .del(&token_key)
+            .query(self)?;
+        if let Some(ref openid) = openid {
+            let _: i32 = self.srem(format!("UserRefreshTokens:{}", openid), token)?;
         }
+        Ok(openid)
     }
-}
 
-fn deal_expire(pubsub: &mut redis::PubSub, client: &redis::Client) -> Result<()> {
-    let msg = pubsub.get_message()?;
-    let key: String = msg.get_payload()?;
-    let v: Vec<&str> = key.split(":").collect();
-    if v.len() != 2 {
-        return Err(ServiceError::String(format!("key is {},can't use", key)));
+    pub fn remove_refresh_tokens(&self, openid: &str) -> Result<()> {
+        let user_key = format!("UserRefreshTokens:{}", openid);
+        let tokens: Vec<String> = self.smembers(&user_key)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for token in tokens {
+            pipe.del(format!("RefreshToken:{}", token));
+        }
+        pipe.del(&user_key)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //注销的access token保留到它自然过期为止
+    pub fn revoke_token(&self, jti: &str, ttl: i64) -> Result<()> {
+        if ttl <= 0 {
+            return Ok(());
+        }
+        self.set_ex(format!("RevokedToken:{}", jti), 1, ttl as usize)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn is_revoked(&self, jti: &str) -> Result<bool> {
+        self.exists(format!("RevokedToken:{}", jti))
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //UserAccessTokens:{openid}保存jti到过期时间，最后签发的令牌过期后整体过期
+    pub fn add_access_token(&self, openid: &str, jti: &str, ttl: i64) -> Result<()> {
+        let user_key = format!("UserAccessTokens:{}", openid);
+        redis::pipe()
+            .atomic()
+            .hset(&user_key, jti, util::now() + ttl)
+            .expire(&user_key, ttl as usize)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn revoke_user_tokens(&self, openid: &str) -> Result<()> {
+        let user_key = format!("UserAccessTokens:{}", openid);
+        let tokens: HashMap<String, i64> = self.hgetall(&user_key)?;
+        let now = util::now();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (jti, exp) in tokens.into_iter().filter(|&(_, exp)| exp > now) {
+            pipe.set_ex(format!("RevokedToken:{}", jti), 1, (exp - now) as usize);
+        }
+        pipe.del(&user_key)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
     }
-    let ex_key = format!("OrderEx:{}", v[1]);
-    let trip_id: String = client.hget(&ex_key, "trip_id")?;
-    let count: i32 = client.hget(&ex_key, "count")?;
-    let _: i32 = client.hincr(
-        format!("Trip:{}", trip_id),
-        "current_seat",
-        count,
-    )?;
-    let _: i32 = client.del(&ex_key)?;
-    let _: i32 = client.srem(
-        format!("TripOrders:{}", &trip_id),
-        format!("Order:{}", v[1]),
-    )?;
-    Ok(())
-}
\ No newline at end of file
+
+    //事件保存在Events流中，每个订阅者一个消费组，消费者名称与组名相同
+    pub fn publish_event(&self, event: &Event) -> Result<()> {
+        let events = encode_events(&[event.clone()])?;
+        let mut pipe = redis::pipe();
+        add_events(&mut pipe, &events);
+        pipe.query(self).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn add_event_group(&self, group: &str) -> Result<()> {
+        let result: redis::RedisResult<()> = redis::cmd("XGROUP")
+            .arg("CREATE")
+            .arg("Events")
+            .arg(group)
+            .arg("$")
+            .arg("MKSTREAM")
+            .query(self);
+        match result {
+            Err(ref err) if err.to_string().contains("BUSYGROUP") => Ok(()),
+            result => result.map_err(|err| ServiceError::RedisError(err)),
+        }
+    }
+
+    pub fn read_events(&self, group: &str, count: usize) -> Result<Vec<(String, Event)>> {
+        let mut events = self.claim_due(group, count)?;
+        if events.len() < count {
+            let fresh = self.read_new(group, count - events.len())?;
+            events.extend(fresh);
+        }
+        Ok(events)
+    }
+
+    //未确认的事件按失败次数计算等待时间，XPENDING的空闲时间达到后用XCLAIM重新领取
+    //XCLAIM会把空闲时间清零，下次失败后重新计时
+    fn claim_due(&self, group: &str, count: usize) -> Result<Vec<(String, Event)>> {
+        //每项为[id, 消费者, 空闲毫秒, 投递次数]
+        let pending: Vec<(String, String, i64, i64)> = redis::cmd("XPENDING")
+            .arg("Events")
+            .arg(group)
+            .arg("-")
+            .arg("+")
+            .arg(BATCH_SCAN)
+            .query(self)?;
+        if pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        let failures: HashMap<String, i64> = self.hgetall(format!("EventFailures:{}", group))?;
+        let due: Vec<String> = pending
+            .into_iter()
+            .filter(|&(ref id, _, idle, _)| idle >= event::retry_delay(failures.get(id).cloned().unwrap_or(0)))
+            .map(|(id, _, _, _)| id)
+            .take(count)
+            .collect();
+        if due.is_empty() {
+            return Ok(Vec::new());
+        }
+        let entries: Vec<Option<(String, Vec<String>)>> = redis::cmd("XCLAIM")
+            .arg("Events")
+            .arg(group)
+            .arg(group)
+            .arg(0)
+            .arg(&due)
+            .query(self)?;
+        let claimed: Vec<(String, Vec<String>)> = entries.into_iter().filter_map(|entry| entry).collect();
+        //被裁剪掉的事件不会返回，直接确认
+        for id in due.iter().filter(|id| !claimed.iter().any(|&(ref claimed, _)| claimed == *id)) {
+            println!("event {} was trimmed", id);
+            self.ack_event(group, id)?;
+        }
+        self.decode_events(group, claimed)
+    }
+
+    fn read_new(&self, group: &str, count: usize) -> Result<Vec<(String, Event)>> {
+        let reply: Option<Vec<(String, Vec<(String, Vec<String>)>)>> = redis::cmd("XREADGROUP")
+            .arg("GROUP")
+            .arg(group)
+            .arg(group)
+            .arg("COUNT")
+            .arg(count)
+            .arg("STREAMS")
+            .arg("Events")
+            .arg(">")
+            .query(self)?;
+        let mut events = Vec::new();
+        for (_, entries) in reply.unwrap_or_default() {
+            events.extend(self.decode_events(group, entries)?);
+        }
+        Ok(events)
+    }
+
+    //fields为[event, json]，无法解析的事件直接确认
+    fn decode_events(&self, group: &str, entries: Vec<(String, Vec<String>)>) -> Result<Vec<(String, Event)>> {
+        let mut events = Vec::new();
+        for (id, fields) in entries {
+            match fields.get(1).map(|data| serde_json::from_str(data)) {
+                Some(Ok(event)) => events.push((id, event)),
+                _ => {
+                    println!("event {} can't decode: {:?}", id, fields);
+                    self.ack_event(group, &id)?;
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    pub fn ack_event(&self, group: &str, id: &str) -> Result<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("XACK")
+            .arg("Events")
+            .arg(group)
+            .arg(id)
+            .hdel(format!("EventFailures:{}", group), id)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn fail_event(&self, group: &str, id: &str) -> Result<i64> {
+        self.hincr(format!("EventFailures:{}", group), id, 1)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //DeadEvents保存多次失败的事件，和确认放在同一个MULTI里
+    pub fn dead_letter_event(&self, group: &str, id: &str, event: &Event) -> Result<()> {
+        let data = serde_json::to_string(event).map_err(|err| ServiceError::String(format!("{:?}", err)))?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("XADD")
+            .arg("DeadEvents")
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(10000)
+            .arg("*")
+            .arg("group")
+            .arg(group)
+            .arg("id")
+            .arg(id)
+            .arg("event")
+            .arg(data)
+            .ignore()
+            .cmd("XACK")
+            .arg("Events")
+            .arg(group)
+            .arg(id)
+            .hdel(format!("EventFailures:{}", group), id)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn get_dead_events(&self, count: usize) -> Result<Vec<(String, Event)>> {
+        let entries: Vec<(String, Vec<String>)> = redis::cmd("XREVRANGE")
+            .arg("DeadEvents")
+            .arg("+")
+            .arg("-")
+            .arg("COUNT")
+            .arg(count)
+            .query(self)?;
+        //fields为[group, 消费组, id, 原事件id, event, json]
+        Ok(
+            entries
+                .into_iter()
+                .filter_map(|(_, fields)| {
+                    let event = serde_json::from_str(fields.get(5)?).ok()?;
+                    Some((fields.get(1)?.clone(), event))
+                })
+                .collect(),
+        )
+    }
+
+    pub fn publish_seats(&self, update: &entity::SeatUpdate) -> Result<()> {
+        let data = serde_json::to_string(update).map_err(|err| ServiceError::String(format!("{:?}", err)))?;
+        self.publish(format!("TripSeats:{}", update.trip_id), data)
+            .map(|_: i64| ())
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //UserMessages:{openid}推送该用户收到的消息
+    pub fn publish_message(&self, receiver: &str, m: &entity::Message) -> Result<()> {
+        let data = serde_json::to_string(m).map_err(|err| ServiceError::String(format!("{:?}", err)))?;
+        self.publish(format!("UserMessages:{}", receiver), data)
+            .map(|_: i64| ())
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //MessageRead:{conversation_id}保存每个成员已读到的seq
+    pub fn mark_read(&self, conversation_id: &str, openid: &str, seq: i64) -> Result<()> {
+        let read_key = format!("MessageRead:{}", conversation_id);
+        redis::transaction(self, &[&read_key], |pipe| {
+            let old: Option<i64> = self.hget(&read_key, openid)?;
+            if old.map_or(false, |old| old >= seq) {
+                return pipe.query(self).map(|_: ()| Some(()));
+            }
+            pipe.hset(&read_key, openid, seq);
+            pipe.query(self).map(|_: ()| Some(()))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //RideRequests保存待接单的需求id，UserRideRequests:{openid}保存用户发布的需求id
+    pub fn add_ride_request(&self, r: &entity::RideRequest, events: &[Event]) -> Result<()> {
+        let request_key = format!("{}:{}", entity::RideRequest::get_name(), r.id);
+        let events = encode_events(events)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(
+                &request_key,
+                &[("_id", &r.id), ("openid", &r.openid), ("start", &r.start), ("end", &r.end)],
+            )
+            .hset_multiple(
+                &request_key,
+                &[
+                    ("earliest", r.earliest),
+                    ("latest", r.latest),
+                    ("count", r.count),
+                    ("max_price", r.max_price),
+                    ("create_time", r.create_time),
+                ],
+            )
+            .hset(&request_key, "status", &r.status)
+            .lpush("RideRequests", &r.id)
+            .lpush(format!("UserRideRequests:{}", r.openid), &r.id);
+        if let Some(ref tel) = r.tel {
+            pipe.hset(&request_key, "tel", pii::seal(tel)?);
+        }
+        if let Some(ref msg) = r.message {
+            pipe.hset(&request_key, "message", msg);
+        }
+        add_events(&mut pipe, &events);
+        pipe.query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn close_ride_request(&self, id: &str, status: &entity::RideRequestStatus) -> Result<bool> {
+        let removed: i64 = self.lrem("RideRequests", 1, id)?;
+        if removed == 0 {
+            return Ok(false);
+        }
+        self.hset(format!("RideRequest:{}", id), "status", status)
+            .map(|_: i64| true)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn reopen_ride_request(&self, id: &str) -> Result<()> {
+        redis::pipe()
+            .atomic()
+            .lpush("RideRequests", id)
+            .hset(format!("RideRequest:{}", id), "status", &entity::RideRequestStatus::Open)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn set_ride_request_order(&self, id: &str, trip_id: &str, order_id: &str, events: &[Event]) -> Result<()> {
+        let events = encode_events(events)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().hset_multiple(
+            format!("RideRequest:{}", id),
+            &[("trip_id", trip_id), ("order_id", order_id)],
+        );
+        add_events(&mut pipe, &events);
+        pipe.query(self).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn add_match(&self, request_id: &str, trip_id: &str, events: &[Event]) -> Result<bool> {
+        let matches_key = format!("RideMatches:{}", request_id);
+        let events = encode_events(events)?;
+        redis::transaction(self, &[&matches_key], |pipe| {
+            let matched: bool = self.sismember(&matches_key, trip_id)?;
+            if matched {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            pipe.sadd(&matches_key, trip_id);
+            add_events(pipe, &events);
+            pipe.query(self).map(|_: ()| Some(true))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //Redemption:{order_id}保存使用记录，CouponUses:{coupon_id}保存每个用户的使用次数
+    pub fn redeem_coupon(&self, r: &entity::Redemption, limit: i64) -> Result<bool> {
+        let uses_key = format!("CouponUses:{}", r.coupon_id);
+        let redemption_key = format!("{}:{}", entity::Redemption::get_name(), r.order_id);
+        redis::transaction(self, &[&uses_key], |pipe| {
+            let used: Option<i64> = self.hget(&uses_key, &r.openid)?;
+            if used.unwrap_or(0) >= limit {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            pipe.hincr(&uses_key, &r.openid, 1)
+                .hset_multiple(
+                    &redemption_key,
+                    &[("order_id", &r.order_id), ("coupon_id", &r.coupon_id), ("openid", &r.openid)],
+                )
+                .hset_multiple(
+                    &redemption_key,
+                    &[("discount", r.discount), ("create_time", r.create_time)],
+                )
+                .lpush(format!("CouponRedemptions:{}", r.coupon_id), &r.order_id)
+                .query(self)
+                .map(|_: ()| Some(true))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //删除成功的一方负责退还次数，保证同一订单只退还一次
+    pub fn release_coupon(&self, order_id: &str) -> Result<Option<entity::Redemption>> {
+        let r: entity::Redemption = match self.get_object(order_id) {
+            Ok(r) => r,
+            Err(ServiceError::NotFound(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let removed: i64 = self.del(format!("{}:{}", entity::Redemption::get_name(), order_id))?;
+        if removed == 0 {
+            return Ok(None);
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hincr(format!("CouponUses:{}", r.coupon_id), &r.openid, -1)
+            .lrem(format!("CouponRedemptions:{}", r.coupon_id), 1, order_id)
+            .query(self)
+            .map(|_: ()| Some(r))
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //Users保存首次登录时间，UserDevices保存最近登录的设备
+    pub fn add_login(&self, openid: &str, device: Option<&str>) -> Result<bool> {
+        if let Some(device) = device {
+            let _: () = self.hset("UserDevices", openid, device)?;
+        }
+        self.hset_nx("Users", openid, util::now())
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //ReferralCodes保存邀请码到用户，UserReferralCodes保存用户到邀请码
+    pub fn add_referral_code(&self, openid: &str, code: &str) -> Result<bool> {
+        redis::transaction(self, &["ReferralCodes", "UserReferralCodes"], |pipe| {
+            let owner: Option<String> = self.hget("ReferralCodes", code)?;
+            let existing: Option<String> = self.hget("UserReferralCodes", openid)?;
+            if owner.is_some() || existing.is_some() {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            pipe.hset("ReferralCodes", code, openid)
+                .hset("UserReferralCodes", openid, code)
+                .query(self)
+                .map(|_: ()| Some(true))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //Referral:{referee}保存邀请关系，Referrals按时间保存所有邀请用于运营统计
+    pub fn add_referral(&self, r: &entity::Referral) -> Result<bool> {
+        let referral_key = format!("{}:{}", entity::Referral::get_name(), r.id);
+        redis::transaction(self, &[&referral_key], |pipe| {
+            let exists: bool = self.exists(&referral_key)?;
+            if exists {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            pipe.hset_multiple(
+                &referral_key,
+                &[("_id", &r.id), ("referrer", &r.referrer), ("code", &r.code)],
+            ).hset(&referral_key, "status", &r.status)
+                .hset(&referral_key, "create_time", r.create_time)
+                .lpush("Referrals", &r.id)
+                .lpush(format!("UserReferrals:{}", r.referrer), &r.id);
+            if let Some(ref device) = r.device {
+                pipe.hset(&referral_key, "device", device);
+            }
+            if let Some(ref reason) = r.reason {
+                pipe.hset(&referral_key, "reason", reason);
+            }
+            if let Some(finish_time) = r.finish_time {
+                pipe.hset(&referral_key, "finish_time", finish_time);
+            }
+            pipe.query(self).map(|_: ()| Some(true))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn get_referral(&self, referee: &str) -> Result<Option<entity::Referral>> {
+        match self.get_object(referee) {
+            Ok(r) => Ok(Some(r)),
+            Err(ServiceError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn finish_referral(&self, referee: &str, status: &entity::ReferralStatus, reason: Option<&str>, order_id: &str) -> Result<bool> {
+        let referral_key = format!("{}:{}", entity::Referral::get_name(), referee);
+        redis::transaction(self, &[&referral_key], |pipe| {
+            let old: Option<entity::ReferralStatus> = self.hget(&referral_key, "status")?;
+            if old != Some(entity::ReferralStatus::Pending) {
+                return pipe.query(self).map(|_: ()| Some(false));
+            }
+            pipe.hset(&referral_key, "status", status)
+                .hset(&referral_key, "order_id", order_id)
+                .hset(&referral_key, "finish_time", util::now());
+            if let Some(reason) = reason {
+                pipe.hset(&referral_key, "reason", reason);
+            }
+            pipe.query(self).map(|_: ()| Some(true))
+        }).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //TemplateTrips:{template_id}保存日期到行程id的对应
+    pub fn claim_occurrence(&self, template_id: &str, date: &str, trip_id: &str) -> Result<bool> {
+        self.hset_nx(format!("TemplateTrips:{}", template_id), date, trip_id)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn get_occurrence(&self, template_id: &str, date: &str) -> Result<Option<String>> {
+        self.hget(format!("TemplateTrips:{}", template_id), date)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn release_occurrence(&self, template_id: &str, date: &str) -> Result<()> {
+        self.hdel(format!("TemplateTrips:{}", template_id), date)
+            .map(|_: i64| ())
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //Waitlist:{trip_id}按加入顺序保存等待中的候补id，OrderWaiter:{order_id}指向获得预留订单的候补
+    pub fn add_waiter(&self, w: &entity::Waiter) -> Result<()> {
+        let waiter_key = format!("{}:{}", entity::Waiter::get_name(), w.id);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(
+                &waiter_key,
+                &[("_id", &w.id), ("trip_id", &w.trip_id), ("openid", &w.openid)],
+            )
+            .hset_multiple(
+                &waiter_key,
+                &[("count", w.count), ("create_time", w.create_time)],
+            )
+            .hset(&waiter_key, "status", &w.status)
+            .rpush(format!("Waitlist:{}", w.trip_id), &w.id)
+            .lpush(format!("UserWaiters:{}", w.openid), &w.id);
+        if let Some(ref tel) = w.tel {
+            pipe.hset(&waiter_key, "tel", pii::seal(tel)?);
+        }
+        pipe.query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn take_waiter(&self, w: &entity::Waiter, status: &entity::WaitStatus) -> Result<bool> {
+        let removed: i64 = self.lrem(format!("Waitlist:{}", w.trip_id), 1, &w.id)?;
+        if removed == 0 {
+            return Ok(false);
+        }
+        self.hset(format!("Waiter:{}", w.id), "status", status)
+            .map(|_: i64| true)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn requeue_waiter(&self, w: &entity::Waiter) -> Result<()> {
+        redis::pipe()
+            .atomic()
+            .lpush(format!("Waitlist:{}", w.trip_id), &w.id)
+            .hset(format!("Waiter:{}", w.id), "status", &entity::WaitStatus::Waiting)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn offer_waiter(&self, id: &str, order_id: &str, events: &[Event]) -> Result<()> {
+        let events = encode_events(events)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset(format!("Waiter:{}", id), "order_id", order_id)
+            .set(format!("OrderWaiter:{}", order_id), id);
+        add_events(&mut pipe, &events);
+        pipe.query(self).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    pub fn close_offer(&self, order_id: &str, status: &entity::WaitStatus) -> Result<Option<entity::Waiter>> {
+        let offer_key = format!("OrderWaiter:{}", order_id);
+        let (id, _): (Option<String>, i32) = redis::pipe()
+            .atomic()
+            .get(&offer_key)
+            .del(&offer_key)
+            .query(self)?;
+        match id {
+            Some(id) => {
+                let _: i64 = self.hset(format!("Waiter:{}", id), "status", status)?;
+                self.get_object(&id).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    //有序集合保存窗口内每次请求的毫秒时间戳
+    //行程、订单、需求、候补中的个人信息字段，订单同时移出手机号索引
+    pub fn erase_user(&self, openid: &str) -> Result<()> {
+        let trip_keys: Vec<String> = self.smembers(format!("UserTrips:{}", openid))?;
+        let order_keys: Vec<String> = self.smembers(format!("UserOrders:{}", openid))?;
+        let request_ids: Vec<String> = self.lrange(format!("UserRideRequests:{}", openid), 0, -1)?;
+        let waiter_ids: Vec<String> = self.lrange(format!("UserWaiters:{}", openid), 0, -1)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for key in &trip_keys {
+            pipe.hset_multiple(key, &[("tel", ""), ("plate_number", ""), ("car_type", "")])
+                .hdel(key, "message");
+        }
+        for key in &order_keys {
+            let tel = key.splitn(2, ':').nth(1)
+                .and_then(|id| skip_broken(id, self.get_object::<entity::Order>(id)))
+                .and_then(|order| order.tel);
+            if let Some(tel) = tel {
+                pipe.srem(format!("TelOrders:{}", pii::blind_index(&tel)), key);
+            }
+            pipe.hdel(key, "tel");
+        }
+        for id in &request_ids {
+            pipe.hdel(format!("{}:{}", entity::RideRequest::get_name(), id), vec!["tel", "message"]);
+        }
+        for id in &waiter_ids {
+            pipe.hdel(format!("{}:{}", entity::Waiter::get_name(), id), "tel");
+        }
+        //邀请关系保留用于防止重复邀请，只删除设备
+        let referral_key = format!("{}:{}", entity::Referral::get_name(), openid);
+        pipe.hdel(&referral_key, "device");
+        let code: Option<String> = self.hget("UserReferralCodes", openid)?;
+        if let Some(code) = code {
+            pipe.hdel("ReferralCodes", code);
+        }
+        pipe.hdel("UserReferralCodes", openid)
+            .hdel("Users", openid)
+            .hdel("UserDevices", openid)
+            .query(self)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    //按id列表读取对象，列表中保存的是id
+    fn get_list<'de, T>(&self, list_key: &str, start: isize, end: isize) -> Result<Vec<T>>
+    where
+        T: GetName + Deserialize<'de>,
+    {
+        let ids: Vec<String> = self.lrange(list_key, start, end)?;
+        Ok(
+            ids.iter()
+                .filter_map(|id| skip_broken(id, self.get_object::<T>(id)))
+                .collect(),
+        )
+    }
+
+    pub fn get_object<'de, T>(&self, id: &str) -> Result<T>
+    where
+        T: GetName + Deserialize<'de>,
+    {
+        let value: redis::Value = self.hgetall(format!("{}:{}", T::get_name(), id))?;
+        if value == redis::Value::Bulk(Vec::new()) {
+            return Err(ServiceError::NotFound(T::get_name().to_owned()));
+        }
+        pii::open_value(value)?.deserialize().map_err(
+            |err| ServiceError::RedisDecodeError(err),
+        )
+    }
+}
+
+impl TripStore for Storage {
+    fn add_trip(&self, t: &entity::Trip, events: &[Event]) -> Result<()> {
+        self.cache.add_trip(t, events)
+    }
+
+    fn get_trip(&self, id: &str) -> Result<entity::Trip> {
+        self.cache.get_trip(id)
+    }
+
+    fn get_trips(&self, start: isize, end: isize) -> Result<Vec<entity::Trip>> {
+        self.cache.get_trips(start, end)
+    }
+
+    fn check_trip_finish(&self, id: &str, events: &[Event]) -> Result<bool> {
+        self.cache.check_trip_finish(id, events)
+    }
+
+    fn remove_trip(&self, id: &str) -> Result<bool> {
+        self.cache.remove_trip(id)
+    }
+}
+
+impl OrderStore for Storage {
+    fn add_order(&self, order: &entity::Order, events: &[Event]) -> Result<()> {
+        self.cache.add_order(order, events)
+    }
+
+    fn get_order(&self, id: &str) -> Result<entity::Order> {
+        self.cache.get_object(id)
+    }
+
+    fn pay_order(&self, id: &str, transaction_id: &str, events: &[Event]) -> Result<bool> {
+        self.cache.pay_order(id, transaction_id, events)
+    }
+
+    fn add_refund(&self, order_id: &str, refunded: i64, fee: i64) -> Result<()> {
+        self.cache.add_refund(order_id, refunded, fee)
+    }
+
+    fn submit_order(&self, order: &entity::Order, income: i64, events: &[Event]) -> Result<String> {
+        self.cache.submit_order(order, income, events)
+    }
+
+    fn expire_order(&self, id: &str) -> Result<Option<String>> {
+        self.cache.expire_order(id)
+    }
+
+    fn get_user_orders(&self, openid: &str) -> Result<Vec<entity::Order>> {
+        self.cache.get_user_orders(openid)
+    }
+
+    fn get_trip_orders(&self, trip_id: &str) -> Result<Vec<entity::Order>> {
+        self.cache.get_trip_orders(trip_id)
+    }
+
+    fn find_orders_by_tel(&self, tel: &str) -> Result<Vec<entity::Order>> {
+        let keys: Vec<String> = self.cache.smembers(format!("TelOrders:{}", pii::blind_index(tel)))?;
+        Ok(
+            keys.iter()
+                .filter_map(|key| key.splitn(2, ':').nth(1))
+                .filter_map(|id| skip_broken(id, self.cache.get_object::<entity::Order>(id)))
+                .collect(),
+        )
+    }
+
+    fn get_expired_count(&self, openid: &str) -> Result<i64> {
+        self.cache.get_expired_count(openid)
+    }
+}
+
+impl WalletStore for Storage {
+    fn get_wallet(&self, openid: &str) -> Result<entity::Wallet> {
+        self.cache.get_wallet(openid)
+    }
+
+    fn add_withdraw(&self, w: &entity::Withdraw, day_count: i64, day_amount: i64) -> Result<()> {
+        self.cache.add_withdraw(w, day_count, day_amount)
+    }
+
+    fn claim_withdraw(&self, id: &str) -> Result<entity::Withdraw> {
+        self.cache.claim_withdraw(id)
+    }
+
+    fn release_withdraw(&self, id: &str) -> Result<()> {
+        self.cache.release_withdraw(id)
+    }
+
+    fn finish_withdraw(&self, w: &entity::Withdraw, approved: bool) -> Result<()> {
+        self.cache.finish_withdraw(w, approved)
+    }
+
+    fn get_processing_withdraws(&self, before: i64) -> Result<Vec<entity::Withdraw>> {
+        self.cache.get_processing_withdraws(before)
+    }
+
+    fn get_withdraws(&self, openid: &str, start: isize, end: isize) -> Result<Vec<entity::Withdraw>> {
+        self.cache.get_withdraws(openid, start, end)
+    }
+
+    fn get_pending_withdraws(&self, start: isize, end: isize) -> Result<Vec<entity::Withdraw>> {
+        self.cache.get_pending_withdraws(start, end)
+    }
+
+    fn get_wallet_logs(&self, openid: &str, start: isize, end: isize) -> Result<Vec<entity::WalletLog>> {
+        self.cache.get_wallet_logs(openid, start, end)
+    }
+
+    fn credit_wallet(&self, openid: &str, amount: i64, reason: &str, ref_id: &str) -> Result<()> {
+        self.cache.credit_wallet(openid, amount, reason, ref_id)
+    }
+}
+
+impl TokenStore for Storage {
+    fn add_refresh_token(&self, token: &str, openid: &str, ttl: i64) -> Result<()> {
+        self.cache.add_refresh_token(token, openid, ttl)
+    }
+
+    fn take_refresh_token(&self, token: &str) -> Result<Option<String>> {
+        self.cache.take_refresh_token(token)
+    }
+
+    fn remove_refresh_tokens(&self, openid: &str) -> Result<()> {
+        self.cache.remove_refresh_tokens(openid)
+    }
+
+    fn revoke_token(&self, jti: &str, ttl: i64) -> Result<()> {
+        self.cache.revoke_token(jti, ttl)
+    }
+
+    fn is_revoked(&self, jti: &str) -> Result<bool> {
+        self.cache.is_revoked(jti)
+    }
+
+    fn add_access_token(&self, openid: &str, jti: &str, ttl: i64) -> Result<()> {
+        self.cache.add_access_token(openid, jti, ttl)
+    }
+
+    fn revoke_user_tokens(&self, openid: &str) -> Result<()> {
+        self.cache.revoke_user_tokens(openid)
+    }
+}
+
+impl AuditStore for Storage {
+    fn add_audit(&self, log: &entity::AuditLog) -> Result<()> {
+        self.conn.add(log).map(|_| ())
+    }
+
+    fn get_audits(&self, entity: &str, entity_id: &str) -> Result<Vec<entity::AuditLog>> {
+        let mut filter = Document::new();
+        filter.insert("entity", entity);
+        filter.insert("entity_id", entity_id);
+        let mut sort = Document::new();
+        sort.insert("time", 1);
+        sort.insert("_id", 1);
+        self.conn.find(filter, sort)
+    }
+}
+
+impl EventStore for Storage {
+    fn publish_event(&self, event: &Event) -> Result<()> {
+        self.cache.publish_event(event)
+    }
+
+    fn add_event_group(&self, group: &str) -> Result<()> {
+        self.cache.add_event_group(group)
+    }
+
+    fn read_events(&self, group: &str, count: usize) -> Result<Vec<(String, Event)>> {
+        self.cache.read_events(group, count)
+    }
+
+    fn ack_event(&self, group: &str, id: &str) -> Result<()> {
+        self.cache.ack_event(group, id)
+    }
+
+    fn fail_event(&self, group: &str, id: &str) -> Result<i64> {
+        self.cache.fail_event(group, id)
+    }
+
+    fn dead_letter_event(&self, group: &str, id: &str, event: &Event) -> Result<()> {
+        self.cache.dead_letter_event(group, id, event)
+    }
+
+    fn get_dead_events(&self, count: usize) -> Result<Vec<(String, Event)>> {
+        self.cache.get_dead_events(count)
+    }
+}
+
+impl SeatStore for Storage {
+    fn publish_seats(&self, update: &entity::SeatUpdate) -> Result<()> {
+        self.cache.publish_seats(update)
+    }
+
+    //订阅需要独占一个连接，不使用连接池
+    fn subscribe_seats(&self, trip_ids: &[String]) -> Result<SeatUpdates> {
+        let client = redis::Client::open(setting::get().app.redis.as_str())?;
+        let mut pubsub = client.get_pubsub()?;
+        for id in trip_ids {
+            pubsub.subscribe(format!("TripSeats:{}", id))?;
+        }
+        pubsub.set_read_timeout(Some(Duration::from_secs(HEARTBEAT)))?;
+        Ok(Box::new(Subscription(pubsub, PhantomData)))
+    }
+}
+
+//两次心跳之间的秒数
+pub const HEARTBEAT: u64 = 15;
+
+struct Subscription<T>(redis::PubSub, PhantomData<T>);
+
+impl<T: DeserializeOwned> Iterator for Subscription<T> {
+    type Item = Option<T>;
+
+    //超时返回Some(None)，连接断开时结束
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.get_message() {
+            Ok(msg) => {
+                let data: String = msg.get_payload().ok()?;
+                Some(serde_json::from_str(&data).ok())
+            }
+            Err(ref err) if err.is_timeout() => Some(None),
+            Err(err) => {
+                println!("subscription closed: {:?}", err);
+                None
+            }
+        }
+    }
+}
+
+//模板保存在mongodb的文档，手机号加密，tel_index为手机号的盲索引，用于客服按手机号查找
+pub fn template_document(t: &entity::TripTemplate) -> Result<Document> {
+    let mut doc = to_doc(t)?;
+    doc.insert("tel", pii::seal(&t.tel)?);
+    doc.insert("tel_index", pii::blind_index(&t.tel));
+    Ok(doc)
+}
+
+impl TemplateStore for Storage {
+    fn add_template(&self, t: &entity::TripTemplate) -> Result<()> {
+        self.conn.add_document::<entity::TripTemplate>(template_document(t)?).map(|_| ())
+    }
+
+    fn get_template(&self, id: &str) -> Result<entity::TripTemplate> {
+        self.conn.get_one(id)
+    }
+
+    fn update_template(&self, t: &entity::TripTemplate) -> Result<()> {
+        self.conn.replace_document::<entity::TripTemplate>(&t.id, template_document(t)?)
+    }
+
+    fn get_templates(&self, openid: &str) -> Result<Vec<entity::TripTemplate>> {
+        let mut filter = Document::new();
+        filter.insert("openid", openid);
+        let mut sort = Document::new();
+        sort.insert("create_time", -1);
+        sort.insert("_id", -1);
+        self.conn.find(filter, sort)
+    }
+
+    fn find_templates_by_tel(&self, tel: &str) -> Result<Vec<entity::TripTemplate>> {
+        let mut filter = Document::new();
+        filter.insert("tel_index", pii::blind_index(tel));
+        let mut sort = Document::new();
+        sort.insert("create_time", -1);
+        sort.insert("_id", -1);
+        self.conn.find(filter, sort)
+    }
+
+    fn get_active_templates(&self, date: &str) -> Result<Vec<entity::TripTemplate>> {
+        let mut gte = Document::new();
+        gte.insert("$gte", date);
+        let mut filter = Document::new();
+        filter.insert("end_date", gte);
+        let mut sort = Document::new();
+        sort.insert("_id", 1);
+        self.conn.find(filter, sort)
+    }
+
+    fn claim_occurrence(&self, template_id: &str, date: &str, trip_id: &str) -> Result<bool> {
+        self.cache.claim_occurrence(template_id, date, trip_id)
+    }
+
+    fn get_occurrence(&self, template_id: &str, date: &str) -> Result<Option<String>> {
+        self.cache.get_occurrence(template_id, date)
+    }
+
+    fn release_occurrence(&self, template_id: &str, date: &str) -> Result<()> {
+        self.cache.release_occurrence(template_id, date)
+    }
+}
+
+impl RideRequestStore for Storage {
+    fn add_ride_request(&self, r: &entity::RideRequest, events: &[Event]) -> Result<()> {
+        self.cache.add_ride_request(r, events)
+    }
+
+    fn get_ride_request(&self, id: &str) -> Result<entity::RideRequest> {
+        self.cache.get_object(id)
+    }
+
+    fn get_open_ride_requests(&self) -> Result<Vec<entity::RideRequest>> {
+        self.cache.get_list("RideRequests", 0, -1)
+    }
+
+    fn get_user_ride_requests(&self, openid: &str) -> Result<Vec<entity::RideRequest>> {
+        self.cache.get_list(&format!("UserRideRequests:{}", openid), 0, -1)
+    }
+
+    fn close_ride_request(&self, id: &str, status: &entity::RideRequestStatus) -> Result<bool> {
+        self.cache.close_ride_request(id, status)
+    }
+
+    fn reopen_ride_request(&self, id: &str) -> Result<()> {
+        self.cache.reopen_ride_request(id)
+    }
+
+    fn set_ride_request_order(&self, id: &str, trip_id: &str, order_id: &str, events: &[Event]) -> Result<()> {
+        self.cache.set_ride_request_order(id, trip_id, order_id, events)
+    }
+}
+
+impl CouponStore for Storage {
+    fn add_coupon(&self, c: &entity::Coupon) -> Result<()> {
+        self.conn.add(c).map(|_| ())
+    }
+
+    fn find_coupon(&self, code: &str) -> Result<Option<entity::Coupon>> {
+        let mut filter = Document::new();
+        filter.insert("code", code);
+        let mut sort = Document::new();
+        sort.insert("_id", -1);
+        self.conn.find(filter, sort).map(|coupons: Vec<entity::Coupon>| coupons.into_iter().next())
+    }
+
+    fn get_coupons(&self) -> Result<Vec<entity::Coupon>> {
+        let mut sort = Document::new();
+        sort.insert("create_time", -1);
+        sort.insert("_id", -1);
+        self.conn.find(Document::new(), sort)
+    }
+
+    fn redeem_coupon(&self, r: &entity::Redemption, limit: i64) -> Result<bool> {
+        self.cache.redeem_coupon(r, limit)
+    }
+
+    fn release_coupon(&self, order_id: &str) -> Result<Option<entity::Redemption>> {
+        self.cache.release_coupon(order_id)
+    }
+
+    fn get_redemptions(&self, coupon_id: &str) -> Result<Vec<entity::Redemption>> {
+        self.cache.get_list(&format!("CouponRedemptions:{}", coupon_id), 0, -1)
+    }
+
+    fn get_user_coupons(&self, openid: &str) -> Result<Vec<entity::Coupon>> {
+        let mut filter = Document::new();
+        filter.insert("owner", openid);
+        let mut sort = Document::new();
+        sort.insert("create_time", -1);
+        sort.insert("_id", -1);
+        self.conn.find(filter, sort)
+    }
+}
+
+impl ReferralStore for Storage {
+    fn add_login(&self, openid: &str, device: Option<&str>) -> Result<bool> {
+        self.cache.add_login(openid, device)
+    }
+
+    fn get_device(&self, openid: &str) -> Result<Option<String>> {
+        self.cache.hget("UserDevices", openid).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn get_referral_code(&self, openid: &str) -> Result<Option<String>> {
+        self.cache.hget("UserReferralCodes", openid).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn add_referral_code(&self, openid: &str, code: &str) -> Result<bool> {
+        self.cache.add_referral_code(openid, code)
+    }
+
+    fn find_referrer(&self, code: &str) -> Result<Option<String>> {
+        self.cache.hget("ReferralCodes", code).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn claim_fingerprint(&self, fingerprint: &str) -> Result<bool> {
+        self.cache.sadd("ReferralFingerprints", fingerprint)
+            .map(|added: i64| added == 1)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn add_referral(&self, r: &entity::Referral) -> Result<bool> {
+        self.cache.add_referral(r)
+    }
+
+    fn get_referral(&self, referee: &str) -> Result<Option<entity::Referral>> {
+        self.cache.get_referral(referee)
+    }
+
+    fn finish_referral(&self, referee: &str, status: &entity::ReferralStatus, reason: Option<&str>, order_id: &str) -> Result<bool> {
+        self.cache.finish_referral(referee, status, reason, order_id)
+    }
+
+    fn get_referral_grants(&self, referee: &str) -> Result<Vec<String>> {
+        self.cache.smembers(format!("ReferralGrants:{}", referee)).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn add_referral_grant(&self, referee: &str, openid: &str, events: &[Event]) -> Result<()> {
+        let events = encode_events(events)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().sadd(format!("ReferralGrants:{}", referee), openid);
+        add_events(&mut pipe, &events);
+        pipe.query(&self.cache).map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn get_referrals(&self, start: isize, end: isize) -> Result<Vec<entity::Referral>> {
+        self.cache.get_list("Referrals", start, end)
+    }
+
+    fn get_user_referrals(&self, referrer: &str) -> Result<Vec<entity::Referral>> {
+        self.cache.get_list(&format!("UserReferrals:{}", referrer), 0, -1)
+    }
+}
+
+impl MatchStore for Storage {
+    fn add_match(&self, request_id: &str, trip_id: &str, events: &[Event]) -> Result<bool> {
+        self.cache.add_match(request_id, trip_id, events)
+    }
+}
+
+impl WaitlistStore for Storage {
+    fn add_waiter(&self, w: &entity::Waiter) -> Result<()> {
+        self.cache.add_waiter(w)
+    }
+
+    fn get_waiter(&self, id: &str) -> Result<entity::Waiter> {
+        self.cache.get_object(id)
+    }
+
+    fn get_waiters(&self, trip_id: &str) -> Result<Vec<entity::Waiter>> {
+        self.cache.get_list(&format!("Waitlist:{}", trip_id), 0, -1)
+    }
+
+    fn get_user_waiters(&self, openid: &str) -> Result<Vec<entity::Waiter>> {
+        self.cache.get_list(&format!("UserWaiters:{}", openid), 0, -1)
+    }
+
+    fn take_waiter(&self, w: &entity::Waiter, status: &entity::WaitStatus) -> Result<bool> {
+        self.cache.take_waiter(w, status)
+    }
+
+    fn requeue_waiter(&self, w: &entity::Waiter) -> Result<()> {
+        self.cache.requeue_waiter(w)
+    }
+
+    fn offer_waiter(&self, id: &str, order_id: &str, events: &[Event]) -> Result<()> {
+        self.cache.offer_waiter(id, order_id, events)
+    }
+
+    fn close_offer(&self, order_id: &str, status: &entity::WaitStatus) -> Result<Option<entity::Waiter>> {
+        self.cache.close_offer(order_id, status)
+    }
+}
+
+impl MessageStore for Storage {
+    fn add_conversation(&self, c: &entity::Conversation) -> Result<()> {
+        self.conn.add(c).map(|_| ())
+    }
+
+    fn get_conversation(&self, id: &str) -> Result<Option<entity::Conversation>> {
+        let mut filter = Document::new();
+        filter.insert("_id", id);
+        self.conn.find(filter, Document::new()).map(|conversations: Vec<entity::Conversation>| conversations.into_iter().next())
+    }
+
+    fn update_conversation(&self, c: &entity::Conversation) -> Result<()> {
+        self.conn.replace(&c.id, c)
+    }
+
+    fn get_user_conversations(&self, openid: &str) -> Result<Vec<entity::Conversation>> {
+        let mut driver = Document::new();
+        driver.insert("driver", openid);
+        let mut passenger = Document::new();
+        passenger.insert("passenger", openid);
+        let mut filter = Document::new();
+        filter.insert("$or", vec![Bson::Document(driver), Bson::Document(passenger)]);
+        let mut sort = Document::new();
+        sort.insert("create_time", -1);
+        sort.insert("_id", -1);
+        self.conn.find(filter, sort)
+    }
+
+    fn get_trip_conversations(&self, trip_id: &str) -> Result<Vec<entity::Conversation>> {
+        let mut filter = Document::new();
+        filter.insert("trip_id", trip_id);
+        let mut sort = Document::new();
+        sort.insert("create_time", -1);
+        sort.insert("_id", -1);
+        self.conn.find(filter, sort)
+    }
+
+    //MessageSeq:{conversation_id}为会话最后一条消息的seq
+    fn next_message_seq(&self, conversation_id: &str) -> Result<i64> {
+        self.cache.incr(format!("MessageSeq:{}", conversation_id), 1)
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn get_message_seq(&self, conversation_id: &str) -> Result<i64> {
+        self.cache.get(format!("MessageSeq:{}", conversation_id))
+            .map(|seq: Option<i64>| seq.unwrap_or(0))
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn add_message(&self, m: &entity::Message) -> Result<()> {
+        self.conn.add(m).map(|_| ())
+    }
+
+    fn get_messages(&self, conversation_id: &str, after: i64, count: usize) -> Result<Vec<entity::Message>> {
+        let mut seq = Document::new();
+        seq.insert("$gt", after);
+        let mut filter = Document::new();
+        filter.insert("conversation_id", conversation_id);
+        filter.insert("seq", seq);
+        let mut sort = Document::new();
+        sort.insert("seq", 1);
+        self.conn.find_limit(filter, sort, Some(count))
+    }
+
+    fn mark_read(&self, conversation_id: &str, openid: &str, seq: i64) -> Result<()> {
+        self.cache.mark_read(conversation_id, openid, seq)
+    }
+
+    fn get_read(&self, conversation_id: &str, openid: &str) -> Result<i64> {
+        self.cache.hget(format!("MessageRead:{}", conversation_id), openid)
+            .map(|seq: Option<i64>| seq.unwrap_or(0))
+            .map_err(|err| ServiceError::RedisError(err))
+    }
+
+    fn publish_message(&self, receiver: &str, m: &entity::Message) -> Result<()> {
+        self.cache.publish_message(receiver, m)
+    }
+
+    fn subscribe_messages(&self, openid: &str) -> Result<MessageUpdates> {
+        let client = redis::Client::open(setting::get().app.redis.as_str())?;
+        let mut pubsub = client.get_pubsub()?;
+        pubsub.subscribe(format!("UserMessages:{}", openid))?;
+        pubsub.set_read_timeout(Some(Duration::from_secs(HEARTBEAT)))?;
+        Ok(Box::new(Subscription(pubsub, PhantomData)))
+    }
+}
+
+impl AccountStore for Storage {
+    fn get_user_trips(&self, openid: &str) -> Result<Vec<entity::Trip>> {
+        let keys: Vec<String> = self.cache.smembers(format!("UserTrips:{}", openid))?;
+        let mut trips: Vec<entity::Trip> = keys.iter()
+            .filter_map(|key| key.splitn(2, ':').nth(1))
+            .filter_map(|id| skip_broken(id, self.cache.get_trip(id)))
+            .collect();
+        trips.sort_by(|a, b| b.start_time.cmp(&a.start_time));
+        Ok(trips)
+    }
+
+    fn add_complain(&self, c: &entity::Complain) -> Result<()> {
+        self.conn.add(c).map(|_| ())
+    }
+
+    fn get_user_complains(&self, openid: &str) -> Result<Vec<entity::Complain>> {
+        let mut filter = Document::new();
+        filter.insert("openid", openid);
+        let mut sort = Document::new();
+        sort.insert("_id", -1);
+        self.conn.find(filter, sort)
+    }
+
+    //发送的消息只清空内容，保留seq以免对方的会话出现空缺
+    fn erase_user(&self, openid: &str) -> Result<()> {
+        self.cache.erase_user(openid)?;
+        let mut filter = Document::new();
+        filter.insert("sender", openid);
+        let mut content = Document::new();
+        content.insert("content", "");
+        let mut update = Document::new();
+        update.insert("$set", content);
+        self.conn.update_many::<entity::Message>(filter, update)?;
+        let mut filter = Document::new();
+        filter.insert("openid", openid);
+        self.conn.delete_many::<entity::Complain>(filter)?;
+        let mut filter = Document::new();
+        filter.insert("openid", openid);
+        self.conn.delete_many::<entity::Notification>(filter)
+    }
+}
+
+impl NotificationStore for Storage {
+    fn add_notification(&self, n: &entity::Notification) -> Result<()> {
+        let mut filter = Document::new();
+        filter.insert("_id", &n.id);
+        let sort = Document::new();
+        if !self.conn.find::<entity::Notification>(filter, sort)?.is_empty() {
+            return Ok(());
+        }
+        self.conn.add(n).map(|_| ())
+    }
+
+    fn get_notifications(&self, openid: &str) -> Result<Vec<entity::Notification>> {
+        let mut filter = Document::new();
+        filter.insert("openid", openid);
+        let mut sort = Document::new();
+        sort.insert("create_time", -1);
+        self.conn.find(filter, sort)
+    }
+}
+
+//限流使用单独的redis连接，每个工作线程一个，不占用请求的连接池
+pub struct LimitConn;
+
+thread_local! {
+    static LIMIT_CONN: RefCell<Option<Connection>> = RefCell::new(None);
+}
+
+impl LimitConn {
+    fn with<T, F>(f: F) -> Result<T>
+    where
+        F: FnOnce(&Connection) -> redis::RedisResult<T>,
+    {
+        LIMIT_CONN.with(|cell| {
+            let mut cell = cell.borrow_mut();
+            let conn = match cell.take() {
+                Some(conn) => conn,
+                None => redis::Client::open(setting::get().app.redis.as_str())?.get_connection()?,
+            };
+            let result = f(&conn);
+            //出错的连接丢弃，下次重新连接
+            if result.is_ok() {
+                *cell = Some(conn);
+            }
+            result.map_err(|err| ServiceError::RedisError(err))
+        })
+    }
+}
+
+//RateLimit:{key}是请求时间（毫秒）的有序集合，成员带随机后缀，撤销时只删除本次加入的成员
+impl LimitStore for LimitConn {
+    fn hit(&self, key: &str, limit: i64, window: i64) -> Result<Option<String>> {
+        let key = format!("RateLimit:{}", key);
+        let now = util::now_millis();
+        let member = format!("{}-{}", now, util::random_string(8));
+        LimitConn::with(|conn| {
+            let (_, _, count, _): (i64, i64, i64, i64) = redis::pipe()
+                .atomic()
+                .zrembyscore(&key, 0, now - window * 1000)
+                .zadd(&key, &member, now)
+                .zcard(&key)
+                .expire(&key, window as usize)
+                .query(conn)?;
+            if count > limit {
+                let _: i64 = conn.zrem(&key, &member)?;
+                return Ok(None);
+            }
+            Ok(Some(member.clone()))
+        })
+    }
+
+    fn unhit(&self, key: &str, member: &str) -> Result<()> {
+        LimitConn::with(|conn| conn.zrem(format!("RateLimit:{}", key), member).map(|_: i64| ()))
+    }
+}
+
+pub fn check_expire(database: Database, pool: Pool) -> Result<()> {
+    let client = redis::Client::open(setting::get().app.redis.as_str())?;
+    let mut pubsub = client.get_pubsub()?;
+    pubsub.subscribe("__keyevent@0__:expired")?;
+    loop {
+        if let Err(err) = deal_expire(&mut pubsub, &database, &pool) {
+            println!("{:?}", err);
+        }
+    }
+}
+
+fn deal_expire(pubsub: &mut redis::PubSub, database: &Database, pool: &Pool) -> Result<()> {
+    let msg = pubsub.get_message()?;
+    let key: String = msg.get_payload()?;
+    let v: Vec<&str> = key.split(":").collect();
+    if v.len() != 2 || v[0] != entity::Order::get_name() {
+        metrics::inc("expire_events_total", &[("result", "ignored")]);
+        return Err(ServiceError::String(format!("key is {},can't use", key)));
+    }
+    let cache = pool.get()
+        .map(|conn| CacheConn(conn))
+        .map_err(|err| {
+            metrics::inc("redis_pool_timeouts_total", &[]);
+            metrics::inc("expire_events_total", &[("result", "error")]);
+            ServiceError::String(format!("{:?}", err))
+        })?;
+    let result = Service::new(DbConn(database.clone()), cache).expire_order(v[1]);
+    metrics::inc("expire_events_total", &[("result", if result.is_ok() { "handled" } else { "error" })]);
+    result
+}
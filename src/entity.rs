@@ -6,8 +6,11 @@ use rocket::Outcome;
 use rocket::http::{Status,RawStr};
 use rocket::request::{self, Request, FromRequest, FromFormValue};
 use bson::oid::ObjectId;
-use service::ServiceError;
+use service::{Service, ServiceError};
 use redis;
+use setting;
+use util;
+use validate::Validator;
 
 #[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
 pub struct Order {
@@ -20,9 +23,22 @@ pub struct Order {
     pub transaction_id: Option<String>,//微信支付参数 
     pub tel: Option<String>,
     pub status: OrderStatus,
-    pub price: i64,
+    pub price: i64, //所乘路段每个座位的价格
     pub count:i64,
-    pub start_time: i64
+    pub start_time: i64,
+    //上车和下车的站点序号，起点为0，旧订单没有这两项
+    #[serde(default)]
+    pub from: i64,
+    #[serde(default)]
+    pub to: i64,
+    //使用的优惠券和减免金额，减免由平台补贴，司机收入仍按price计算
+    #[serde(default)]
+    pub coupon_id: Option<String>,
+    #[serde(default)]
+    pub discount: i64,
+    //车主优惠累计退还的金额，从司机收入中扣除
+    #[serde(default)]
+    pub refunded: i64,
 }
 
 
@@ -44,9 +60,16 @@ pub struct Trip {
     pub plate_number: String,
     pub tel: String,
     pub car_type: String,
+    //以下三项在redis中单独保存，读取行程时再填充
+    #[serde(default)]
+    pub stops: Vec<String>, //途经点，不含起点和终点
+    #[serde(default)]
+    pub fares: Vec<i64>, //每一段每个座位的价格，为空时只有一段，价格为price
+    #[serde(default)]
+    pub free_seats: Vec<i64>, //每一段剩余的座位，current_seat为全程都空着的座位数
 }
 
-#[derive(FromForm)]
+#[derive(FromForm, Deserialize)]
 pub struct TripForm {
     pub seat_count : i64,
     pub start_time : i64,
@@ -58,6 +81,52 @@ pub struct TripForm {
     pub plate_number: String,
     pub car_type: String,
     pub tel: String,
+    pub stops: Option<String>, //途经点，按顺序用逗号分隔
+    pub fares: Option<String>, //每一段的价格，用逗号分隔，合计应等于price
+}
+
+#[derive(Deserialize)]
+pub struct ApplyForm {
+    pub trip_id: String,
+    pub count: i64,
+    pub tel: Option<String>,
+    pub from: Option<i64>, //不填时为全程
+    pub to: Option<i64>,
+    pub coupon: Option<String>, //优惠码
+}
+
+#[derive(Deserialize)]
+pub struct DiscountForm {
+    pub order_id: String,
+    pub fee: i64,
+}
+
+#[derive(Deserialize)]
+pub struct OrderForm {
+    pub order_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshForm {
+    pub refresh_token: String,
+}
+
+//新用户首次登录时可以填写邀请码，device为小程序生成的设备标识，用于防止刷邀请奖励
+#[derive(Deserialize)]
+pub struct LoginForm {
+    pub code: String,
+    pub referral_code: Option<String>,
+    pub device: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WithdrawForm {
+    pub amount: i64,
+}
+
+#[derive(Deserialize)]
+pub struct WithdrawIdForm {
+    pub withdraw_id: String,
 }
 
 #[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
@@ -69,6 +138,363 @@ pub enum TripStatus {
     Cancel
 }
 
+//司机钱包，金额单位为分
+#[derive(PartialEq, Debug, Serialize, Deserialize,Default,Clone)]
+pub struct Wallet {
+    pub openid: String,
+    pub balance: i64,  //可提现余额
+    pub frozen: i64,   //提现审核中的金额
+}
+
+//钱包流水，每次余额变动都会记录一条
+#[derive(PartialEq, Debug, Serialize, Deserialize,Default,Clone)]
+pub struct WalletLog {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub openid: String,
+    pub change: i64,
+    pub balance: i64,  //变动后的余额
+    pub reason: String,
+    pub ref_id: String, //关联的订单或提现id
+    pub time: i64,
+}
+
+//推送给小程序的行程座位和状态
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct SeatUpdate {
+    pub trip_id: String,
+    pub current_seat: i64,
+    pub status: TripStatus,
+}
+
+//订阅的行程id，用逗号分隔
+#[derive(FromForm)]
+pub struct SeatQuery {
+    pub ids: String,
+}
+
+//行程和订单的状态变化记录，只追加不修改
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct AuditLog {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub entity: String, //Trip或Order
+    pub entity_id: String,
+    pub from: Option<String>, //创建时为空
+    pub to: String,
+    pub actor: String, //操作用户的openid，定时任务和回调为system:开头
+    pub reason: String,
+    pub time: i64,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct Withdraw {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub openid: String,
+    pub amount: i64,
+    pub status: WithdrawStatus,
+    pub create_time: i64,
+    pub handle_time: Option<i64>,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub enum WithdrawStatus {
+    Pending,
+    Processing, //管理员已审核，正在打款
+    Approved,
+    Rejected,
+}
+
+//乘客发布的用车需求，车主接单后生成订单
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct RideRequest {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub openid: String,
+    pub start: String,
+    pub end: String,
+    pub earliest: i64, //可以出发的时间范围
+    pub latest: i64,
+    pub count: i64,
+    pub max_price: i64, //每个座位最高价格
+    pub tel: Option<String>,
+    pub message: Option<String>,
+    pub status: RideRequestStatus,
+    pub trip_id: Option<String>,
+    pub order_id: Option<String>,
+    pub create_time: i64,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub enum RideRequestStatus {
+    Open,
+    Accepted,
+    Cancelled,
+}
+
+#[derive(FromForm, Deserialize)]
+pub struct RideRequestForm {
+    pub start: String,
+    pub end: String,
+    pub earliest: i64,
+    pub latest: i64,
+    pub count: i64,
+    pub max_price: i64,
+    pub tel: Option<String>,
+    pub message: Option<String>,
+}
+
+//车主查看需求时的筛选条件，起点终点按包含匹配，时间与需求的出发范围有交集即可
+#[derive(FromForm)]
+pub struct RideRequestQuery {
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub page: Option<isize>,
+}
+
+//自动匹配的结果，score为0-100
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct TripMatch {
+    pub score: i64,
+    pub trip: Trip,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct RequestMatch {
+    pub score: i64,
+    pub request: RideRequest,
+}
+
+//接单时指定自己已发布的行程，或者填写新行程
+#[derive(Deserialize)]
+pub struct AcceptForm {
+    pub trip_id: Option<String>,
+    pub trip: Option<TripForm>,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum CouponKind {
+    Fixed, //满减，value为减免金额
+    Percent, //折扣，value为减免的百分比
+}
+
+//优惠券保存在mongodb，乘客下单时填写code使用
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Coupon {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub code: String,
+    pub name: String,
+    pub kind: CouponKind,
+    pub value: i64,
+    pub max_discount: Option<i64>, //折扣券的最高减免金额
+    pub min_spend: i64, //订单金额不低于该值才能使用
+    pub start_time: i64,
+    pub end_time: i64,
+    pub per_user_limit: i64,
+    pub new_user_only: bool, //只限没有订单的用户，用于首单优惠
+    pub start: Option<String>, //线路限制，上车点包含该地名
+    pub end: Option<String>, //下车点包含该地名
+    pub city: Option<String>, //上车点或下车点包含该城市名
+    pub create_time: i64,
+    #[serde(default)]
+    pub owner: Option<String>, //发给个人的优惠券，如邀请奖励，只有该用户可以使用
+}
+
+#[derive(Deserialize)]
+pub struct CouponForm {
+    pub code: String,
+    pub name: String,
+    pub kind: CouponKind,
+    pub value: i64,
+    pub max_discount: Option<i64>,
+    pub min_spend: Option<i64>,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub per_user_limit: Option<i64>,
+    pub new_user_only: Option<bool>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub city: Option<String>,
+}
+
+//优惠券的使用记录，即平台对该订单的补贴，以订单id为key
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Redemption {
+    pub order_id: String,
+    pub coupon_id: String,
+    pub openid: String,
+    pub discount: i64,
+    pub create_time: i64,
+}
+
+//邀请关系，以被邀请用户的openid为id，每个用户只能被邀请一次
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct Referral {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub referrer: String,
+    pub code: String,
+    pub device: Option<String>,
+    pub status: ReferralStatus,
+    pub reason: Option<String>, //未发放奖励的原因
+    pub order_id: Option<String>, //触发奖励的订单
+    pub create_time: i64,
+    pub finish_time: Option<i64>,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub enum ReferralStatus {
+    Pending, //等待被邀请用户完成第一个订单
+    Rewarded,
+    Rejected,
+}
+
+#[derive(Serialize)]
+pub struct ReferralCode {
+    pub code: String,
+}
+
+//行程的车主和一位已支付乘客之间的会话，id为{trip_id}_{passenger}，双方只能看到对方的openid，看不到手机号
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct Conversation {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub trip_id: String,
+    pub driver: String,
+    pub passenger: String,
+    pub create_time: i64,
+    pub close_time: Option<i64>, //行程完成后设置，到达该时间后不能再发消息
+}
+
+//会话中的一条消息，seq在会话内从1开始递增
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct Message {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub conversation_id: String,
+    pub seq: i64,
+    pub sender: String,
+    pub content: String,
+    pub create_time: i64,
+}
+
+//会话列表中的一项，peer_read为对方已读到的seq，不大于它的消息显示为已读
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub conversation: Conversation,
+    pub last_seq: i64,
+    pub unread: i64,
+    pub peer_read: i64,
+}
+
+//车主发消息时需要填写passenger，乘客发消息时忽略
+#[derive(Deserialize)]
+pub struct MessageForm {
+    pub trip_id: String,
+    pub passenger: Option<String>,
+    pub content: String,
+}
+
+//固定线路的行程模板，在start_date到end_date之间每逢weekdays生成一个行程
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct TripTemplate {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub openid: String,
+    pub start: String,
+    pub end: String,
+    pub venue: String,
+    pub message: Option<String>,
+    pub plate_number: String,
+    pub car_type: String,
+    pub tel: String,
+    pub seat_count: i64,
+    pub price: i64,
+    pub depart_time: i64, //北京时间当天0点起的秒数
+    pub weekdays: Vec<i64>, //1-7表示周一到周日
+    pub start_date: String, //YYYY-MM-DD
+    pub end_date: String,
+    pub skip_dates: Vec<String>, //不出发的日期
+    pub overrides: Vec<Occurrence>, //单独修改过的日期
+    pub create_time: i64,
+}
+
+//某一天的行程修改，未填写的字段沿用模板
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct Occurrence {
+    pub date: String,
+    pub depart_time: Option<i64>,
+    pub seat_count: Option<i64>,
+    pub price: Option<i64>,
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TemplateForm {
+    pub start: String,
+    pub end: String,
+    pub venue: String,
+    pub message: Option<String>,
+    pub plate_number: String,
+    pub car_type: String,
+    pub tel: String,
+    pub seat_count: i64,
+    pub price: i64,
+    pub depart_time: i64,
+    pub weekdays: Vec<i64>,
+    pub start_date: String,
+    pub end_date: String,
+    pub skip_dates: Option<Vec<String>>,
+}
+
+//行程已满时排队等座，有座位释放时按顺序为等待者预留订单
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct Waiter {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub trip_id: String,
+    pub openid: String,
+    pub count: i64,
+    pub tel: Option<String>,
+    pub status: WaitStatus,
+    pub order_id: Option<String>, //预留的订单，需要在支付超时前支付
+    pub create_time: i64,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub enum WaitStatus {
+    Waiting,
+    Offered, //已预留订单，等待支付
+    Accepted, //预留订单已支付
+    Expired, //预留订单超时未支付
+    Removed, //主动退出或不再符合下单条件
+}
+
+//发给用户的站内通知，id由事件内容生成，事件重试时不会重复通知
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub struct Notification {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub openid: String,
+    pub kind: NotificationKind,
+    pub content: String,
+    pub trip_id: String,
+    pub order_id: Option<String>, //需要支付的订单
+    pub request_id: Option<String>, //匹配或接单的拼车需求
+    pub create_time: i64,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize,Clone)]
+pub enum NotificationKind {
+    SeatOffered, //候补获得预留订单
+    RideMatched, //需求和行程匹配，车主和乘客都会收到
+    RideRequestAccepted, //车主接单，乘客需要支付
+}
+
 #[derive(PartialEq, Debug, Serialize, Deserialize,Default,Clone)]
 pub struct Complain {
     #[serde(rename = "_id")]
@@ -77,6 +503,28 @@ pub struct Complain {
     pub content: String
 }
 
+//用户数据导出，包含与openid相关的全部记录，手机号不打码
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    pub openid: String,
+    pub export_time: i64,
+    pub trips: Vec<Trip>,
+    pub templates: Vec<TripTemplate>,
+    pub orders: Vec<Order>, //作为乘客的订单，包含支付流水号
+    pub ride_requests: Vec<RideRequest>,
+    pub waiters: Vec<Waiter>,
+    pub wallet: Wallet,
+    pub wallet_logs: Vec<WalletLog>,
+    pub withdraws: Vec<Withdraw>,
+    pub coupons: Vec<Coupon>,
+    pub referral: Option<Referral>, //被邀请的记录
+    pub referrals: Vec<Referral>, //邀请他人的记录
+    pub conversations: Vec<Conversation>,
+    pub messages: Vec<Message>, //本人发送的消息
+    pub complains: Vec<Complain>,
+    pub notifications: Vec<Notification>,
+}
+
 //weixin api result
 #[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub struct ApiResult {
@@ -110,20 +558,56 @@ pub enum OrderStatus {
     Submit,
 }
 
-#[derive(Default, RustcDecodable, RustcEncodable, Debug)]
+#[derive(Default, RustcDecodable, RustcEncodable, Debug, Clone)]
 pub struct JwtUser {
     pub id : String,   //ID 如果是微信登录就是openid
 	pub name : String,
 	pub role : String,
 	pub user_type: String,
     pub exp : i64,
+    pub jti : String, //token id，注销时加入黑名单
+}
+
+//登录返回的令牌，access token过期后用refresh token换取新的令牌
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct TokenPair {
+    pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
 }
 
 impl JwtUser {
+    //微信登录用户，有效期为jwt.access_ttl
+    pub fn weixin(openid: String) -> Self {
+        JwtUser {
+            id: openid,
+            name: String::new(),
+            role: "user".to_owned(),
+            user_type: "weixin".to_owned(),
+            exp: util::now() + setting::get().jwt.access_ttl,
+            jti: ObjectId::new().unwrap().to_hex(),
+        }
+    }
+
+    //使用当前密钥签名，kid写入header用于验证时选择密钥
+    pub fn sign(self) -> Result<String, ServiceError> {
+        let config = setting::get();
+        let secret = config.jwt.keys.get(&config.jwt.current_kid)?;
+        let header = Header {
+            kid: Some(config.jwt.current_kid.clone()),
+            ..Default::default()
+        };
+        Token::new(header, self)
+            .signed(secret.as_bytes(), Sha256::new())
+            .map_err(|_| ServiceError::String("can't sign jwt".to_owned()))
+    }
+
+    //签名错误、密钥不存在或已过期时返回None
 	pub fn from_jwt(s: &str) -> Option<Self> {
-		let token = Token::<Header, JwtUser>::parse(s).unwrap();
-        println!("{:?}", token);
-		 if token.verify(b"geekgogo", Sha256::new()) {
+		let token = Token::<Header, JwtUser>::parse(s).ok()?;
+        let config = setting::get();
+        let secret = config.jwt.keys.get(token.header.kid.as_ref()?)?;
+		if token.verify(secret.as_bytes(), Sha256::new()) && token.claims.exp > util::now() {
 	        Some(token.claims)
 	    } else {
 	        None
@@ -131,23 +615,43 @@ impl JwtUser {
 	}
 }
 
+//读取Authorization: Bearer <token>并检查是否已注销
+fn jwt_from_request(request: &Request) -> Option<JwtUser> {
+    let keys: Vec<_> = request.headers().get("Authorization").collect();
+    if keys.len() != 1 || !keys[0].starts_with("Bearer ") {
+        return None;
+    }
+    let user = JwtUser::from_jwt(keys[0]["Bearer ".len()..].trim())?;
+    let service = request.guard::<Service>().succeeded()?;
+    match service.is_revoked(&user.jti) {
+        Ok(false) => Some(user),
+        _ => None,
+    }
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for JwtUser {
     type Error = ServiceError;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<JwtUser, ServiceError> {
-        let keys: Vec<_> = request.headers().get("Authorization").collect();
-        if keys.len() != 1 {
-            return Outcome::Failure((Status::Unauthorized, ServiceError::NoAuth));
-        }
-        let (_, key) = keys[0].split_at(7);
-        if let Some(user) = JwtUser::from_jwt(key) {
-            if user.user_type == "weixin" {
-                Outcome::Success(user)
-            } else {
-                Outcome::Failure((Status::Unauthorized, ServiceError::NoAuth))
-            }
-        } else {
-        	Outcome::Failure((Status::Unauthorized, ServiceError::NoAuth))
+        match jwt_from_request(request) {
+            Some(ref user) if user.user_type != "weixin" => Outcome::Failure((Status::Unauthorized, ServiceError::NoAuth)),
+            Some(user) => Outcome::Success(user),
+            None => Outcome::Failure((Status::Unauthorized, ServiceError::NoAuth)),
+        }
+    }
+}
+
+//后台管理员，role为admin
+pub struct AdminUser(pub JwtUser);
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminUser {
+    type Error = ServiceError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AdminUser, ServiceError> {
+        match jwt_from_request(request) {
+            Some(ref user) if user.role != "admin" => Outcome::Failure((Status::Unauthorized, ServiceError::NoAuth)),
+            Some(user) => Outcome::Success(AdminUser(user)),
+            None => Outcome::Failure((Status::Unauthorized, ServiceError::NoAuth)),
         }
     }
 }
@@ -191,6 +695,140 @@ impl<'a> redis::ToRedisArgs for &'a TripStatus {
     }
 }
 
+impl fmt::Display for WithdrawStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WithdrawStatus::Pending => write!(f, "Pending"),
+            WithdrawStatus::Processing => write!(f, "Processing"),
+            WithdrawStatus::Approved => write!(f, "Approved"),
+            WithdrawStatus::Rejected => write!(f, "Rejected"),
+        }
+    }
+}
+
+impl fmt::Display for RideRequestStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RideRequestStatus::Open => write!(f, "Open"),
+            RideRequestStatus::Accepted => write!(f, "Accepted"),
+            RideRequestStatus::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl<'a> redis::ToRedisArgs for &'a RideRequestStatus {
+    fn to_redis_args(&self) -> Vec<Vec<u8>> {
+        vec![format!("{}",self).into_bytes()]
+    }
+}
+
+impl redis::FromRedisValue for RideRequestStatus {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        if let redis::Value::Data(ref data) = *v {
+            let s = String::from_utf8_lossy(&data);
+            match &*s {
+                "Open" => Ok(RideRequestStatus::Open),
+                "Accepted" => Ok(RideRequestStatus::Accepted),
+                "Cancelled" => Ok(RideRequestStatus::Cancelled),
+                _ => Err(redis::RedisError::from((redis::ErrorKind::TypeError,"unknown ride request status"))),
+            }
+        } else {
+            Err(redis::RedisError::from((redis::ErrorKind::TypeError,"not a Data")))
+        }
+    }
+}
+
+impl fmt::Display for ReferralStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReferralStatus::Pending => write!(f, "Pending"),
+            ReferralStatus::Rewarded => write!(f, "Rewarded"),
+            ReferralStatus::Rejected => write!(f, "Rejected"),
+        }
+    }
+}
+
+impl<'a> redis::ToRedisArgs for &'a ReferralStatus {
+    fn to_redis_args(&self) -> Vec<Vec<u8>> {
+        vec![format!("{}",self).into_bytes()]
+    }
+}
+
+impl redis::FromRedisValue for ReferralStatus {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        if let redis::Value::Data(ref data) = *v {
+            let s = String::from_utf8_lossy(&data);
+            match &*s {
+                "Pending" => Ok(ReferralStatus::Pending),
+                "Rewarded" => Ok(ReferralStatus::Rewarded),
+                "Rejected" => Ok(ReferralStatus::Rejected),
+                _ => Err(redis::RedisError::from((redis::ErrorKind::TypeError,"unknown referral status"))),
+            }
+        } else {
+            Err(redis::RedisError::from((redis::ErrorKind::TypeError,"not a Data")))
+        }
+    }
+}
+
+impl fmt::Display for WaitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WaitStatus::Waiting => write!(f, "Waiting"),
+            WaitStatus::Offered => write!(f, "Offered"),
+            WaitStatus::Accepted => write!(f, "Accepted"),
+            WaitStatus::Expired => write!(f, "Expired"),
+            WaitStatus::Removed => write!(f, "Removed"),
+        }
+    }
+}
+
+impl<'a> redis::ToRedisArgs for &'a WaitStatus {
+    fn to_redis_args(&self) -> Vec<Vec<u8>> {
+        vec![format!("{}",self).into_bytes()]
+    }
+}
+
+impl redis::FromRedisValue for WaitStatus {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        if let redis::Value::Data(ref data) = *v {
+            let s = String::from_utf8_lossy(&data);
+            match &*s {
+                "Waiting" => Ok(WaitStatus::Waiting),
+                "Offered" => Ok(WaitStatus::Offered),
+                "Accepted" => Ok(WaitStatus::Accepted),
+                "Expired" => Ok(WaitStatus::Expired),
+                "Removed" => Ok(WaitStatus::Removed),
+                _ => Err(redis::RedisError::from((redis::ErrorKind::TypeError,"unknown wait status"))),
+            }
+        } else {
+            Err(redis::RedisError::from((redis::ErrorKind::TypeError,"not a Data")))
+        }
+    }
+}
+
+impl<'a> redis::ToRedisArgs for &'a WithdrawStatus {
+    fn to_redis_args(&self) -> Vec<Vec<u8>> {
+        vec![format!("{}",self).into_bytes()]
+    }
+}
+
+impl redis::FromRedisValue for WithdrawStatus {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        if let redis::Value::Data(ref data) = *v {
+            let s = String::from_utf8_lossy(&data);
+            match &*s {
+                "Pending" => Ok(WithdrawStatus::Pending),
+                "Processing" => Ok(WithdrawStatus::Processing),
+                "Approved" => Ok(WithdrawStatus::Approved),
+                "Rejected" => Ok(WithdrawStatus::Rejected),
+                _ => Err(redis::RedisError::from((redis::ErrorKind::TypeError,"unknown withdraw status"))),
+            }
+        } else {
+            Err(redis::RedisError::from((redis::ErrorKind::TypeError,"not a Data")))
+        }
+    }
+}
+
 impl<'a> redis::ToRedisArgs for &'a OrderStatus {
     fn to_redis_args(&self) -> Vec<Vec<u8>> {
         vec![format!("{}",self).into_bytes()]
@@ -213,9 +851,27 @@ impl redis::FromRedisValue for OrderStatus {
     }
 }
 
+fn split_stops(stops: &Option<String>) -> Vec<String> {
+    stops.as_ref().map_or(Vec::new(), |stops| {
+        stops.split(',').map(|stop| stop.trim().to_owned()).collect()
+    })
+}
+
+fn split_fares(fares: &Option<String>) -> Option<Vec<i64>> {
+    match *fares {
+        Some(ref fares) => fares.split(',').map(|fare| fare.trim().parse().ok()).collect(),
+        None => Some(Vec::new()),
+    }
+}
+
 impl Trip {
     pub fn new(openid:String,form:TripForm) -> Trip{
+        let stops = split_stops(&form.stops);
+        let fares = split_fares(&form.fares).unwrap_or_default();
         Trip{
+            free_seats:vec![form.seat_count; stops.len() + 1],
+            stops,
+            fares,
             openid,
             tel:form.tel,
             id:ObjectId::new().unwrap().to_hex(),
@@ -232,11 +888,137 @@ impl Trip {
             car_type:form.car_type,
         }
     }
+
+    //最后一个站点（终点）的序号，也是路段数
+    pub fn last_stop(&self) -> i64 {
+        self.stops.len() as i64 + 1
+    }
+
+    //站点名称，0为起点，last_stop()为终点
+    pub fn stop_name(&self, index:i64) -> &str {
+        if index <= 0 {
+            &self.start
+        } else if index >= self.last_stop() {
+            &self.end
+        } else {
+            &self.stops[index as usize - 1]
+        }
+    }
+
+    pub fn check_segment(&self, from:i64, to:i64) -> Result<(), ServiceError> {
+        Validator::new()
+            .check(from >= 0 && from < self.last_stop(), "from", "must be a stop before the end")
+            .check(to > from && to <= self.last_stop(), "to", "must be a stop after from")
+            .finish()
+    }
+
+    //from到to之间每个座位的价格
+    pub fn segment_price(&self, from:i64, to:i64) -> i64 {
+        if self.fares.is_empty() {
+            self.price
+        } else {
+            self.fares[from as usize..to as usize].iter().sum()
+        }
+    }
+
+    //from到to之间每一段都空着的座位数
+    pub fn segment_seats(&self, from:i64, to:i64) -> i64 {
+        if self.free_seats.is_empty() {
+            return self.current_seat;
+        }
+        self.free_seats[from as usize..to as usize].iter().cloned().min().unwrap_or(0)
+    }
+
+    //占用from到to之间的座位，座位不够时返回false
+    //乘客下车后的路段座位不受影响，可以再卖给后面上车的乘客
+    pub fn take_seats(&mut self, from:i64, to:i64, count:i64) -> bool {
+        if self.segment_seats(from, to) < count {
+            return false;
+        }
+        self.change_seats(from, to, -count);
+        true
+    }
+
+    pub fn release_seats(&mut self, from:i64, to:i64, count:i64) {
+        self.change_seats(from, to, count);
+    }
+
+    //同时更新current_seat，旧行程没有分段座位时按一段处理
+    fn change_seats(&mut self, from:i64, to:i64, count:i64) {
+        if self.free_seats.is_empty() {
+            self.free_seats = vec![self.current_seat; self.last_stop() as usize];
+        }
+        for seats in &mut self.free_seats[from as usize..to as usize] {
+            *seats += count;
+        }
+        self.current_seat = self.free_seats.iter().cloned().min().unwrap_or(0);
+    }
+}
+
+impl TripForm {
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        let mut v = Validator::new();
+        v.check(self.seat_count >= 1, "seat_count", "must be at least 1")
+            .check(self.start_time > util::now(), "start_time", "must be in the future")
+            .check(self.price >= 0, "price", "must not be negative")
+            .not_empty(&self.start, "start")
+            .not_empty(&self.end, "end")
+            .not_empty(&self.venue, "venue")
+            .not_empty(&self.plate_number, "plate_number")
+            .tel(&self.tel, "tel");
+        if let Some(ref message) = self.message {
+            v.text(message, "message");
+        }
+        let stops = split_stops(&self.stops);
+        v.check(stops.iter().all(|stop| !stop.is_empty()), "stops", "must not contain empty stops");
+        match split_fares(&self.fares) {
+            Some(ref fares) if fares.is_empty() => {
+                v.check(stops.is_empty(), "fares", "are required when there are stops");
+            }
+            Some(fares) => {
+                v.check(fares.len() == stops.len() + 1, "fares", "must have one fare for each segment")
+                    .check(fares.iter().all(|&fare| fare >= 0), "fares", "must not be negative")
+                    .check(fares.iter().sum::<i64>() == self.price, "fares", "must add up to price");
+            }
+            None => {
+                v.check(false, "fares", "must be comma separated numbers");
+            }
+        }
+        v.finish()
+    }
 }
 
 impl Order {
+    //订座数量不能超过行程的座位数
+    pub fn validate(trip: &Trip, count: i64, tel: &Option<String>) -> Result<(), ServiceError> {
+        let mut v = Validator::new();
+        v.check(count >= 1, "count", "must be at least 1")
+            .check(count <= trip.seat_count, "count", "must not exceed the trip's seats");
+        if let Some(ref tel) = *tel {
+            v.tel(tel, "tel");
+        }
+        v.finish()
+    }
+
+    //优惠券减免前的金额，司机收入按这个减去车主的退款计算
+    pub fn gross(&self) -> i64 {
+        self.price * self.count
+    }
+
+    //乘客实际支付的金额
+    pub fn total(&self) -> i64 {
+        self.gross() - self.discount
+    }
+
+    //全程
     pub fn new(trip:Trip, openid:String,count:i64,tel:Option<String>) -> Self {
+        let to = trip.last_stop();
+        Order::segment(trip, openid, count, tel, 0, to)
+    }
+
+    pub fn segment(trip:Trip, openid:String,count:i64,tel:Option<String>,from:i64,to:i64) -> Self {
         Order{
+            price:trip.segment_price(from, to),
             id:ObjectId::new().unwrap().to_hex(),
             trip_id:trip.id,
             trip_owner:trip.openid,
@@ -246,12 +1028,448 @@ impl Order {
             tel,
             status:OrderStatus::Unpaid,
             count,
-            price:trip.price,
             start_time:trip.start_time,
+            from,
+            to,
+            coupon_id:None,
+            discount:0,
+            refunded:0,
+        }
+    }
+}
+
+impl Withdraw {
+    pub fn new(openid:String, amount:i64) -> Self {
+        Withdraw{
+            id:ObjectId::new().unwrap().to_hex(),
+            openid,
+            amount,
+            status:WithdrawStatus::Pending,
+            create_time:util::now(),
+            handle_time:None,
+        }
+    }
+}
+
+impl WalletLog {
+    pub fn new(openid:&str, change:i64, balance:i64, reason:&str, ref_id:&str) -> Self {
+        WalletLog{
+            id:ObjectId::new().unwrap().to_hex(),
+            openid:openid.to_owned(),
+            change,
+            balance,
+            reason:reason.to_owned(),
+            ref_id:ref_id.to_owned(),
+            time:util::now(),
+        }
+    }
+}
+
+impl RideRequestForm {
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        let mut v = Validator::new();
+        v.not_empty(&self.start, "start")
+            .not_empty(&self.end, "end")
+            .check(self.earliest > util::now(), "earliest", "must be in the future")
+            .check(
+                self.latest >= self.earliest && self.latest - self.earliest <= 86400,
+                "latest",
+                "must be within a day after earliest",
+            )
+            .check(self.count >= 1, "count", "must be at least 1")
+            .check(self.max_price >= 0, "max_price", "must not be negative");
+        if let Some(ref tel) = self.tel {
+            v.tel(tel, "tel");
+        }
+        if let Some(ref message) = self.message {
+            v.text(message, "message");
+        }
+        v.finish()
+    }
+}
+
+impl CouponForm {
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        let mut v = Validator::new();
+        v.not_empty(&self.code, "code")
+            .not_empty(&self.name, "name")
+            .check(self.value >= 1, "value", "must be at least 1")
+            .check(self.kind != CouponKind::Percent || self.value <= 100, "value", "must not exceed 100 percent")
+            .check(self.max_discount.map_or(true, |max| max >= 1), "max_discount", "must be at least 1")
+            .check(self.min_spend.map_or(true, |min| min >= 0), "min_spend", "must not be negative")
+            .check(self.end_time > self.start_time, "end_time", "must be after start_time")
+            .check(self.per_user_limit.map_or(true, |limit| limit >= 1), "per_user_limit", "must be at least 1");
+        v.finish()
+    }
+}
+
+impl Coupon {
+    pub fn new(form:CouponForm) -> Self {
+        Coupon{
+            id:ObjectId::new().unwrap().to_hex(),
+            code:form.code,
+            name:form.name,
+            kind:form.kind,
+            value:form.value,
+            max_discount:form.max_discount,
+            min_spend:form.min_spend.unwrap_or(0),
+            start_time:form.start_time,
+            end_time:form.end_time,
+            per_user_limit:form.per_user_limit.unwrap_or(1),
+            new_user_only:form.new_user_only.unwrap_or(false),
+            start:form.start,
+            end:form.end,
+            city:form.city,
+            create_time:util::now(),
+            owner:None,
+        }
+    }
+
+    //发给个人的满减券，使用一次
+    pub fn reward(openid:&str, amount:i64, days:i64) -> Self {
+        let now = util::now();
+        Coupon{
+            id:ObjectId::new().unwrap().to_hex(),
+            code:format!("R{}", util::random_string(10).to_uppercase()),
+            name:"referral reward".to_owned(),
+            kind:CouponKind::Fixed,
+            value:amount,
+            max_discount:None,
+            min_spend:0,
+            start_time:now,
+            end_time:now + days * 86400,
+            per_user_limit:1,
+            new_user_only:false,
+            start:None,
+            end:None,
+            city:None,
+            create_time:now,
+            owner:Some(openid.to_owned()),
+        }
+    }
+
+    //订单可以减免的金额，不满足使用条件时返回None
+    //至少保留1分钱，微信支付不接受0元订单
+    pub fn discount(&self, trip:&Trip, order:&Order, now:i64) -> Option<i64> {
+        let amount = order.price * order.count;
+        let start = trip.stop_name(order.from);
+        let end = trip.stop_name(order.to);
+        let place = |limit: &Option<String>, name: &str| limit.as_ref().map_or(true, |limit| name.contains(limit.as_str()));
+        let city = self.city.as_ref().map_or(true, |city| start.contains(city.as_str()) || end.contains(city.as_str()));
+        if now < self.start_time || now >= self.end_time || amount < self.min_spend || amount <= 1 ||
+            !place(&self.start, start) || !place(&self.end, end) || !city
+        {
+            return None;
+        }
+        let discount = match self.kind {
+            CouponKind::Fixed => self.value,
+            CouponKind::Percent => {
+                let discount = amount * self.value / 100;
+                self.max_discount.map_or(discount, |max| discount.min(max))
+            }
+        };
+        Some(discount.min(amount - 1))
+    }
+}
+
+impl Referral {
+    pub fn new(referrer:&str, referee:&str, code:&str, device:Option<String>) -> Self {
+        Referral{
+            id:referee.to_owned(),
+            referrer:referrer.to_owned(),
+            code:code.to_owned(),
+            device,
+            status:ReferralStatus::Pending,
+            reason:None,
+            order_id:None,
+            create_time:util::now(),
+            finish_time:None,
+        }
+    }
+
+    pub fn reject(&mut self, reason:&str) {
+        self.status = ReferralStatus::Rejected;
+        self.reason = Some(reason.to_owned());
+        self.finish_time = Some(util::now());
+    }
+}
+
+impl Redemption {
+    pub fn new(coupon:&Coupon, order:&Order) -> Self {
+        Redemption{
+            order_id:order.id.clone(),
+            coupon_id:coupon.id.clone(),
+            openid:order.openid.clone(),
+            discount:order.discount,
+            create_time:util::now(),
+        }
+    }
+}
+
+impl Conversation {
+    pub fn new(trip:&Trip, passenger:&str) -> Self {
+        Conversation{
+            id:Conversation::id_of(&trip.id, passenger),
+            trip_id:trip.id.clone(),
+            driver:trip.openid.clone(),
+            passenger:passenger.to_owned(),
+            create_time:util::now(),
+            close_time:None,
+        }
+    }
+
+    pub fn id_of(trip_id:&str, passenger:&str) -> String {
+        format!("{}_{}", trip_id, passenger)
+    }
+
+    //会话的另一方，不是会话成员时返回None
+    pub fn peer(&self, openid:&str) -> Option<&str> {
+        if openid == self.driver {
+            Some(&self.passenger)
+        } else if openid == self.passenger {
+            Some(&self.driver)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.close_time.map_or(false, |time| util::now() >= time)
+    }
+}
+
+impl Message {
+    pub fn new(conversation:&Conversation, seq:i64, sender:&str, content:String) -> Self {
+        Message{
+            id:ObjectId::new().unwrap().to_hex(),
+            conversation_id:conversation.id.clone(),
+            seq,
+            sender:sender.to_owned(),
+            content,
+            create_time:util::now(),
+        }
+    }
+}
+
+impl MessageForm {
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        Validator::new()
+            .not_empty(&self.trip_id, "trip_id")
+            .not_empty(&self.content, "content")
+            .text(&self.content, "content")
+            .finish()
+    }
+}
+
+impl RideRequest {
+    pub fn new(openid:String, form:RideRequestForm) -> Self {
+        RideRequest{
+            id:ObjectId::new().unwrap().to_hex(),
+            openid,
+            start:form.start,
+            end:form.end,
+            earliest:form.earliest,
+            latest:form.latest,
+            count:form.count,
+            max_price:form.max_price,
+            tel:form.tel,
+            message:form.message,
+            status:RideRequestStatus::Open,
+            trip_id:None,
+            order_id:None,
+            create_time:util::now(),
+        }
+    }
+
+    //行程的出发时间、价格和座位要满足需求
+    pub fn check_trip(&self, trip:&Trip) -> Result<(), ServiceError> {
+        Validator::new()
+            .check(
+                trip.start_time >= self.earliest && trip.start_time <= self.latest,
+                "start_time",
+                "must be within the requested time",
+            )
+            .check(trip.price <= self.max_price, "price", "must not exceed the requested price")
+            .check(trip.current_seat >= self.count, "seat_count", "must have enough seats for the request")
+            .check(trip.status == TripStatus::Prepare, "trip_id", "must be a trip not yet started")
+            .finish()
+    }
+
+    pub fn matches(&self, query:&RideRequestQuery) -> bool {
+        query.start.as_ref().map_or(true, |start| self.start.contains(start.as_str())) &&
+            query.end.as_ref().map_or(true, |end| self.end.contains(end.as_str())) &&
+            query.from.map_or(true, |from| self.latest >= from) &&
+            query.to.map_or(true, |to| self.earliest <= to)
+    }
+}
+
+impl TemplateForm {
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        let mut v = Validator::new();
+        v.check(self.seat_count >= 1, "seat_count", "must be at least 1")
+            .check(self.price >= 0, "price", "must not be negative")
+            .check(self.depart_time >= 0 && self.depart_time < 86400, "depart_time", "must be within a day")
+            .check(
+                !self.weekdays.is_empty() && self.weekdays.iter().all(|&d| d >= 1 && d <= 7),
+                "weekdays",
+                "must be days of week from 1 to 7",
+            )
+            .not_empty(&self.start, "start")
+            .not_empty(&self.end, "end")
+            .not_empty(&self.venue, "venue")
+            .not_empty(&self.plate_number, "plate_number")
+            .tel(&self.tel, "tel")
+            .date(&self.start_date, "start_date")
+            .date(&self.end_date, "end_date");
+        if let (Some(start), Some(end)) = (util::parse_date(&self.start_date), util::parse_date(&self.end_date)) {
+            v.check(start <= end && end - start <= 366, "end_date", "must be within a year after start_date")
+                .check(end >= util::today(), "end_date", "must not be in the past");
+        }
+        for date in self.skip_dates.iter().flat_map(|dates| dates.iter()) {
+            v.date(date, "skip_dates");
+        }
+        if let Some(ref message) = self.message {
+            v.text(message, "message");
+        }
+        v.finish()
+    }
+}
+
+impl Occurrence {
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        let mut v = Validator::new();
+        v.date(&self.date, "date");
+        if let Some(depart_time) = self.depart_time {
+            v.check(depart_time >= 0 && depart_time < 86400, "depart_time", "must be within a day");
+        }
+        if let Some(seat_count) = self.seat_count {
+            v.check(seat_count >= 1, "seat_count", "must be at least 1");
+        }
+        if let Some(price) = self.price {
+            v.check(price >= 0, "price", "must not be negative");
+        }
+        if let Some(ref message) = self.message {
+            v.text(message, "message");
+        }
+        v.finish()
+    }
+}
+
+impl TripTemplate {
+    pub fn new(openid:String, form:TemplateForm) -> Self {
+        let mut weekdays = form.weekdays;
+        weekdays.sort();
+        weekdays.dedup();
+        TripTemplate{
+            id:ObjectId::new().unwrap().to_hex(),
+            openid,
+            start:form.start,
+            end:form.end,
+            venue:form.venue,
+            message:form.message,
+            plate_number:form.plate_number,
+            car_type:form.car_type,
+            tel:form.tel,
+            seat_count:form.seat_count,
+            price:form.price,
+            depart_time:form.depart_time,
+            weekdays,
+            start_date:form.start_date,
+            end_date:form.end_date,
+            skip_dates:form.skip_dates.unwrap_or_default(),
+            overrides:Vec::new(),
+            create_time:util::now(),
+        }
+    }
+
+    //日期在范围内、是出发的星期并且没有被跳过
+    pub fn runs_on(&self, date:&str) -> bool {
+        match util::parse_date(date) {
+            Some(day) => {
+                date >= self.start_date.as_str() && date <= self.end_date.as_str() &&
+                    self.weekdays.contains(&util::weekday(day)) &&
+                    !self.skip_dates.iter().any(|d| d == date)
+            }
+            None => false,
+        }
+    }
+
+    //按模板和当天的修改生成行程
+    pub fn trip(&self, date:&str) -> Option<Trip> {
+        let day = util::parse_date(date)?;
+        let occurrence = self.overrides.iter().find(|o| o.date == date);
+        let form = TripForm {
+            seat_count: occurrence.and_then(|o| o.seat_count).unwrap_or(self.seat_count),
+            start_time: util::day_start(day) + occurrence.and_then(|o| o.depart_time).unwrap_or(self.depart_time),
+            start: self.start.clone(),
+            end: self.end.clone(),
+            price: occurrence.and_then(|o| o.price).unwrap_or(self.price),
+            venue: self.venue.clone(),
+            message: occurrence.and_then(|o| o.message.clone()).or_else(|| self.message.clone()),
+            plate_number: self.plate_number.clone(),
+            car_type: self.car_type.clone(),
+            tel: self.tel.clone(),
+            stops: None,
+            fares: None,
+        };
+        Some(Trip::new(self.openid.clone(), form))
+    }
+}
+
+impl Waiter {
+    pub fn new(trip_id:String, openid:String, count:i64, tel:Option<String>) -> Self {
+        Waiter{
+            id:ObjectId::new().unwrap().to_hex(),
+            trip_id,
+            openid,
+            count,
+            tel,
+            status:WaitStatus::Waiting,
+            order_id:None,
+            create_time:util::now(),
         }
     }
 }
 
+impl Notification {
+    pub fn new(id:String, openid:&str, kind:NotificationKind, content:String, trip_id:&str, order_id:Option<String>, request_id:Option<String>) -> Self {
+        Notification {
+            id,
+            openid:openid.to_owned(),
+            kind,
+            content,
+            trip_id:trip_id.to_owned(),
+            order_id,
+            request_id,
+            create_time:util::now(),
+        }
+    }
+}
+
+impl Complain {
+    pub fn new(openid:String, content:String) -> Self {
+        Complain {
+            id:Some(ObjectId::new().unwrap().to_hex()),
+            openid,
+            content,
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new(entity:&str, entity_id:&str, from:Option<String>, to:&str, actor:&str, reason:&str) -> Self {
+        AuditLog{
+            id:ObjectId::new().unwrap().to_hex(),
+            entity:entity.to_owned(),
+            entity_id:entity_id.to_owned(),
+            from,
+            to:to.to_owned(),
+            actor:actor.to_owned(),
+            reason:reason.to_owned(),
+            time:util::now(),
+        }
+    }
+}
 
 impl<'t> FromFormValue<'t> for OrderStatus {
     type Error = ServiceError;
@@ -0,0 +1,230 @@
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+use mongodb::db::Database;
+use db;
+use service::{Result, Service};
+use setting;
+
+//一次最多读取的事件数
+const BATCH: usize = 20;
+
+//失败failures次后距离上次投递需要等待的毫秒数，没有失败过的立即投递
+pub fn retry_delay(failures: i64) -> i64 {
+    if failures <= 0 {
+        return 0;
+    }
+    setting::get().event.retry_delay << cmp::min(failures - 1, 10)
+}
+
+//行程和订单的领域事件，由存储在状态变化的同一事务中写入
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum Event {
+    TripPublished { trip_id: String, openid: String },
+    OrderCreated { order_id: String, trip_id: String, openid: String },
+    OrderPaid { order_id: String, transaction_id: String },
+    OrderExpired { order_id: String, trip_id: String },
+    OrderSubmitted { order_id: String, trip_id: String },
+    TripFinished { trip_id: String },
+    TripCancelled { trip_id: String }, //行程取消功能上线后发出
+    RideRequestPosted { request_id: String, openid: String },
+    RideMatched { request_id: String, trip_id: String, passenger: String, driver: String, score: i64 }, //通知双方
+    RideRequestAccepted { request_id: String, order_id: String, trip_id: String, openid: String }, //需要通知乘客支付
+    SeatOffered { waiter_id: String, order_id: String, trip_id: String, openid: String }, //候补获得预留订单，需要通知乘客支付
+    ReferralRewarded { referrer: String, referee: String }, //通知双方领取奖励
+    MessageSent { conversation_id: String, message_id: String, sender: String, receiver: String }, //接收方不在线时通知
+}
+
+//事件订阅者，每个订阅者独立消费和重试
+pub trait Subscriber: Send + Sync {
+    //同时作为消费组名称，修改后会从最新的事件开始消费
+    fn name(&self) -> &'static str;
+    fn handle(&self, service: &Service, event: &Event) -> Result<()>;
+}
+
+pub struct EventBus {
+    subscribers: Vec<Box<Subscriber>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus { subscribers: Vec::new() }
+    }
+
+    pub fn subscribe<S: Subscriber + 'static>(mut self, subscriber: S) -> Self {
+        self.subscribers.push(Box::new(subscriber));
+        self
+    }
+
+    //创建消费组，已存在时不做修改
+    pub fn init(&self, service: &Service) -> Result<()> {
+        for subscriber in &self.subscribers {
+            service.add_event_group(subscriber.name())?;
+        }
+        Ok(())
+    }
+
+    //每个订阅者处理一批事件，先重试到期的失败事件，再读取新事件，返回处理的事件数
+    pub fn poll(&self, service: &Service) -> usize {
+        let mut handled = 0;
+        for subscriber in &self.subscribers {
+            let group = subscriber.name();
+            let events = match service.read_events(group, BATCH) {
+                Ok(events) => events,
+                Err(err) => {
+                    println!("event {} read error: {:?}", group, err);
+                    continue;
+                }
+            };
+            for (id, event) in events {
+                handled += 1;
+                if let Err(err) = deliver(service, subscriber.as_ref(), &id, &event) {
+                    println!("event {} {} error: {:?}", group, id, err);
+                }
+            }
+        }
+        handled
+    }
+}
+
+fn deliver(service: &Service, subscriber: &Subscriber, id: &str, event: &Event) -> Result<()> {
+    let group = subscriber.name();
+    match subscriber.handle(service, event) {
+        Ok(_) => service.ack_event(group, id),
+        Err(err) => {
+            let attempts = service.fail_event(group, id)?;
+            println!("event {} {:?} failed {} times: {:?}", group, event, attempts, err);
+            if attempts >= setting::get().event.max_attempts {
+                println!("event {} {:?} moved to dead letters", group, event);
+                service.dead_letter_event(group, id, event)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+//订单确认后检查行程是否完成
+pub struct TripFinishChecker;
+
+impl Subscriber for TripFinishChecker {
+    fn name(&self) -> &'static str {
+        "trip_finish"
+    }
+
+    fn handle(&self, service: &Service, event: &Event) -> Result<()> {
+        match *event {
+            Event::OrderSubmitted { ref trip_id, .. } => service.check_trip_finish(trip_id),
+            _ => Ok(()),
+        }
+    }
+}
+
+//被邀请用户的订单确认后发放邀请奖励
+pub struct ReferralRewarder;
+
+impl Subscriber for ReferralRewarder {
+    fn name(&self) -> &'static str {
+        "referral"
+    }
+
+    fn handle(&self, service: &Service, event: &Event) -> Result<()> {
+        match *event {
+            Event::OrderSubmitted { ref order_id, .. } => service.reward_referral(order_id),
+            _ => Ok(()),
+        }
+    }
+}
+
+//行程完成后定时关闭会话
+pub struct ConversationCloser;
+
+impl Subscriber for ConversationCloser {
+    fn name(&self) -> &'static str {
+        "messaging"
+    }
+
+    fn handle(&self, service: &Service, event: &Event) -> Result<()> {
+        match *event {
+            Event::TripFinished { ref trip_id } => service.close_conversations(trip_id),
+            _ => Ok(()),
+        }
+    }
+}
+
+//需要用户处理的事件写入站内通知
+pub struct Notifier;
+
+impl Subscriber for Notifier {
+    fn name(&self) -> &'static str {
+        "notify"
+    }
+
+    fn handle(&self, service: &Service, event: &Event) -> Result<()> {
+        match *event {
+            Event::SeatOffered { ref order_id, ref trip_id, ref openid, .. } => {
+                service.notify_seat_offered(order_id, trip_id, openid)
+            }
+            Event::RideMatched { ref request_id, ref trip_id, ref passenger, ref driver, .. } => {
+                service.notify_ride_matched(request_id, trip_id, passenger, driver)
+            }
+            Event::RideRequestAccepted { ref request_id, ref order_id, ref trip_id, ref openid } => {
+                service.notify_ride_request_accepted(request_id, order_id, trip_id, openid)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+//行程发布、座位归还或者需求发布后重新匹配
+pub struct Matcher;
+
+impl Subscriber for Matcher {
+    fn name(&self) -> &'static str {
+        "matching"
+    }
+
+    fn handle(&self, service: &Service, event: &Event) -> Result<()> {
+        match *event {
+            Event::TripPublished { ref trip_id, .. } |
+            Event::OrderExpired { ref trip_id, .. } => service.match_trip(trip_id),
+            Event::RideRequestPosted { ref request_id, .. } => service.match_ride_request(request_id),
+            _ => Ok(()),
+        }
+    }
+}
+
+//内置的订阅者
+pub fn bus() -> EventBus {
+    EventBus::new()
+        .subscribe(TripFinishChecker)
+        .subscribe(Matcher)
+        .subscribe(ReferralRewarder)
+        .subscribe(ConversationCloser)
+        .subscribe(Notifier)
+}
+
+//后台线程，持续分发事件，没有新事件时等待一秒
+pub fn run(bus: EventBus, database: Database, pool: db::Pool) {
+    let mut ready = false;
+    loop {
+        let handled = match pool.get() {
+            Ok(conn) => {
+                let service = Service::new(db::DbConn(database.clone()), db::CacheConn(conn));
+                if !ready {
+                    ready = bus.init(&service)
+                        .map_err(|err| println!("event init error: {:?}", err))
+                        .is_ok();
+                }
+                if ready { bus.poll(&service) } else { 0 }
+            }
+            Err(err) => {
+                println!("event worker can't get redis connection: {:?}", err);
+                0
+            }
+        };
+        if handled == 0 {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
@@ -1,34 +1,208 @@
-use std::io::{self, Write};
-use futures::{Future, Stream};
-use hyper::Client;
-use tokio_core::reactor::Core;
-use service::Result;
-use hyper_tls::HttpsConnector;
-
-pub fn test() -> Result<()> {
-    let mut core = Core::new()?;
-    let handle = core.handle();
-    let client = Client::configure()
-        .connector(HttpsConnector::new(4, &handle).unwrap())
-        .build(&handle);
-    let uri = "https://www.github.com".parse()?;
-    let work = client.get(uri).and_then(|res| {
-        println!("Response: {}", res.status());
-
-        res.body().for_each(|chunk| {
-            io::stdout().write_all(&chunk).map_err(From::from)
-        })
-    });
-    core.run(work)?;
-    Ok(())
-}
-
-pub fn refund(order_id: &str, transaction_id: &str, refund_fee: i64) -> Result<()> {
-    println!("{}{}{}", order_id, transaction_id, refund_fee);
-    Ok(())
-}
-
-pub fn pay_to_client(openid: &str, fee: i64) -> Result<()> {
-    println!("{}{}", openid, fee);
-    Ok(())
-}
\ No newline at end of file
+use std::io::{self, Write};
+use std::collections::BTreeMap;
+use futures::{Future, Stream};
+use hyper::{Client, Method, Request};
+use tokio_core::reactor::Core;
+use service::{Result, ServiceError};
+use hyper_tls::HttpsConnector;
+use crypto::md5::Md5;
+use crypto::digest::Digest;
+use bson::oid::ObjectId;
+use serde_json;
+use entity;
+use setting;
+use util;
+
+pub fn test() -> Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let client = Client::configure()
+        .connector(HttpsConnector::new(4, &handle).unwrap())
+        .build(&handle);
+    let uri = "https://www.github.com".parse()?;
+    let work = client.get(uri).and_then(|res| {
+        println!("Response: {}", res.status());
+
+        res.body().for_each(|chunk| {
+            io::stdout().write_all(&chunk).map_err(From::from)
+        })
+    });
+    core.run(work)?;
+    Ok(())
+}
+
+//发送请求并返回响应内容
+fn request(method: Method, url: &str, body: Option<String>) -> Result<String> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let connector = HttpsConnector::new(4, &handle).map_err(|err| {
+        ServiceError::String(format!("{:?}", err))
+    })?;
+    let client = Client::configure().connector(connector).build(&handle);
+    let mut req = Request::new(method, url.parse()?);
+    if let Some(body) = body {
+        req.set_body(body);
+    }
+    let work = client.request(req).and_then(|res| res.body().concat2());
+    let chunk = core.run(work)?;
+    String::from_utf8(chunk.to_vec()).map_err(|err| ServiceError::String(format!("{:?}", err)))
+}
+
+fn nonce_str() -> String {
+    ObjectId::new().unwrap().to_hex()
+}
+
+//微信支付签名：参数按key排序拼接后加上商户key做MD5
+pub fn sign(params: &BTreeMap<String, String>) -> String {
+    let mut s = params
+        .iter()
+        .filter(|&(k, v)| k != "sign" && !v.is_empty())
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&");
+    s.push_str(&format!("&key={}", setting::get().weixin.key));
+    let mut md5 = Md5::new();
+    md5.input_str(&s);
+    md5.result_str().to_uppercase()
+}
+
+pub fn to_xml(params: &BTreeMap<String, String>) -> String {
+    let mut xml = String::from("<xml>");
+    for (k, v) in params {
+        xml.push_str(&format!("<{0}><![CDATA[{1}]]></{0}>", k, v));
+    }
+    xml.push_str("</xml>");
+    xml
+}
+
+//微信返回的xml只有一层，不需要完整的解析器
+pub fn from_xml(xml: &str) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+    let body = xml.trim()
+        .trim_left_matches("<xml>")
+        .trim_right_matches("</xml>");
+    let mut rest = body;
+    while let Some(start) = rest.find('<') {
+        let after = &rest[start + 1..];
+        let name_end = match after.find('>') {
+            Some(i) => i,
+            None => break,
+        };
+        let name = &after[..name_end];
+        let close = format!("</{}>", name);
+        let value_start = &after[name_end + 1..];
+        let value_end = match value_start.find(&close) {
+            Some(i) => i,
+            None => break,
+        };
+        let value = value_start[..value_end]
+            .trim_left_matches("<![CDATA[")
+            .trim_right_matches("]]>");
+        params.insert(name.to_owned(), value.to_owned());
+        rest = &value_start[value_end + close.len()..];
+    }
+    params
+}
+
+//调用微信支付接口，检查通信和业务结果
+fn mch_request(path: &str, mut params: BTreeMap<String, String>) -> Result<BTreeMap<String, String>> {
+    params.insert("nonce_str".to_owned(), nonce_str());
+    let sign = sign(&params);
+    params.insert("sign".to_owned(), sign);
+    let url = format!("{}{}", setting::get().weixin.mch_url, path);
+    let result = from_xml(&request(Method::Post, &url, Some(to_xml(&params)))?);
+    if result.get("return_code").map(|s| s.as_str()) != Some("SUCCESS") {
+        return Err(ServiceError::String(format!("weixin pay error: {:?}", result.get("return_msg"))));
+    }
+    if result.get("result_code").map(|s| s.as_str()) != Some("SUCCESS") {
+        return Err(ServiceError::String(format!("weixin pay error: {:?}", result.get("err_code_des"))));
+    }
+    Ok(result)
+}
+
+//小程序登录，用code换取openid
+pub fn login(code: &str) -> Result<String> {
+    let config = setting::get();
+    let url = format!(
+        "{}/sns/jscode2session?appid={}&secret={}&js_code={}&grant_type=authorization_code",
+        config.weixin.api_url,
+        config.weixin.appid,
+        config.weixin.secret,
+        code
+    );
+    let body = request(Method::Get, &url, None)?;
+    let result: entity::ApiResult = serde_json::from_str(&body).map_err(|err| {
+        ServiceError::String(format!("{:?}", err))
+    })?;
+    match result.errcode {
+        Some(code) if code != 0 => Err(ServiceError::String(format!("weixin login error: {:?}", result.errmsg))),
+        _ => Ok(result.openid?),
+    }
+}
+
+//统一下单，返回小程序wx.requestPayment需要的参数
+pub fn unified_order(order: &entity::Order) -> Result<BTreeMap<String, String>> {
+    let config = setting::get();
+    let appid = config.weixin.appid.clone();
+    let mut params = BTreeMap::new();
+    params.insert("appid".to_owned(), appid.clone());
+    params.insert("mch_id".to_owned(), config.weixin.mchid.clone());
+    params.insert("body".to_owned(), "拼车".to_owned());
+    params.insert("out_trade_no".to_owned(), order.id.clone());
+    params.insert("total_fee".to_owned(), order.total().to_string());
+    params.insert("spbill_create_ip".to_owned(), config.weixin.ip.clone());
+    params.insert("notify_url".to_owned(), config.weixin.notify_url.clone());
+    params.insert("trade_type".to_owned(), "JSAPI".to_owned());
+    params.insert("openid".to_owned(), order.openid.clone());
+    let result = mch_request("/pay/unifiedorder", params)?;
+
+    let mut pay = BTreeMap::new();
+    pay.insert("appId".to_owned(), appid);
+    pay.insert("timeStamp".to_owned(), util::now().to_string());
+    pay.insert("nonceStr".to_owned(), nonce_str());
+    pay.insert("package".to_owned(), format!("prepay_id={}", result.get("prepay_id")?));
+    pay.insert("signType".to_owned(), "MD5".to_owned());
+    let pay_sign = sign(&pay);
+    pay.insert("paySign".to_owned(), pay_sign);
+    Ok(pay)
+}
+
+//校验支付结果通知，返回通知中的参数
+pub fn check_notify(xml: &str) -> Result<BTreeMap<String, String>> {
+    let params = from_xml(xml);
+    if params.get("sign") != Some(&sign(&params)) {
+        return Err(ServiceError::String("weixin notify sign error".to_owned()));
+    }
+    if params.get("result_code").map(|s| s.as_str()) != Some("SUCCESS") {
+        return Err(ServiceError::String("weixin notify not success".to_owned()));
+    }
+    Ok(params)
+}
+
+//total_fee为订单支付的金额，同一个refund_no重复提交时微信只退款一次
+pub fn refund(refund_no: &str, transaction_id: &str, total_fee: i64, refund_fee: i64) -> Result<()> {
+    let config = setting::get();
+    let mut params = BTreeMap::new();
+    params.insert("appid".to_owned(), config.weixin.appid.clone());
+    params.insert("mch_id".to_owned(), config.weixin.mchid.clone());
+    params.insert("transaction_id".to_owned(), transaction_id.to_owned());
+    params.insert("out_refund_no".to_owned(), refund_no.to_owned());
+    params.insert("total_fee".to_owned(), total_fee.to_string());
+    params.insert("refund_fee".to_owned(), refund_fee.to_string());
+    mch_request("/secapi/pay/refund", params).map(|_| ())
+}
+
+//企业付款到零钱，trade_no用于防止重复付款
+pub fn pay_to_client(trade_no: &str, openid: &str, fee: i64) -> Result<()> {
+    let config = setting::get();
+    let mut params = BTreeMap::new();
+    params.insert("mch_appid".to_owned(), config.weixin.appid.clone());
+    params.insert("mchid".to_owned(), config.weixin.mchid.clone());
+    params.insert("partner_trade_no".to_owned(), trade_no.to_owned());
+    params.insert("openid".to_owned(), openid.to_owned());
+    params.insert("check_name".to_owned(), "NO_CHECK".to_owned());
+    params.insert("amount".to_owned(), fee.to_string());
+    params.insert("desc".to_owned(), "拼车收入提现".to_owned());
+    params.insert("spbill_create_ip".to_owned(), config.weixin.ip.clone());
+    mch_request("/mmpaymkttransfers/promotion/transfers", params).map(|_| ())
+}
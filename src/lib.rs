@@ -8,6 +8,8 @@ extern crate mongodb;
 extern crate serde;
 extern crate rocket;
 #[macro_use]
+extern crate rocket_contrib;
+#[macro_use]
 extern crate serde_derive;
 extern crate redis;
 extern crate r2d2;
@@ -21,10 +23,24 @@ extern crate futures;
 extern crate hyper;
 extern crate tokio_core;
 extern crate hyper_tls;
+extern crate serde_json;
+extern crate rand;
 
 
 pub mod setting;
 pub mod db;
 pub mod entity;
 pub mod service;
-pub mod external;
\ No newline at end of file
+pub mod external;
+pub mod util;
+pub mod store;
+pub mod memory;
+pub mod routes;
+pub mod validate;
+pub mod limit;
+pub mod policy;
+pub mod event;
+pub mod sse;
+pub mod matching;
+pub mod pii;
+pub mod metrics;
\ No newline at end of file
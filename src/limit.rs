@@ -0,0 +1,91 @@
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use rocket::{Outcome, State};
+use rocket::http::Status;
+use rocket::request::{self, Request, FromRequest};
+use db::LimitConn;
+use entity::JwtUser;
+use service::{Backend, ServiceError};
+use setting::{self, RateLimitSetting};
+use store::LimitStore;
+
+//需要限流的接口，name用于区分redis中的计数
+pub trait LimitedRoute {
+    fn name() -> &'static str;
+    fn limit(config: &RateLimitSetting) -> i64;
+}
+
+macro_rules! limited_route {
+    ($route:ident, $field:ident) => {
+        pub struct $route;
+
+        impl LimitedRoute for $route {
+            fn name() -> &'static str {
+                stringify!($field)
+            }
+
+            fn limit(config: &RateLimitSetting) -> i64 {
+                config.$field
+            }
+        }
+    }
+}
+
+limited_route!(Login, login);
+limited_route!(Publish, publish);
+limited_route!(Apply, apply);
+
+//请求守卫，同时按登录用户和来源IP计数，放在其他参数之前
+pub struct RateLimit<R>(PhantomData<R>);
+
+//部署在nginx后面时由nginx设置X-Real-IP，其他来源的请求头可以伪造，不使用
+fn client_ip(request: &Request, config: &RateLimitSetting) -> Option<String> {
+    let remote = request.remote()?.ip();
+    let trusted = config.trusted_proxies.iter().any(|proxy| proxy.parse::<IpAddr>().ok() == Some(remote));
+    match request.headers().get_one("X-Real-IP") {
+        Some(ip) if trusted => Some(ip.to_owned()),
+        _ => Some(remote.to_string()),
+    }
+}
+
+impl<'a, 'r, R: LimitedRoute> FromRequest<'a, 'r> for RateLimit<R> {
+    type Error = ServiceError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<RateLimit<R>, ServiceError> {
+        //线上使用单独的限流连接，不从连接池再取一个连接
+        let backend = match request.guard::<State<Backend>>() {
+            Outcome::Success(backend) => backend,
+            _ => return Outcome::Success(RateLimit(PhantomData)),
+        };
+        let store: &LimitStore = match *backend {
+            Backend::Live(..) => &LimitConn,
+            Backend::Memory(ref store) => store,
+        };
+        let config = setting::get().rate_limit.clone();
+        let limit = R::limit(&config);
+        let mut checks = Vec::new();
+        if let Some(user) = request.guard::<JwtUser>().succeeded() {
+            checks.push((format!("{}:user:{}", R::name(), user.id), limit));
+        }
+        if let Some(ip) = client_ip(request, &config) {
+            checks.push((format!("{}:ip:{}", R::name(), ip), limit * config.ip_multiple));
+        }
+        //redis出错时放行，只记录日志；被拒绝时撤销前面已经计入的次数
+        let mut counted = Vec::new();
+        for (key, limit) in checks {
+            match store.hit(&key, limit, config.window) {
+                Ok(Some(member)) => counted.push((key, member)),
+                Ok(None) => {
+                    for (key, member) in counted {
+                        if let Err(err) = store.unhit(&key, &member) {
+                            println!("undo rate limit {}: {:?}", key, err);
+                        }
+                    }
+                    return Outcome::Failure((Status::TooManyRequests, ServiceError::TooManyRequests))
+                }
+                Err(err) => println!("rate limit {}: {:?}", key, err),
+            }
+        }
+        Outcome::Success(RateLimit(PhantomData))
+    }
+}
@@ -1,27 +1,20 @@
-#![feature(plugin)]
-#![plugin(rocket_codegen)]
-
-extern crate rocket;
-#[macro_use]
-extern crate rocket_contrib;
 extern crate pin_che;
-extern crate mongodb;
-
-extern crate bson;
-extern crate redis;
 extern crate tokio_timer;
 extern crate futures;
 
-use pin_che::{entity, db};
-use pin_che::service::{Result, Service, ServiceError};
-//use rocket::request::LenientForm;
-use rocket_contrib::{Json, Value};
+use pin_che::db;
+use pin_che::service::{Backend, Service};
 use std::time::Duration;
 use tokio_timer::Timer;
 use futures::{Stream, Future};
 use std::thread;
+use std::process;
 
 fn main() {
+    if let Err(errors) = pin_che::setting::init() {
+        eprintln!("{}", errors);
+        process::exit(1);
+    }
     let database = pin_che::db::init_db_conn();
     let pool = pin_che::db::init_redis();
     let service = Service::new(
@@ -41,85 +34,52 @@ fn main() {
             .unwrap();
     });
 
-    thread::spawn(|| {
-        println!("{:?}", pin_che::db::check_expire());
-    });
-
-    rocket::ignite()
-        .mount(
-            "/",
-            routes![
-                publish_trip,
-                test_request,
-                apply_trip,
-                pay,
-                discount,
-                submit,
-                get_trips,
-            ],
-        )
-        .manage(database)
-        .manage(pool)
-        .catch(errors![not_found, noauth])
-        .launch();
-}
-
-#[error(404)]
-fn not_found() -> Json<Value> {
-    Json(json!({
-        "status": "error",   //ok表示逻辑错误，系统运行正常；error表示系统内部运行错误（可能是参数解析错误）
-        "reason": "Resource was not found."
-    }))
-}
-
-#[error(401)]
-fn noauth() -> Result<()> {
-    Err(ServiceError::NoAuth)
-}
+    thread::spawn(|| pin_che::setting::watch(Duration::from_secs(10)));
 
-#[get("/publishTrip?<form>")]
-fn publish_trip(form: entity::TripForm, s: Service) -> Result<Json<entity::Trip>> {
-    //let tel = s.get_tel(&jwt.id)?;
-    let trip = entity::Trip::new("openid".to_owned(), form);
-    s.publish_trip(&trip)?;
-    Ok(Json(trip))
-}
+    let expire_database = database.clone();
+    let expire_pool = pool.clone();
+    thread::spawn(move || {
+        println!("{:?}", pin_che::db::check_expire(expire_database, expire_pool));
+    });
 
-#[get("/applyTrip/<id>/<count>/<tel>")]
-fn apply_trip(
-    id: String,
-    count: i64,
-    tel: Option<String>,
-    s: Service,
-) -> Result<Json<entity::Order>> {
-    s.apply_trip(id, "openid".to_owned(), count, tel).map(
-        |order| {
-            Json(order)
-        },
-    )
-}
+    let event_database = database.clone();
+    let event_pool = pool.clone();
+    thread::spawn(move || pin_che::event::run(pin_che::event::bus(), event_database, event_pool));
 
-//微信支付回调（通过其他服务）
-#[get("/pay/<id>/<sign>")]
-fn pay(id: String, sign: String, s: Service) -> Result<()> {
-    s.pay(id, sign)
-}
+    //每小时按行程模板生成新的行程
+    let schedule_database = database.clone();
+    let schedule_pool = pool.clone();
+    thread::spawn(move || loop {
+        match schedule_pool.get() {
+            Ok(conn) => {
+                let service = Service::new(db::DbConn(schedule_database.clone()), db::CacheConn(conn));
+                match service.generate_trips() {
+                    Ok(count) => println!("generated {} trips from templates", count),
+                    Err(err) => println!("generate trips error: {:?}", err),
+                }
+            }
+            Err(err) => println!("schedule can't get redis connection: {:?}", err),
+        }
+        thread::sleep(Duration::from_secs(3600));
+    });
 
-#[get("/discount/<id>/<fee>")]
-fn discount(id: String, fee: i64, s: Service) -> Result<()> {
-    s.discount(id, fee)
-}
+    //每分钟补完已经打款但没有记录结果的提现
+    let withdraw_database = database.clone();
+    let withdraw_pool = pool.clone();
+    thread::spawn(move || loop {
+        match withdraw_pool.get() {
+            Ok(conn) => {
+                let service = Service::new(db::DbConn(withdraw_database.clone()), db::CacheConn(conn));
+                match service.resume_withdraws() {
+                    Ok(0) => (),
+                    Ok(count) => println!("resumed {} withdraws", count),
+                    Err(err) => println!("resume withdraws error: {:?}", err),
+                }
+            }
+            Err(err) => println!("withdraw sweep can't get redis connection: {:?}", err),
+        }
+        thread::sleep(Duration::from_secs(60));
+    });
 
-#[get("/submit/<id>")]
-fn submit(id: String, s: Service) -> Result<()> {
-    s.submit(id)
+    pin_che::routes::app(Backend::Live(database, pool)).launch();
 }
-#[get("/getTrips/<page>")]
-fn get_trips(s: Service, page: isize) -> Result<Json<Vec<entity::Trip>>> {
-    s.get_trips(page).map(|vec| Json(vec))
-}
-
-#[get("/test/request")]
-fn test_request() -> Result<()> {
-    pin_che::external::test()
-}
\ No newline at end of file
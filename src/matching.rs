@@ -0,0 +1,94 @@
+use std::cmp;
+use std::collections::HashSet;
+use entity;
+
+//每次最多通知的匹配数
+pub const TOP: usize = 3;
+//为需求匹配时只查找最新发布的行程
+pub const CANDIDATES: isize = 200;
+//起点和终点的相似度都不低于该值才算顺路
+pub const MIN_SIMILARITY: f64 = 0.5;
+
+//各项得分的权重，总分为0-100
+const START_WEIGHT: f64 = 30.0;
+const END_WEIGHT: f64 = 30.0;
+const TIME_WEIGHT: f64 = 20.0;
+const PRICE_WEIGHT: f64 = 10.0;
+const SEAT_WEIGHT: f64 = 10.0;
+
+fn bigrams(s: &str) -> Vec<String> {
+    let chars: Vec<char> = s.to_lowercase().chars().filter(|c| !c.is_whitespace()).collect();
+    if chars.len() < 2 {
+        return vec![chars.into_iter().collect()];
+    }
+    chars.windows(2).map(|w| w.iter().collect()).collect()
+}
+
+//地名相似度，按相邻两个字符组成的片段计算Dice系数，忽略大小写和空白，范围0-1
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let a = bigrams(a);
+    let b = bigrams(b);
+    if a == b {
+        return 1.0;
+    }
+    let set: HashSet<&String> = b.iter().collect();
+    let common = a.iter().filter(|g| set.contains(g)).count();
+    2.0 * common as f64 / (a.len() + b.len()) as f64
+}
+
+//需求和行程的匹配得分，不满足时间、价格、座位或者不顺路时返回None
+//有途经站点的行程按每一对上下车站点分别计算，取得分最高的一段，价格和座位按该段计算
+//出发时间越接近需求时间范围的中点、价格越低、越能坐满得分越高
+pub fn score(request: &entity::RideRequest, trip: &entity::Trip) -> Option<i64> {
+    if trip.openid == request.openid || trip.status != entity::TripStatus::Prepare ||
+        trip.start_time < request.earliest || trip.start_time > request.latest
+    {
+        return None;
+    }
+    let half = cmp::max((request.latest - request.earliest) / 2, 1);
+    let middle = request.earliest + (request.latest - request.earliest) / 2;
+    let time = 1.0 - ((trip.start_time - middle).abs() as f64 / half as f64).min(1.0);
+    let last = trip.last_stop();
+    (0..last)
+        .flat_map(|from| (from + 1..last + 1).map(move |to| (from, to)))
+        .filter_map(|(from, to)| segment_score(request, trip, from, to, time))
+        .max()
+}
+
+fn segment_score(
+    request: &entity::RideRequest,
+    trip: &entity::Trip,
+    from: i64,
+    to: i64,
+    time: f64,
+) -> Option<i64> {
+    let fare = trip.segment_price(from, to);
+    let seats = trip.segment_seats(from, to);
+    if seats < request.count || fare > request.max_price {
+        return None;
+    }
+    let start = similarity(&request.start, trip.stop_name(from));
+    let end = similarity(&request.end, trip.stop_name(to));
+    if start < MIN_SIMILARITY || end < MIN_SIMILARITY {
+        return None;
+    }
+    let price = if request.max_price == 0 {
+        1.0
+    } else {
+        1.0 - fare as f64 / request.max_price as f64
+    };
+    let seat = request.count as f64 / seats as f64;
+    let total = START_WEIGHT * start + END_WEIGHT * end + TIME_WEIGHT * time + PRICE_WEIGHT * price +
+        SEAT_WEIGHT * seat;
+    Some(total.round() as i64)
+}
+
+//按得分从高到低取前TOP个，得分相同时按id排序，保证结果稳定
+pub fn top<T, F>(mut candidates: Vec<(i64, T)>, id: F) -> Vec<(i64, T)>
+where
+    F: Fn(&T) -> &str,
+{
+    candidates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| id(&a.1).cmp(id(&b.1))));
+    candidates.truncate(TOP);
+    candidates
+}
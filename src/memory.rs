@@ -0,0 +1,1279 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+use entity;
+use service::{ServiceError, Result};
+use policy;
+use setting;
+use store::{TripStore, OrderStore, WalletStore, TokenStore, AuditStore, EventStore, SeatStore, SeatUpdates, WaitlistStore, TemplateStore, RideRequestStore, MatchStore, CouponStore, ReferralStore, MessageStore, MessageUpdates, NotificationStore, LimitStore, AccountStore};
+use db::HEARTBEAT;
+use event::{self, Event};
+use util;
+
+//内存存储，语义与redis实现一致，用于在进程内测试完整的订座流程
+//clone后共享同一份数据
+#[derive(Default, Clone)]
+pub struct MemoryStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    trips: HashMap<String, entity::Trip>,
+    trip_list: Vec<String>, //最新发布的在前，与redis的lpush一致
+    orders: HashMap<String, entity::Order>,
+    trip_orders: HashMap<String, Vec<String>>,
+    wallets: HashMap<String, entity::Wallet>,
+    wallet_logs: HashMap<String, Vec<entity::WalletLog>>,
+    withdraws: HashMap<String, entity::Withdraw>,
+    user_withdraws: HashMap<String, Vec<String>>,
+    withdraw_queue: Vec<String>,
+    processing: HashMap<String, i64>, //打款中的提现 -> 开始打款时间
+    daily: HashMap<(String, i64), (i64, i64)>, //(openid, 日期) -> (次数, 金额)
+    refresh_tokens: HashMap<String, (String, i64)>, //token -> (openid, 过期时间)
+    revoked: HashMap<String, i64>, //jti -> 过期时间
+    access_tokens: HashMap<String, Vec<(String, i64)>>, //openid -> (jti, 过期时间)
+    hits: HashMap<String, Vec<(i64, String)>>, //限流key -> (请求时间（毫秒）, 成员)
+    expired: HashMap<String, (i64, i64)>, //openid -> (未支付过期次数, 计数过期时间)
+    audits: Vec<entity::AuditLog>,
+    events: Vec<Event>, //事件id为下标加1
+    event_groups: HashMap<String, EventGroup>,
+    dead_events: Vec<(String, Event)>, //(消费组, 事件)，最新的在前
+    seat_subscribers: Vec<(Vec<String>, Sender<entity::SeatUpdate>)>,
+    waiters: HashMap<String, entity::Waiter>,
+    waitlists: HashMap<String, Vec<String>>, //trip_id -> 等待中的候补，先加入的在前
+    user_waiters: HashMap<String, Vec<String>>,
+    order_waiters: HashMap<String, String>, //预留订单 -> 候补
+    templates: Vec<entity::TripTemplate>,
+    ride_requests: HashMap<String, entity::RideRequest>,
+    open_ride_requests: Vec<String>, //最新发布的在前
+    matches: HashSet<(String, String)>, //(需求id, 行程id)
+    occurrences: HashMap<(String, String), String>, //(模板id, 日期) -> 行程id
+    coupons: Vec<entity::Coupon>, //最新创建的在前
+    coupon_uses: HashMap<(String, String), i64>, //(优惠券id, openid) -> 使用次数
+    redemptions: HashMap<String, entity::Redemption>, //订单id -> 使用记录
+    coupon_redemptions: HashMap<String, Vec<String>>, //优惠券id -> 订单id，最新使用的在前
+    users: HashSet<String>, //登录过的用户
+    devices: HashMap<String, String>, //openid -> 最近登录的设备
+    referral_codes: HashMap<String, String>, //邀请码 -> openid
+    user_referral_codes: HashMap<String, String>,
+    fingerprints: HashSet<String>,
+    referrals: HashMap<String, entity::Referral>, //被邀请用户 -> 邀请关系
+    referral_list: Vec<String>, //最新的在前
+    referral_grants: HashMap<String, HashSet<String>>, //被邀请用户 -> 已发放奖励的openid
+    conversations: Vec<entity::Conversation>, //最新创建的在前
+    messages: HashMap<String, Vec<entity::Message>>, //会话id -> 消息，按seq排列
+    message_seqs: HashMap<String, i64>,
+    message_reads: HashMap<(String, String), i64>, //(会话id, openid) -> 已读seq
+    message_subscribers: Vec<(String, Sender<entity::Message>)>,
+    complains: Vec<entity::Complain>, //最新的在前
+    notifications: Vec<entity::Notification>, //最新的在前
+}
+
+//消费组：下一个要读取的下标、已投递未确认的事件和最近投递时间
+#[derive(Default)]
+struct EventGroup {
+    next: usize,
+    pending: Vec<usize>,
+    failures: HashMap<usize, i64>,
+    delivered: HashMap<usize, i64>, //下标 -> 投递时间（毫秒）
+}
+
+fn event_index(id: &str) -> Option<usize> {
+    id.parse::<usize>().ok().and_then(|i| i.checked_sub(1))
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+//与redis的lrange相同的下标规则，end为-1时表示到末尾
+fn range<T: Clone>(v: &[T], start: isize, end: isize) -> Vec<T> {
+    let len = v.len() as isize;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if start > end || start >= len {
+        return Vec::new();
+    }
+    v[start as usize..(end + 1) as usize].to_vec()
+}
+
+impl Inner {
+    fn wallet(&mut self, openid: &str) -> &mut entity::Wallet {
+        self.wallets.entry(openid.to_owned()).or_insert_with(|| {
+            entity::Wallet {
+                openid: openid.to_owned(),
+                balance: 0,
+                frozen: 0,
+            }
+        })
+    }
+
+    fn log_wallet(&mut self, log: entity::WalletLog) {
+        self.wallet_logs
+            .entry(log.openid.clone())
+            .or_insert_with(Vec::new)
+            .insert(0, log);
+    }
+}
+
+impl TripStore for MemoryStore {
+    fn add_trip(&self, t: &entity::Trip, events: &[Event]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.trips.insert(t.id.clone(), t.clone());
+        inner.trip_list.insert(0, t.id.clone());
+        inner.events.extend_from_slice(events);
+        Ok(())
+    }
+
+    fn get_trip(&self, id: &str) -> Result<entity::Trip> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.trips.get(id).cloned()?)
+    }
+
+    fn get_trips(&self, start: isize, end: isize) -> Result<Vec<entity::Trip>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            range(&inner.trip_list, start, end)
+                .iter()
+                .filter_map(|id| inner.trips.get(id).cloned())
+                .collect(),
+        )
+    }
+
+    fn check_trip_finish(&self, id: &str, events: &[Event]) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.trips.get(id)?.status == entity::TripStatus::Finish {
+            return Ok(false);
+        }
+        let finish = inner.trip_orders.get(id).map_or(true, |ids| {
+            ids.iter().all(|order_id| {
+                inner.orders.get(order_id).map_or(false, |order| {
+                    order.status == entity::OrderStatus::Submit
+                })
+            })
+        });
+        if finish {
+            inner.trips.get_mut(id)?.status = entity::TripStatus::Finish;
+            inner.events.extend_from_slice(events);
+        }
+        Ok(finish)
+    }
+
+    fn remove_trip(&self, id: &str) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let booked = inner.trip_orders.get(id).map_or(false, |ids| !ids.is_empty());
+        if booked {
+            return Ok(false);
+        }
+        inner.trips.remove(id);
+        inner.trip_list.retain(|trip_id| trip_id != id);
+        Ok(true)
+    }
+}
+
+impl OrderStore for MemoryStore {
+    fn add_order(&self, order: &entity::Order, events: &[Event]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let orders: Vec<entity::Order> =
+                inner.orders.values().filter(|o| o.openid == order.openid).cloned().collect();
+            policy::check_caps(&orders, &order.trip_id, order.count)?;
+        }
+        {
+            let trip = inner.trips.get_mut(&order.trip_id)?;
+            if !trip.take_seats(order.from, order.to, order.count) {
+                return Err(ServiceError::DontHaveEnoughSeats);
+            }
+        }
+        inner.orders.insert(order.id.clone(), order.clone());
+        inner
+            .trip_orders
+            .entry(order.trip_id.clone())
+            .or_insert_with(Vec::new)
+            .push(order.id.clone());
+        inner.events.extend_from_slice(events);
+        Ok(())
+    }
+
+    fn get_order(&self, id: &str) -> Result<entity::Order> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.orders.get(id).cloned()?)
+    }
+
+    fn pay_order(&self, id: &str, transaction_id: &str, events: &[Event]) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let order = inner.orders.get_mut(id).ok_or(ServiceError::NotFound("Order".to_owned()))?;
+            if order.status != entity::OrderStatus::Unpaid {
+                return Ok(false);
+            }
+            order.status = entity::OrderStatus::Paid;
+            order.transaction_id = Some(transaction_id.to_owned());
+        }
+        inner.events.extend_from_slice(events);
+        Ok(true)
+    }
+
+    fn add_refund(&self, order_id: &str, refunded: i64, fee: i64) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let order = inner.orders.get_mut(order_id)?;
+        if order.refunded != refunded {
+            return Err(ServiceError::RefundConflict);
+        }
+        order.refunded += fee;
+        Ok(())
+    }
+
+    fn submit_order(&self, order: &entity::Order, income: i64, events: &[Event]) -> Result<String> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let stored = inner.orders.get_mut(&order.id)?;
+            if stored.status != entity::OrderStatus::Paid {
+                return Err(ServiceError::NoPay);
+            }
+            stored.status = entity::OrderStatus::Submit;
+        }
+        let balance = {
+            let wallet = inner.wallet(&order.trip_owner);
+            wallet.balance += income;
+            wallet.balance
+        };
+        inner.log_wallet(entity::WalletLog::new(
+            &order.trip_owner,
+            income,
+            balance,
+            "order",
+            &order.id,
+        ));
+        inner.events.extend_from_slice(events);
+        Ok(order.trip_id.clone())
+    }
+
+    fn expire_order(&self, id: &str) -> Result<Option<String>> {
+        let mut inner = self.inner.lock().unwrap();
+        let (trip_id, count, openid, from, to) = match inner.orders.get(id) {
+            Some(order) if order.status == entity::OrderStatus::Unpaid => {
+                (order.trip_id.clone(), order.count, order.openid.clone(), order.from, order.to)
+            }
+            _ => return Ok(None), //已支付或不存在
+        };
+        inner.orders.remove(id);
+        {
+            let now = util::now();
+            let expired = inner.expired.entry(openid).or_insert((0, 0));
+            if expired.1 <= now {
+                expired.0 = 0;
+            }
+            expired.0 += 1;
+            expired.1 = now + setting::get().booking.block_time;
+        }
+        if let Some(trip) = inner.trips.get_mut(&trip_id) {
+            trip.release_seats(from, to, count);
+        }
+        if let Some(ids) = inner.trip_orders.get_mut(&trip_id) {
+            ids.retain(|order_id| order_id != id);
+        }
+        inner.events.push(Event::OrderExpired {
+            order_id: id.to_owned(),
+            trip_id: trip_id.clone(),
+        });
+        Ok(Some(trip_id))
+    }
+
+    fn get_trip_orders(&self, trip_id: &str) -> Result<Vec<entity::Order>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            inner
+                .trip_orders
+                .get(trip_id)
+                .map(|ids| ids.iter().filter_map(|id| inner.orders.get(id)).cloned().collect())
+                .unwrap_or_default(),
+        )
+    }
+
+    fn find_orders_by_tel(&self, tel: &str) -> Result<Vec<entity::Order>> {
+        let inner = self.inner.lock().unwrap();
+        let tel = tel.trim();
+        Ok(
+            inner
+                .orders
+                .values()
+                .filter(|order| order.tel.as_ref().map_or(false, |t| t.trim() == tel))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn get_user_orders(&self, openid: &str) -> Result<Vec<entity::Order>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            inner
+                .orders
+                .values()
+                .filter(|order| order.openid == openid)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn get_expired_count(&self, openid: &str) -> Result<i64> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.expired.get(openid).map_or(0, |&(count, expire)| {
+            if expire > util::now() { count } else { 0 }
+        }))
+    }
+}
+
+impl WalletStore for MemoryStore {
+    fn get_wallet(&self, openid: &str) -> Result<entity::Wallet> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.wallet(openid).clone())
+    }
+
+    fn add_withdraw(&self, w: &entity::Withdraw, day_count: i64, day_amount: i64) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let day = (w.openid.clone(), util::today());
+        let (count, amount) = inner.daily.get(&day).cloned().unwrap_or((0, 0));
+        let balance = {
+            let wallet = inner.wallet(&w.openid);
+            if wallet.balance < w.amount {
+                return Err(ServiceError::BalanceNotEnough);
+            }
+            if count + 1 > day_count || amount + w.amount > day_amount {
+                return Err(ServiceError::WithdrawLimit);
+            }
+            wallet.balance -= w.amount;
+            wallet.frozen += w.amount;
+            wallet.balance
+        };
+        inner.daily.insert(day, (count + 1, amount + w.amount));
+        inner.withdraws.insert(w.id.clone(), w.clone());
+        inner
+            .user_withdraws
+            .entry(w.openid.clone())
+            .or_insert_with(Vec::new)
+            .insert(0, w.id.clone());
+        inner.withdraw_queue.push(w.id.clone());
+        inner.log_wallet(entity::WalletLog::new(
+            &w.openid,
+            -w.amount,
+            balance,
+            "withdraw",
+            &w.id,
+        ));
+        Ok(())
+    }
+
+    fn claim_withdraw(&self, id: &str) -> Result<entity::Withdraw> {
+        let mut inner = self.inner.lock().unwrap();
+        let withdraw = {
+            let withdraw = inner.withdraws.get_mut(id).ok_or(ServiceError::NotFound("Withdraw".to_owned()))?;
+            if withdraw.status != entity::WithdrawStatus::Pending {
+                return Err(ServiceError::WithdrawHandled);
+            }
+            withdraw.status = entity::WithdrawStatus::Processing;
+            withdraw.clone()
+        };
+        inner.withdraw_queue.retain(|queued| queued != id);
+        inner.processing.insert(id.to_owned(), util::now());
+        Ok(withdraw)
+    }
+
+    fn release_withdraw(&self, id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let withdraw = inner.withdraws.get_mut(id)?;
+            if withdraw.status != entity::WithdrawStatus::Processing {
+                return Err(ServiceError::WithdrawHandled);
+            }
+            withdraw.status = entity::WithdrawStatus::Pending;
+        }
+        inner.processing.remove(id);
+        inner.withdraw_queue.insert(0, id.to_owned());
+        Ok(())
+    }
+
+    fn finish_withdraw(&self, w: &entity::Withdraw, approved: bool) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let withdraw = inner.withdraws.get_mut(&w.id)?;
+            if withdraw.status != entity::WithdrawStatus::Processing {
+                return Err(ServiceError::WithdrawHandled);
+            }
+            withdraw.handle_time = Some(util::now());
+            withdraw.status = if approved {
+                entity::WithdrawStatus::Approved
+            } else {
+                entity::WithdrawStatus::Rejected
+            };
+        }
+        inner.processing.remove(&w.id);
+        let balance = {
+            let wallet = inner.wallet(&w.openid);
+            wallet.frozen -= w.amount;
+            if !approved {
+                wallet.balance += w.amount;
+            }
+            wallet.balance
+        };
+        if !approved {
+            inner.log_wallet(entity::WalletLog::new(
+                &w.openid,
+                w.amount,
+                balance,
+                "withdraw_rejected",
+                &w.id,
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_processing_withdraws(&self, before: i64) -> Result<Vec<entity::Withdraw>> {
+        let inner = self.inner.lock().unwrap();
+        let mut withdraws: Vec<entity::Withdraw> = inner
+            .processing
+            .iter()
+            .filter(|&(_, &time)| time <= before)
+            .filter_map(|(id, _)| inner.withdraws.get(id))
+            .cloned()
+            .collect();
+        withdraws.sort_by(|a, b| a.create_time.cmp(&b.create_time));
+        Ok(withdraws)
+    }
+
+    fn get_withdraws(&self, openid: &str, start: isize, end: isize) -> Result<Vec<entity::Withdraw>> {
+        let inner = self.inner.lock().unwrap();
+        let ids = inner.user_withdraws.get(openid).cloned().unwrap_or_default();
+        Ok(
+            range(&ids, start, end)
+                .iter()
+                .filter_map(|id| inner.withdraws.get(id).cloned())
+                .collect(),
+        )
+    }
+
+    fn get_pending_withdraws(&self, start: isize, end: isize) -> Result<Vec<entity::Withdraw>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            range(&inner.withdraw_queue, start, end)
+                .iter()
+                .filter_map(|id| inner.withdraws.get(id).cloned())
+                .collect(),
+        )
+    }
+
+    fn get_wallet_logs(&self, openid: &str, start: isize, end: isize) -> Result<Vec<entity::WalletLog>> {
+        let inner = self.inner.lock().unwrap();
+        let logs = inner.wallet_logs.get(openid).cloned().unwrap_or_default();
+        Ok(range(&logs, start, end))
+    }
+
+    fn credit_wallet(&self, openid: &str, amount: i64, reason: &str, ref_id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let balance = {
+            let wallet = inner.wallet(openid);
+            wallet.balance += amount;
+            wallet.balance
+        };
+        inner.log_wallet(entity::WalletLog::new(openid, amount, balance, reason, ref_id));
+        Ok(())
+    }
+}
+
+impl TokenStore for MemoryStore {
+    fn add_refresh_token(&self, token: &str, openid: &str, ttl: i64) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.refresh_tokens.insert(
+            token.to_owned(),
+            (openid.to_owned(), util::now() + ttl),
+        );
+        Ok(())
+    }
+
+    fn take_refresh_token(&self, token: &str) -> Result<Option<String>> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.refresh_tokens.remove(token).and_then(
+            |(openid, expire)| if expire > util::now() {
+                Some(openid)
+            } else {
+                None
+            },
+        ))
+    }
+
+    fn remove_refresh_tokens(&self, openid: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.refresh_tokens.retain(|_, &mut (ref owner, _)| owner != openid);
+        Ok(())
+    }
+
+    fn revoke_token(&self, jti: &str, ttl: i64) -> Result<()> {
+        if ttl > 0 {
+            let mut inner = self.inner.lock().unwrap();
+            inner.revoked.insert(jti.to_owned(), util::now() + ttl);
+        }
+        Ok(())
+    }
+
+    fn is_revoked(&self, jti: &str) -> Result<bool> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.revoked.get(jti).map_or(false, |&expire| expire > util::now()))
+    }
+
+    fn add_access_token(&self, openid: &str, jti: &str, ttl: i64) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.access_tokens
+            .entry(openid.to_owned())
+            .or_insert_with(Vec::new)
+            .push((jti.to_owned(), util::now() + ttl));
+        Ok(())
+    }
+
+    fn revoke_user_tokens(&self, openid: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let tokens = inner.access_tokens.remove(openid).unwrap_or_default();
+        inner.revoked.extend(tokens);
+        Ok(())
+    }
+}
+
+impl AuditStore for MemoryStore {
+    fn add_audit(&self, log: &entity::AuditLog) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.audits.push(log.clone());
+        Ok(())
+    }
+
+    fn get_audits(&self, entity: &str, entity_id: &str) -> Result<Vec<entity::AuditLog>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            inner
+                .audits
+                .iter()
+                .filter(|log| log.entity == entity && log.entity_id == entity_id)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+impl EventStore for MemoryStore {
+    fn publish_event(&self, event: &Event) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.events.push(event.clone());
+        Ok(())
+    }
+
+    fn add_event_group(&self, group: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let next = inner.events.len();
+        inner.event_groups.entry(group.to_owned()).or_insert_with(|| {
+            EventGroup {
+                next,
+                ..Default::default()
+            }
+        });
+        Ok(())
+    }
+
+    fn read_events(&self, group: &str, count: usize) -> Result<Vec<(String, Event)>> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let events = &inner.events;
+        let group = inner.event_groups.get_mut(group).ok_or(ServiceError::NotFound("EventGroup".to_owned()))?;
+        let now = util::now_millis();
+        //未确认的事件按失败次数等待，等待中的事件不影响读取新事件
+        let mut due: Vec<usize> = {
+            let (failures, delivered) = (&group.failures, &group.delivered);
+            group
+                .pending
+                .iter()
+                .cloned()
+                .filter(|i| {
+                    let failures = failures.get(i).cloned().unwrap_or(0);
+                    now - delivered.get(i).cloned().unwrap_or(0) >= event::retry_delay(failures)
+                })
+                .take(count)
+                .collect()
+        };
+        if due.len() < count {
+            let end = events.len().min(group.next + count - due.len());
+            group.pending.extend(group.next..end);
+            due.extend(group.next..end);
+            group.next = end;
+        }
+        for &i in &due {
+            group.delivered.insert(i, now);
+        }
+        Ok(due.into_iter().map(|i| ((i + 1).to_string(), events[i].clone())).collect())
+    }
+
+    fn ack_event(&self, group: &str, id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = event_index(id)?;
+        let group = inner.event_groups.get_mut(group)?;
+        group.pending.retain(|&i| i != index);
+        group.failures.remove(&index);
+        group.delivered.remove(&index);
+        Ok(())
+    }
+
+    fn fail_event(&self, group: &str, id: &str) -> Result<i64> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = event_index(id)?;
+        let failures = inner.event_groups.get_mut(group)?.failures.entry(index).or_insert(0);
+        *failures += 1;
+        Ok(*failures)
+    }
+
+    fn dead_letter_event(&self, group: &str, id: &str, event: &Event) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = event_index(id)?;
+        {
+            let group = inner.event_groups.get_mut(group)?;
+            group.pending.retain(|&i| i != index);
+            group.failures.remove(&index);
+            group.delivered.remove(&index);
+        }
+        inner.dead_events.insert(0, (group.to_owned(), event.clone()));
+        Ok(())
+    }
+
+    fn get_dead_events(&self, count: usize) -> Result<Vec<(String, Event)>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.dead_events.iter().take(count).cloned().collect())
+    }
+}
+
+impl SeatStore for MemoryStore {
+    fn publish_seats(&self, update: &entity::SeatUpdate) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        //发送失败说明订阅者已断开
+        inner.seat_subscribers.retain(|&(ref ids, ref tx)| {
+            !ids.contains(&update.trip_id) || tx.send(update.clone()).is_ok()
+        });
+        Ok(())
+    }
+
+    fn subscribe_seats(&self, trip_ids: &[String]) -> Result<SeatUpdates> {
+        let (tx, rx) = mpsc::channel();
+        let mut inner = self.inner.lock().unwrap();
+        inner.seat_subscribers.push((trip_ids.to_vec(), tx));
+        Ok(Box::new(Subscription(rx)))
+    }
+}
+
+struct Subscription<T>(Receiver<T>);
+
+impl<T> Iterator for Subscription<T> {
+    type Item = Option<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.recv_timeout(Duration::from_secs(HEARTBEAT)) {
+            Ok(update) => Some(Some(update)),
+            Err(RecvTimeoutError::Timeout) => Some(None),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+impl TemplateStore for MemoryStore {
+    fn add_template(&self, t: &entity::TripTemplate) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.templates.insert(0, t.clone());
+        Ok(())
+    }
+
+    fn get_template(&self, id: &str) -> Result<entity::TripTemplate> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.templates.iter().find(|t| t.id == id).cloned()?)
+    }
+
+    fn update_template(&self, t: &entity::TripTemplate) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.templates.iter_mut().find(|stored| stored.id == t.id)? = t.clone();
+        Ok(())
+    }
+
+    fn get_templates(&self, openid: &str) -> Result<Vec<entity::TripTemplate>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.templates.iter().filter(|t| t.openid == openid).cloned().collect())
+    }
+
+    fn find_templates_by_tel(&self, tel: &str) -> Result<Vec<entity::TripTemplate>> {
+        let inner = self.inner.lock().unwrap();
+        let tel = tel.trim();
+        Ok(inner.templates.iter().filter(|t| t.tel.trim() == tel).cloned().collect())
+    }
+
+    fn get_active_templates(&self, date: &str) -> Result<Vec<entity::TripTemplate>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.templates.iter().filter(|t| t.end_date.as_str() >= date).cloned().collect())
+    }
+
+    fn claim_occurrence(&self, template_id: &str, date: &str, trip_id: &str) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (template_id.to_owned(), date.to_owned());
+        if inner.occurrences.contains_key(&key) {
+            return Ok(false);
+        }
+        inner.occurrences.insert(key, trip_id.to_owned());
+        Ok(true)
+    }
+
+    fn get_occurrence(&self, template_id: &str, date: &str) -> Result<Option<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.occurrences.get(&(template_id.to_owned(), date.to_owned())).cloned())
+    }
+
+    fn release_occurrence(&self, template_id: &str, date: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.occurrences.remove(&(template_id.to_owned(), date.to_owned()));
+        Ok(())
+    }
+}
+
+impl RideRequestStore for MemoryStore {
+    fn add_ride_request(&self, r: &entity::RideRequest, events: &[Event]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ride_requests.insert(r.id.clone(), r.clone());
+        inner.open_ride_requests.insert(0, r.id.clone());
+        inner.events.extend_from_slice(events);
+        Ok(())
+    }
+
+    fn get_ride_request(&self, id: &str) -> Result<entity::RideRequest> {
+        let inner = self.inner.lock().unwrap();
+        inner.ride_requests.get(id).cloned().ok_or(ServiceError::NotFound("RideRequest".to_owned()))
+    }
+
+    fn get_open_ride_requests(&self) -> Result<Vec<entity::RideRequest>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            inner
+                .open_ride_requests
+                .iter()
+                .filter_map(|id| inner.ride_requests.get(id).cloned())
+                .collect(),
+        )
+    }
+
+    fn get_user_ride_requests(&self, openid: &str) -> Result<Vec<entity::RideRequest>> {
+        let inner = self.inner.lock().unwrap();
+        let mut requests: Vec<entity::RideRequest> = inner
+            .ride_requests
+            .values()
+            .filter(|r| r.openid == openid)
+            .cloned()
+            .collect();
+        requests.sort_by(|a, b| b.create_time.cmp(&a.create_time).then(b.id.cmp(&a.id)));
+        Ok(requests)
+    }
+
+    fn close_ride_request(&self, id: &str, status: &entity::RideRequestStatus) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.open_ride_requests.len();
+        inner.open_ride_requests.retain(|open| open != id);
+        if inner.open_ride_requests.len() == before {
+            return Ok(false);
+        }
+        inner.ride_requests.get_mut(id)?.status = status.clone();
+        Ok(true)
+    }
+
+    fn reopen_ride_request(&self, id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ride_requests.get_mut(id)?.status = entity::RideRequestStatus::Open;
+        inner.open_ride_requests.insert(0, id.to_owned());
+        Ok(())
+    }
+
+    fn set_ride_request_order(&self, id: &str, trip_id: &str, order_id: &str, events: &[Event]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let request = inner.ride_requests.get_mut(id)?;
+            request.trip_id = Some(trip_id.to_owned());
+            request.order_id = Some(order_id.to_owned());
+        }
+        inner.events.extend_from_slice(events);
+        Ok(())
+    }
+}
+
+impl CouponStore for MemoryStore {
+    fn add_coupon(&self, c: &entity::Coupon) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.coupons.insert(0, c.clone());
+        Ok(())
+    }
+
+    fn find_coupon(&self, code: &str) -> Result<Option<entity::Coupon>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.coupons.iter().find(|c| c.code == code).cloned())
+    }
+
+    fn get_coupons(&self) -> Result<Vec<entity::Coupon>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.coupons.clone())
+    }
+
+    fn redeem_coupon(&self, r: &entity::Redemption, limit: i64) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let used = inner
+                .coupon_uses
+                .entry((r.coupon_id.clone(), r.openid.clone()))
+                .or_insert(0);
+            if *used >= limit {
+                return Ok(false);
+            }
+            *used += 1;
+        }
+        inner.redemptions.insert(r.order_id.clone(), r.clone());
+        inner
+            .coupon_redemptions
+            .entry(r.coupon_id.clone())
+            .or_insert_with(Vec::new)
+            .insert(0, r.order_id.clone());
+        Ok(true)
+    }
+
+    fn release_coupon(&self, order_id: &str) -> Result<Option<entity::Redemption>> {
+        let mut inner = self.inner.lock().unwrap();
+        let r = match inner.redemptions.remove(order_id) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if let Some(used) = inner.coupon_uses.get_mut(&(r.coupon_id.clone(), r.openid.clone())) {
+            *used -= 1;
+        }
+        if let Some(ids) = inner.coupon_redemptions.get_mut(&r.coupon_id) {
+            ids.retain(|id| id != order_id);
+        }
+        Ok(Some(r))
+    }
+
+    fn get_user_coupons(&self, openid: &str) -> Result<Vec<entity::Coupon>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            inner
+                .coupons
+                .iter()
+                .filter(|c| c.owner.as_ref().map(|owner| owner.as_str()) == Some(openid))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn get_redemptions(&self, coupon_id: &str) -> Result<Vec<entity::Redemption>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            inner
+                .coupon_redemptions
+                .get(coupon_id)
+                .map_or(Vec::new(), |ids| {
+                    ids.iter().filter_map(|id| inner.redemptions.get(id).cloned()).collect()
+                }),
+        )
+    }
+}
+
+impl ReferralStore for MemoryStore {
+    fn add_login(&self, openid: &str, device: Option<&str>) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(device) = device {
+            inner.devices.insert(openid.to_owned(), device.to_owned());
+        }
+        Ok(inner.users.insert(openid.to_owned()))
+    }
+
+    fn get_device(&self, openid: &str) -> Result<Option<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.devices.get(openid).cloned())
+    }
+
+    fn get_referral_code(&self, openid: &str) -> Result<Option<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.user_referral_codes.get(openid).cloned())
+    }
+
+    fn add_referral_code(&self, openid: &str, code: &str) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.referral_codes.contains_key(code) || inner.user_referral_codes.contains_key(openid) {
+            return Ok(false);
+        }
+        inner.referral_codes.insert(code.to_owned(), openid.to_owned());
+        inner.user_referral_codes.insert(openid.to_owned(), code.to_owned());
+        Ok(true)
+    }
+
+    fn find_referrer(&self, code: &str) -> Result<Option<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.referral_codes.get(code).cloned())
+    }
+
+    fn claim_fingerprint(&self, fingerprint: &str) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.fingerprints.insert(fingerprint.to_owned()))
+    }
+
+    fn add_referral(&self, r: &entity::Referral) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.referrals.contains_key(&r.id) {
+            return Ok(false);
+        }
+        inner.referrals.insert(r.id.clone(), r.clone());
+        inner.referral_list.insert(0, r.id.clone());
+        Ok(true)
+    }
+
+    fn get_referral(&self, referee: &str) -> Result<Option<entity::Referral>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.referrals.get(referee).cloned())
+    }
+
+    fn finish_referral(&self, referee: &str, status: &entity::ReferralStatus, reason: Option<&str>, order_id: &str) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let r = match inner.referrals.get_mut(referee) {
+            Some(r) => r,
+            None => return Ok(false),
+        };
+        if r.status != entity::ReferralStatus::Pending {
+            return Ok(false);
+        }
+        r.status = status.clone();
+        r.order_id = Some(order_id.to_owned());
+        r.finish_time = Some(util::now());
+        if let Some(reason) = reason {
+            r.reason = Some(reason.to_owned());
+        }
+        Ok(true)
+    }
+
+    fn get_referral_grants(&self, referee: &str) -> Result<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.referral_grants.get(referee).map_or(Vec::new(), |grants| grants.iter().cloned().collect()))
+    }
+
+    fn add_referral_grant(&self, referee: &str, openid: &str, events: &[Event]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.referral_grants.entry(referee.to_owned()).or_insert_with(HashSet::new).insert(openid.to_owned());
+        inner.events.extend_from_slice(events);
+        Ok(())
+    }
+
+    fn get_referrals(&self, start: isize, end: isize) -> Result<Vec<entity::Referral>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            range(&inner.referral_list, start, end)
+                .iter()
+                .filter_map(|id| inner.referrals.get(id).cloned())
+                .collect(),
+        )
+    }
+
+    fn get_user_referrals(&self, referrer: &str) -> Result<Vec<entity::Referral>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(
+            inner
+                .referral_list
+                .iter()
+                .filter_map(|id| inner.referrals.get(id))
+                .filter(|r| r.referrer == referrer)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+impl MatchStore for MemoryStore {
+    fn add_match(&self, request_id: &str, trip_id: &str, events: &[Event]) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.matches.insert((request_id.to_owned(), trip_id.to_owned())) {
+            return Ok(false);
+        }
+        inner.events.extend_from_slice(events);
+        Ok(true)
+    }
+}
+
+impl WaitlistStore for MemoryStore {
+    fn add_waiter(&self, w: &entity::Waiter) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.waiters.insert(w.id.clone(), w.clone());
+        inner
+            .waitlists
+            .entry(w.trip_id.clone())
+            .or_insert_with(Vec::new)
+            .push(w.id.clone());
+        inner
+            .user_waiters
+            .entry(w.openid.clone())
+            .or_insert_with(Vec::new)
+            .insert(0, w.id.clone());
+        Ok(())
+    }
+
+    fn get_waiter(&self, id: &str) -> Result<entity::Waiter> {
+        let inner = self.inner.lock().unwrap();
+        inner.waiters.get(id).cloned().ok_or(ServiceError::NotFound("Waiter".to_owned()))
+    }
+
+    fn get_waiters(&self, trip_id: &str) -> Result<Vec<entity::Waiter>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.waitlists.get(trip_id).map_or(Vec::new(), |ids| {
+            ids.iter()
+                .filter_map(|id| inner.waiters.get(id).cloned())
+                .collect()
+        }))
+    }
+
+    fn get_user_waiters(&self, openid: &str) -> Result<Vec<entity::Waiter>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.user_waiters.get(openid).map_or(Vec::new(), |ids| {
+            ids.iter()
+                .filter_map(|id| inner.waiters.get(id).cloned())
+                .collect()
+        }))
+    }
+
+    fn take_waiter(&self, w: &entity::Waiter, status: &entity::WaitStatus) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let ids = match inner.waitlists.get_mut(&w.trip_id) {
+                Some(ids) => ids,
+                None => return Ok(false),
+            };
+            match ids.iter().position(|id| *id == w.id) {
+                Some(i) => ids.remove(i),
+                None => return Ok(false),
+            };
+        }
+        inner.waiters.get_mut(&w.id)?.status = status.clone();
+        Ok(true)
+    }
+
+    fn requeue_waiter(&self, w: &entity::Waiter) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .waitlists
+            .entry(w.trip_id.clone())
+            .or_insert_with(Vec::new)
+            .insert(0, w.id.clone());
+        inner.waiters.get_mut(&w.id)?.status = entity::WaitStatus::Waiting;
+        Ok(())
+    }
+
+    fn offer_waiter(&self, id: &str, order_id: &str, events: &[Event]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.waiters.get_mut(id)?.order_id = Some(order_id.to_owned());
+        inner.order_waiters.insert(order_id.to_owned(), id.to_owned());
+        inner.events.extend_from_slice(events);
+        Ok(())
+    }
+
+    fn close_offer(&self, order_id: &str, status: &entity::WaitStatus) -> Result<Option<entity::Waiter>> {
+        let mut inner = self.inner.lock().unwrap();
+        let id = match inner.order_waiters.remove(order_id) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let waiter = inner.waiters.get_mut(&id)?;
+        waiter.status = status.clone();
+        Ok(Some(waiter.clone()))
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn add_conversation(&self, c: &entity::Conversation) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.conversations.iter().any(|old| old.id == c.id) {
+            return Err(ServiceError::String(format!("duplicate conversation {}", c.id)));
+        }
+        inner.conversations.insert(0, c.clone());
+        Ok(())
+    }
+
+    fn get_conversation(&self, id: &str) -> Result<Option<entity::Conversation>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.conversations.iter().find(|c| c.id == id).cloned())
+    }
+
+    fn update_conversation(&self, c: &entity::Conversation) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old) = inner.conversations.iter_mut().find(|old| old.id == c.id) {
+            *old = c.clone();
+        }
+        Ok(())
+    }
+
+    fn get_user_conversations(&self, openid: &str) -> Result<Vec<entity::Conversation>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.conversations
+            .iter()
+            .filter(|c| c.driver == openid || c.passenger == openid)
+            .cloned()
+            .collect())
+    }
+
+    fn get_trip_conversations(&self, trip_id: &str) -> Result<Vec<entity::Conversation>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.conversations.iter().filter(|c| c.trip_id == trip_id).cloned().collect())
+    }
+
+    fn next_message_seq(&self, conversation_id: &str) -> Result<i64> {
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.message_seqs.entry(conversation_id.to_owned()).or_insert(0);
+        *seq += 1;
+        Ok(*seq)
+    }
+
+    fn get_message_seq(&self, conversation_id: &str) -> Result<i64> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.message_seqs.get(conversation_id).cloned().unwrap_or(0))
+    }
+
+    fn add_message(&self, m: &entity::Message) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let messages = inner.messages.entry(m.conversation_id.clone()).or_insert_with(Vec::new);
+        let pos = messages.iter().position(|old| old.seq > m.seq).unwrap_or(messages.len());
+        messages.insert(pos, m.clone());
+        Ok(())
+    }
+
+    fn get_messages(&self, conversation_id: &str, after: i64, count: usize) -> Result<Vec<entity::Message>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.messages
+            .get(conversation_id)
+            .map(|messages| messages.iter().filter(|m| m.seq > after).take(count).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn mark_read(&self, conversation_id: &str, openid: &str, seq: i64) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let read = inner.message_reads
+            .entry((conversation_id.to_owned(), openid.to_owned()))
+            .or_insert(0);
+        *read = (*read).max(seq);
+        Ok(())
+    }
+
+    fn get_read(&self, conversation_id: &str, openid: &str) -> Result<i64> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.message_reads
+            .get(&(conversation_id.to_owned(), openid.to_owned()))
+            .cloned()
+            .unwrap_or(0))
+    }
+
+    fn publish_message(&self, receiver: &str, m: &entity::Message) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.message_subscribers.retain(|&(ref openid, ref tx)| {
+            openid != receiver || tx.send(m.clone()).is_ok()
+        });
+        Ok(())
+    }
+
+    fn subscribe_messages(&self, openid: &str) -> Result<MessageUpdates> {
+        let (tx, rx) = mpsc::channel();
+        let mut inner = self.inner.lock().unwrap();
+        inner.message_subscribers.push((openid.to_owned(), tx));
+        Ok(Box::new(Subscription(rx)))
+    }
+}
+
+impl NotificationStore for MemoryStore {
+    fn add_notification(&self, n: &entity::Notification) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.notifications.iter().any(|old| old.id == n.id) {
+            inner.notifications.insert(0, n.clone());
+        }
+        Ok(())
+    }
+
+    fn get_notifications(&self, openid: &str) -> Result<Vec<entity::Notification>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.notifications.iter().filter(|n| n.openid == openid).cloned().collect())
+    }
+}
+
+impl LimitStore for MemoryStore {
+    fn hit(&self, key: &str, limit: i64, window: i64) -> Result<Option<String>> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = util::now_millis();
+        let hits = inner.hits.entry(key.to_owned()).or_insert_with(Vec::new);
+        hits.retain(|&(time, _)| time > now - window * 1000);
+        if hits.len() as i64 >= limit {
+            return Ok(None);
+        }
+        let member = format!("{}-{}", now, util::random_string(8));
+        hits.push((now, member.clone()));
+        Ok(Some(member))
+    }
+
+    fn unhit(&self, key: &str, member: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(hits) = inner.hits.get_mut(key) {
+            hits.retain(|&(_, ref hit)| hit != member);
+        }
+        Ok(())
+    }
+}
+
+impl AccountStore for MemoryStore {
+    fn get_user_trips(&self, openid: &str) -> Result<Vec<entity::Trip>> {
+        let inner = self.inner.lock().unwrap();
+        let mut trips: Vec<entity::Trip> = inner.trips.values().filter(|t| t.openid == openid).cloned().collect();
+        trips.sort_by(|a, b| b.start_time.cmp(&a.start_time));
+        Ok(trips)
+    }
+
+    fn add_complain(&self, c: &entity::Complain) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.complains.insert(0, c.clone());
+        Ok(())
+    }
+
+    fn get_user_complains(&self, openid: &str) -> Result<Vec<entity::Complain>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.complains.iter().filter(|c| c.openid == openid).cloned().collect())
+    }
+
+    fn erase_user(&self, openid: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        for trip in inner.trips.values_mut().filter(|t| t.openid == openid) {
+            trip.tel = String::new();
+            trip.plate_number = String::new();
+            trip.car_type = String::new();
+            trip.message = None;
+        }
+        for order in inner.orders.values_mut().filter(|o| o.openid == openid) {
+            order.tel = None;
+        }
+        for request in inner.ride_requests.values_mut().filter(|r| r.openid == openid) {
+            request.tel = None;
+            request.message = None;
+        }
+        for waiter in inner.waiters.values_mut().filter(|w| w.openid == openid) {
+            waiter.tel = None;
+        }
+        for message in inner.messages.values_mut().flat_map(|messages| messages.iter_mut()).filter(|m| m.sender == openid) {
+            message.content = String::new();
+        }
+        inner.complains.retain(|c| c.openid != openid);
+        inner.notifications.retain(|n| n.openid != openid);
+        inner.devices.remove(openid);
+        inner.users.remove(openid);
+        if let Some(referral) = inner.referrals.get_mut(openid) {
+            referral.device = None;
+        }
+        if let Some(code) = inner.user_referral_codes.remove(openid) {
+            inner.referral_codes.remove(&code);
+        }
+        Ok(())
+    }
+}
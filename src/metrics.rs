@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+use rocket::{Data, Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+
+//进程内累计的指标，/metrics按Prometheus文本格式输出，进程重启后清零
+
+//与Prometheus客户端默认的桶一致，单位为秒
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+//指标名、类型和说明，按这个顺序输出，没有数据的指标只输出说明
+const METRICS: &[(&str, &str, &str)] = &[
+    ("http_request_duration_seconds", "histogram", "HTTP request latency by route, method and status"),
+    ("redis_command_duration_seconds", "histogram", "Redis command latency, pipelines and transactions count as one call"),
+    ("redis_errors_total", "counter", "Redis commands that returned an error"),
+    ("mongo_operation_duration_seconds", "histogram", "MongoDB operation latency by operation and collection"),
+    ("mongo_errors_total", "counter", "MongoDB operations that returned an error"),
+    ("redis_pool_connections", "gauge", "Connections opened by the redis pool"),
+    ("redis_pool_idle_connections", "gauge", "Idle connections in the redis pool"),
+    ("redis_pool_timeouts_total", "counter", "Requests that could not get a redis connection"),
+    ("trips_published_total", "counter", "Trips published, by source"),
+    ("orders_created_total", "counter", "Orders created"),
+    ("orders_paid_total", "counter", "Orders paid"),
+    ("orders_expired_total", "counter", "Unpaid orders expired and their seats released"),
+    ("seats_reserved_total", "counter", "Seats reserved by created orders"),
+    ("refunds_total", "counter", "Refunds requested from weixin pay, by result"),
+    ("payout_failures_total", "counter", "Withdraw payouts that failed and went back to the queue"),
+    ("expire_events_total", "counter", "Key expiry events handled by check_expire, by result"),
+];
+
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+struct Histogram {
+    buckets: [u64; 11], //落在每个桶内的次数，输出时再累加
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    values: BTreeMap<Key, f64>, //counter和gauge
+    histograms: BTreeMap<Key, Histogram>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+    static ref START: Instant = Instant::now();
+}
+
+fn key(name: &'static str, labels: &[(&'static str, &str)]) -> Key {
+    (name, labels.iter().map(|&(k, v)| (k, v.to_owned())).collect())
+}
+
+pub fn inc(name: &'static str, labels: &[(&'static str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+pub fn add(name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.values.entry(key(name, labels)).or_insert(0.0) += value;
+}
+
+pub fn set(name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.values.insert(key(name, labels), value);
+}
+
+pub fn observe(name: &'static str, labels: &[(&'static str, &str)], seconds: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let histogram = registry.histograms.entry(key(name, labels)).or_insert(Histogram {
+        buckets: [0; 11],
+        sum: 0.0,
+        count: 0,
+    });
+    if let Some(i) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+        histogram.buckets[i] += 1;
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+fn seconds(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
+
+//记录耗时，返回错误时errors计数加1
+pub fn time<T, E, F>(duration: &'static str, errors: &'static str, labels: &[(&'static str, &str)], f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E>,
+{
+    let start = Instant::now();
+    let result = f();
+    observe(duration, labels, seconds(start));
+    if result.is_err() {
+        inc(errors, labels);
+    }
+    result
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels.iter().map(|&(k, ref v)| format!("{}=\"{}\"", k, escape(v))).collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for &(name, kind, help) in METRICS {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (&(_, ref labels), value) in registry.values.iter().filter(|&(k, _)| k.0 == name) {
+            let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+        }
+        for (&(_, ref labels), histogram) in registry.histograms.iter().filter(|&(k, _)| k.0 == name) {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let le = bound.to_string();
+                let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some(&le)), cumulative);
+            }
+            let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some("+Inf")), histogram.count);
+            let _ = writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", name, format_labels(labels, None), histogram.count);
+        }
+    }
+    out
+}
+
+//记录每个请求的耗时和状态，开始时间放在请求头里传到on_response
+pub struct RequestTimer;
+
+const START_HEADER: &str = "X-Metrics-Start";
+
+impl Fairing for RequestTimer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let elapsed = START.elapsed();
+        let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        request.replace_header(Header::new(START_HEADER, nanos.to_string()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let start = match request.headers().get_one(START_HEADER).and_then(|v| v.parse::<u64>().ok()) {
+            Some(start) => start,
+            None => return,
+        };
+        let elapsed = START.elapsed();
+        let nanos = (elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64).saturating_sub(start);
+        //用路由的模板作为标签，避免路径参数造成过多的时间序列
+        let route = request.route().map_or("unmatched".to_owned(), |route| route.uri.path().to_owned());
+        let method = request.method().to_string();
+        let status = response.status().code.to_string();
+        observe(
+            "http_request_duration_seconds",
+            &[("route", &route), ("method", &method), ("status", &status)],
+            nanos as f64 / 1e9,
+        );
+    }
+}
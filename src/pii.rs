@@ -0,0 +1,145 @@
+use bson::{Bson, Document};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+use redis;
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::hex::ToHex;
+use service::{Result, ServiceError};
+use setting;
+
+//个人信息字段的加密：每个值使用随机生成的数据密钥加密，数据密钥再用pii.keys中的主密钥加密后一起保存
+//格式为 pii:{kid}:{加密后的数据密钥}:{密文}，轮换主密钥时加入新密钥并切换current_kid，旧数据仍可用原kid解密
+pub const PREFIX: &str = "pii:";
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+fn error(message: &str) -> ServiceError {
+    ServiceError::String(format!("pii: {}", message))
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut rng = OsRng::new()?;
+    let mut bytes = vec![0u8; len];
+    rng.fill_bytes(&mut bytes);
+    Ok(bytes)
+}
+
+//输出为 nonce + 密文 + tag
+fn encrypt(key: &[u8], aad: &[u8], plain: &[u8]) -> Result<Vec<u8>> {
+    let nonce = random_bytes(NONCE_LEN)?;
+    let mut cipher = AesGcm::new(KeySize::KeySize256, key, &nonce, aad);
+    let mut out = vec![0u8; plain.len()];
+    let mut tag = [0u8; TAG_LEN];
+    cipher.encrypt(plain, &mut out, &mut tag);
+    let mut sealed = nonce;
+    sealed.extend_from_slice(&out);
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+fn decrypt(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(error("ciphertext too short"));
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (data, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let mut cipher = AesGcm::new(KeySize::KeySize256, key, nonce, aad);
+    let mut out = vec![0u8; data.len()];
+    if cipher.decrypt(data, &mut out, tag) {
+        Ok(out)
+    } else {
+        Err(error("authentication failed"))
+    }
+}
+
+//主密钥为base64编码的32字节
+pub fn decode_key(key: &str) -> Option<Vec<u8>> {
+    key.from_base64().ok().and_then(|key| if key.len() == KEY_LEN { Some(key) } else { None })
+}
+
+fn master_key(kid: &str) -> Result<Vec<u8>> {
+    let config = setting::get();
+    config.pii.keys.get(kid)
+        .and_then(|key| decode_key(key))
+        .ok_or_else(|| error(&format!("unknown key {}", kid)))
+}
+
+pub fn seal(plain: &str) -> Result<String> {
+    let kid = setting::get().pii.current_kid.clone();
+    let data_key = random_bytes(KEY_LEN)?;
+    let wrapped = encrypt(&master_key(&kid)?, kid.as_bytes(), &data_key)?;
+    let sealed = encrypt(&data_key, &[], plain.as_bytes())?;
+    Ok(format!("{}{}:{}:{}", PREFIX, kid, wrapped.to_base64(STANDARD), sealed.to_base64(STANDARD)))
+}
+
+//没有前缀的值是加密上线前保存的明文，原样返回
+pub fn open(value: &str) -> Result<String> {
+    if !value.starts_with(PREFIX) {
+        return Ok(value.to_owned());
+    }
+    let parts: Vec<&str> = value[PREFIX.len()..].split(':').collect();
+    if parts.len() != 3 {
+        return Err(error("malformed value"));
+    }
+    let wrapped = parts[1].from_base64().map_err(|_| error("malformed key"))?;
+    let sealed = parts[2].from_base64().map_err(|_| error("malformed data"))?;
+    let data_key = decrypt(&master_key(parts[0])?, parts[0].as_bytes(), &wrapped)?;
+    let plain = decrypt(&data_key, &[], &sealed)?;
+    String::from_utf8(plain).map_err(|_| error("invalid utf8"))
+}
+
+pub fn seal_option(value: &Option<String>) -> Result<Option<String>> {
+    match *value {
+        Some(ref value) => seal(value).map(Some),
+        None => Ok(None),
+    }
+}
+
+//盲索引：用index_key计算的HMAC，相同的值得到相同的索引，用于按手机号查找而不保存明文
+pub fn blind_index(value: &str) -> String {
+    let key = setting::get().pii.index_key.clone();
+    let mut hmac = Hmac::new(Sha256::new(), key.as_bytes());
+    hmac.input(value.trim().as_bytes());
+    hmac.result().code().to_hex()
+}
+
+//解密hgetall的结果中所有加密的字段
+pub fn open_value(value: redis::Value) -> Result<redis::Value> {
+    match value {
+        redis::Value::Data(data) => {
+            if data.starts_with(PREFIX.as_bytes()) {
+                let value = String::from_utf8(data).map_err(|_| error("invalid utf8"))?;
+                open(&value).map(|plain| redis::Value::Data(plain.into_bytes()))
+            } else {
+                Ok(redis::Value::Data(data))
+            }
+        }
+        redis::Value::Bulk(values) => values.into_iter().map(open_value).collect::<Result<Vec<_>>>().map(redis::Value::Bulk),
+        value => Ok(value),
+    }
+}
+
+//解密mongodb文档中所有加密的字段
+pub fn open_document(doc: Document) -> Result<Document> {
+    let mut opened = Document::new();
+    for (key, value) in doc {
+        opened.insert(key, open_bson(value)?);
+    }
+    Ok(opened)
+}
+
+fn open_bson(value: Bson) -> Result<Bson> {
+    match value {
+        Bson::String(ref s) if s.starts_with(PREFIX) => open(s).map(Bson::String),
+        Bson::Document(doc) => open_document(doc).map(Bson::Document),
+        Bson::Array(values) => values.into_iter().map(open_bson).collect::<Result<Vec<_>>>().map(Bson::Array),
+        value => Ok(value),
+    }
+}
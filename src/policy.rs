@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+use entity;
+use service::{Result, ServiceError};
+use setting;
+use store::Store;
+use util;
+
+//下单前的防滥用检查，在扣减座位之前调用
+pub fn check_booking<S: Store + ?Sized>(store: &S, trip: &entity::Trip, openid: &str, count: i64) -> Result<()> {
+    let config = setting::get();
+    if trip.openid == openid {
+        return Err(ServiceError::SelfBooking);
+    }
+    if store.get_expired_count(openid)? >= config.booking.expire_limit {
+        return Err(ServiceError::BookingBlocked);
+    }
+    //并发下单时由add_order在事务内再次检查，这里提前返回准确的错误
+    check_caps(&store.get_user_orders(openid)?, &trip.id, count)
+}
+
+//未支付订单数和同一行程的座位数上限，orders为该用户当前的订单
+pub fn check_caps(orders: &[entity::Order], trip_id: &str, count: i64) -> Result<()> {
+    let config = setting::get();
+    let unpaid = orders
+        .iter()
+        .filter(|order| order.status == entity::OrderStatus::Unpaid)
+        .count() as i64;
+    if unpaid >= config.booking.max_unpaid {
+        return Err(ServiceError::TooManyUnpaid);
+    }
+    let booked: i64 = orders
+        .iter()
+        .filter(|order| order.trip_id == trip_id)
+        .map(|order| order.count)
+        .sum();
+    if booked + count > config.booking.max_seats {
+        return Err(ServiceError::SeatLimit);
+    }
+    Ok(())
+}
+
+//联系方式只在行程完成或取消之前开放，之后双方通过站内消息联系
+pub fn contact_open(trip: &entity::Trip) -> bool {
+    trip.status != entity::TripStatus::Finish && trip.status != entity::TripStatus::Cancel
+}
+
+//车主的手机号只对车主本人和已支付的乘客显示，paid_trips为查看者已支付订单的行程id
+pub fn trip_for(mut trip: entity::Trip, viewer: Option<&str>, paid_trips: &HashSet<String>) -> entity::Trip {
+    let owner = viewer == Some(trip.openid.as_str());
+    if !owner && !(paid_trips.contains(&trip.id) && contact_open(&trip)) {
+        trip.tel = util::mask_tel(&trip.tel);
+    }
+    trip
+}
+
+//乘客的手机号只对乘客本人和该订单已支付后的车主显示
+pub fn order_for(mut order: entity::Order, trip: &entity::Trip, viewer: &str) -> entity::Order {
+    let paid = order.status != entity::OrderStatus::Unpaid;
+    if viewer != order.openid && !(viewer == order.trip_owner && paid && contact_open(trip)) {
+        order.tel = order.tel.map(|tel| util::mask_tel(&tel));
+    }
+    order
+}
+
+//用车需求的手机号只对发布者显示，车主接单并且乘客支付后从订单中查看
+pub fn ride_request_for(mut request: entity::RideRequest, viewer: &str) -> entity::RideRequest {
+    if viewer != request.openid {
+        request.tel = request.tel.map(|tel| util::mask_tel(&tel));
+    }
+    request
+}
+
+//查看者已支付或已确认订单的行程id
+pub fn paid_trips<S: Store + ?Sized>(store: &S, viewer: Option<&str>) -> Result<HashSet<String>> {
+    match viewer {
+        Some(openid) => Ok(store
+            .get_user_orders(openid)?
+            .into_iter()
+            .filter(|order| order.status != entity::OrderStatus::Unpaid)
+            .map(|order| order.trip_id)
+            .collect()),
+        None => Ok(HashSet::new()),
+    }
+}
@@ -0,0 +1,519 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use rocket::{self, Rocket, State};
+use rocket::http::ContentType;
+use rocket::response::{Response, Stream};
+use rocket::response::content::{Content, Xml};
+use rocket_contrib::Json;
+use serde_json;
+use entity;
+use limit::{self, RateLimit};
+use metrics::{self, RequestTimer};
+use external;
+use setting;
+use sse::{EventStream, MessageStream, SeatStream, StreamPermit};
+use service::{Backend, Result, Service, ServiceError};
+
+//组装rocket应用，main和集成测试共用
+pub fn app(backend: Backend) -> Rocket {
+    rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                login_json,
+                refresh_token,
+                logout,
+                publish_trip,
+                publish_trip_json,
+                test_request,
+                add_template,
+                templates,
+                skip_occurrence,
+                edit_occurrence,
+                apply_trip,
+                apply_trip_json,
+                apply_segment,
+                post_ride_request,
+                post_ride_request_json,
+                ride_requests,
+                my_ride_requests,
+                cancel_ride_request,
+                accept_ride_request,
+                ride_request_matches,
+                trip_matches,
+                join_waitlist,
+                join_waitlist_json,
+                leave_waitlist,
+                waitlist,
+                prepay,
+                wx_notify,
+                discount,
+                discount_json,
+                submit,
+                submit_json,
+                get_trips,
+                order,
+                trip_orders,
+                timeline,
+                seat_stream,
+                wallet,
+                wallet_logs,
+                withdraw,
+                withdraws,
+                pending_withdraws,
+                approve_withdraw,
+                reject_withdraw,
+                add_coupon,
+                coupons,
+                redemptions,
+                orders_by_tel,
+                templates_by_tel,
+                referral_code,
+                my_referrals,
+                my_coupons,
+                send_message,
+                conversations,
+                messages,
+                read_messages,
+                message_stream,
+                notifications,
+                export_data,
+                delete_account,
+                referrals,
+                reload_setting,
+                metrics_text,
+            ],
+        )
+        .manage(backend)
+        .attach(RequestTimer)
+        .catch(errors![bad_request, not_found, noauth, too_many_requests])
+}
+
+//连接池状态在抓取时读取，只对管理员开放
+#[get("/metrics")]
+fn metrics_text(_admin: entity::AdminUser, backend: State<Backend>) -> Content<String> {
+    if let Backend::Live(_, ref pool) = *backend {
+        let state = pool.state();
+        metrics::set("redis_pool_connections", &[], state.connections as f64);
+        metrics::set("redis_pool_idle_connections", &[], state.idle_connections as f64);
+    }
+    Content(ContentType::Plain, metrics::render())
+}
+
+#[error(400)]
+fn bad_request() -> Result<()> {
+    Err(ServiceError::BadRequest)
+}
+
+#[error(404)]
+fn not_found() -> Result<()> {
+    Err(ServiceError::NotFound("resource".to_owned()))
+}
+
+#[error(401)]
+fn noauth() -> Result<()> {
+    Err(ServiceError::NoAuth)
+}
+
+#[error(429)]
+fn too_many_requests() -> Result<()> {
+    Err(ServiceError::TooManyRequests)
+}
+
+#[get("/login/<code>")]
+fn login(_limit: RateLimit<limit::Login>, code: String, s: Service) -> Result<Json<entity::TokenPair>> {
+    let form = entity::LoginForm {
+        code,
+        referral_code: None,
+        device: None,
+    };
+    s.login(form).map(|tokens| Json(tokens))
+}
+
+#[post("/login", format = "application/json", data = "<form>")]
+fn login_json(_limit: RateLimit<limit::Login>, form: Json<entity::LoginForm>, s: Service) -> Result<Json<entity::TokenPair>> {
+    s.login(form.into_inner()).map(|tokens| Json(tokens))
+}
+
+#[post("/refreshToken", format = "application/json", data = "<form>")]
+fn refresh_token(_limit: RateLimit<limit::Login>, form: Json<entity::RefreshForm>, s: Service) -> Result<Json<entity::TokenPair>> {
+    s.refresh(&form.into_inner().refresh_token).map(|tokens| Json(tokens))
+}
+
+#[get("/logout")]
+fn logout(user: entity::JwtUser, s: Service) -> Result<()> {
+    s.logout(&user)
+}
+
+#[get("/publishTrip?<form>")]
+fn publish_trip(_limit: RateLimit<limit::Publish>, user: entity::JwtUser, form: entity::TripForm, s: Service) -> Result<Json<entity::Trip>> {
+    //let tel = s.get_tel(&jwt.id)?;
+    s.publish_trip(user.id, form).map(|trip| Json(trip))
+}
+
+#[post("/publishTrip", format = "application/json", data = "<form>")]
+fn publish_trip_json(_limit: RateLimit<limit::Publish>, user: entity::JwtUser, form: Json<entity::TripForm>, s: Service) -> Result<Json<entity::Trip>> {
+    s.publish_trip(user.id, form.into_inner()).map(|trip| Json(trip))
+}
+
+#[post("/addTemplate", format = "application/json", data = "<form>")]
+fn add_template(_limit: RateLimit<limit::Publish>, user: entity::JwtUser, form: Json<entity::TemplateForm>, s: Service) -> Result<Json<entity::TripTemplate>> {
+    s.add_template(user.id, form.into_inner()).map(|template| Json(template))
+}
+
+#[get("/templates")]
+fn templates(user: entity::JwtUser, s: Service) -> Result<Json<Vec<entity::TripTemplate>>> {
+    s.get_templates(&user.id).map(|vec| Json(vec))
+}
+
+#[get("/skipOccurrence/<id>/<date>")]
+fn skip_occurrence(user: entity::JwtUser, id: String, date: String, s: Service) -> Result<()> {
+    s.skip_occurrence(&id, &user.id, &date)
+}
+
+#[post("/editOccurrence/<id>", format = "application/json", data = "<occurrence>")]
+fn edit_occurrence(user: entity::JwtUser, id: String, occurrence: Json<entity::Occurrence>, s: Service) -> Result<()> {
+    s.edit_occurrence(&id, &user.id, occurrence.into_inner())
+}
+
+#[get("/applyTrip/<id>/<count>/<tel>")]
+fn apply_trip(
+    _limit: RateLimit<limit::Apply>,
+    user: entity::JwtUser,
+    id: String,
+    count: i64,
+    tel: Option<String>,
+    s: Service,
+) -> Result<Json<entity::Order>> {
+    let form = entity::ApplyForm {
+        trip_id: id,
+        count,
+        tel,
+        from: None,
+        to: None,
+        coupon: None,
+    };
+    s.apply_trip(user.id, form).map(
+        |order| {
+            Json(order)
+        },
+    )
+}
+
+#[get("/applySegment/<id>/<from>/<to>/<count>/<tel>")]
+fn apply_segment(
+    _limit: RateLimit<limit::Apply>,
+    user: entity::JwtUser,
+    id: String,
+    from: i64,
+    to: i64,
+    count: i64,
+    tel: Option<String>,
+    s: Service,
+) -> Result<Json<entity::Order>> {
+    let form = entity::ApplyForm {
+        trip_id: id,
+        count,
+        tel,
+        from: Some(from),
+        to: Some(to),
+        coupon: None,
+    };
+    s.apply_trip(user.id, form).map(|order| Json(order))
+}
+
+#[post("/applyTrip", format = "application/json", data = "<form>")]
+fn apply_trip_json(_limit: RateLimit<limit::Apply>, user: entity::JwtUser, form: Json<entity::ApplyForm>, s: Service) -> Result<Json<entity::Order>> {
+    s.apply_trip(user.id, form.into_inner()).map(|order| Json(order))
+}
+
+#[get("/postRideRequest?<form>")]
+fn post_ride_request(_limit: RateLimit<limit::Apply>, user: entity::JwtUser, form: entity::RideRequestForm, s: Service) -> Result<Json<entity::RideRequest>> {
+    s.post_ride_request(user.id, form).map(|request| Json(request))
+}
+
+#[post("/postRideRequest", format = "application/json", data = "<form>")]
+fn post_ride_request_json(_limit: RateLimit<limit::Apply>, user: entity::JwtUser, form: Json<entity::RideRequestForm>, s: Service) -> Result<Json<entity::RideRequest>> {
+    s.post_ride_request(user.id, form.into_inner()).map(|request| Json(request))
+}
+
+#[get("/rideRequests?<query>")]
+fn ride_requests(user: entity::JwtUser, query: entity::RideRequestQuery, s: Service) -> Result<Json<Vec<entity::RideRequest>>> {
+    s.get_ride_requests(&query, &user.id).map(|vec| Json(vec))
+}
+
+#[get("/myRideRequests")]
+fn my_ride_requests(user: entity::JwtUser, s: Service) -> Result<Json<Vec<entity::RideRequest>>> {
+    s.get_user_ride_requests(&user.id).map(|vec| Json(vec))
+}
+
+#[get("/cancelRideRequest/<id>")]
+fn cancel_ride_request(user: entity::JwtUser, id: String, s: Service) -> Result<()> {
+    s.cancel_ride_request(&id, &user.id)
+}
+
+#[post("/acceptRideRequest/<id>", format = "application/json", data = "<form>")]
+fn accept_ride_request(_limit: RateLimit<limit::Publish>, user: entity::JwtUser, id: String, form: Json<entity::AcceptForm>, s: Service) -> Result<Json<entity::Order>> {
+    s.accept_ride_request(&id, user.id, form.into_inner()).map(|order| Json(order))
+}
+
+#[get("/rideRequestMatches/<id>")]
+fn ride_request_matches(user: entity::JwtUser, id: String, s: Service) -> Result<Json<Vec<entity::TripMatch>>> {
+    s.get_request_matches(&id, &user.id).map(|vec| Json(vec))
+}
+
+#[get("/tripMatches/<id>")]
+fn trip_matches(user: entity::JwtUser, id: String, s: Service) -> Result<Json<Vec<entity::RequestMatch>>> {
+    s.get_trip_matches(&id, &user.id).map(|vec| Json(vec))
+}
+
+#[get("/joinWaitlist/<id>/<count>/<tel>")]
+fn join_waitlist(
+    _limit: RateLimit<limit::Apply>,
+    user: entity::JwtUser,
+    id: String,
+    count: i64,
+    tel: Option<String>,
+    s: Service,
+) -> Result<Json<entity::Waiter>> {
+    s.join_waitlist(id, user.id, count, tel).map(|waiter| Json(waiter))
+}
+
+#[post("/joinWaitlist", format = "application/json", data = "<form>")]
+fn join_waitlist_json(_limit: RateLimit<limit::Apply>, user: entity::JwtUser, form: Json<entity::ApplyForm>, s: Service) -> Result<Json<entity::Waiter>> {
+    let form = form.into_inner();
+    s.join_waitlist(form.trip_id, user.id, form.count, form.tel).map(|waiter| Json(waiter))
+}
+
+#[get("/leaveWaitlist/<id>")]
+fn leave_waitlist(user: entity::JwtUser, id: String, s: Service) -> Result<()> {
+    s.leave_waitlist(&id, &user.id)
+}
+
+#[get("/waitlist")]
+fn waitlist(user: entity::JwtUser, s: Service) -> Result<Json<Vec<entity::Waiter>>> {
+    s.get_waitlist(&user.id).map(|vec| Json(vec))
+}
+
+#[get("/prepay/<id>")]
+fn prepay(user: entity::JwtUser, id: String, s: Service) -> Result<Json<BTreeMap<String, String>>> {
+    s.prepay(&id, &user.id).map(|params| Json(params))
+}
+
+//微信支付结果通知
+#[post("/wxNotify", data = "<body>")]
+fn wx_notify(body: String, s: Service) -> Xml<String> {
+    let code = match s.pay_notify(&body) {
+        Ok(_) => "SUCCESS",
+        Err(err) => {
+            println!("weixin notify error: {:?}", err);
+            "FAIL"
+        }
+    };
+    Xml(format!("<xml><return_code><![CDATA[{}]]></return_code></xml>", code))
+}
+
+#[get("/discount/<id>/<fee>")]
+fn discount(user: entity::JwtUser, id: String, fee: i64, s: Service) -> Result<()> {
+    s.discount(id, user.id, fee)
+}
+
+#[post("/discount", format = "application/json", data = "<form>")]
+fn discount_json(user: entity::JwtUser, form: Json<entity::DiscountForm>, s: Service) -> Result<()> {
+    let form = form.into_inner();
+    s.discount(form.order_id, user.id, form.fee)
+}
+
+#[get("/submit/<id>")]
+fn submit(user: entity::JwtUser, id: String, s: Service) -> Result<()> {
+    s.submit(id, &user.id)
+}
+
+#[post("/submit", format = "application/json", data = "<form>")]
+fn submit_json(user: entity::JwtUser, form: Json<entity::OrderForm>, s: Service) -> Result<()> {
+    s.submit(form.into_inner().order_id, &user.id)
+}
+//不需要登录，登录后可以看到已支付行程的车主手机号
+#[get("/getTrips/<page>")]
+fn get_trips(user: Option<entity::JwtUser>, s: Service, page: isize) -> Result<Json<Vec<entity::Trip>>> {
+    s.get_trips(page, user.as_ref().map(|user| user.id.as_str())).map(|vec| Json(vec))
+}
+
+#[get("/order/<id>")]
+fn order(user: entity::JwtUser, id: String, s: Service) -> Result<Json<entity::Order>> {
+    s.get_order(&id, &user.id).map(|order| Json(order))
+}
+
+#[get("/tripOrders/<id>")]
+fn trip_orders(user: entity::JwtUser, id: String, s: Service) -> Result<Json<Vec<entity::Order>>> {
+    s.get_trip_orders(&id, &user.id).map(|vec| Json(vec))
+}
+
+//推送行程的座位变化，ids为逗号分隔的行程id
+#[get("/seatStream?<query>")]
+fn seat_stream(user: entity::JwtUser, query: entity::SeatQuery, s: Service) -> Result<Content<Stream<SeatStream>>> {
+    let permit = StreamPermit::acquire(&user.id)?;
+    let (current, updates) = s.subscribe_seats(&query.ids)?;
+    Ok(Content(
+        ContentType::new("text", "event-stream"),
+        Stream::from(EventStream::new("seats", current, updates, permit)),
+    ))
+}
+
+//kind为order或trip
+#[get("/timeline/<kind>/<id>")]
+fn timeline(user: entity::JwtUser, kind: String, id: String, s: Service) -> Result<Json<Vec<entity::AuditLog>>> {
+    s.get_timeline(&kind, &id, &user.id).map(|logs| Json(logs))
+}
+
+#[get("/wallet")]
+fn wallet(user: entity::JwtUser, s: Service) -> Result<Json<entity::Wallet>> {
+    s.get_wallet(&user.id).map(|wallet| Json(wallet))
+}
+
+#[get("/walletLogs/<page>")]
+fn wallet_logs(user: entity::JwtUser, page: isize, s: Service) -> Result<Json<Vec<entity::WalletLog>>> {
+    s.get_wallet_logs(&user.id, page).map(|vec| Json(vec))
+}
+
+//提现和审核会改变余额，只提供POST接口
+#[post("/withdraw", format = "application/json", data = "<form>")]
+fn withdraw(user: entity::JwtUser, form: Json<entity::WithdrawForm>, s: Service) -> Result<Json<entity::Withdraw>> {
+    s.apply_withdraw(user.id, form.into_inner().amount).map(|w| Json(w))
+}
+
+#[get("/withdraws/<page>")]
+fn withdraws(user: entity::JwtUser, page: isize, s: Service) -> Result<Json<Vec<entity::Withdraw>>> {
+    s.get_withdraws(&user.id, page).map(|vec| Json(vec))
+}
+
+#[get("/admin/withdraws/<page>")]
+fn pending_withdraws(_admin: entity::AdminUser, page: isize, s: Service) -> Result<Json<Vec<entity::Withdraw>>> {
+    s.get_pending_withdraws(page).map(|vec| Json(vec))
+}
+
+#[post("/admin/approveWithdraw", format = "application/json", data = "<form>")]
+fn approve_withdraw(_admin: entity::AdminUser, form: Json<entity::WithdrawIdForm>, s: Service) -> Result<()> {
+    s.approve_withdraw(&form.into_inner().withdraw_id)
+}
+
+#[post("/admin/rejectWithdraw", format = "application/json", data = "<form>")]
+fn reject_withdraw(_admin: entity::AdminUser, form: Json<entity::WithdrawIdForm>, s: Service) -> Result<()> {
+    s.reject_withdraw(&form.into_inner().withdraw_id)
+}
+
+#[get("/referralCode")]
+fn referral_code(user: entity::JwtUser, s: Service) -> Result<Json<entity::ReferralCode>> {
+    s.get_referral_code(&user.id).map(|code| Json(code))
+}
+
+#[get("/myReferrals")]
+fn my_referrals(user: entity::JwtUser, s: Service) -> Result<Json<Vec<entity::Referral>>> {
+    s.get_user_referrals(&user.id).map(|vec| Json(vec))
+}
+
+#[get("/myCoupons")]
+fn my_coupons(user: entity::JwtUser, s: Service) -> Result<Json<Vec<entity::Coupon>>> {
+    s.get_user_coupons(&user.id).map(|vec| Json(vec))
+}
+
+#[post("/sendMessage", format = "application/json", data = "<form>")]
+fn send_message(user: entity::JwtUser, form: Json<entity::MessageForm>, s: Service) -> Result<Json<entity::Message>> {
+    s.send_message(user.id, form.into_inner()).map(|message| Json(message))
+}
+
+#[get("/conversations")]
+fn conversations(user: entity::JwtUser, s: Service) -> Result<Json<Vec<entity::ConversationSummary>>> {
+    s.get_conversations(&user.id).map(|vec| Json(vec))
+}
+
+//轮询新消息，after为已收到的最后一条消息的seq
+#[get("/messages/<id>/<after>")]
+fn messages(user: entity::JwtUser, id: String, after: i64, s: Service) -> Result<Json<Vec<entity::Message>>> {
+    s.get_messages(&user.id, &id, after).map(|vec| Json(vec))
+}
+
+//已读回执，seq及之前的消息标记为已读
+#[get("/readMessages/<id>/<seq>")]
+fn read_messages(user: entity::JwtUser, id: String, seq: i64, s: Service) -> Result<()> {
+    s.read_messages(&user.id, &id, seq)
+}
+
+//推送收到的新消息，断线后用/messages补齐
+#[get("/messageStream")]
+fn message_stream(user: entity::JwtUser, s: Service) -> Result<Content<Stream<MessageStream>>> {
+    let permit = StreamPermit::acquire(&user.id)?;
+    let updates = s.subscribe_messages(&user.id)?;
+    Ok(Content(
+        ContentType::new("text", "event-stream"),
+        Stream::from(EventStream::new("message", Vec::new(), updates, permit)),
+    ))
+}
+
+//本人的站内通知，最新的在前
+#[get("/notifications")]
+fn notifications(user: entity::JwtUser, s: Service) -> Result<Json<Vec<entity::Notification>>> {
+    s.get_notifications(&user.id).map(|vec| Json(vec))
+}
+
+//下载本人的全部数据
+#[get("/exportData")]
+fn export_data(user: entity::JwtUser, s: Service) -> Result<Response<'static>> {
+    let export = s.export_user(&user.id)?;
+    let body = serde_json::to_string(&export).map_err(|err| ServiceError::String(err.to_string()))?;
+    Ok(Response::build()
+        .header(ContentType::JSON)
+        .raw_header("Content-Disposition", format!("attachment; filename=\"pinche-{}.json\"", user.id))
+        .sized_body(Cursor::new(body))
+        .finalize())
+}
+
+#[post("/deleteAccount")]
+fn delete_account(user: entity::JwtUser, s: Service) -> Result<()> {
+    s.delete_account(&user)
+}
+
+#[get("/admin/referrals/<page>")]
+fn referrals(_admin: entity::AdminUser, page: isize, s: Service) -> Result<Json<Vec<entity::Referral>>> {
+    s.get_referrals(page).map(|vec| Json(vec))
+}
+
+#[post("/admin/addCoupon", format = "application/json", data = "<form>")]
+fn add_coupon(_admin: entity::AdminUser, form: Json<entity::CouponForm>, s: Service) -> Result<Json<entity::Coupon>> {
+    s.add_coupon(form.into_inner()).map(|coupon| Json(coupon))
+}
+
+#[get("/admin/coupons")]
+fn coupons(_admin: entity::AdminUser, s: Service) -> Result<Json<Vec<entity::Coupon>>> {
+    s.get_coupons().map(|vec| Json(vec))
+}
+
+#[get("/admin/ordersByTel/<tel>")]
+fn orders_by_tel(_admin: entity::AdminUser, tel: String, s: Service) -> Result<Json<Vec<entity::Order>>> {
+    s.find_orders_by_tel(&tel).map(|vec| Json(vec))
+}
+
+#[get("/admin/templatesByTel/<tel>")]
+fn templates_by_tel(_admin: entity::AdminUser, tel: String, s: Service) -> Result<Json<Vec<entity::TripTemplate>>> {
+    s.find_templates_by_tel(&tel).map(|vec| Json(vec))
+}
+
+//优惠券的使用记录和平台补贴金额
+#[get("/admin/redemptions/<id>")]
+fn redemptions(_admin: entity::AdminUser, id: String, s: Service) -> Result<Json<Vec<entity::Redemption>>> {
+    s.get_redemptions(&id).map(|vec| Json(vec))
+}
+
+//重新加载业务配置，返回变化的配置项
+#[get("/admin/reloadSetting")]
+fn reload_setting(_admin: entity::AdminUser) -> Result<Json<Vec<String>>> {
+    setting::reload()
+        .map(|changes| Json(changes))
+        .map_err(ServiceError::from)
+}
+
+#[get("/test/request")]
+fn test_request() -> Result<()> {
+    external::test()
+}
\ No newline at end of file
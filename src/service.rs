@@ -1,10 +1,20 @@
 use std::{self,fmt, result, error, convert, option};
 use std::io::Cursor;
+use std::collections::BTreeMap;
 use bson;
 use mongodb;
 use db;
 use entity;
+use event::Event;
 use external;
+use store::{Store, SeatUpdates, MessageUpdates};
+use memory::MemoryStore;
+use matching;
+use metrics;
+use policy;
+use setting;
+use util;
+use validate::{FieldError, Validator};
 use redis;
 use serde_redis;
 use rocket::request::{self, FromRequest};
@@ -14,6 +24,7 @@ use rocket::http::ContentType;
 use mongodb::db::Database;
 use rocket::http::Status;
 use hyper;
+use serde_json;
 
   
 pub type Result<T> = result::Result<T, ServiceError>;
@@ -25,6 +36,29 @@ pub enum ServiceError {
     NoAuth,
     NoPay, //没有支付
     TripNotYours, //你不是车主
+    BalanceNotEnough, //钱包余额不足
+    WithdrawTooSmall, //低于最低提现金额
+    WithdrawLimit, //超过每日提现限制
+    WithdrawHandled, //提现申请已处理
+    Validation(Vec<FieldError>), //参数校验失败
+    BadRequest, //请求格式错误
+    FeatureDisabled, //功能开关已关闭
+    NotFound(String), //数据不存在，参数为数据类型
+    TooManyRequests, //请求太频繁
+    SelfBooking, //不能预订自己的行程
+    TooManyUnpaid, //未支付订单过多
+    SeatLimit, //超过每人可预订的座位数
+    BookingBlocked, //多次未支付，暂时禁止预订
+    SeatsAvailable, //座位足够时直接下单，不能候补
+    AlreadyWaiting, //已在该行程的候补中
+    NotWaiting, //候补已获得座位或已退出
+    TripBooked, //行程已有订单，不能删除或修改
+    RequestClosed, //用车需求已被接单、取消或过期
+    CouponUnavailable, //优惠券不存在、已过期或不满足使用条件
+    CouponUsedUp, //已达到优惠券的使用次数上限
+    ConversationClosed, //行程完成后会话已关闭
+    AccountInUse, //还有未完成的订单或钱包余额，不能注销
+    RefundConflict, //同一订单有并发的退款
     BsonEncoderError(bson::EncoderError),
     BsonDecoderError(bson::DecoderError),
     MongodbError(mongodb::Error),
@@ -46,6 +80,35 @@ impl fmt::Display for ServiceError {
                 write!(f, "you are not paid this trip")
             }
             ServiceError::DontHaveEnoughSeats => write!(f, "this trip have not enough seats!"),
+            ServiceError::BalanceNotEnough => write!(f, "your wallet balance is not enough"),
+            ServiceError::WithdrawTooSmall => write!(f, "withdraw amount is less than the minimum"),
+            ServiceError::WithdrawLimit => write!(f, "withdraw exceeds today's limit"),
+            ServiceError::WithdrawHandled => write!(f, "this withdraw has been handled"),
+            ServiceError::Validation(ref errors) => {
+                let fields: Vec<_> = errors
+                    .iter()
+                    .map(|e| format!("{} {}", e.field, e.message))
+                    .collect();
+                write!(f, "invalid params: {}", fields.join(", "))
+            }
+            ServiceError::BadRequest => write!(f, "bad request"),
+            ServiceError::FeatureDisabled => write!(f, "this feature is disabled"),
+            ServiceError::NotFound(ref name) => write!(f, "{} not found", name),
+            ServiceError::TooManyRequests => write!(f, "too many requests"),
+            ServiceError::SelfBooking => write!(f, "you can not book your own trip"),
+            ServiceError::TooManyUnpaid => write!(f, "too many unpaid orders"),
+            ServiceError::SeatLimit => write!(f, "seats per user exceed the limit"),
+            ServiceError::BookingBlocked => write!(f, "booking is temporarily blocked"),
+            ServiceError::SeatsAvailable => write!(f, "seats are available"),
+            ServiceError::AlreadyWaiting => write!(f, "already waiting for the trip"),
+            ServiceError::NotWaiting => write!(f, "not waiting"),
+            ServiceError::TripBooked => write!(f, "trip already has orders"),
+            ServiceError::RequestClosed => write!(f, "ride request is closed"),
+            ServiceError::CouponUnavailable => write!(f, "coupon is not applicable to this order"),
+            ServiceError::CouponUsedUp => write!(f, "coupon has been used up"),
+            ServiceError::ConversationClosed => write!(f, "conversation is closed"),
+            ServiceError::AccountInUse => write!(f, "account in use"),
+            ServiceError::RefundConflict => write!(f, "another refund of this order was recorded"),
             ServiceError::String(ref s) => write!(f, "{}", s),
             ServiceError::BsonEncoderError(ref e) => e.fmt(f),
             ServiceError::BsonDecoderError(ref e) => e.fmt(f),
@@ -61,118 +124,199 @@ impl fmt::Display for ServiceError {
     }
 }
 
-impl<'r> Responder<'r> for ServiceError {
-    fn respond_to(self, _: &Request) -> response::Result<'r> {
-        let mut builder = Response::build();
-        builder.header(ContentType::JSON);
-        match self {
-            ServiceError::NoAuth => {
-                builder.status(Status::Unauthorized).sized_body(
-                    Cursor::new(
-                        r#"{"status": "ok", "reason": "Unauthorized, please login"}"#,
-                    ),
-                );
-            }
-            ServiceError::TripNotYours => {
-                builder.status(Status::NotAcceptable).sized_body(
-                    Cursor::new(
-                        r#"{"status": "ok", "reason": "this trip is not yours, you can't discount"}"#,
-                    ),
-                );
-            },
-            ServiceError::NoPay => {
-                builder.status(Status::NotAcceptable).sized_body(
-                    Cursor::new(
-                        r#"{"status": "ok", "reason": "you are not paid this trip"}"#,
-                    ),
-                );
-            },
-            ServiceError::DontHaveEnoughSeats => {
-                builder.status(Status::NotAcceptable).sized_body(
-                    Cursor::new(
-                        r#"{"status": "ok", "reason": "this trip have not enough seats!"}"#,
-                    ),
-                );
-            },
-            ServiceError::String(ref s) => {
-                builder.status(Status::BadRequest).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"ok\",\"reason\":\"{}\"}}",s),
-                    ),
-                );
-            },
-            ServiceError::BsonEncoderError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"BsonEncoderError: {:?}\"}}",e),
-                    ),
-                );
-            },
-            ServiceError::BsonDecoderError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"BsonDecoderError: {:?}\"}}",e),
-                    ),
-                );
-            },
-            ServiceError::MongodbError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"MongodbError: {:?}\"}}",e),
-                    ),
-                );
-            },
-            ServiceError::BsonOidError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"BsonOidError: {:?}\"}}",e),
-                    ),
-                );
-            },
-            ServiceError::NoneError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"NoneError: {:?}\"}}",e),
-                    ),
-                );
-            },
-            ServiceError::RedisError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"RedisError: {:?}\"}}",e),
-                    ),
-                );
-            },
-            ServiceError::RedisDecodeError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"RedisDecodeError: {:?}\"}}",e),
-                    ),
-                );
-            },
-            ServiceError::StdIoError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"StdIoError: {:?}\"}}",e),
-                    ),
-                );
-            },
-            ServiceError::HyperUriError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"HyperUriError: {:?}\"}}",e),
-                    ),
-                );
+//错误码和提示语，错误码保持稳定，客户端根据它做判断
+impl ServiceError {
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ServiceError::NoAuth => "NO_AUTH",
+            ServiceError::TripNotYours => "TRIP_NOT_YOURS",
+            ServiceError::NoPay => "NOT_PAID",
+            ServiceError::DontHaveEnoughSeats => "NOT_ENOUGH_SEATS",
+            ServiceError::BalanceNotEnough => "BALANCE_NOT_ENOUGH",
+            ServiceError::WithdrawTooSmall => "WITHDRAW_TOO_SMALL",
+            ServiceError::WithdrawLimit => "WITHDRAW_LIMIT",
+            ServiceError::WithdrawHandled => "WITHDRAW_HANDLED",
+            ServiceError::Validation(_) => "INVALID_PARAMS",
+            ServiceError::BadRequest => "BAD_REQUEST",
+            ServiceError::FeatureDisabled => "FEATURE_DISABLED",
+            ServiceError::NotFound(_) | ServiceError::NoneError(_) => "NOT_FOUND",
+            ServiceError::TooManyRequests => "TOO_MANY_REQUESTS",
+            ServiceError::SelfBooking => "SELF_BOOKING",
+            ServiceError::TooManyUnpaid => "TOO_MANY_UNPAID",
+            ServiceError::SeatLimit => "SEAT_LIMIT",
+            ServiceError::BookingBlocked => "BOOKING_BLOCKED",
+            ServiceError::SeatsAvailable => "SEATS_AVAILABLE",
+            ServiceError::AlreadyWaiting => "ALREADY_WAITING",
+            ServiceError::NotWaiting => "NOT_WAITING",
+            ServiceError::TripBooked => "TRIP_BOOKED",
+            ServiceError::RequestClosed => "REQUEST_CLOSED",
+            ServiceError::CouponUnavailable => "COUPON_UNAVAILABLE",
+            ServiceError::CouponUsedUp => "COUPON_USED_UP",
+            ServiceError::ConversationClosed => "CONVERSATION_CLOSED",
+            ServiceError::AccountInUse => "ACCOUNT_IN_USE",
+            ServiceError::RefundConflict => "REFUND_CONFLICT",
+            ServiceError::BsonEncoderError(_) |
+            ServiceError::BsonDecoderError(_) |
+            ServiceError::MongodbError(_) |
+            ServiceError::BsonOidError(_) => "DATABASE_ERROR",
+            ServiceError::RedisError(_) |
+            ServiceError::RedisDecodeError(_) => "CACHE_ERROR",
+            ServiceError::StdIoError(_) |
+            ServiceError::HyperUriError(_) |
+            ServiceError::HyperError(_) => "NETWORK_ERROR",
+            ServiceError::String(_) => "INTERNAL_ERROR",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match *self {
+            ServiceError::NoAuth => Status::Unauthorized,
+            ServiceError::TripNotYours |
+            ServiceError::NoPay |
+            ServiceError::DontHaveEnoughSeats |
+            ServiceError::BalanceNotEnough |
+            ServiceError::WithdrawTooSmall |
+            ServiceError::WithdrawLimit |
+            ServiceError::WithdrawHandled |
+            ServiceError::SelfBooking |
+            ServiceError::TooManyUnpaid |
+            ServiceError::SeatLimit |
+            ServiceError::BookingBlocked |
+            ServiceError::SeatsAvailable |
+            ServiceError::AlreadyWaiting |
+            ServiceError::NotWaiting |
+            ServiceError::TripBooked |
+            ServiceError::RequestClosed |
+            ServiceError::CouponUnavailable |
+            ServiceError::CouponUsedUp |
+            ServiceError::ConversationClosed |
+            ServiceError::AccountInUse |
+            ServiceError::RefundConflict => Status::NotAcceptable,
+            ServiceError::Validation(_) | ServiceError::BadRequest => Status::BadRequest,
+            ServiceError::NotFound(_) | ServiceError::NoneError(_) => Status::NotFound,
+            ServiceError::FeatureDisabled => Status::ServiceUnavailable,
+            ServiceError::TooManyRequests => Status::TooManyRequests,
+            _ => Status::InternalServerError,
+        }
+    }
+
+    //系统内部错误，详细信息只记录日志不返回给客户端
+    pub fn is_internal(&self) -> bool {
+        self.status() == Status::InternalServerError
+    }
+
+    pub fn message(&self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::Zh => match *self {
+                ServiceError::NoAuth => "请先登录",
+                ServiceError::TripNotYours => "这不是你的行程",
+                ServiceError::NoPay => "订单还没有支付",
+                ServiceError::DontHaveEnoughSeats => "座位不足",
+                ServiceError::BalanceNotEnough => "钱包余额不足",
+                ServiceError::WithdrawTooSmall => "低于最低提现金额",
+                ServiceError::WithdrawLimit => "超过今日提现限制",
+                ServiceError::WithdrawHandled => "提现申请已处理",
+                ServiceError::Validation(_) => "参数错误",
+                ServiceError::BadRequest => "请求格式错误",
+                ServiceError::FeatureDisabled => "该功能暂时关闭",
+                ServiceError::NotFound(_) | ServiceError::NoneError(_) => "数据不存在",
+                ServiceError::TooManyRequests => "操作太频繁，请稍后再试",
+                ServiceError::SelfBooking => "不能预订自己发布的行程",
+                ServiceError::TooManyUnpaid => "未支付的订单太多，请先支付或等待过期",
+                ServiceError::SeatLimit => "超过每人可预订的座位数",
+                ServiceError::BookingBlocked => "多次下单未支付，暂时不能预订",
+                ServiceError::SeatsAvailable => "当前座位充足，请直接下单",
+                ServiceError::AlreadyWaiting => "您已在该行程的候补队列中",
+                ServiceError::NotWaiting => "该候补已不在队列中",
+                ServiceError::TripBooked => "该行程已有乘客预订，不能修改",
+                ServiceError::RequestClosed => "该用车需求已被接单或已取消",
+                ServiceError::CouponUnavailable => "该优惠券不可用于此订单",
+                ServiceError::CouponUsedUp => "优惠券使用次数已用完",
+                ServiceError::ConversationClosed => "行程已结束，会话已关闭",
+                ServiceError::AccountInUse => "还有未完成的订单或钱包余额，请处理后再注销",
+                ServiceError::RefundConflict => "该订单正在退款，请刷新后重试",
+                _ => "系统繁忙，请稍后再试",
             },
-            ServiceError::HyperError(ref e) => {
-                builder.status(Status::UnprocessableEntity).sized_body(
-                    Cursor::new(
-                        format!("{{\"status\":\"error\",\"reason\":\"HyperError: {:?}\"}}",e),
-                    ),
-                );
+            Lang::En => match *self {
+                ServiceError::NoAuth => "Unauthorized, please login",
+                ServiceError::TripNotYours => "this trip is not yours",
+                ServiceError::NoPay => "you are not paid this trip",
+                ServiceError::DontHaveEnoughSeats => "this trip have not enough seats",
+                ServiceError::BalanceNotEnough => "your wallet balance is not enough",
+                ServiceError::WithdrawTooSmall => "withdraw amount is less than the minimum",
+                ServiceError::WithdrawLimit => "withdraw exceeds today's limit",
+                ServiceError::WithdrawHandled => "this withdraw has been handled",
+                ServiceError::Validation(_) => "invalid params",
+                ServiceError::BadRequest => "bad request, please check the params",
+                ServiceError::FeatureDisabled => "this feature is temporarily disabled",
+                ServiceError::NotFound(_) | ServiceError::NoneError(_) => "resource was not found",
+                ServiceError::TooManyRequests => "too many requests, please try again later",
+                ServiceError::SelfBooking => "you can not book your own trip",
+                ServiceError::TooManyUnpaid => "too many unpaid orders, please pay them first",
+                ServiceError::SeatLimit => "you have booked too many seats on this trip",
+                ServiceError::BookingBlocked => "too many unpaid orders expired, booking is temporarily blocked",
+                ServiceError::SeatsAvailable => "seats are available, please book directly",
+                ServiceError::AlreadyWaiting => "you are already on the waiting list of this trip",
+                ServiceError::NotWaiting => "this waiting entry is no longer in the queue",
+                ServiceError::TripBooked => "this trip already has bookings and can not be changed",
+                ServiceError::RequestClosed => "this ride request has been accepted or cancelled",
+                ServiceError::CouponUnavailable => "this coupon cannot be used for this order",
+                ServiceError::CouponUsedUp => "you have used up this coupon",
+                ServiceError::ConversationClosed => "the trip has finished and this conversation is closed",
+                ServiceError::AccountInUse => "finish your orders and withdraw your balance before deleting the account",
+                ServiceError::RefundConflict => "the order was refunded by another request, please refresh and try again",
+                _ => "server is busy, please try again later",
             },
         }
-        builder.ok()
+    }
+}
+
+//客户端语言，根据Accept-Language选择，默认中文
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Lang {
+    Zh,
+    En,
+}
+
+impl Lang {
+    pub fn from_request(request: &Request) -> Lang {
+        match request.headers().get_one("Accept-Language") {
+            Some(lang) if lang.trim_left().starts_with("en") => Lang::En,
+            _ => Lang::Zh,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: &'static str, //错误响应都是error，具体原因看code
+    code: &'static str,
+    reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a Vec<FieldError>>,
+}
+
+impl<'r> Responder<'r> for ServiceError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let status = self.status();
+        if self.is_internal() {
+            println!("{} {}: {:?}", request.uri(), self.code(), self);
+        }
+        let body = ErrorBody {
+            status: "error",
+            code: self.code(),
+            reason: self.message(Lang::from_request(request)),
+            errors: match self {
+                ServiceError::Validation(ref errors) => Some(errors),
+                _ => None,
+            },
+        };
+        let body = serde_json::to_string(&body).map_err(|_| Status::InternalServerError)?;
+        Response::build()
+            .header(ContentType::JSON)
+            .status(status)
+            .sized_body(Cursor::new(body))
+            .ok()
     }
 }
 
@@ -183,6 +327,29 @@ impl error::Error for ServiceError {
             ServiceError::TripNotYours => "this trip is not yours",
             ServiceError::NoPay => "you are not paid this trip",
             ServiceError::DontHaveEnoughSeats => "this trip have not enough seats!",
+            ServiceError::BalanceNotEnough => "your wallet balance is not enough",
+            ServiceError::WithdrawTooSmall => "withdraw amount is less than the minimum",
+            ServiceError::WithdrawLimit => "withdraw exceeds today's limit",
+            ServiceError::WithdrawHandled => "this withdraw has been handled",
+            ServiceError::Validation(_) => "invalid params",
+            ServiceError::BadRequest => "bad request",
+            ServiceError::FeatureDisabled => "this feature is disabled",
+            ServiceError::NotFound(_) => "not found",
+            ServiceError::TooManyRequests => "too many requests",
+            ServiceError::SelfBooking => "you can not book your own trip",
+            ServiceError::TooManyUnpaid => "too many unpaid orders",
+            ServiceError::SeatLimit => "seats per user exceed the limit",
+            ServiceError::BookingBlocked => "booking is temporarily blocked",
+            ServiceError::SeatsAvailable => "seats are available",
+            ServiceError::AlreadyWaiting => "already waiting for the trip",
+            ServiceError::NotWaiting => "not waiting",
+            ServiceError::TripBooked => "trip already has orders",
+            ServiceError::RequestClosed => "ride request is closed",
+            ServiceError::CouponUnavailable => "coupon is not applicable to this order",
+            ServiceError::CouponUsedUp => "coupon has been used up",
+            ServiceError::ConversationClosed => "conversation is closed",
+            ServiceError::AccountInUse => "account in use",
+            ServiceError::RefundConflict => "another refund of this order was recorded",
             ServiceError::String(ref s) => s.as_str(),
             ServiceError::BsonEncoderError(ref e) => e.description(),
             ServiceError::BsonDecoderError(ref e) => e.description(),
@@ -219,6 +386,24 @@ impl convert::From<mongodb::Error> for ServiceError {
     }
 }
 
+//配置错误作为参数错误返回给管理员
+impl convert::From<setting::ConfigErrors> for ServiceError {
+    fn from(errors: setting::ConfigErrors) -> Self {
+        ServiceError::Validation(
+            errors
+                .0
+                .into_iter()
+                .map(|message| {
+                    FieldError {
+                        field: "config".to_owned(),
+                        message,
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
 impl convert::From<option::NoneError> for ServiceError {
     fn from(err: option::NoneError) -> Self {
         ServiceError::NoneError(err)
@@ -249,66 +434,1322 @@ impl convert::From<hyper::Error> for ServiceError {
     }
 }
 
+//Service使用的存储后端，由rocket托管
+pub enum Backend {
+    Live(Database, db::Pool),
+    Memory(MemoryStore),
+}
+
 pub struct Service {
-    conn: db::DbConn,
-    cache: db::CacheConn,
+    store: Box<Store + Send>,
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for Service {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Service, ()> {
-        let pool = request.guard::<State<db::Pool>>()?;
-        let database = request.guard::<State<Database>>()?;
-        match pool.get() {
-            Ok(con) => {
-                let service = Service {
-                    conn: db::DbConn(database.clone()),
-                    cache: db::CacheConn(con),
-                };
-                Outcome::Success(service)
-            }
-            Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
+        let backend = request.guard::<State<Backend>>()?;
+        match *backend {
+            Backend::Live(ref database, ref pool) => match pool.get() {
+                Ok(con) => {
+                    let service = Service::new(
+                        db::DbConn(database.clone()),
+                        db::CacheConn(con),
+                    );
+                    Outcome::Success(service)
+                }
+                Err(_) => {
+                    metrics::inc("redis_pool_timeouts_total", &[]);
+                    Outcome::Failure((Status::ServiceUnavailable, ()))
+                }
+            },
+            Backend::Memory(ref store) => Outcome::Success(Service::with_store(store.clone())),
         }
     }
 }
 
+//每次最多拉取的消息数
+const MESSAGE_BATCH: usize = 50;
+
+//开始打款超过这个秒数仍在Processing的提现视为记录结果失败
+const WITHDRAW_RESUME_AFTER: i64 = 600;
+
+//分页下标，每页page_size个：0-9,10-19
+fn page_range(page: isize) -> (isize, isize) {
+    let size = setting::get().business.page_size as isize;
+    ((page - 1) * size, page * size - 1)
+}
+
+fn published(trip: &entity::Trip) -> Event {
+    Event::TripPublished {
+        trip_id: trip.id.clone(),
+        openid: trip.openid.clone(),
+    }
+}
+
+fn created(order: &entity::Order) -> Event {
+    Event::OrderCreated {
+        order_id: order.id.clone(),
+        trip_id: order.trip_id.clone(),
+        openid: order.openid.clone(),
+    }
+}
+
+//订单创建成功后计数
+fn count_order(order: &entity::Order) {
+    metrics::inc("orders_created_total", &[]);
+    metrics::add("seats_reserved_total", &[], order.count as f64);
+}
+
 impl Service {
     pub fn new(conn:db::DbConn,cache:db::CacheConn) -> Self {
-        Service{conn,cache}
+        Service::with_store(db::Storage{conn,cache})
+    }
+
+    pub fn with_store<S: Store + Send + 'static>(store:S) -> Self {
+        Service{store: Box::new(store)}
+    }
+
+    //首次登录时处理邀请码，邀请码无效或被判定为刷奖励时不影响登录
+    pub fn login(&self, form:entity::LoginForm) -> Result<entity::TokenPair> {
+        let openid = external::login(&form.code)?;
+        let device = form.device.as_ref().map(|device| device.as_str());
+        if self.store.add_login(&openid, device)? {
+            if let Some(ref code) = form.referral_code {
+                if let Err(err) = self.refer(&openid, code, device) {
+                    println!("referral of {} failed: {:?}", openid, err);
+                }
+            }
+        }
+        self.issue_tokens(openid)
+    }
+
+    //refresh token只能使用一次，换取新的令牌对
+    pub fn refresh(&self, refresh_token:&str) -> Result<entity::TokenPair> {
+        let openid = self.store.take_refresh_token(refresh_token)?.ok_or(ServiceError::NoAuth)?;
+        self.issue_tokens(openid)
+    }
+
+    //注销当前access token，并使该用户所有refresh token失效
+    pub fn logout(&self, user:&entity::JwtUser) -> Result<()> {
+        self.store.revoke_token(&user.jti, user.exp - util::now())?;
+        self.store.remove_refresh_tokens(&user.id)
+    }
+
+    pub fn is_revoked(&self, jti:&str) -> Result<bool> {
+        self.store.is_revoked(jti)
+    }
+
+    fn issue_tokens(&self, openid:String) -> Result<entity::TokenPair> {
+        let config = setting::get();
+        let refresh_token = util::random_string(40);
+        self.store.add_refresh_token(&refresh_token, &openid, config.jwt.refresh_ttl)?;
+        let user = entity::JwtUser::weixin(openid);
+        self.store.add_access_token(&user.id, &user.jti, config.jwt.access_ttl)?;
+        Ok(entity::TokenPair {
+            token: user.sign()?,
+            refresh_token,
+            expires_in: config.jwt.access_ttl,
+        })
+    }
+
+    pub fn publish_trip(&self, openid:String, form:entity::TripForm) -> Result<entity::Trip> {
+        if !setting::get().features.publish {
+            return Err(ServiceError::FeatureDisabled);
+        }
+        form.validate()?;
+        let trip = entity::Trip::new(openid, form);
+        self.store.add_trip(&trip, &[published(&trip)])?;
+        metrics::inc("trips_published_total", &[("source", "publish")]);
+        self.audit("Trip", &trip.id, None, &trip.status.to_string(), &trip.openid, "publish");
+        Ok(trip)
+    }
+
+    //创建模板后立即生成最近几天的行程
+    pub fn add_template(&self, openid:String, form:entity::TemplateForm) -> Result<entity::TripTemplate> {
+        if !setting::get().features.publish {
+            return Err(ServiceError::FeatureDisabled);
+        }
+        form.validate()?;
+        let template = entity::TripTemplate::new(openid, form);
+        self.store.add_template(&template)?;
+        self.generate_template_trips(&template)?;
+        Ok(template)
+    }
+
+    pub fn get_templates(&self, openid:&str) -> Result<Vec<entity::TripTemplate>> {
+        self.store.get_templates(openid)
+    }
+
+    //跳过某一天，已经生成的行程在没有订单时一并删除
+    pub fn skip_occurrence(&self, id:&str, openid:&str, date:&str) -> Result<()> {
+        let mut template = self.owned_template(id, openid)?;
+        Validator::new().date(date, "date").finish()?;
+        self.remove_occurrence(&template, date)?;
+        if !template.skip_dates.iter().any(|d| d == date) {
+            template.skip_dates.push(date.to_owned());
+            self.store.update_template(&template)?;
+        }
+        Ok(())
+    }
+
+    //修改某一天的行程，已经生成的行程在没有订单时按修改后的内容重新生成
+    pub fn edit_occurrence(&self, id:&str, openid:&str, occurrence:entity::Occurrence) -> Result<()> {
+        let mut template = self.owned_template(id, openid)?;
+        occurrence.validate()?;
+        self.remove_occurrence(&template, &occurrence.date)?;
+        template.skip_dates.retain(|d| *d != occurrence.date);
+        template.overrides.retain(|o| o.date != occurrence.date);
+        template.overrides.push(occurrence);
+        self.store.update_template(&template)?;
+        self.generate_template_trips(&template).map(|_| ())
+    }
+
+    //按所有未结束的模板生成行程，返回新生成的数量，由定时任务调用
+    pub fn generate_trips(&self) -> Result<usize> {
+        if !setting::get().features.publish {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for template in self.store.get_active_templates(&util::format_date(util::today()))? {
+            match self.generate_template_trips(&template) {
+                Ok(n) => count += n,
+                Err(err) => println!("generate trips of template {} failed: {:?}", template.id, err),
+            }
+        }
+        Ok(count)
+    }
+
+    //生成今天起schedule.days_ahead天内还没有生成的行程，跳过节假日和已经出发的时间
+    fn generate_template_trips(&self, template:&entity::TripTemplate) -> Result<usize> {
+        let config = setting::get();
+        let today = util::today();
+        let mut count = 0;
+        for day in today..today + config.schedule.days_ahead + 1 {
+            let date = util::format_date(day);
+            if !template.runs_on(&date) || config.schedule.holidays.contains(&date) {
+                continue;
+            }
+            let trip = template.trip(&date)?;
+            if trip.start_time <= util::now() || !self.store.claim_occurrence(&template.id, &date, &trip.id)? {
+                continue;
+            }
+            if let Err(err) = self.store.add_trip(&trip, &[published(&trip)]) {
+                self.store.release_occurrence(&template.id, &date)?;
+                return Err(err);
+            }
+            metrics::inc("trips_published_total", &[("source", "schedule")]);
+            self.audit("Trip", &trip.id, None, &trip.status.to_string(), "system:schedule", &template.id);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn owned_template(&self, id:&str, openid:&str) -> Result<entity::TripTemplate> {
+        let template = self.store.get_template(id)?;
+        if template.openid != openid {
+            return Err(ServiceError::TripNotYours);
+        }
+        Ok(template)
+    }
+
+    //删除某天已经生成的行程，已有订单时返回TripBooked
+    fn remove_occurrence(&self, template:&entity::TripTemplate, date:&str) -> Result<()> {
+        if let Some(trip_id) = self.store.get_occurrence(&template.id, date)? {
+            if !self.store.remove_trip(&trip_id)? {
+                return Err(ServiceError::TripBooked);
+            }
+            self.store.release_occurrence(&template.id, date)?;
+            self.audit("Trip", &trip_id, Some(entity::TripStatus::Prepare.to_string()), "Removed", &template.openid, date);
+        }
+        Ok(())
+    }
+
+    //from和to为上车和下车的站点序号，不填时分别为起点和终点
+    pub fn apply_trip(&self, openid:String, form:entity::ApplyForm) -> Result<entity::Order>{
+        if !setting::get().features.apply {
+            return Err(ServiceError::FeatureDisabled);
+        }
+        let trip = self.store.get_trip(&form.trip_id)?;
+        entity::Order::validate(&trip, form.count, &form.tel)?;
+        let from = form.from.unwrap_or(0);
+        let to = form.to.unwrap_or(trip.last_stop());
+        trip.check_segment(from, to)?;
+        policy::check_booking(&*self.store, &trip, &openid, form.count)?;
+        let coupon = match form.coupon {
+            Some(ref code) => Some(self.store.find_coupon(code)?.ok_or(ServiceError::CouponUnavailable)?),
+            None => None,
+        };
+        let mut order = entity::Order::segment(trip.clone(),openid,form.count,form.tel,from,to);
+        if let Some(ref coupon) = coupon {
+            if coupon.owner.as_ref().map_or(false, |owner| *owner != order.openid) ||
+                coupon.new_user_only && !self.store.get_user_orders(&order.openid)?.is_empty()
+            {
+                return Err(ServiceError::CouponUnavailable);
+            }
+            order.discount = coupon.discount(&trip, &order, util::now()).ok_or(ServiceError::CouponUnavailable)?;
+            order.coupon_id = Some(coupon.id.clone());
+            if !self.store.redeem_coupon(&entity::Redemption::new(coupon, &order), coupon.per_user_limit)? {
+                return Err(ServiceError::CouponUsedUp);
+            }
+        }
+        if let Err(err) = self.store.add_order(&order, &[created(&order)]) {
+            self.release_coupon(&order.id);
+            return Err(err);
+        }
+        count_order(&order);
+        self.publish_seats(&order.trip_id);
+        self.audit("Order", &order.id, None, &order.status.to_string(), &order.openid, "apply");
+        Ok(order)
+    }
+
+    //返回小程序调起支付需要的参数
+    pub fn prepay(&self, order_id:&str, openid:&str) -> Result<BTreeMap<String, String>> {
+        let order = self.store.get_order(order_id)?;
+        if order.openid != openid {
+            return Err(ServiceError::NoAuth);
+        }
+        external::unified_order(&order)
+    }
+
+    //微信支付结果通知
+    pub fn pay_notify(&self, xml:&str) -> Result<()> {
+        let params = external::check_notify(xml)?;
+        let order_id = params.get("out_trade_no")?;
+        let transaction_id = params.get("transaction_id")?;
+        let paid = Event::OrderPaid {
+            order_id: order_id.to_owned(),
+            transaction_id: transaction_id.to_owned(),
+        };
+        if self.store.pay_order(order_id, transaction_id, &[paid])? {
+            metrics::inc("orders_paid_total", &[]);
+            self.audit("Order", order_id, Some("Unpaid".to_owned()), "Paid", "system:wx_notify", transaction_id);
+            self.close_offer(order_id, entity::WaitStatus::Accepted);
+        }
+        Ok(())
+    }
+
+    //未支付订单超时，由redis过期通知触发，归还的座位优先预留给候补
+    //trip_id只能在存储中读取，OrderExpired事件由expire_order写入
+    pub fn expire_order(&self, id:&str) -> Result<()> {
+        if let Some(trip_id) = self.store.expire_order(id)? {
+            metrics::inc("orders_expired_total", &[]);
+            self.audit("Order", id, Some("Unpaid".to_owned()), "Expired", "system:expire_job", "pay timeout");
+            self.close_offer(id, entity::WaitStatus::Expired);
+            self.release_coupon(id);
+            if let Err(err) = self.offer_seats(&trip_id) {
+                println!("offer seats of {} failed: {:?}", trip_id, err);
+            }
+            self.publish_seats(&trip_id);
+        }
+        Ok(())
+    }
+
+    pub fn post_ride_request(&self, openid:String, form:entity::RideRequestForm) -> Result<entity::RideRequest> {
+        if !setting::get().features.apply {
+            return Err(ServiceError::FeatureDisabled);
+        }
+        form.validate()?;
+        Validator::new()
+            .check(form.count <= setting::get().booking.max_seats, "count", "must not exceed the seat limit")
+            .finish()?;
+        let request = entity::RideRequest::new(openid, form);
+        let posted = Event::RideRequestPosted {
+            request_id: request.id.clone(),
+            openid: request.openid.clone(),
+        };
+        self.store.add_ride_request(&request, &[posted])?;
+        self.audit("RideRequest", &request.id, None, &request.status.to_string(), &request.openid, "post");
+        Ok(request)
+    }
+
+    //行程变化后重新匹配待接单的需求，新出现的匹配通知双方，行程已删除时忽略
+    pub fn match_trip(&self, trip_id:&str) -> Result<()> {
+        let trip = match self.store.get_trip(trip_id) {
+            Ok(trip) => trip,
+            Err(ServiceError::NoneError(_)) | Err(ServiceError::NotFound(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for (score, request) in self.trip_matches(&trip)? {
+            self.notify_match(&request, &trip, score)?;
+        }
+        Ok(())
+    }
+
+    pub fn match_ride_request(&self, id:&str) -> Result<()> {
+        let request = self.store.get_ride_request(id)?;
+        if request.status != entity::RideRequestStatus::Open {
+            return Ok(());
+        }
+        for (score, trip) in self.request_matches(&request)? {
+            self.notify_match(&request, &trip, score)?;
+        }
+        Ok(())
+    }
+
+    //乘客查看需求当前的匹配行程
+    pub fn get_request_matches(&self, id:&str, openid:&str) -> Result<Vec<entity::TripMatch>> {
+        let request = self.store.get_ride_request(id)?;
+        if request.openid != openid {
+            return Err(ServiceError::NoAuth);
+        }
+        let paid = policy::paid_trips(&*self.store, Some(openid))?;
+        Ok(
+            self.request_matches(&request)?
+                .into_iter()
+                .map(|(score, trip)| entity::TripMatch { score, trip: policy::trip_for(trip, Some(openid), &paid) })
+                .collect(),
+        )
+    }
+
+    //车主查看行程当前的匹配需求
+    pub fn get_trip_matches(&self, trip_id:&str, openid:&str) -> Result<Vec<entity::RequestMatch>> {
+        let trip = self.store.get_trip(trip_id)?;
+        if trip.openid != openid {
+            return Err(ServiceError::TripNotYours);
+        }
+        Ok(
+            self.trip_matches(&trip)?
+                .into_iter()
+                .map(|(score, request)| entity::RequestMatch { score, request: policy::ride_request_for(request, openid) })
+                .collect(),
+        )
+    }
+
+    fn trip_matches(&self, trip:&entity::Trip) -> Result<Vec<(i64, entity::RideRequest)>> {
+        let now = util::now();
+        let candidates: Vec<(i64, entity::RideRequest)> = self.store
+            .get_open_ride_requests()?
+            .into_iter()
+            .filter(|r| r.latest > now)
+            .filter_map(|r| matching::score(&r, trip).map(|score| (score, r)))
+            .collect();
+        Ok(matching::top(candidates, |r| r.id.as_str()))
+    }
+
+    fn request_matches(&self, request:&entity::RideRequest) -> Result<Vec<(i64, entity::Trip)>> {
+        let candidates: Vec<(i64, entity::Trip)> = self.store
+            .get_trips(0, matching::CANDIDATES - 1)?
+            .into_iter()
+            .filter_map(|trip| matching::score(request, &trip).map(|score| (score, trip)))
+            .collect();
+        Ok(matching::top(candidates, |trip| trip.id.as_str()))
+    }
+
+    //同一对需求和行程只通知一次
+    fn notify_match(&self, request:&entity::RideRequest, trip:&entity::Trip, score:i64) -> Result<()> {
+        let matched = Event::RideMatched {
+            request_id: request.id.clone(),
+            trip_id: trip.id.clone(),
+            passenger: request.openid.clone(),
+            driver: trip.openid.clone(),
+            score,
+        };
+        self.store.add_match(&request.id, &trip.id, &[matched]).map(|_| ())
+    }
+
+    //待接单且还没到最晚出发时间的需求
+    pub fn get_ride_requests(&self, query:&entity::RideRequestQuery, openid:&str) -> Result<Vec<entity::RideRequest>> {
+        let size = setting::get().business.page_size as usize;
+        let page = query.page.unwrap_or(1).max(1) as usize;
+        let now = util::now();
+        Ok(
+            self.store
+                .get_open_ride_requests()?
+                .into_iter()
+                .filter(|r| r.latest > now && r.matches(query))
+                .skip((page - 1) * size)
+                .take(size)
+                .map(|r| policy::ride_request_for(r, openid))
+                .collect(),
+        )
+    }
+
+    pub fn get_user_ride_requests(&self, openid:&str) -> Result<Vec<entity::RideRequest>> {
+        self.store.get_user_ride_requests(openid)
+    }
+
+    pub fn cancel_ride_request(&self, id:&str, openid:&str) -> Result<()> {
+        let request = self.store.get_ride_request(id)?;
+        if request.openid != openid {
+            return Err(ServiceError::NoAuth);
+        }
+        if !self.store.close_ride_request(id, &entity::RideRequestStatus::Cancelled)? {
+            return Err(ServiceError::RequestClosed);
+        }
+        self.audit("RideRequest", id, Some(request.status.to_string()), "Cancelled", openid, "cancel");
+        Ok(())
+    }
+
+    //车主接单：使用自己已发布的行程或者新建行程，为乘客生成未支付订单，支付时限与普通订单相同
+    pub fn accept_ride_request(&self, id:&str, openid:String, form:entity::AcceptForm) -> Result<entity::Order> {
+        if !setting::get().features.publish {
+            return Err(ServiceError::FeatureDisabled);
+        }
+        let request = self.store.get_ride_request(id)?;
+        if request.status != entity::RideRequestStatus::Open || request.latest <= util::now() {
+            return Err(ServiceError::RequestClosed);
+        }
+        Validator::new()
+            .check(form.trip_id.is_some() != form.trip.is_some(), "trip_id", "either trip_id or trip is required")
+            .finish()?;
+        let (trip, new_trip) = match form.trip {
+            Some(trip) => {
+                trip.validate()?;
+                (entity::Trip::new(openid, trip), true)
+            }
+            None => {
+                let trip = self.store.get_trip(&form.trip_id?)?;
+                if trip.openid != openid {
+                    return Err(ServiceError::TripNotYours);
+                }
+                (trip, false)
+            }
+        };
+        request.check_trip(&trip)?;
+        entity::Order::validate(&trip, request.count, &request.tel)?;
+        policy::check_booking(&*self.store, &trip, &request.openid, request.count)?;
+        if !self.store.close_ride_request(id, &entity::RideRequestStatus::Accepted)? {
+            return Err(ServiceError::RequestClosed);
+        }
+        if new_trip {
+            if let Err(err) = self.store.add_trip(&trip, &[published(&trip)]) {
+                self.reopen_failed_accept(id);
+                return Err(err);
+            }
+            metrics::inc("trips_published_total", &[("source", "ride_request")]);
+            self.audit("Trip", &trip.id, None, &trip.status.to_string(), &trip.openid, id);
+        }
+        let order = entity::Order::new(trip.clone(), request.openid.clone(), request.count, request.tel.clone());
+        if let Err(err) = self.store.add_order(&order, &[created(&order)]) {
+            //为这个需求新发布的行程一并删除，已经写入的TripPublished在处理时会跳过不存在的行程
+            if new_trip {
+                match self.store.remove_trip(&trip.id) {
+                    Ok(true) => self.audit("Trip", &trip.id, Some(trip.status.to_string()), "Removed", &trip.openid, id),
+                    Ok(false) => (),
+                    Err(remove_err) => println!("remove trip {} of ride request {} failed: {:?}", trip.id, id, remove_err),
+                }
+            }
+            self.reopen_failed_accept(id);
+            return Err(err);
+        }
+        count_order(&order);
+        let accepted = Event::RideRequestAccepted {
+            request_id: id.to_owned(),
+            order_id: order.id.clone(),
+            trip_id: order.trip_id.clone(),
+            openid: order.openid.clone(),
+        };
+        self.store.set_ride_request_order(id, &order.trip_id, &order.id, &[accepted])?;
+        self.publish_seats(&order.trip_id);
+        self.audit("RideRequest", id, Some(request.status.to_string()), "Accepted", &order.trip_owner, &order.id);
+        //订单记在乘客名下，过期后本人仍可查看时间线
+        self.audit("Order", &order.id, None, &order.status.to_string(), &order.openid, id);
+        //乘客支付前车主看不到完整手机号
+        Ok(policy::order_for(order, &trip, &trip.openid))
+    }
+
+    //接单失败时重新开放需求，失败只记录日志，调用者返回接单的错误
+    fn reopen_failed_accept(&self, id:&str) {
+        if let Err(err) = self.store.reopen_ride_request(id) {
+            println!("reopen ride request {} failed: {:?}", id, err);
+        }
+    }
+
+    //座位不足时加入候补，座位足够时应直接下单
+    pub fn join_waitlist(&self, trip_id:String, openid:String, count:i64, tel:Option<String>) -> Result<entity::Waiter> {
+        if !setting::get().features.apply {
+            return Err(ServiceError::FeatureDisabled);
+        }
+        let trip = self.store.get_trip(&trip_id)?;
+        entity::Order::validate(&trip, count, &tel)?;
+        policy::check_booking(&*self.store, &trip, &openid, count)?;
+        if trip.current_seat >= count {
+            return Err(ServiceError::SeatsAvailable);
+        }
+        let waiting = self.store.get_user_waiters(&openid)?.iter().any(|w| {
+            w.trip_id == trip_id &&
+                (w.status == entity::WaitStatus::Waiting || w.status == entity::WaitStatus::Offered)
+        });
+        if waiting {
+            return Err(ServiceError::AlreadyWaiting);
+        }
+        let waiter = entity::Waiter::new(trip_id, openid, count, tel);
+        self.store.add_waiter(&waiter)?;
+        self.audit("Waiter", &waiter.id, None, &waiter.status.to_string(), &waiter.openid, "join");
+        Ok(waiter)
+    }
+
+    //只能退出还在等待的候补，已预留的订单不支付即可
+    pub fn leave_waitlist(&self, id:&str, openid:&str) -> Result<()> {
+        let waiter = self.store.get_waiter(id)?;
+        if waiter.openid != openid {
+            return Err(ServiceError::NoAuth);
+        }
+        if !self.store.take_waiter(&waiter, &entity::WaitStatus::Removed)? {
+            return Err(ServiceError::NotWaiting);
+        }
+        self.audit("Waiter", id, Some(waiter.status.to_string()), "Removed", openid, "leave");
+        Ok(())
+    }
+
+    pub fn get_waitlist(&self, openid:&str) -> Result<Vec<entity::Waiter>> {
+        self.store.get_user_waiters(openid)
+    }
+
+    //按加入顺序为候补预留订单，座位数不够的候补继续等待，不再符合下单条件的候补移出队列
+    //预留订单和普通订单一样在支付超时后过期，座位再释放给下一位候补
+    //目前只有未支付订单过期会释放座位，行程取消和部分退款上线后也应调用
+    fn offer_seats(&self, trip_id:&str) -> Result<()> {
+        let mut trip = self.store.get_trip(trip_id)?;
+        if trip.status != entity::TripStatus::Prepare && trip.status != entity::TripStatus::Full {
+            return Ok(());
+        }
+        for waiter in self.store.get_waiters(trip_id)? {
+            if waiter.count > trip.current_seat {
+                continue;
+            }
+            if let Err(err) = policy::check_booking(&*self.store, &trip, &waiter.openid, waiter.count) {
+                if self.store.take_waiter(&waiter, &entity::WaitStatus::Removed)? {
+                    self.audit("Waiter", &waiter.id, Some(waiter.status.to_string()), "Removed", "system:waitlist", err.code());
+                }
+                continue;
+            }
+            if !self.store.take_waiter(&waiter, &entity::WaitStatus::Offered)? {
+                continue;
+            }
+            let order = entity::Order::new(trip.clone(), waiter.openid.clone(), waiter.count, waiter.tel.clone());
+            if let Err(err) = self.store.add_order(&order, &[created(&order)]) {
+                self.store.requeue_waiter(&waiter)?;
+                return match err {
+                    ServiceError::DontHaveEnoughSeats => Ok(()), //座位已被直接下单的用户抢到
+                    err => Err(err),
+                };
+            }
+            count_order(&order);
+            let offered = Event::SeatOffered {
+                waiter_id: waiter.id.clone(),
+                order_id: order.id.clone(),
+                trip_id: order.trip_id.clone(),
+                openid: order.openid.clone(),
+            };
+            self.store.offer_waiter(&waiter.id, &order.id, &[offered])?;
+            let last_stop = trip.last_stop();
+            trip.take_seats(0, last_stop, order.count);
+            //订单记在候补用户名下，过期后本人仍可查看时间线
+            self.audit("Order", &order.id, None, &order.status.to_string(), &order.openid, "waitlist");
+            self.audit("Waiter", &waiter.id, Some(waiter.status.to_string()), "Offered", "system:waitlist", &order.id);
+        }
+        Ok(())
+    }
+
+    //预留订单支付或过期后更新候补状态，失败只记录日志
+    fn close_offer(&self, order_id:&str, status:entity::WaitStatus) {
+        match self.store.close_offer(order_id, &status) {
+            Ok(Some(waiter)) => {
+                self.audit("Waiter", &waiter.id, Some("Offered".to_owned()), &status.to_string(), "system:waitlist", order_id);
+            }
+            Ok(None) => (),
+            Err(err) => println!("close offer of {} failed: {:?}", order_id, err),
+        }
+    }
+
+    //订单未成功或已过期时退还优惠券的使用次数
+    fn release_coupon(&self, order_id:&str) {
+        match self.store.release_coupon(order_id) {
+            Ok(Some(r)) => {
+                self.audit("Coupon", &r.coupon_id, Some("Redeemed".to_owned()), "Released", "system:coupon", order_id);
+            }
+            Ok(None) => (),
+            Err(err) => println!("release coupon of {} failed: {:?}", order_id, err),
+        }
+    }
+
+    pub fn add_coupon(&self, form:entity::CouponForm) -> Result<entity::Coupon> {
+        form.validate()?;
+        Validator::new()
+            .check(self.store.find_coupon(&form.code)?.is_none(), "code", "already exists")
+            .finish()?;
+        let coupon = entity::Coupon::new(form);
+        self.store.add_coupon(&coupon)?;
+        Ok(coupon)
+    }
+
+    pub fn get_coupons(&self) -> Result<Vec<entity::Coupon>> {
+        self.store.get_coupons()
+    }
+
+    //每条记录的discount即平台为该订单承担的补贴
+    pub fn get_redemptions(&self, coupon_id:&str) -> Result<Vec<entity::Redemption>> {
+        self.store.get_redemptions(coupon_id)
+    }
+
+    //车主给已支付的订单优惠，fee从乘客支付的金额中退还，多次优惠累计不超过上限
+    pub fn discount(&self,order_id:String,openid:String,fee:i64) -> Result<()> {
+        let order = self.store.get_order(&order_id)?;
+        if order.trip_owner != openid {
+            return Err(ServiceError::TripNotYours);
+        }
+        let transaction_id = order.transaction_id.clone().ok_or(ServiceError::NoPay)?;
+        Validator::new()
+            .check(fee >= 1, "fee", "must be at least 1")
+            .check(
+                (order.refunded + fee) as f64 <= order.total() as f64 * setting::get().business.max_refund_rate,
+                "fee",
+                "must not exceed the refundable amount",
+            )
+            .finish()?;
+        //退款单号按已退金额生成，并发的重复请求由微信按单号去重
+        let refund_no = format!("{}-{}", order_id, order.refunded);
+        let result = external::refund(&refund_no,&transaction_id,order.total(),fee);
+        metrics::inc("refunds_total", &[("result", if result.is_ok() { "success" } else { "failure" })]);
+        result?;
+        self.store.add_refund(&order_id, order.refunded, fee)
+    }
+
+    //乘客确认到达，司机收入记入钱包
+    pub fn submit(&self, id:String, openid:&str) -> Result<()> {
+        let order = self.store.get_order(&id)?;
+        if order.openid != openid {
+            return Err(ServiceError::NoAuth);
+        }
+        //优惠券的减免由平台承担，车主的优惠退款由司机承担
+        let income = ((order.gross() - order.refunded) as f64 * (1.0 - setting::get().business.commission)) as i64;
+        let submitted = Event::OrderSubmitted {
+            order_id: id.clone(),
+            trip_id: order.trip_id.clone(),
+        };
+        self.store.submit_order(&order, income, &[submitted])?;
+        self.audit("Order", &id, Some(order.status.to_string()), "Submit", openid, "submit");
+        Ok(())
+    }
+
+    //由OrderSubmitted事件触发，所有订单都已确认时完成行程
+    pub fn check_trip_finish(&self, trip_id:&str) -> Result<()> {
+        let trip = self.store.get_trip(trip_id)?;
+        if self.store.check_trip_finish(trip_id, &[Event::TripFinished { trip_id: trip_id.to_owned() }])? {
+            self.audit("Trip", trip_id, Some(trip.status.to_string()), "Finish", "system:event", "all orders submitted");
+            self.publish_seats(trip_id);
+        }
+        Ok(())
+    }
+
+    //订单的时间线乘客和车主可以查看，行程的时间线只有车主可以查看
+    pub fn get_timeline(&self, kind:&str, id:&str, openid:&str) -> Result<Vec<entity::AuditLog>> {
+        let entity = match kind {
+            "order" => "Order",
+            "trip" => "Trip",
+            _ => return Err(ServiceError::NotFound("timeline".to_owned())),
+        };
+        let logs = self.store.get_audits(entity, id)?;
+        let allowed = if entity == "Trip" {
+            self.store.get_trip(id)?.openid == openid
+        } else {
+            match self.store.get_order(id) {
+                Ok(order) => order.openid == openid || order.trip_owner == openid,
+                //过期的订单已删除，只能由下单人查看
+                Err(_) => logs.first().map_or(false, |log| log.actor == openid),
+            }
+        };
+        if allowed {
+            Ok(logs)
+        } else {
+            Err(ServiceError::NoAuth)
+        }
+    }
+
+    pub fn add_event_group(&self, group:&str) -> Result<()> {
+        self.store.add_event_group(group)
     }
 
-    pub fn publish_trip(&self, trip: &entity::Trip) -> Result<()> {
-       self.cache.add_trip(trip)
+    pub fn read_events(&self, group:&str, count:usize) -> Result<Vec<(String, Event)>> {
+        self.store.read_events(group, count)
     }
 
-    pub fn apply_trip(&self, trip_id:String, openid:String, count:i64, tel:Option<String>) -> Result<entity::Order>{
-        self.cache.get_object::<entity::Trip>(&trip_id)
-            .map(|trip|entity::Order::new(trip,openid,count,tel))
-            .and_then(|order| self.cache.add_order(&order).map(|_|order))
+    pub fn ack_event(&self, group:&str, id:&str) -> Result<()> {
+        self.store.ack_event(group, id)
     }
 
-    pub fn pay(&self,order_id:String,sign:String) -> Result<()> {
-        println!("need check sign {}", sign); //?
-        self.cache.pay_order(order_id)
+    pub fn fail_event(&self, group:&str, id:&str) -> Result<i64> {
+        self.store.fail_event(group, id)
     }
 
-    pub fn discount(&self,order_id:String,fee:i64) -> Result<()> {
-        let openid = "openid".to_owned();  //?
-        self.cache.change_order_price(&order_id,&openid,-fee)
-            .and_then(|transaction_id|external::refund(&order_id,&transaction_id,fee))
+    pub fn dead_letter_event(&self, group:&str, id:&str, event:&Event) -> Result<()> {
+        self.store.dead_letter_event(group, id, event)
     }
 
-    pub fn submit(&self, id:String) -> Result<()> {
-        let trip_id = self.cache.submit_order(&id)?;
-        let order:entity::Order = self.cache.get_object(&id)?;
-        external::pay_to_client(&order.trip_owner, (order.price as f64 * 0.95) as i64)?;
-        self.cache.check_trip_finish(&trip_id)
+    //订阅行程的座位变化，返回当前状态和后续的更新
+    pub fn subscribe_seats(&self, ids:&str) -> Result<(Vec<entity::SeatUpdate>, SeatUpdates)> {
+        let ids: Vec<String> = ids.split(',')
+            .map(|id| id.trim().to_owned())
+            .filter(|id| !id.is_empty())
+            .collect();
+        Validator::new()
+            .check(!ids.is_empty() && ids.len() <= 20, "ids", "must contain 1 to 20 trip ids")
+            .finish()?;
+        //先订阅再读取当前状态，避免漏掉中间的变化
+        let updates = self.store.subscribe_seats(&ids)?;
+        let current = ids.iter()
+            .filter_map(|id| self.store.get_trip(id).ok())
+            .map(|trip| entity::SeatUpdate {
+                trip_id: trip.id,
+                current_seat: trip.current_seat,
+                status: trip.status,
+            })
+            .collect();
+        Ok((current, updates))
+    }
+
+    //座位或状态变化后通知订阅者，失败只记录日志
+    fn publish_seats(&self, trip_id:&str) {
+        let result = self.store.get_trip(trip_id).and_then(|trip| {
+            self.store.publish_seats(&entity::SeatUpdate {
+                trip_id: trip.id,
+                current_seat: trip.current_seat,
+                status: trip.status,
+            })
+        });
+        if let Err(err) = result {
+            println!("publish seats of {} failed: {:?}", trip_id, err);
+        }
+    }
+
+    //发布事件，失败只记录日志
+    //消息保存在mongodb，不能和事件一起提交，其他事件由存储在状态变化时写入
+    fn emit(&self, event:Event) {
+        if let Err(err) = self.store.publish_event(&event) {
+            println!("publish {:?} failed: {:?}", event, err);
+        }
+    }
+
+    //记录状态变化，写入失败只记录日志，不影响已经完成的操作
+    fn audit(&self, entity:&str, id:&str, from:Option<String>, to:&str, actor:&str, reason:&str) {
+        let log = entity::AuditLog::new(entity, id, from, to, actor, reason);
+        if let Err(err) = self.store.add_audit(&log) {
+            println!("audit {:?} failed: {:?}", log, err);
+        }
     }  
 
-    pub fn get_trips(&self,page:isize) -> Result<Vec<entity::Trip>> {
-        //每页10个  0-9,10-19
-        self.cache.get_trips((page-1)*10, page*10-1)
+    //未登录或未支付时车主手机号只显示部分
+    pub fn get_trips(&self,page:isize,viewer:Option<&str>) -> Result<Vec<entity::Trip>> {
+        let (start, end) = page_range(page);
+        let paid = policy::paid_trips(&*self.store, viewer)?;
+        Ok(self.store.get_trips(start, end)?
+            .into_iter()
+            .map(|trip| policy::trip_for(trip, viewer, &paid))
+            .collect())
+    }
+
+    //乘客或车主查看订单
+    pub fn get_order(&self, id:&str, openid:&str) -> Result<entity::Order> {
+        let order = self.store.get_order(id)?;
+        if order.openid != openid && order.trip_owner != openid {
+            return Err(ServiceError::NoAuth);
+        }
+        let trip = self.store.get_trip(&order.trip_id)?;
+        Ok(policy::order_for(order, &trip, openid))
+    }
+
+    //客服按手机号查找订单，手机号加密保存，通过盲索引查找
+    pub fn find_orders_by_tel(&self, tel:&str) -> Result<Vec<entity::Order>> {
+        Validator::new().tel(tel, "tel").finish()?;
+        let mut orders = self.store.find_orders_by_tel(tel)?;
+        orders.sort_by(|a, b| b.start_time.cmp(&a.start_time).then_with(|| a.id.cmp(&b.id)));
+        Ok(orders)
+    }
+
+    //客服按手机号查找司机的行程模板，同样通过盲索引查找
+    pub fn find_templates_by_tel(&self, tel:&str) -> Result<Vec<entity::TripTemplate>> {
+        Validator::new().tel(tel, "tel").finish()?;
+        self.store.find_templates_by_tel(tel)
+    }
+
+    //车主查看行程的订单，已支付的订单显示乘客手机号
+    pub fn get_trip_orders(&self, trip_id:&str, openid:&str) -> Result<Vec<entity::Order>> {
+        let trip = self.store.get_trip(trip_id)?;
+        if trip.openid != openid {
+            return Err(ServiceError::TripNotYours);
+        }
+        Ok(self.store.get_trip_orders(trip_id)?
+            .into_iter()
+            .map(|order| policy::order_for(order, &trip, openid))
+            .collect())
+    }
+
+    pub fn get_wallet(&self, openid:&str) -> Result<entity::Wallet> {
+        self.store.get_wallet(openid)
+    }
+
+    pub fn get_wallet_logs(&self, openid:&str, page:isize) -> Result<Vec<entity::WalletLog>> {
+        let (start, end) = page_range(page);
+        self.store.get_wallet_logs(openid, start, end)
+    }
+
+    pub fn apply_withdraw(&self, openid:String, amount:i64) -> Result<entity::Withdraw> {
+        let config = setting::get();
+        if !config.features.withdraw {
+            return Err(ServiceError::FeatureDisabled);
+        }
+        if amount < config.wallet.min_withdraw {
+            return Err(ServiceError::WithdrawTooSmall);
+        }
+        let withdraw = entity::Withdraw::new(openid, amount);
+        self.store.add_withdraw(
+            &withdraw,
+            config.wallet.daily_count,
+            config.wallet.daily_amount,
+        ).map(|_| withdraw)
+    }
+
+    pub fn get_withdraws(&self, openid:&str, page:isize) -> Result<Vec<entity::Withdraw>> {
+        let (start, end) = page_range(page);
+        self.store.get_withdraws(openid, start, end)
+    }
+
+    //导出用户的全部数据，手机号等个人信息不打码
+    pub fn export_user(&self, openid:&str) -> Result<entity::UserExport> {
+        let conversations = self.store.get_user_conversations(openid)?;
+        let mut messages = Vec::new();
+        for conversation in &conversations {
+            let mut after = 0;
+            loop {
+                let batch = self.store.get_messages(&conversation.id, after, MESSAGE_BATCH)?;
+                let done = batch.len() < MESSAGE_BATCH;
+                after = batch.last().map_or(after, |m| m.seq);
+                messages.extend(batch.into_iter().filter(|m| m.sender == openid));
+                if done {
+                    break;
+                }
+            }
+        }
+        Ok(entity::UserExport {
+            openid: openid.to_owned(),
+            export_time: util::now(),
+            trips: self.store.get_user_trips(openid)?,
+            templates: self.store.get_templates(openid)?,
+            orders: self.store.get_user_orders(openid)?,
+            ride_requests: self.store.get_user_ride_requests(openid)?,
+            waiters: self.store.get_user_waiters(openid)?,
+            wallet: self.store.get_wallet(openid)?,
+            wallet_logs: self.store.get_wallet_logs(openid, 0, -1)?,
+            withdraws: self.store.get_withdraws(openid, 0, -1)?,
+            coupons: self.store.get_user_coupons(openid)?,
+            referral: self.store.get_referral(openid)?,
+            referrals: self.store.get_user_referrals(openid)?,
+            conversations,
+            messages,
+            complains: self.store.get_user_complains(openid)?,
+            notifications: self.store.get_notifications(openid)?,
+        })
+    }
+
+    //注销账户：没有已支付未完成的订单且钱包已清零时，撤下进行中的行程、需求、候补和模板，
+    //抹去个人信息并使令牌失效；订单、支付和钱包流水按财务要求保留
+    pub fn delete_account(&self, user:&entity::JwtUser) -> Result<()> {
+        let openid = user.id.as_str();
+        let wallet = self.store.get_wallet(openid)?;
+        if wallet.balance != 0 || wallet.frozen != 0 {
+            return Err(ServiceError::AccountInUse);
+        }
+        let paid = |order:&entity::Order| order.status == entity::OrderStatus::Paid;
+        if self.store.get_user_orders(openid)?.iter().any(&paid) {
+            return Err(ServiceError::AccountInUse);
+        }
+        let trips = self.store.get_user_trips(openid)?;
+        for trip in &trips {
+            if self.store.get_trip_orders(&trip.id)?.iter().any(&paid) {
+                return Err(ServiceError::AccountInUse);
+            }
+        }
+
+        //有未支付订单的行程删除不了，个人信息在下面一起抹去
+        for trip in trips.iter().filter(|t| t.status != entity::TripStatus::Finish && t.status != entity::TripStatus::Cancel) {
+            if self.store.remove_trip(&trip.id)? {
+                self.audit("Trip", &trip.id, Some(trip.status.to_string()), "Removed", openid, "delete_account");
+            }
+        }
+        for request in self.store.get_user_ride_requests(openid)? {
+            if request.status == entity::RideRequestStatus::Open
+                && self.store.close_ride_request(&request.id, &entity::RideRequestStatus::Cancelled)? {
+                self.audit("RideRequest", &request.id, Some(request.status.to_string()), "Cancelled", openid, "delete_account");
+            }
+        }
+        for waiter in self.store.get_user_waiters(openid)? {
+            if waiter.status == entity::WaitStatus::Waiting && self.store.take_waiter(&waiter, &entity::WaitStatus::Removed)? {
+                self.audit("Waiter", &waiter.id, Some(waiter.status.to_string()), "Removed", openid, "delete_account");
+            }
+        }
+        //模板从昨天起结束，不再生成行程
+        let yesterday = util::format_date(util::today() - 1);
+        for mut template in self.store.get_templates(openid)? {
+            if template.end_date > yesterday {
+                template.end_date = yesterday.clone();
+            }
+            template.tel = String::new();
+            template.plate_number = String::new();
+            template.car_type = String::new();
+            template.message = None;
+            for occurrence in &mut template.overrides {
+                occurrence.message = None;
+            }
+            self.store.update_template(&template)?;
+        }
+        let now = util::now();
+        for mut conversation in self.store.get_user_conversations(openid)? {
+            if !conversation.is_closed() {
+                conversation.close_time = Some(now);
+                self.store.update_conversation(&conversation)?;
+            }
+        }
+
+        //所有设备上的登录都失效，不只是当前会话
+        self.store.erase_user(openid)?;
+        self.store.revoke_user_tokens(openid)?;
+        self.store.remove_refresh_tokens(openid)?;
+        self.audit("User", openid, None, "Deleted", openid, "delete_account");
+        Ok(())
+    }
+
+    //没有邀请码时生成一个，邀请码冲突时重试
+    pub fn get_referral_code(&self, openid:&str) -> Result<entity::ReferralCode> {
+        for _ in 0..5 {
+            if let Some(code) = self.store.get_referral_code(openid)? {
+                return Ok(entity::ReferralCode { code });
+            }
+            self.store.add_referral_code(openid, &util::random_string(8).to_uppercase())?;
+        }
+        Err(ServiceError::String("referral code conflict".to_owned()))
+    }
+
+    pub fn get_user_referrals(&self, openid:&str) -> Result<Vec<entity::Referral>> {
+        self.store.get_user_referrals(openid)
+    }
+
+    //所有邀请记录，包括未发放奖励的原因，供运营统计
+    pub fn get_referrals(&self, page:isize) -> Result<Vec<entity::Referral>> {
+        let (start, end) = page_range(page);
+        self.store.get_referrals(start, end)
+    }
+
+    //只有还没有下过单的新用户可以被邀请，同一设备只能被邀请一次，也不能和邀请人是同一设备
+    fn refer(&self, openid:&str, code:&str, device:Option<&str>) -> Result<()> {
+        let referrer = match self.store.find_referrer(code)? {
+            Some(ref referrer) if referrer == openid => return Ok(()),
+            Some(referrer) => referrer,
+            None => return Ok(()),
+        };
+        if !self.store.get_user_orders(openid)?.is_empty() {
+            return Ok(());
+        }
+        let mut referral = entity::Referral::new(&referrer, openid, code, device.map(|d| d.to_owned()));
+        //没有设备标识时无法排除同一设备反复注册
+        match device {
+            None => referral.reject("missing device"),
+            Some(device) => if self.store.get_device(&referrer)?.as_ref().map(|d| d.as_str()) == Some(device) {
+                referral.reject("same device as referrer");
+            } else if !self.store.claim_fingerprint(&format!("device:{}", device))? {
+                referral.reject("device already referred");
+            },
+        }
+        if self.store.add_referral(&referral)? {
+            self.audit("Referral", openid, None, &referral.status.to_string(), openid, code);
+        }
+        Ok(())
+    }
+
+    //由OrderSubmitted事件触发，被邀请用户第一个留了手机号并确认的订单使双方各得一份奖励
+    //先把邀请置为已完成再逐个发放，发放失败时返回错误由事件重试，已发放的不会重复发放
+    pub fn reward_referral(&self, order_id:&str) -> Result<()> {
+        let order = self.store.get_order(order_id)?;
+        let referral = match self.store.get_referral(&order.openid)? {
+            Some(referral) => referral,
+            None => return Ok(()),
+        };
+        match referral.status {
+            entity::ReferralStatus::Pending => (),
+            entity::ReferralStatus::Rewarded => return self.grant_referral_rewards(&referral),
+            entity::ReferralStatus::Rejected => return Ok(()),
+        }
+        //没有手机号无法检查是否重复领取，等待之后的订单
+        let tel = match order.tel {
+            Some(ref tel) => tel,
+            None => return Ok(()),
+        };
+        let mut reason = None;
+        let same_tel = self.store.get_user_orders(&referral.referrer)?.iter().any(|o| o.tel.as_ref() == Some(tel));
+        if same_tel {
+            reason = Some("same tel as referrer");
+        } else if !self.store.claim_fingerprint(&format!("tel:{}", tel))? {
+            reason = Some("tel already referred");
+        }
+        let status = if reason.is_some() {
+            entity::ReferralStatus::Rejected
+        } else {
+            entity::ReferralStatus::Rewarded
+        };
+        if !self.store.finish_referral(&referral.id, &status, reason, order_id)? {
+            return Ok(());
+        }
+        self.audit("Referral", &referral.id, Some("Pending".to_owned()), &status.to_string(), "system:referral", reason.unwrap_or(order_id));
+        if status == entity::ReferralStatus::Rewarded {
+            self.grant_referral_rewards(&referral)?;
+        }
+        Ok(())
+    }
+
+    //发放还没有发放的奖励，全部发放后发出ReferralRewarded事件
+    fn grant_referral_rewards(&self, referral:&entity::Referral) -> Result<()> {
+        let granted = self.store.get_referral_grants(&referral.id)?;
+        let pending: Vec<&String> = [&referral.referrer, &referral.id]
+            .iter()
+            .cloned()
+            .filter(|openid| !granted.contains(*openid))
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let last = pending.len() - 1;
+        for (i, openid) in pending.into_iter().enumerate() {
+            self.grant_referral_reward(openid, &referral.id)?;
+            //最后一份发放后和记录一起写入事件
+            let rewarded = Event::ReferralRewarded {
+                referrer: referral.referrer.clone(),
+                referee: referral.id.clone(),
+            };
+            let events = if i == last { vec![rewarded] } else { Vec::new() };
+            self.store.add_referral_grant(&referral.id, openid, &events)?;
+        }
+        Ok(())
+    }
+
+    fn grant_referral_reward(&self, openid:&str, referral_id:&str) -> Result<()> {
+        let config = setting::get();
+        if config.referral.reward == "wallet" {
+            self.store.credit_wallet(openid, config.referral.amount, "referral", referral_id)
+        } else {
+            let coupon = entity::Coupon::reward(openid, config.referral.amount, config.referral.coupon_days);
+            self.store.add_coupon(&coupon)
+        }
+    }
+
+    pub fn get_user_coupons(&self, openid:&str) -> Result<Vec<entity::Coupon>> {
+        self.store.get_user_coupons(openid)
+    }
+
+    //车主和已支付的乘客之间发消息，双方只通过openid联系，看不到对方手机号
+    pub fn send_message(&self, openid:String, form:entity::MessageForm) -> Result<entity::Message> {
+        form.validate()?;
+        let trip = self.store.get_trip(&form.trip_id)?;
+        let passenger = if trip.openid == openid {
+            Validator::new()
+                .check(form.passenger.as_ref().map_or(false, |p| *p != openid), "passenger", "is required for the driver")
+                .finish()?;
+            form.passenger.clone()?
+        } else {
+            openid.clone()
+        };
+        let conversation = self.conversation(&trip, &passenger)?;
+        if conversation.is_closed() {
+            return Err(ServiceError::ConversationClosed);
+        }
+        let seq = self.store.next_message_seq(&conversation.id)?;
+        let message = entity::Message::new(&conversation, seq, &openid, form.content);
+        self.store.add_message(&message)?;
+        //自己发的消息视为已读
+        self.store.mark_read(&conversation.id, &openid, seq)?;
+        let receiver = conversation.peer(&openid)?.to_owned();
+        if let Err(err) = self.store.publish_message(&receiver, &message) {
+            println!("publish message {} failed: {:?}", message.id, err);
+        }
+        self.emit(Event::MessageSent {
+            conversation_id: conversation.id.clone(),
+            message_id: message.id.clone(),
+            sender: openid,
+            receiver,
+        });
+        Ok(message)
+    }
+
+    //会话在第一条消息时创建，乘客需要有该行程已支付或已确认的订单
+    fn conversation(&self, trip:&entity::Trip, passenger:&str) -> Result<entity::Conversation> {
+        let id = entity::Conversation::id_of(&trip.id, passenger);
+        if let Some(conversation) = self.store.get_conversation(&id)? {
+            return Ok(conversation);
+        }
+        let paid = self.store.get_user_orders(passenger)?.iter().any(|order| {
+            order.trip_id == trip.id && order.status != entity::OrderStatus::Unpaid
+        });
+        if !paid {
+            return Err(ServiceError::NoPay);
+        }
+        let mut conversation = entity::Conversation::new(trip, passenger);
+        if trip.status == entity::TripStatus::Finish {
+            conversation.close_time = Some(util::now() + setting::get().messaging.close_after);
+        }
+        //双方同时发起时只有一个能创建成功
+        match self.store.add_conversation(&conversation) {
+            Ok(_) => Ok(conversation),
+            Err(err) => self.store.get_conversation(&id)?.ok_or(err),
+        }
+    }
+
+    pub fn get_conversations(&self, openid:&str) -> Result<Vec<entity::ConversationSummary>> {
+        self.store.get_user_conversations(openid)?
+            .into_iter()
+            .map(|conversation| -> Result<entity::ConversationSummary> {
+                let last_seq = self.store.get_message_seq(&conversation.id)?;
+                let read = self.store.get_read(&conversation.id, openid)?;
+                let peer_read = match conversation.peer(openid) {
+                    Some(peer) => self.store.get_read(&conversation.id, peer)?,
+                    None => 0,
+                };
+                Ok(entity::ConversationSummary {
+                    conversation,
+                    last_seq,
+                    unread: last_seq - read,
+                    peer_read,
+                })
+            })
+            .collect()
+    }
+
+    //轮询seq大于after的消息
+    pub fn get_messages(&self, openid:&str, conversation_id:&str, after:i64) -> Result<Vec<entity::Message>> {
+        self.member_conversation(openid, conversation_id)?;
+        self.store.get_messages(conversation_id, after, MESSAGE_BATCH)
+    }
+
+    //已读回执，seq不能超过最后一条消息
+    pub fn read_messages(&self, openid:&str, conversation_id:&str, seq:i64) -> Result<()> {
+        self.member_conversation(openid, conversation_id)?;
+        let last_seq = self.store.get_message_seq(conversation_id)?;
+        self.store.mark_read(conversation_id, openid, seq.min(last_seq))
+    }
+
+    //推送该用户收到的新消息
+    pub fn subscribe_messages(&self, openid:&str) -> Result<MessageUpdates> {
+        self.store.subscribe_messages(openid)
+    }
+
+    fn member_conversation(&self, openid:&str, id:&str) -> Result<entity::Conversation> {
+        let conversation = self.store.get_conversation(id)?.ok_or(ServiceError::NotFound("conversation".to_owned()))?;
+        if conversation.peer(openid).is_none() {
+            return Err(ServiceError::NoAuth);
+        }
+        Ok(conversation)
+    }
+
+    //由TripFinished事件触发，行程完成close_after秒后关闭该行程的所有会话
+    pub fn close_conversations(&self, trip_id:&str) -> Result<()> {
+        let close_time = util::now() + setting::get().messaging.close_after;
+        for mut conversation in self.store.get_trip_conversations(trip_id)? {
+            if conversation.close_time.is_none() {
+                conversation.close_time = Some(close_time);
+                self.store.update_conversation(&conversation)?;
+            }
+        }
+        Ok(())
+    }
+
+    //由SeatOffered事件触发，提醒候补在支付超时前支付预留订单
+    pub fn notify_seat_offered(&self, order_id:&str, trip_id:&str, openid:&str) -> Result<()> {
+        let content = format!("候补成功，已为您预留座位，请在{}分钟内支付", setting::get().business.pay_timeout / 60);
+        let notification = entity::Notification::new(
+            format!("SeatOffered:{}", order_id),
+            openid,
+            entity::NotificationKind::SeatOffered,
+            content,
+            trip_id,
+            Some(order_id.to_owned()),
+            None,
+        );
+        self.store.add_notification(&notification)
+    }
+
+    //由RideMatched事件触发，乘客和车主各收到一条通知
+    pub fn notify_ride_matched(&self, request_id:&str, trip_id:&str, passenger:&str, driver:&str) -> Result<()> {
+        let notifications = [
+            (passenger, "有新的行程与您的拼车需求匹配"),
+            (driver, "有新的拼车需求与您的行程匹配"),
+        ];
+        for &(openid, content) in &notifications {
+            self.store.add_notification(&entity::Notification::new(
+                format!("RideMatched:{}:{}:{}", request_id, trip_id, openid),
+                openid,
+                entity::NotificationKind::RideMatched,
+                content.to_owned(),
+                trip_id,
+                None,
+                Some(request_id.to_owned()),
+            ))?;
+        }
+        Ok(())
+    }
+
+    //由RideRequestAccepted事件触发，提醒乘客支付，车主收到接单确认
+    pub fn notify_ride_request_accepted(&self, request_id:&str, order_id:&str, trip_id:&str, openid:&str) -> Result<()> {
+        let trip = self.store.get_trip(trip_id)?;
+        let notifications = [
+            (openid, format!("车主已接单，请在{}分钟内支付", setting::get().business.pay_timeout / 60)),
+            (trip.openid.as_str(), "已接单，等待乘客支付".to_owned()),
+        ];
+        for &(openid, ref content) in &notifications {
+            self.store.add_notification(&entity::Notification::new(
+                format!("RideRequestAccepted:{}:{}", order_id, openid),
+                openid,
+                entity::NotificationKind::RideRequestAccepted,
+                content.clone(),
+                trip_id,
+                Some(order_id.to_owned()),
+                Some(request_id.to_owned()),
+            ))?;
+        }
+        Ok(())
+    }
+
+    pub fn get_notifications(&self, openid:&str) -> Result<Vec<entity::Notification>> {
+        self.store.get_notifications(openid)
+    }
+
+    pub fn get_pending_withdraws(&self, page:isize) -> Result<Vec<entity::Withdraw>> {
+        let (start, end) = page_range(page);
+        self.store.get_pending_withdraws(start, end)
+    }
+
+    //打款成功后记录结果失败时提现停留在Processing，由resume_withdraws补完
+    pub fn approve_withdraw(&self, id:&str) -> Result<()> {
+        let withdraw = self.store.claim_withdraw(id)?;
+        if let Err(err) = external::pay_to_client(&withdraw.id, &withdraw.openid, withdraw.amount) {
+            metrics::inc("payout_failures_total", &[]);
+            self.store.release_withdraw(id)?;
+            return Err(err);
+        }
+        self.store.finish_withdraw(&withdraw, true)
+    }
+
+    //补完开始打款超过WITHDRAW_RESUME_AFTER秒仍未完成的提现，由定时任务调用，返回完成的数量
+    //使用同一个partner_trade_no重新打款，微信对已付款的单号直接返回成功，不会重复付款
+    pub fn resume_withdraws(&self) -> Result<usize> {
+        let mut count = 0;
+        for withdraw in self.store.get_processing_withdraws(util::now() - WITHDRAW_RESUME_AFTER)? {
+            let result = external::pay_to_client(&withdraw.id, &withdraw.openid, withdraw.amount)
+                .and_then(|_| self.store.finish_withdraw(&withdraw, true));
+            match result {
+                Ok(()) => count += 1,
+                //管理员的请求已经完成了这笔提现
+                Err(ServiceError::WithdrawHandled) => (),
+                Err(err) => {
+                    metrics::inc("payout_failures_total", &[]);
+                    println!("resume withdraw {} error: {:?}", withdraw.id, err);
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn reject_withdraw(&self, id:&str) -> Result<()> {
+        let withdraw = self.store.claim_withdraw(id)?;
+        self.store.finish_withdraw(&withdraw, false)
     }
 
     pub fn test(&self) {
@@ -1,24 +1,645 @@
-
-use std::sync::RwLock;
-use config::Config;
-use config::File;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::thread;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use config::{Config, ConfigError, Environment, File};
+use pii;
+use util;
 
 lazy_static! {
-	static ref SETTINGS: RwLock<Config> = get_config();
+	static ref SETTINGS: RwLock<Option<Arc<AppConfig>>> = RwLock::new(None);
+}
+
+//配置来源依次为：config文件、config.{profile}文件、PINCHE_开头的环境变量
+//环境变量用__分隔层级，例如PINCHE_WEIXIN__APPID对应weixin.appid
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfig {
+    pub profile: String, //dev/test/prod，由PINCHE_PROFILE指定
+    pub app: AppSetting,
+    pub weixin: WeixinSetting,
+    pub wallet: WalletSetting,
+    pub business: BusinessSetting,
+    pub features: FeatureSetting,
+    pub jwt: JwtSetting,
+    pub rate_limit: RateLimitSetting,
+    pub booking: BookingSetting,
+    pub schedule: ScheduleSetting,
+    pub referral: ReferralSetting,
+    pub messaging: MessagingSetting,
+    pub stream: StreamSetting,
+    pub event: EventSetting,
+    pub pii: PiiSetting,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AppSetting {
+    pub dburl: String,
+    pub dbport: i64,
+    pub dbname: String,
+    pub redis: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WeixinSetting {
+    pub appid: String,
+    pub secret: String,
+    pub mchid: String,
+    pub key: String, //商户支付密钥
+    pub ip: String,
+    pub notify_url: String,
+    pub api_url: String,
+    pub mch_url: String,
+}
+
+//以下几项可以在运行时重新加载
+
+//金额单位为分
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WalletSetting {
+    pub min_withdraw: i64,
+    pub daily_count: i64,
+    pub daily_amount: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BusinessSetting {
+    pub commission: f64, //平台抽成比例
+    pub pay_timeout: i64, //未支付订单保留的秒数
+    pub page_size: i64,
+    pub max_refund_rate: f64, //车主优惠退款最多占订单金额的比例
+}
+
+//功能开关，关闭时对应接口返回FEATURE_DISABLED
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FeatureSetting {
+    pub publish: bool,
+    pub apply: bool,
+    pub withdraw: bool,
+}
+
+//jwt签名密钥按kid保存，轮换时先加入新密钥再切换current_kid，旧token在过期前仍可验证
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct JwtSetting {
+    pub current_kid: String,
+    pub keys: BTreeMap<String, String>,
+    pub access_ttl: i64, //access token有效秒数
+    pub refresh_ttl: i64, //refresh token有效秒数
+}
+
+//接口限流，window秒内每个用户最多请求的次数；同一IP的上限为用户上限乘以ip_multiple
+//只有来自trusted_proxies的请求才使用X-Real-IP作为来源IP
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RateLimitSetting {
+    pub window: i64,
+    pub ip_multiple: i64,
+    pub login: i64,
+    pub publish: i64,
+    pub apply: i64,
+    pub trusted_proxies: Vec<String>,
+}
+
+//下单限制：未支付订单数、每人每个行程的座位数，block_time内过期expire_limit次后暂停预订
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BookingSetting {
+    pub max_unpaid: i64,
+    pub max_seats: i64,
+    pub expire_limit: i64,
+    pub block_time: i64, //秒
+}
+
+//行程模板提前days_ahead天生成行程，holidays中的日期（YYYY-MM-DD）不生成
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ScheduleSetting {
+    pub days_ahead: i64,
+    pub holidays: Vec<String>,
+}
+
+//邀请奖励：reward为coupon时双方各得一张amount分的满减券，有效coupon_days天；为wallet时直接记入钱包
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ReferralSetting {
+    pub reward: String,
+    pub amount: i64,
+    pub coupon_days: i64,
+}
+
+//行程留言和站内消息的内容审核：最长max_length个字符，不能包含blocked_words中的词（忽略大小写）
+//行程完成close_after秒后关闭会话
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MessagingSetting {
+    pub max_length: i64,
+    pub blocked_words: Vec<String>,
+    pub close_after: i64,
+}
+
+//SSE推送同时保持的连接数，每个连接占用一个rocket工作线程，max_streams应明显小于workers
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StreamSetting {
+    pub max_streams: i64,
+    pub max_user_streams: i64, //每个用户的连接数
+}
+
+//事件处理失败后等待retry_delay毫秒重试，之后每次失败等待时间加倍，失败max_attempts次后移入死信
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct EventSetting {
+    pub retry_delay: i64,
+    pub max_attempts: i64,
+}
+
+//个人信息加密的主密钥按kid保存，值为base64编码的32字节；index_key用于计算盲索引，修改后已有的索引失效
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PiiSetting {
+    pub current_kid: String,
+    pub keys: BTreeMap<String, String>,
+    pub index_key: String,
+}
+
+enum Kind {
+    Str,
+    Int,
+    Float,
+    Bool,
+    Table,
+    Array,
 }
 
-fn get_config() -> RwLock<Config> {
+const KEYS: &[(&str, Kind)] = &[
+    ("app.dburl", Kind::Str),
+    ("app.dbport", Kind::Int),
+    ("app.dbname", Kind::Str),
+    ("app.redis", Kind::Str),
+    ("weixin.appid", Kind::Str),
+    ("weixin.secret", Kind::Str),
+    ("weixin.mchid", Kind::Str),
+    ("weixin.key", Kind::Str),
+    ("weixin.ip", Kind::Str),
+    ("weixin.notify_url", Kind::Str),
+    ("weixin.api_url", Kind::Str),
+    ("weixin.mch_url", Kind::Str),
+    ("wallet.min_withdraw", Kind::Int),
+    ("wallet.daily_count", Kind::Int),
+    ("wallet.daily_amount", Kind::Int),
+    ("business.commission", Kind::Float),
+    ("business.pay_timeout", Kind::Int),
+    ("business.page_size", Kind::Int),
+    ("business.max_refund_rate", Kind::Float),
+    ("features.publish", Kind::Bool),
+    ("features.apply", Kind::Bool),
+    ("features.withdraw", Kind::Bool),
+    ("jwt.current_kid", Kind::Str),
+    ("jwt.keys", Kind::Table),
+    ("jwt.access_ttl", Kind::Int),
+    ("jwt.refresh_ttl", Kind::Int),
+    ("rate_limit.window", Kind::Int),
+    ("rate_limit.ip_multiple", Kind::Int),
+    ("rate_limit.login", Kind::Int),
+    ("rate_limit.publish", Kind::Int),
+    ("rate_limit.apply", Kind::Int),
+    ("rate_limit.trusted_proxies", Kind::Array),
+    ("booking.max_unpaid", Kind::Int),
+    ("booking.max_seats", Kind::Int),
+    ("booking.expire_limit", Kind::Int),
+    ("booking.block_time", Kind::Int),
+    ("schedule.days_ahead", Kind::Int),
+    ("schedule.holidays", Kind::Array),
+    ("referral.reward", Kind::Str),
+    ("referral.amount", Kind::Int),
+    ("referral.coupon_days", Kind::Int),
+    ("messaging.max_length", Kind::Int),
+    ("messaging.blocked_words", Kind::Array),
+    ("messaging.close_after", Kind::Int),
+    ("stream.max_streams", Kind::Int),
+    ("stream.max_user_streams", Kind::Int),
+    ("event.retry_delay", Kind::Int),
+    ("event.max_attempts", Kind::Int),
+    ("pii.current_kid", Kind::Str),
+    ("pii.keys", Kind::Table),
+    ("pii.index_key", Kind::Str),
+];
+
+//所有配置错误，启动时一次性列出
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+fn defaults(settings: &mut Config) -> Result<(), ConfigError> {
+    settings.set_default("app.dbname", "test")?;
+    settings.set_default("weixin.api_url", "https://api.weixin.qq.com")?;
+    settings.set_default("weixin.mch_url", "https://api.mch.weixin.qq.com")?;
+    settings.set_default("wallet.min_withdraw", 100)?;
+    settings.set_default("wallet.daily_count", 3)?;
+    settings.set_default("wallet.daily_amount", 500000)?;
+    settings.set_default("business.commission", 0.05)?;
+    settings.set_default("business.pay_timeout", 3 * 60)?;
+    settings.set_default("business.page_size", 10)?;
+    settings.set_default("business.max_refund_rate", 1.0)?;
+    settings.set_default("features.publish", true)?;
+    settings.set_default("features.apply", true)?;
+    settings.set_default("features.withdraw", true)?;
+    settings.set_default("jwt.access_ttl", 2 * 3600)?;
+    settings.set_default("jwt.refresh_ttl", 30 * 24 * 3600)?;
+    settings.set_default("rate_limit.window", 60)?;
+    settings.set_default("rate_limit.ip_multiple", 5)?;
+    settings.set_default("rate_limit.login", 10)?;
+    settings.set_default("rate_limit.publish", 5)?;
+    settings.set_default("rate_limit.apply", 10)?;
+    settings.set_default("rate_limit.trusted_proxies", vec!["127.0.0.1".to_owned()])?;
+    settings.set_default("booking.max_unpaid", 2)?;
+    settings.set_default("booking.max_seats", 4)?;
+    settings.set_default("booking.expire_limit", 3)?;
+    settings.set_default("booking.block_time", 24 * 3600)?;
+    settings.set_default("schedule.days_ahead", 7)?;
+    settings.set_default("schedule.holidays", Vec::<String>::new())?;
+    settings.set_default("referral.reward", "coupon")?;
+    settings.set_default("referral.amount", 500)?;
+    settings.set_default("referral.coupon_days", 30)?;
+    settings.set_default("messaging.max_length", 500)?;
+    settings.set_default("messaging.blocked_words", Vec::<String>::new())?;
+    settings.set_default("messaging.close_after", 24 * 3600)?;
+    settings.set_default("stream.max_streams", 8)?;
+    settings.set_default("stream.max_user_streams", 2)?;
+    settings.set_default("event.retry_delay", 5000)?;
+    settings.set_default("event.max_attempts", 8)?;
+    Ok(())
+}
+
+//读取并校验配置，不修改全局配置
+pub fn load() -> Result<AppConfig, ConfigErrors> {
+    let profile = env::var("PINCHE_PROFILE").unwrap_or_else(|_| "dev".to_owned());
     let mut settings = Config::default();
-    settings.merge(File::with_name("config")).unwrap();
-    RwLock::new(settings)
+    let mut errors = Vec::new();
+    if let Err(err) = defaults(&mut settings) {
+        errors.push(format!("defaults: {}", err));
+    }
+    if let Err(err) = settings.set("profile", profile.as_str()) {
+        errors.push(format!("profile: {}", err));
+    }
+    if let Err(err) = settings.merge(File::with_name("config").required(false)) {
+        errors.push(format!("config: {}", err));
+    }
+    if let Err(err) = settings.merge(File::with_name(&format!("config.{}", profile)).required(false)) {
+        errors.push(format!("config.{}: {}", profile, err));
+    }
+    if let Err(err) = settings.merge(Environment::with_prefix("PINCHE").separator("__")) {
+        errors.push(format!("environment: {}", err));
+    }
+
+    for &(key, ref kind) in KEYS {
+        let result = match *kind {
+            Kind::Str => settings.get_str(key).map(|_| ()),
+            Kind::Int => settings.get_int(key).map(|_| ()),
+            Kind::Float => settings.get_float(key).map(|_| ()),
+            Kind::Bool => settings.get_bool(key).map(|_| ()),
+            Kind::Table => settings.get_table(key).map(|_| ()),
+            Kind::Array => settings.get_array(key).map(|_| ()),
+        };
+        match result {
+            Ok(_) => (),
+            Err(ConfigError::NotFound(_)) => errors.push(format!("{} is missing", key)),
+            Err(err) => errors.push(format!("{} is invalid: {}", key, err)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ConfigErrors(errors));
+    }
+
+    let config: AppConfig = settings
+        .try_into()
+        .map_err(|err| ConfigErrors(vec![err.to_string()]))?;
+    config.validate().map(|_| config)
+}
+
+impl AppConfig {
+    fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut errors = Vec::new();
+        {
+            let mut check = |ok: bool, message: &str| if !ok {
+                errors.push(message.to_owned());
+            };
+            check(
+                self.profile == "dev" || self.profile == "test" || self.profile == "prod",
+                "profile must be one of dev, test, prod",
+            );
+            check(
+                self.app.dbport > 0 && self.app.dbport <= 65535,
+                "app.dbport must be between 1 and 65535",
+            );
+            check(
+                self.weixin.api_url.starts_with("http"),
+                "weixin.api_url must be a http(s) url",
+            );
+            check(
+                self.weixin.mch_url.starts_with("http"),
+                "weixin.mch_url must be a http(s) url",
+            );
+            check(
+                self.profile != "prod" || self.weixin.notify_url.starts_with("https://"),
+                "weixin.notify_url must use https in prod",
+            );
+            //微信企业付款最低1元
+            check(
+                self.wallet.min_withdraw >= 100,
+                "wallet.min_withdraw must be at least 100",
+            );
+            check(
+                self.wallet.daily_count >= 1,
+                "wallet.daily_count must be at least 1",
+            );
+            check(
+                self.wallet.daily_amount >= self.wallet.min_withdraw,
+                "wallet.daily_amount must not be less than wallet.min_withdraw",
+            );
+            check(
+                self.business.commission >= 0.0 && self.business.commission < 1.0,
+                "business.commission must be in [0, 1)",
+            );
+            check(
+                self.business.pay_timeout >= 60,
+                "business.pay_timeout must be at least 60 seconds",
+            );
+            check(
+                self.business.page_size >= 1 && self.business.page_size <= 100,
+                "business.page_size must be between 1 and 100",
+            );
+            check(
+                self.business.max_refund_rate >= 0.0 && self.business.max_refund_rate <= 1.0,
+                "business.max_refund_rate must be in [0, 1]",
+            );
+            check(
+                self.jwt.keys.contains_key(&self.jwt.current_kid),
+                "jwt.keys must contain jwt.current_kid",
+            );
+            check(
+                self.jwt.keys.values().all(|key| key.len() >= 16),
+                "jwt.keys must be at least 16 characters",
+            );
+            check(
+                self.jwt.access_ttl >= 60 && self.jwt.access_ttl < self.jwt.refresh_ttl,
+                "jwt.access_ttl must be at least 60 and less than jwt.refresh_ttl",
+            );
+            check(
+                self.rate_limit.window >= 1 && self.rate_limit.ip_multiple >= 1,
+                "rate_limit.window and rate_limit.ip_multiple must be at least 1",
+            );
+            check(
+                self.rate_limit.login >= 1 && self.rate_limit.publish >= 1 && self.rate_limit.apply >= 1,
+                "rate_limit limits must be at least 1",
+            );
+            check(
+                self.rate_limit.trusted_proxies.iter().all(|ip| ip.parse::<IpAddr>().is_ok()),
+                "rate_limit.trusted_proxies must be IP addresses",
+            );
+            check(
+                self.booking.max_unpaid >= 1 && self.booking.max_seats >= 1 && self.booking.expire_limit >= 1,
+                "booking limits must be at least 1",
+            );
+            check(
+                self.booking.block_time >= 60,
+                "booking.block_time must be at least 60 seconds",
+            );
+            check(
+                self.schedule.days_ahead >= 1 && self.schedule.days_ahead <= 30,
+                "schedule.days_ahead must be between 1 and 30",
+            );
+            check(
+                self.schedule.holidays.iter().all(|date| util::parse_date(date).is_some()),
+                "schedule.holidays must be dates like 2018-10-01",
+            );
+            check(
+                self.referral.reward == "coupon" || self.referral.reward == "wallet",
+                "referral.reward must be coupon or wallet",
+            );
+            check(
+                self.referral.amount >= 1,
+                "referral.amount must be at least 1",
+            );
+            check(
+                self.referral.coupon_days >= 1 && self.referral.coupon_days <= 365,
+                "referral.coupon_days must be between 1 and 365",
+            );
+            check(
+                self.messaging.max_length >= 1,
+                "messaging.max_length must be at least 1",
+            );
+            check(
+                self.messaging.blocked_words.iter().all(|word| !word.trim().is_empty()),
+                "messaging.blocked_words must not contain empty words",
+            );
+            check(
+                self.messaging.close_after >= 0,
+                "messaging.close_after must not be negative",
+            );
+            check(
+                self.stream.max_user_streams >= 1 && self.stream.max_streams >= self.stream.max_user_streams,
+                "stream.max_user_streams must be at least 1 and not exceed stream.max_streams",
+            );
+            check(
+                self.event.retry_delay >= 0 && self.event.max_attempts >= 1,
+                "event.retry_delay must not be negative and event.max_attempts must be at least 1",
+            );
+            check(
+                self.pii.keys.contains_key(&self.pii.current_kid),
+                "pii.keys must contain pii.current_kid",
+            );
+            check(
+                self.pii.keys.values().all(|key| pii::decode_key(key).is_some()),
+                "pii.keys must be base64 encoded 32 byte keys",
+            );
+            check(
+                self.pii.index_key.len() >= 16,
+                "pii.index_key must be at least 16 characters",
+            );
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+}
+
+//启动时调用，加载失败时返回全部错误
+pub fn init() -> Result<Arc<AppConfig>, ConfigErrors> {
+    let config = Arc::new(load()?);
+    *SETTINGS.write().unwrap() = Some(config.clone());
+    Ok(config)
+}
+
+pub fn get() -> Arc<AppConfig> {
+    if let Some(ref config) = *SETTINGS.read().unwrap() {
+        return config.clone();
+    }
+    match init() {
+        Ok(config) => config,
+        Err(errors) => panic!("{}", errors),
+    }
 }
 
-pub fn get_str(key: &str) -> String {
-    let setting = SETTINGS.read().unwrap();
-    setting.get_str(key).unwrap()
+macro_rules! diff {
+    ($changes:ident, $old:expr, $new:expr, $($section:ident . $field:ident),*) => {
+        $(
+            if $old.$section.$field != $new.$section.$field {
+                $changes.push(format!(
+                    "{}.{}: {:?} -> {:?}",
+                    stringify!($section),
+                    stringify!($field),
+                    $old.$section.$field,
+                    $new.$section.$field
+                ));
+            }
+        )*
+    }
 }
 
-pub fn get_int64(key: &str) -> i64 {
-    let setting = SETTINGS.read().unwrap();
-    setting.get_int(key).unwrap()
-}
\ No newline at end of file
+//重新加载运行时可调整的配置（wallet、business、features、jwt、rate_limit、booking、schedule、referral、messaging、stream、event、pii的密钥），返回变化的配置项
+//新配置校验失败时保留原配置；数据库和微信配置需要重启才能生效
+pub fn reload() -> Result<Vec<String>, ConfigErrors> {
+    let new = load().map_err(|errors| {
+        println!("setting reload failed, keep previous config\n{}", errors);
+        errors
+    })?;
+    let mut settings = SETTINGS.write().unwrap();
+    let old = match *settings {
+        Some(ref old) => old.clone(),
+        None => {
+            *settings = Some(Arc::new(new));
+            return Ok(Vec::new());
+        }
+    };
+    if old.app != new.app || old.weixin != new.weixin {
+        println!("setting: app and weixin changes need restart, ignored");
+    }
+    let mut config = (*old).clone();
+    config.wallet = new.wallet;
+    config.business = new.business;
+    config.features = new.features;
+    config.jwt = new.jwt;
+    config.rate_limit = new.rate_limit;
+    config.booking = new.booking;
+    config.schedule = new.schedule;
+    config.referral = new.referral;
+    config.messaging = new.messaging;
+    config.stream = new.stream;
+    config.event = new.event;
+    //index_key修改后已有的盲索引无法再匹配，需要重建索引，不在运行时修改
+    if old.pii.index_key != new.pii.index_key {
+        println!("setting: pii.index_key changes need reindex, ignored");
+    }
+    //已有数据可能仍在使用旧的主密钥，删除或修改密钥需要先重新加密，运行时只能加入新密钥
+    config.pii.current_kid = new.pii.current_kid;
+    let mut keys = new.pii.keys;
+    for (kid, key) in &old.pii.keys {
+        if keys.get(kid) != Some(key) {
+            println!("setting: pii.keys.{} is still used by stored values, removing or changing it needs re-encryption, ignored", kid);
+            keys.insert(kid.clone(), key.clone());
+        }
+    }
+    config.pii.keys = keys;
+
+    let mut changes = Vec::new();
+    diff!(
+        changes,
+        old,
+        config,
+        wallet.min_withdraw,
+        wallet.daily_count,
+        wallet.daily_amount,
+        business.commission,
+        business.pay_timeout,
+        business.page_size,
+        business.max_refund_rate,
+        features.publish,
+        features.apply,
+        features.withdraw,
+        jwt.current_kid,
+        jwt.access_ttl,
+        jwt.refresh_ttl,
+        rate_limit.window,
+        rate_limit.ip_multiple,
+        rate_limit.login,
+        rate_limit.publish,
+        rate_limit.apply,
+        rate_limit.trusted_proxies,
+        booking.max_unpaid,
+        booking.max_seats,
+        booking.expire_limit,
+        booking.block_time,
+        schedule.days_ahead,
+        schedule.holidays,
+        referral.reward,
+        referral.amount,
+        referral.coupon_days,
+        messaging.max_length,
+        messaging.blocked_words,
+        messaging.close_after,
+        stream.max_streams,
+        stream.max_user_streams,
+        event.retry_delay,
+        event.max_attempts,
+        pii.current_kid
+    );
+    //只记录kid，不记录密钥
+    if old.jwt.keys.keys().ne(config.jwt.keys.keys()) {
+        changes.push(format!(
+            "jwt.keys: {:?} -> {:?}",
+            old.jwt.keys.keys().collect::<Vec<_>>(),
+            config.jwt.keys.keys().collect::<Vec<_>>()
+        ));
+    }
+    if old.pii.keys.keys().ne(config.pii.keys.keys()) {
+        changes.push(format!(
+            "pii.keys: {:?} -> {:?}",
+            old.pii.keys.keys().collect::<Vec<_>>(),
+            config.pii.keys.keys().collect::<Vec<_>>()
+        ));
+    }
+    for change in &changes {
+        println!("setting changed: {}", change);
+    }
+    *settings = Some(Arc::new(config));
+    Ok(changes)
+}
+
+//配置文件的修改时间，用于判断是否需要重新加载
+fn config_files() -> Vec<(String, Option<SystemTime>)> {
+    let mut files: Vec<_> = fs::read_dir(".")
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("config."))
+                .map(|entry| {
+                    (
+                        entry.file_name().to_string_lossy().into_owned(),
+                        entry.metadata().and_then(|m| m.modified()).ok(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+//定时检查配置文件，有修改时重新加载
+pub fn watch(interval: Duration) {
+    let mut last = config_files();
+    loop {
+        thread::sleep(interval);
+        let current = config_files();
+        if current != last {
+            last = current;
+            let _ = reload();
+        }
+    }
+}
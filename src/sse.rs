@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use serde::ser::Serialize;
+use serde_json;
+use entity::{Message, SeatUpdate};
+use service::{Result, ServiceError};
+use setting;
+
+lazy_static! {
+    //每个用户当前的连接数
+    static ref STREAMS: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+//推送连接的名额，连接断开、推送结束时释放
+pub struct StreamPermit {
+    openid: String,
+}
+
+impl StreamPermit {
+    //连接数达到stream配置的上限时返回TooManyRequests，避免推送占满工作线程
+    pub fn acquire(openid: &str) -> Result<StreamPermit> {
+        let config = setting::get();
+        let mut streams = STREAMS.lock().unwrap();
+        let total: i64 = streams.values().sum();
+        let own = streams.get(openid).cloned().unwrap_or(0);
+        if total >= config.stream.max_streams || own >= config.stream.max_user_streams {
+            return Err(ServiceError::TooManyRequests);
+        }
+        *streams.entry(openid.to_owned()).or_insert(0) += 1;
+        Ok(StreamPermit { openid: openid.to_owned() })
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let mut streams = STREAMS.lock().unwrap();
+        let empty = match streams.get_mut(&self.openid) {
+            Some(count) => {
+                *count -= 1;
+                *count <= 0
+            }
+            None => false,
+        };
+        if empty {
+            streams.remove(&self.openid);
+        }
+    }
+}
+
+//Server-Sent Events格式的推送，先发送当前状态，没有更新时发送注释行作为心跳
+//每个连接占用一个rocket工作线程，需要先取得StreamPermit
+pub struct EventStream<T> {
+    event: &'static str,
+    updates: Box<Iterator<Item = Option<T>> + Send>,
+    buf: Vec<u8>,
+    pos: usize,
+    flushed: bool,
+    _permit: StreamPermit,
+}
+
+//行程座位推送
+pub type SeatStream = EventStream<SeatUpdate>;
+//站内消息推送
+pub type MessageStream = EventStream<Message>;
+
+impl<T: Serialize> EventStream<T> {
+    pub fn new(
+        event: &'static str,
+        current: Vec<T>,
+        updates: Box<Iterator<Item = Option<T>> + Send>,
+        permit: StreamPermit,
+    ) -> Self {
+        let buf: Vec<u8> = current.iter().flat_map(|update| message(event, update)).collect();
+        EventStream {
+            event,
+            updates,
+            flushed: buf.is_empty(),
+            buf,
+            pos: 0,
+            _permit: permit,
+        }
+    }
+}
+
+fn message<T: Serialize>(event: &str, update: &T) -> Vec<u8> {
+    let data = serde_json::to_string(update).unwrap_or_default();
+    format!("event: {}\ndata: {}\n\n", event, data).into_bytes()
+}
+
+impl<T: Serialize> Read for EventStream<T> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buf.len() {
+            //rocket会一直读到缓冲区满才发送，每条消息后返回一次0让它立即发送
+            if !self.flushed {
+                self.flushed = true;
+                return Ok(0);
+            }
+            self.buf = match self.updates.next() {
+                Some(Some(update)) => message(self.event, &update),
+                Some(None) => b":\n\n".to_vec(),
+                None => return Ok(0),
+            };
+            self.pos = 0;
+            self.flushed = false;
+        }
+        let n = (&self.buf[self.pos..]).read(out)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
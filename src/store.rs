@@ -0,0 +1,248 @@
+use entity;
+use event::Event;
+use service::Result;
+
+//带events参数的方法在状态变化提交的同时写入事件，没有发生变化时不写入
+
+//行程存储
+pub trait TripStore {
+    fn add_trip(&self, t: &entity::Trip, events: &[Event]) -> Result<()>;
+    fn get_trip(&self, id: &str) -> Result<entity::Trip>;
+    fn get_trips(&self, start: isize, end: isize) -> Result<Vec<entity::Trip>>;
+    //所有订单都已确认时把行程置为Finish，返回本次是否改变了状态
+    fn check_trip_finish(&self, id: &str, events: &[Event]) -> Result<bool>;
+    //删除还没有订单的行程，已有订单时返回false，行程不存在时视为已删除
+    fn remove_trip(&self, id: &str) -> Result<bool>;
+}
+
+//订单存储，add_order负责扣减座位并检查用户的下单上限，expire_order负责归还未支付订单的座位
+pub trait OrderStore {
+    fn add_order(&self, order: &entity::Order, events: &[Event]) -> Result<()>;
+    fn get_order(&self, id: &str) -> Result<entity::Order>;
+    //订单已过期时返回错误，重复的支付通知直接忽略并返回false
+    fn pay_order(&self, id: &str, transaction_id: &str, events: &[Event]) -> Result<bool>;
+    //退款成功后累计退款金额，refunded与记录的不一致说明有并发的退款，返回RefundConflict
+    fn add_refund(&self, order_id: &str, refunded: i64, fee: i64) -> Result<()>;
+    //订单确认并把司机收入记入钱包，返回trip_id
+    fn submit_order(&self, order: &entity::Order, income: i64, events: &[Event]) -> Result<String>;
+    //过期时同时累计该用户的未支付过期次数，计数保留booking.block_time秒
+    //返回归还座位的trip_id并写入OrderExpired事件，已支付或已处理过的订单返回None
+    fn expire_order(&self, id: &str) -> Result<Option<String>>;
+    fn get_user_orders(&self, openid: &str) -> Result<Vec<entity::Order>>;
+    fn get_trip_orders(&self, trip_id: &str) -> Result<Vec<entity::Order>>;
+    //按手机号的盲索引查找，用于客服
+    fn find_orders_by_tel(&self, tel: &str) -> Result<Vec<entity::Order>>;
+    fn get_expired_count(&self, openid: &str) -> Result<i64>;
+}
+
+//司机钱包存储
+pub trait WalletStore {
+    fn get_wallet(&self, openid: &str) -> Result<entity::Wallet>;
+    fn add_withdraw(&self, w: &entity::Withdraw, day_count: i64, day_amount: i64) -> Result<()>;
+    fn claim_withdraw(&self, id: &str) -> Result<entity::Withdraw>;
+    fn release_withdraw(&self, id: &str) -> Result<()>;
+    fn finish_withdraw(&self, w: &entity::Withdraw, approved: bool) -> Result<()>;
+    //开始打款的时间不晚于before仍未完成的提现
+    fn get_processing_withdraws(&self, before: i64) -> Result<Vec<entity::Withdraw>>;
+    fn get_withdraws(&self, openid: &str, start: isize, end: isize) -> Result<Vec<entity::Withdraw>>;
+    fn get_pending_withdraws(&self, start: isize, end: isize) -> Result<Vec<entity::Withdraw>>;
+    fn get_wallet_logs(&self, openid: &str, start: isize, end: isize) -> Result<Vec<entity::WalletLog>>;
+    //订单以外的入账，例如邀请奖励，同时记录流水
+    fn credit_wallet(&self, openid: &str, amount: i64, reason: &str, ref_id: &str) -> Result<()>;
+}
+
+//登录令牌：refresh token和已注销的access token
+pub trait TokenStore {
+    fn add_refresh_token(&self, token: &str, openid: &str, ttl: i64) -> Result<()>;
+    //取出后立即删除，每个refresh token只能用一次
+    fn take_refresh_token(&self, token: &str) -> Result<Option<String>>;
+    fn remove_refresh_tokens(&self, openid: &str) -> Result<()>;
+    fn revoke_token(&self, jti: &str, ttl: i64) -> Result<()>;
+    fn is_revoked(&self, jti: &str) -> Result<bool>;
+    //记录签发给用户的access token，注销账户时全部撤销
+    fn add_access_token(&self, openid: &str, jti: &str, ttl: i64) -> Result<()>;
+    fn revoke_user_tokens(&self, openid: &str) -> Result<()>;
+}
+
+//状态变化记录，保存在mongodb
+pub trait AuditStore {
+    fn add_audit(&self, log: &entity::AuditLog) -> Result<()>;
+    //按时间先后返回
+    fn get_audits(&self, entity: &str, entity_id: &str) -> Result<Vec<entity::AuditLog>>;
+}
+
+//领域事件，按订阅者分组消费，确认前会重复投递
+pub trait EventStore {
+    fn publish_event(&self, event: &Event) -> Result<()>;
+    //创建消费组，从创建之后发布的事件开始消费
+    fn add_event_group(&self, group: &str) -> Result<()>;
+    //先返回到了重试时间的未确认事件（见event::retry_delay），不足count个时再返回新事件
+    //等待重试的事件不影响后面的事件
+    fn read_events(&self, group: &str, count: usize) -> Result<Vec<(String, Event)>>;
+    fn ack_event(&self, group: &str, id: &str) -> Result<()>;
+    //记录一次处理失败，返回累计失败次数
+    fn fail_event(&self, group: &str, id: &str) -> Result<i64>;
+    //多次失败的事件确认后移入死信，保留消费组名称用于排查和重放
+    fn dead_letter_event(&self, group: &str, id: &str, event: &Event) -> Result<()>;
+    //最新的在前，返回(消费组, 事件)
+    fn get_dead_events(&self, count: usize) -> Result<Vec<(String, Event)>>;
+}
+
+//行程座位变化的发布订阅，订阅返回的迭代器在没有消息时定期返回None用于心跳
+pub trait SeatStore {
+    fn publish_seats(&self, update: &entity::SeatUpdate) -> Result<()>;
+    fn subscribe_seats(&self, trip_ids: &[String]) -> Result<SeatUpdates>;
+}
+
+pub type SeatUpdates = Box<Iterator<Item = Option<entity::SeatUpdate>> + Send>;
+
+//行程模板保存在mongodb，每个日期生成的行程id保存在redis
+pub trait TemplateStore {
+    fn add_template(&self, t: &entity::TripTemplate) -> Result<()>;
+    fn get_template(&self, id: &str) -> Result<entity::TripTemplate>;
+    fn update_template(&self, t: &entity::TripTemplate) -> Result<()>;
+    //最新创建的在前
+    fn get_templates(&self, openid: &str) -> Result<Vec<entity::TripTemplate>>;
+    //客服按手机号查找，最新创建的在前
+    fn find_templates_by_tel(&self, tel: &str) -> Result<Vec<entity::TripTemplate>>;
+    //end_date不早于date的模板
+    fn get_active_templates(&self, date: &str) -> Result<Vec<entity::TripTemplate>>;
+    //记录某天生成的行程，这一天已经生成过时返回false
+    fn claim_occurrence(&self, template_id: &str, date: &str, trip_id: &str) -> Result<bool>;
+    fn get_occurrence(&self, template_id: &str, date: &str) -> Result<Option<String>>;
+    fn release_occurrence(&self, template_id: &str, date: &str) -> Result<()>;
+}
+
+//乘客的用车需求，待接单的需求按发布时间排列
+pub trait RideRequestStore {
+    fn add_ride_request(&self, r: &entity::RideRequest, events: &[Event]) -> Result<()>;
+    fn get_ride_request(&self, id: &str) -> Result<entity::RideRequest>;
+    //最新发布的在前
+    fn get_open_ride_requests(&self) -> Result<Vec<entity::RideRequest>>;
+    fn get_user_ride_requests(&self, openid: &str) -> Result<Vec<entity::RideRequest>>;
+    //关闭需求并修改状态，已关闭时返回false，保证同一需求只被接单或取消一次
+    fn close_ride_request(&self, id: &str, status: &entity::RideRequestStatus) -> Result<bool>;
+    //接单失败时重新开放
+    fn reopen_ride_request(&self, id: &str) -> Result<()>;
+    fn set_ride_request_order(&self, id: &str, trip_id: &str, order_id: &str, events: &[Event]) -> Result<()>;
+}
+
+//已经通知过的匹配，同一个需求和行程只通知一次
+pub trait MatchStore {
+    //新的匹配返回true
+    fn add_match(&self, request_id: &str, trip_id: &str, events: &[Event]) -> Result<bool>;
+}
+
+//行程候补队列，队列中只保留等待中的候补，按加入顺序排列
+pub trait WaitlistStore {
+    fn add_waiter(&self, w: &entity::Waiter) -> Result<()>;
+    fn get_waiter(&self, id: &str) -> Result<entity::Waiter>;
+    fn get_waiters(&self, trip_id: &str) -> Result<Vec<entity::Waiter>>;
+    //最新加入的在前
+    fn get_user_waiters(&self, openid: &str) -> Result<Vec<entity::Waiter>>;
+    //移出队列并修改状态，已被移出时返回false，保证同一候补只被处理一次
+    fn take_waiter(&self, w: &entity::Waiter, status: &entity::WaitStatus) -> Result<bool>;
+    //预留失败时放回队首
+    fn requeue_waiter(&self, w: &entity::Waiter) -> Result<()>;
+    fn offer_waiter(&self, id: &str, order_id: &str, events: &[Event]) -> Result<()>;
+    //预留订单支付或过期后修改候补状态，不是预留订单时返回None
+    fn close_offer(&self, order_id: &str, status: &entity::WaitStatus) -> Result<Option<entity::Waiter>>;
+}
+
+//优惠券保存在mongodb，使用记录和每个用户的使用次数保存在redis
+pub trait CouponStore {
+    fn add_coupon(&self, c: &entity::Coupon) -> Result<()>;
+    fn find_coupon(&self, code: &str) -> Result<Option<entity::Coupon>>;
+    //最新创建的在前
+    fn get_coupons(&self) -> Result<Vec<entity::Coupon>>;
+    //该用户的使用次数未达到limit时记录本次使用，否则返回false
+    fn redeem_coupon(&self, r: &entity::Redemption, limit: i64) -> Result<bool>;
+    //退还订单使用的优惠券，订单没有使用优惠券时返回None
+    fn release_coupon(&self, order_id: &str) -> Result<Option<entity::Redemption>>;
+    //最新使用的在前
+    fn get_redemptions(&self, coupon_id: &str) -> Result<Vec<entity::Redemption>>;
+    //发给个人的优惠券，最新的在前
+    fn get_user_coupons(&self, openid: &str) -> Result<Vec<entity::Coupon>>;
+}
+
+//邀请码、邀请关系和防刷记录
+pub trait ReferralStore {
+    //记录用户最近登录的设备，首次登录时返回true
+    fn add_login(&self, openid: &str, device: Option<&str>) -> Result<bool>;
+    fn get_device(&self, openid: &str) -> Result<Option<String>>;
+    fn get_referral_code(&self, openid: &str) -> Result<Option<String>>;
+    //邀请码已被占用或该用户已有邀请码时返回false
+    fn add_referral_code(&self, openid: &str, code: &str) -> Result<bool>;
+    fn find_referrer(&self, code: &str) -> Result<Option<String>>;
+    //设备或手机号已经参与过邀请时返回false
+    fn claim_fingerprint(&self, fingerprint: &str) -> Result<bool>;
+    //该用户已被邀请过时返回false
+    fn add_referral(&self, r: &entity::Referral) -> Result<bool>;
+    fn get_referral(&self, referee: &str) -> Result<Option<entity::Referral>>;
+    //只能结束等待中的邀请，已结束时返回false，保证奖励只发放一次
+    fn finish_referral(&self, referee: &str, status: &entity::ReferralStatus, reason: Option<&str>, order_id: &str) -> Result<bool>;
+    //已发放奖励的openid，发放成功后逐个记录，失败的在事件重试时补发
+    fn get_referral_grants(&self, referee: &str) -> Result<Vec<String>>;
+    fn add_referral_grant(&self, referee: &str, openid: &str, events: &[Event]) -> Result<()>;
+    //最新的在前
+    fn get_referrals(&self, start: isize, end: isize) -> Result<Vec<entity::Referral>>;
+    fn get_user_referrals(&self, referrer: &str) -> Result<Vec<entity::Referral>>;
+}
+
+//会话和消息保存在mongodb，消息序号和已读位置保存在redis
+pub trait MessageStore {
+    fn add_conversation(&self, c: &entity::Conversation) -> Result<()>;
+    fn get_conversation(&self, id: &str) -> Result<Option<entity::Conversation>>;
+    fn update_conversation(&self, c: &entity::Conversation) -> Result<()>;
+    //车主或乘客参与的会话，最新创建的在前
+    fn get_user_conversations(&self, openid: &str) -> Result<Vec<entity::Conversation>>;
+    fn get_trip_conversations(&self, trip_id: &str) -> Result<Vec<entity::Conversation>>;
+    //分配会话内的下一个消息序号
+    fn next_message_seq(&self, conversation_id: &str) -> Result<i64>;
+    fn get_message_seq(&self, conversation_id: &str) -> Result<i64>;
+    fn add_message(&self, m: &entity::Message) -> Result<()>;
+    //seq大于after的消息，按seq从小到大最多返回count条
+    fn get_messages(&self, conversation_id: &str, after: i64, count: usize) -> Result<Vec<entity::Message>>;
+    //已读位置只增不减
+    fn mark_read(&self, conversation_id: &str, openid: &str, seq: i64) -> Result<()>;
+    fn get_read(&self, conversation_id: &str, openid: &str) -> Result<i64>;
+    //推送给接收方，与座位推送一样没有消息时定期返回None
+    fn publish_message(&self, receiver: &str, m: &entity::Message) -> Result<()>;
+    fn subscribe_messages(&self, openid: &str) -> Result<MessageUpdates>;
+}
+
+pub type MessageUpdates = Box<Iterator<Item = Option<entity::Message>> + Send>;
+
+//站内通知保存在mongodb
+pub trait NotificationStore {
+    //相同id的通知已存在时忽略
+    fn add_notification(&self, n: &entity::Notification) -> Result<()>;
+    //最新的在前
+    fn get_notifications(&self, openid: &str) -> Result<Vec<entity::Notification>>;
+}
+
+//滑动窗口限流，被拒绝的请求不计数；由RateLimit守卫直接使用，不属于Store
+pub trait LimitStore {
+    //允许时返回本次计入的成员，拒绝时返回None
+    fn hit(&self, key: &str, limit: i64, window: i64) -> Result<Option<String>>;
+    //撤销hit计入的成员，用于多个key中后面的key拒绝时
+    fn unhit(&self, key: &str, member: &str) -> Result<()>;
+}
+
+//用户数据导出和注销
+pub trait AccountStore {
+    //用户发布的行程，最新出发的在前
+    fn get_user_trips(&self, openid: &str) -> Result<Vec<entity::Trip>>;
+    fn add_complain(&self, c: &entity::Complain) -> Result<()>;
+    fn get_user_complains(&self, openid: &str) -> Result<Vec<entity::Complain>>;
+    //抹去个人信息：手机号、车辆信息、留言、发送的消息、投诉和通知，以及登录记录、设备和邀请码，订单金额、支付记录和钱包流水保留
+    fn erase_user(&self, openid: &str) -> Result<()>;
+}
+
+pub trait Store: TripStore + OrderStore + WalletStore + TokenStore + AuditStore + EventStore + SeatStore + WaitlistStore + TemplateStore + RideRequestStore + MatchStore + CouponStore + ReferralStore + MessageStore + NotificationStore + AccountStore {}
+
+impl<T> Store for T
+where
+    T: TripStore + OrderStore + WalletStore + TokenStore + AuditStore + EventStore + SeatStore + WaitlistStore + TemplateStore + RideRequestStore + MatchStore + CouponStore + ReferralStore + MessageStore + NotificationStore + AccountStore,
+{
+}
@@ -0,0 +1,82 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::{self, Rng};
+
+//当前时间戳（秒）
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+//当前时间戳（毫秒）
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64 * 1000 + d.subsec_nanos() as i64 / 1_000_000)
+        .unwrap_or(0)
+}
+
+//北京时间的日期序号，用于按天统计
+pub fn today() -> i64 {
+    (now() + 8 * 3600) / 86400
+}
+
+//北京时间某天0点的时间戳
+pub fn day_start(day: i64) -> i64 {
+    day * 86400 - 8 * 3600
+}
+
+//1970-01-01是周四，返回1-7表示周一到周日，只用于1970年之后的日期
+pub fn weekday(day: i64) -> i64 {
+    (day + 3) % 7 + 1
+}
+
+//YYYY-MM-DD转为日期序号，与today()使用相同的序号
+pub fn parse_date(date: &str) -> Option<i64> {
+    let parts: Vec<&str> = date.split('-').collect();
+    if parts.len() != 3 || parts[0].len() != 4 || parts[1].len() != 2 || parts[2].len() != 2 {
+        return None;
+    }
+    let y: i64 = parts[0].parse().ok()?;
+    let m: i64 = parts[1].parse().ok()?;
+    let d: i64 = parts[2].parse().ok()?;
+    if y < 1970 || m < 1 || m > 12 || d < 1 || d > 31 {
+        return None;
+    }
+    //公历日期转天数，2月30日这类不存在的日期转回后不一致
+    let yy = if m <= 2 { y - 1 } else { y };
+    let era = yy / 400;
+    let yoe = yy - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let day = era * 146097 + doe - 719468;
+    if format_date(day) == date { Some(day) } else { None }
+}
+
+pub fn format_date(day: i64) -> String {
+    let z = day + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+//随机字母数字串，用于refresh token
+pub fn random_string(len: usize) -> String {
+    rand::thread_rng().gen_ascii_chars().take(len).collect()
+}
+
+//隐藏手机号中间四位：13812345678 -> 138****5678，其他格式全部隐藏
+pub fn mask_tel(tel: &str) -> String {
+    if tel.len() == 11 && tel.is_char_boundary(3) && tel.is_char_boundary(7) {
+        format!("{}****{}", &tel[..3], &tel[7..])
+    } else {
+        "*".repeat(tel.chars().count())
+    }
+}
@@ -0,0 +1,73 @@
+use service::{Result, ServiceError};
+use setting;
+use util;
+
+//参数校验错误，一次返回所有字段的问题
+#[derive(PartialEq, Debug, Serialize, Clone)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    //ok为false时记录错误
+    pub fn check(&mut self, ok: bool, field: &str, message: &str) -> &mut Self {
+        if !ok {
+            self.errors.push(FieldError {
+                field: field.to_owned(),
+                message: message.to_owned(),
+            });
+        }
+        self
+    }
+
+    pub fn not_empty(&mut self, value: &str, field: &str) -> &mut Self {
+        self.check(!value.trim().is_empty(), field, "must not be empty")
+    }
+
+    pub fn tel(&mut self, value: &str, field: &str) -> &mut Self {
+        self.check(is_tel(value), field, "must be a valid mobile number")
+    }
+
+    pub fn date(&mut self, value: &str, field: &str) -> &mut Self {
+        self.check(util::parse_date(value).is_some(), field, "must be a date like 2018-01-31")
+    }
+
+    //行程留言和站内消息共用的内容审核
+    pub fn text(&mut self, value: &str, field: &str) -> &mut Self {
+        let config = setting::get();
+        self.check(
+            value.chars().count() as i64 <= config.messaging.max_length,
+            field,
+            "is too long",
+        ).check(is_clean(value, &config.messaging.blocked_words), field, "contains blocked words")
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ServiceError::Validation(self.errors.drain(..).collect()))
+        }
+    }
+}
+
+//中国大陆手机号：1开头的11位数字
+pub fn is_tel(s: &str) -> bool {
+    s.len() == 11 && s.starts_with('1') && s.chars().all(|c| c.is_digit(10))
+}
+
+//不包含屏蔽词，忽略大小写
+pub fn is_clean(s: &str, blocked_words: &[String]) -> bool {
+    let s = s.to_lowercase();
+    blocked_words.iter().all(|word| !s.contains(&word.to_lowercase()))
+}
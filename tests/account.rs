@@ -0,0 +1,120 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::Client;
+use pin_che::{entity, util};
+use pin_che::memory::MemoryStore;
+use pin_che::store::{AccountStore, MessageStore, OrderStore, ReferralStore, RideRequestStore, WalletStore};
+use common::{admin, apply_tel, client, drain, login, notify, publish_with};
+
+fn export(client: &Client, user: &Header<'static>) -> serde_json::Value {
+    let mut response = client.get("/exportData").header(user.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let disposition = response.headers().get_one("Content-Disposition").unwrap().to_owned();
+    assert!(disposition.starts_with("attachment"));
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+//返回(状态, 错误码)
+fn delete(client: &Client, user: &Header<'static>) -> (Status, Option<String>) {
+    let mut response = client.post("/deleteAccount").header(user.clone()).dispatch();
+    let code = response.body_string()
+        .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+        .and_then(|body| body["code"].as_str().map(|code| code.to_owned()));
+    (response.status(), code)
+}
+
+#[test]
+fn users_export_and_delete_their_data() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish_with(&client, &driver, "tel=13812345678");
+    let order = apply_tel(&client, &passenger, &trip.id, 1, "13987654321");
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    let now = util::now();
+    let mut response = client
+        .get(format!(
+            "/postRideRequest?start=East&end=Airport&earliest={}&latest={}&count=1&max_price=1500&tel=13987654321",
+            now + 3600,
+            now + 7200
+        ))
+        .header(passenger.clone())
+        .dispatch();
+    let request: entity::RideRequest = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let response = client
+        .post("/sendMessage")
+        .header(ContentType::JSON)
+        .header(passenger.clone())
+        .body(format!(r#"{{"trip_id":"{}","passenger":null,"content":"I am at gate 3"}}"#, trip.id))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    store.add_complain(&entity::Complain::new("passenger".to_owned(), "driver was late".to_owned())).unwrap();
+    let referral = entity::Referral {
+        id: "passenger".to_owned(),
+        referrer: "driver".to_owned(),
+        code: "DRIVER".to_owned(),
+        device: Some("phone".to_owned()),
+        status: entity::ReferralStatus::Rejected,
+        reason: Some("self referral".to_owned()),
+        order_id: None,
+        create_time: now,
+        finish_time: Some(now),
+    };
+    assert!(store.add_referral(&referral).unwrap());
+    let mut response = client.get("/referralCode").header(passenger.clone()).dispatch();
+    let code: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let code = code["code"].as_str().unwrap().to_owned();
+    let other_session = login(&client, "passenger");
+
+    let data = export(&client, &passenger);
+    assert_eq!(data["openid"].as_str(), Some("passenger"));
+    assert_eq!(data["orders"][0]["tel"].as_str(), Some("13987654321"));
+    assert!(data["orders"][0]["transaction_id"].is_string());
+    assert_eq!(data["ride_requests"][0]["_id"].as_str(), Some(request.id.as_str()));
+    assert_eq!(data["messages"][0]["content"].as_str(), Some("I am at gate 3"));
+    assert_eq!(data["complains"][0]["content"].as_str(), Some("driver was late"));
+    assert_eq!(export(&client, &driver)["trips"][0]["tel"].as_str(), Some("13812345678"));
+
+    //已支付未完成的订单不能注销
+    let in_use = (Status::NotAcceptable, Some("ACCOUNT_IN_USE".to_owned()));
+    assert_eq!(delete(&client, &passenger), in_use);
+    assert_eq!(delete(&client, &driver), in_use);
+    assert_eq!(client.get(format!("/submit/{}", order.id)).header(passenger.clone()).dispatch().status(), Status::Ok);
+    drain(&store);
+    assert_eq!(delete(&client, &passenger).0, Status::Ok);
+
+    //个人信息已抹去，订单金额和支付记录保留
+    let kept = store.get_order(&order.id).unwrap();
+    assert_eq!(kept.tel, None);
+    assert_eq!((kept.price, kept.transaction_id), (order.price, Some(format!("wx_{}", order.id))));
+    let request = store.get_ride_request(&request.id).unwrap();
+    assert_eq!((request.status, request.tel), (entity::RideRequestStatus::Cancelled, None));
+    let conversation = store.get_user_conversations("passenger").unwrap().remove(0);
+    assert!(conversation.is_closed());
+    assert_eq!(store.get_messages(&conversation.id, 0, 10).unwrap()[0].content, "");
+    assert!(store.get_user_complains("passenger").unwrap().is_empty());
+    let mut response = client.get("/admin/ordersByTel/13987654321").header(admin()).dispatch();
+    assert_eq!(response.body_string().unwrap(), "[]");
+    assert_eq!(client.get("/exportData").header(passenger.clone()).dispatch().status(), Status::Unauthorized);
+    assert_eq!(client.get("/exportData").header(other_session).dispatch().status(), Status::Unauthorized);
+    assert_eq!(store.get_referral("passenger").unwrap().unwrap().device, None);
+    assert_eq!(store.find_referrer(&code).unwrap(), None);
+    assert_eq!(store.get_referral_code("passenger").unwrap(), None);
+    assert_eq!(store.get_device("passenger").unwrap(), None);
+    //登录记录已删除，再次登录视为新用户
+    assert!(store.add_login("passenger", None).unwrap());
+
+    //司机的收入需要先提现
+    assert!(store.get_wallet("driver").unwrap().balance > 0);
+    assert_eq!(delete(&client, &driver), in_use);
+}
@@ -0,0 +1,92 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{Header, Status};
+use rocket::local::Client;
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use pin_che::service::Service;
+use common::{apply, client, drain, login, notify, publish};
+
+//返回(from, to, actor)列表
+fn timeline(client: &Client, user: &Header<'static>, path: &str) -> Vec<(Option<String>, String, String)> {
+    let mut response = client.get(path).header(user.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let logs: Vec<entity::AuditLog> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    logs.into_iter().map(|log| (log.from, log.to, log.actor)).collect()
+}
+
+fn step(from: Option<&str>, to: &str, actor: &str) -> (Option<String>, String, String) {
+    (from.map(|s| s.to_owned()), to.to_owned(), actor.to_owned())
+}
+
+#[test]
+fn transitions_are_recorded() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish(&client, &driver);
+    let order = apply(&client, &passenger, &trip.id, 1);
+
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    //重复通知不产生新记录
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    let response = client.get(format!("/submit/{}", order.id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    drain(&store);
+
+    let order_path = format!("/timeline/order/{}", order.id);
+    let expected = vec![
+        step(None, "Unpaid", "passenger"),
+        step(Some("Unpaid"), "Paid", "system:wx_notify"),
+        step(Some("Paid"), "Submit", "passenger"),
+    ];
+    assert_eq!(timeline(&client, &passenger, &order_path), expected);
+    assert_eq!(timeline(&client, &driver, &order_path), expected);
+    assert_eq!(
+        timeline(&client, &driver, &format!("/timeline/trip/{}", trip.id)),
+        vec![
+            step(None, "Prepare", "driver"),
+            step(Some("Prepare"), "Finish", "system:event"),
+        ]
+    );
+
+    //其他用户不能查看
+    let other = login(&client, "other");
+    let response = client.get(order_path).header(other.clone()).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let response = client
+        .get(format!("/timeline/trip/{}", trip.id))
+        .header(passenger)
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn expired_order_keeps_timeline() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish(&client, &driver);
+    let order = apply(&client, &passenger, &trip.id, 1);
+
+    let service = Service::with_store(store.clone());
+    service.expire_order(&order.id).unwrap();
+    service.expire_order(&order.id).unwrap();
+    assert_eq!(
+        timeline(&client, &passenger, &format!("/timeline/order/{}", order.id)),
+        vec![
+            step(None, "Unpaid", "passenger"),
+            step(Some("Unpaid"), "Expired", "system:expire_job"),
+        ]
+    );
+}
@@ -0,0 +1,92 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::{Client, LocalResponse};
+use pin_che::entity::JwtUser;
+use pin_che::memory::MemoryStore;
+use pin_che::util;
+use common::client;
+
+fn body(response: &mut LocalResponse) -> serde_json::Value {
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+fn bearer(token: &str) -> Header<'static> {
+    Header::new("Authorization", format!("Bearer {}", token))
+}
+
+fn refresh(client: &Client, refresh_token: &str) -> LocalResponse {
+    client
+        .post("/refreshToken")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{"refresh_token":"{}"}}"#, refresh_token))
+        .dispatch()
+}
+
+#[test]
+fn refresh_token_is_single_use() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let mut response = client.get("/login/driver").dispatch();
+    let tokens = body(&mut response);
+    assert_eq!(tokens["expires_in"], 7200);
+    let refresh_token = tokens["refresh_token"].as_str().unwrap().to_owned();
+
+    let mut response = refresh(&client, &refresh_token);
+    assert_eq!(response.status(), Status::Ok);
+    let renewed = body(&mut response);
+    assert_ne!(renewed["refresh_token"], tokens["refresh_token"]);
+    let response = client
+        .get("/wallet")
+        .header(bearer(renewed["token"].as_str().unwrap()))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = refresh(&client, &refresh_token);
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn logout_revokes_tokens() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let mut response = client.get("/login/passenger").dispatch();
+    let tokens = body(&mut response);
+    let token = bearer(tokens["token"].as_str().unwrap());
+
+    let response = client.get("/logout").header(token.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.get("/wallet").header(token).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let response = refresh(&client, tokens["refresh_token"].as_str().unwrap());
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn invalid_tokens_are_rejected() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let mut expired = JwtUser::weixin("driver".to_owned());
+    expired.exp = util::now() - 1;
+    let expired = expired.sign().unwrap();
+
+    let headers = vec![
+        bearer(&expired),
+        bearer("not.a.jwt"),
+        bearer(""),
+        Header::new("Authorization", "Bearer"),
+        Header::new("Authorization", expired),
+    ];
+    for header in headers {
+        let response = client.get("/wallet").header(header).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+}
@@ -0,0 +1,84 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::Status;
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use pin_che::store::OrderStore;
+use common::{client, login, publish_with, try_apply};
+
+#[test]
+fn driver_can_not_book_own_trip() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let trip = publish_with(&client, &driver, "seat_count=6");
+    let (status, body) = try_apply(&client, &driver, &trip.id, 1);
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("SELF_BOOKING")));
+}
+
+#[test]
+fn unpaid_orders_and_seats_are_capped() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let first = publish_with(&client, &driver, "seat_count=6");
+    let second = publish_with(&client, &driver, "seat_count=6");
+    let third = publish_with(&client, &driver, "seat_count=6");
+
+    //每人每个行程最多4个座位
+    assert_eq!(try_apply(&client, &passenger, &first.id, 3).0, Status::Ok);
+    let (status, body) = try_apply(&client, &passenger, &first.id, 2);
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("SEAT_LIMIT")));
+
+    //最多2个未支付订单
+    assert_eq!(try_apply(&client, &passenger, &second.id, 1).0, Status::Ok);
+    let (status, body) = try_apply(&client, &passenger, &third.id, 1);
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("TOO_MANY_UNPAID")));
+}
+
+//并发请求都通过了下单前的检查，add_order仍然按上限拒绝
+#[test]
+fn caps_hold_when_checks_race() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let first = publish_with(&client, &driver, "seat_count=6");
+    let second = publish_with(&client, &driver, "seat_count=6");
+    let order = |trip: &entity::Trip, count: i64| {
+        entity::Order::segment(trip.clone(), "passenger".to_owned(), count, None, 0, trip.last_stop())
+    };
+
+    store.add_order(&order(&first, 3), &[]).unwrap();
+    assert_eq!(store.add_order(&order(&first, 2), &[]).unwrap_err().code(), "SEAT_LIMIT");
+    store.add_order(&order(&second, 1), &[]).unwrap();
+    assert_eq!(store.add_order(&order(&second, 1), &[]).unwrap_err().code(), "TOO_MANY_UNPAID");
+    assert_eq!(store.get_user_orders("passenger").unwrap().len(), 2);
+}
+
+#[test]
+fn repeated_expiry_blocks_booking() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish_with(&client, &driver, "seat_count=6");
+
+    for _ in 0..3 {
+        let (status, order) = try_apply(&client, &passenger, &trip.id, 1);
+        assert_eq!(status, Status::Ok);
+        store.expire_order(order["_id"].as_str().unwrap()).unwrap();
+    }
+    assert_eq!(store.get_expired_count("passenger").unwrap(), 3);
+    let (status, body) = try_apply(&client, &passenger, &trip.id, 1);
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("BOOKING_BLOCKED")));
+    assert_eq!(try_apply(&client, &login(&client, "other"), &trip.id, 1).0, Status::Ok);
+}
@@ -0,0 +1,250 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use futures::future::{self, FutureResult};
+use hyper::{self, StatusCode};
+use hyper::server::{Http, Request, Response, Service};
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::Client;
+use serde_json;
+use pin_che::{entity, external, routes, setting, util};
+use pin_che::memory::MemoryStore;
+use pin_che::event;
+use pin_che::service::{Backend, Service};
+
+pub type Calls = Arc<Mutex<Vec<String>>>;
+
+//模拟api.weixin.qq.com和api.mch.weixin.qq.com，记录每次调用的路径
+#[derive(Clone)]
+struct MockWeixin {
+    calls: Calls,
+}
+
+fn success_xml(fields: &[(&str, &str)]) -> String {
+    let mut xml = String::from(
+        "<xml><return_code><![CDATA[SUCCESS]]></return_code><result_code><![CDATA[SUCCESS]]></result_code>",
+    );
+    for &(k, v) in fields {
+        xml.push_str(&format!("<{0}><![CDATA[{1}]]></{0}>", k, v));
+    }
+    xml.push_str("</xml>");
+    xml
+}
+
+impl Service for MockWeixin {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = FutureResult<Response, hyper::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let path = req.path().to_owned();
+        self.calls.lock().unwrap().push(path.clone());
+        let body = match path.as_str() {
+            //测试中code直接作为openid返回
+            "/sns/jscode2session" => {
+                let code = req.query()
+                    .unwrap_or("")
+                    .split('&')
+                    .find(|p| p.starts_with("js_code="))
+                    .map(|p| p["js_code=".len()..].to_owned())
+                    .unwrap_or_default();
+                format!(r#"{{"openid":"{}","session_key":"session"}}"#, code)
+            }
+            "/pay/unifiedorder" => success_xml(&[("prepay_id", "wx_prepay_id")]),
+            "/secapi/pay/refund" => success_xml(&[("refund_id", "wx_refund_id")]),
+            "/mmpaymkttransfers/promotion/transfers" => success_xml(&[("payment_no", "wx_payment_no")]),
+            _ => return future::ok(Response::new().with_status(StatusCode::NotFound)),
+        };
+        future::ok(Response::new().with_body(body))
+    }
+}
+
+lazy_static! {
+    pub static ref WEIXIN: Calls = setup();
+}
+
+//启动模拟服务并把配置指向它
+fn setup() -> Calls {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let mock = MockWeixin { calls: calls.clone() };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = Http::new().bind(&addr, move || Ok(mock.clone())).unwrap();
+        tx.send(server.local_addr().unwrap()).unwrap();
+        server.run().unwrap();
+    });
+    let base = format!("http://{}", rx.recv().unwrap());
+
+    env::set_var("PINCHE_PROFILE", "test");
+    env::set_var("PINCHE_APP__DBURL", "127.0.0.1");
+    env::set_var("PINCHE_APP__DBPORT", "27017");
+    env::set_var("PINCHE_APP__REDIS", "redis://127.0.0.1/");
+    env::set_var("PINCHE_WEIXIN__API_URL", &base);
+    env::set_var("PINCHE_WEIXIN__MCH_URL", &base);
+    env::set_var("PINCHE_WEIXIN__APPID", "wx_appid");
+    env::set_var("PINCHE_WEIXIN__SECRET", "wx_secret");
+    env::set_var("PINCHE_WEIXIN__MCHID", "wx_mchid");
+    env::set_var("PINCHE_WEIXIN__KEY", "wx_key");
+    env::set_var("PINCHE_WEIXIN__IP", "127.0.0.1");
+    env::set_var("PINCHE_WEIXIN__NOTIFY_URL", "http://127.0.0.1/wxNotify");
+    env::set_var("PINCHE_JWT__CURRENT_KID", "k1");
+    env::set_var("PINCHE_JWT__KEYS__K1", "test_jwt_secret_k1");
+    env::set_var("PINCHE_PII__CURRENT_KID", "k1");
+    env::set_var("PINCHE_PII__KEYS__K1", "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=");
+    env::set_var("PINCHE_PII__INDEX_KEY", "test_pii_index_key");
+    setting::init().unwrap();
+    calls
+}
+
+pub fn client(store: &MemoryStore) -> Client {
+    let _ = &*WEIXIN;
+    event::bus().init(&Service::with_store(store.clone())).unwrap();
+    Client::new(routes::app(Backend::Memory(store.clone()))).unwrap()
+}
+
+//同步处理所有待处理的事件，代替后台线程
+pub fn drain(store: &MemoryStore) {
+    let service = Service::with_store(store.clone());
+    let bus = event::bus();
+    while bus.poll(&service) > 0 {}
+}
+
+pub fn called(path: &str) -> bool {
+    WEIXIN.lock().unwrap().iter().any(|p| p == path)
+}
+
+//默认的行程参数：4个座位，A到B，每座10元
+const TRIP: &[(&str, &str)] = &[
+    ("seat_count", "4"),
+    ("start_time", "1900000000"),
+    ("start", "A"),
+    ("end", "B"),
+    ("price", "1000"),
+    ("venue", "station"),
+    ("plate_number", "A12345"),
+    ("car_type", "suv"),
+    ("tel", "13800000000"),
+];
+
+//发布行程的地址，params中的参数覆盖或补充默认值，例如"seat_count=2&tel=13812345678"
+pub fn publish_path(params: &str) -> String {
+    let mut pairs: Vec<(String, String)> = TRIP.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+    for param in params.split('&').filter(|p| !p.is_empty()) {
+        let mut kv = param.splitn(2, '=');
+        let (key, value) = (kv.next().unwrap(), kv.next().unwrap_or(""));
+        match pairs.iter().position(|&(ref k, _)| k == key) {
+            Some(i) => pairs[i].1 = value.to_owned(),
+            None => pairs.push((key.to_owned(), value.to_owned())),
+        }
+    }
+    let query: Vec<String> = pairs.iter().map(|&(ref k, ref v)| format!("{}={}", k, v)).collect();
+    format!("/publishTrip?{}", query.join("&"))
+}
+
+pub fn publish_with(client: &Client, driver: &Header<'static>, params: &str) -> entity::Trip {
+    let mut response = client.get(publish_path(params)).header(driver.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+pub fn publish(client: &Client, driver: &Header<'static>) -> entity::Trip {
+    publish_with(client, driver, "")
+}
+
+//返回(状态, 响应内容)
+pub fn get_json(client: &Client, user: &Header<'static>, path: &str) -> (Status, serde_json::Value) {
+    let mut response = client.get(path.to_owned()).header(user.clone()).dispatch();
+    let body = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    (response.status(), body)
+}
+
+pub fn post_json(client: &Client, user: &Header<'static>, path: &str, body: &str) -> (Status, serde_json::Value) {
+    let mut response = client
+        .post(path.to_owned())
+        .header(ContentType::JSON)
+        .header(user.clone())
+        .body(body.to_owned())
+        .dispatch();
+    let body = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    (response.status(), body)
+}
+
+//全程下单，返回(状态, 订单或错误)
+pub fn try_apply_tel(client: &Client, user: &Header<'static>, trip_id: &str, count: i64, tel: &str) -> (Status, serde_json::Value) {
+    get_json(client, user, &format!("/applyTrip/{}/{}/{}", trip_id, count, tel))
+}
+
+pub fn try_apply(client: &Client, user: &Header<'static>, trip_id: &str, count: i64) -> (Status, serde_json::Value) {
+    try_apply_tel(client, user, trip_id, count, "13900000000")
+}
+
+//从第from站到第to站下单，站点下标从起点0开始
+pub fn try_apply_segment(client: &Client, user: &Header<'static>, trip_id: &str, from: i64, to: i64, count: i64) -> (Status, serde_json::Value) {
+    get_json(client, user, &format!("/applySegment/{}/{}/{}/{}/13900000000", trip_id, from, to, count))
+}
+
+//使用优惠码下单
+pub fn try_apply_coupon(client: &Client, user: &Header<'static>, trip_id: &str, count: i64, coupon: &str) -> (Status, serde_json::Value) {
+    let body = format!(r#"{{"trip_id":"{}","count":{},"coupon":"{}"}}"#, trip_id, count, coupon);
+    post_json(client, user, "/applyTrip", &body)
+}
+
+pub fn apply_tel(client: &Client, user: &Header<'static>, trip_id: &str, count: i64, tel: &str) -> entity::Order {
+    let (status, body) = try_apply_tel(client, user, trip_id, count, tel);
+    assert_eq!(status, Status::Ok, "{}", body);
+    serde_json::from_value(body).unwrap()
+}
+
+pub fn apply(client: &Client, user: &Header<'static>, trip_id: &str, count: i64) -> entity::Order {
+    apply_tel(client, user, trip_id, count, "13900000000")
+}
+
+//模拟服务把code当作openid，所以code就是登录用户的openid
+pub fn login(client: &Client, code: &str) -> Header<'static> {
+    let mut response = client.get(format!("/login/{}", code)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let token = body["token"].as_str().unwrap();
+    Header::new("Authorization", format!("Bearer {}", token))
+}
+
+//模拟微信支付成功通知，返回接口的响应内容
+pub fn notify(client: &Client, order_id: &str) -> String {
+    let mut params = BTreeMap::new();
+    params.insert("return_code".to_owned(), "SUCCESS".to_owned());
+    params.insert("result_code".to_owned(), "SUCCESS".to_owned());
+    params.insert("out_trade_no".to_owned(), order_id.to_owned());
+    params.insert("transaction_id".to_owned(), format!("wx_{}", order_id));
+    let sign = external::sign(&params);
+    params.insert("sign".to_owned(), sign);
+    let mut response = client
+        .post("/wxNotify")
+        .body(external::to_xml(&params))
+        .dispatch();
+    response.body_string().unwrap()
+}
+
+//本人的站内通知，最新的在前
+pub fn notifications(client: &Client, user: &Header<'static>) -> Vec<entity::Notification> {
+    let mut response = client.get("/notifications").header(user.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+pub fn admin() -> Header<'static> {
+    let token = entity::JwtUser {
+        id: "admin".to_owned(),
+        name: "admin".to_owned(),
+        role: "admin".to_owned(),
+        user_type: "admin".to_owned(),
+        exp: util::now() + 3600,
+        jti: "admin".to_owned(),
+    }.sign()
+        .unwrap();
+    Header::new("Authorization", format!("Bearer {}", token))
+}
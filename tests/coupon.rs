@@ -0,0 +1,141 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Status};
+use rocket::local::Client;
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use pin_che::service::Service;
+use pin_che::store::{CouponStore, WalletStore};
+use pin_che::util;
+use common::{admin, client, drain, login, notify, publish_with, try_apply_coupon};
+
+fn add_coupon(client: &Client, body: &str) -> entity::Coupon {
+    let mut response = client
+        .post("/admin/addCoupon")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(body.to_owned())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+//有效期从一分钟前到一天后
+fn coupon_json(fields: &str) -> String {
+    format!(
+        r#"{{"name":"promotion","start_time":{},"end_time":{},{}}}"#,
+        util::now() - 60,
+        util::now() + 86400,
+        fields
+    )
+}
+
+#[test]
+fn first_ride_discount_is_subsidized() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let coupon = add_coupon(&client, &coupon_json(r#""code":"FIRST5","kind":"Fixed","value":500,"new_user_only":true"#));
+    let trip = publish_with(&client, &driver, "start=East&end=Airport");
+
+    let (status, body) = try_apply_coupon(&client, &passenger, &trip.id, 1, "FIRST5");
+    assert_eq!(status, Status::Ok);
+    let order: entity::Order = serde_json::from_value(body).unwrap();
+    assert_eq!((order.price, order.discount, order.total()), (1000, 500, 500));
+    assert_eq!(order.coupon_id, Some(coupon.id.clone()));
+
+    //已经有订单，不再是首单
+    let other = publish_with(&client, &driver, "start=East&end=Airport");
+    let (status, body) = try_apply_coupon(&client, &login(&client, "passenger"), &other.id, 1, "FIRST5");
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("COUPON_UNAVAILABLE")));
+
+    //司机收入仍按原价计算
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    assert_eq!(client.get(format!("/submit/{}", order.id)).header(passenger.clone()).dispatch().status(), Status::Ok);
+    drain(&store);
+    assert_eq!(store.get_wallet("driver").unwrap().balance, 950);
+
+    let mut response = client.get(format!("/admin/redemptions/{}", coupon.id)).header(admin()).dispatch();
+    let redemptions: Vec<entity::Redemption> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(redemptions.len(), 1);
+    assert_eq!((redemptions[0].order_id.as_str(), redemptions[0].discount), (order.id.as_str(), 500));
+}
+
+#[test]
+fn conditions_are_checked() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    add_coupon(
+        &client,
+        &coupon_json(r#""code":"EAST20","kind":"Percent","value":20,"max_discount":300,"min_spend":1500,"start":"East""#),
+    );
+    let east = publish_with(&client, &driver, "start=East%20Gate&end=Airport");
+    let west = publish_with(&client, &driver, "start=West%20Gate&end=Airport");
+
+    for &(trip_id, count, code) in &[(east.id.as_str(), 1, "EAST20"), (west.id.as_str(), 2, "EAST20"), (east.id.as_str(), 2, "NONE")] {
+        let (status, body) = try_apply_coupon(&client, &passenger, trip_id, count, code);
+        assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("COUPON_UNAVAILABLE")));
+    }
+    //2000的20%为400，最多减300
+    let (status, body) = try_apply_coupon(&client, &passenger, &east.id, 2, "EAST20");
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body["discount"].as_i64(), Some(300));
+}
+
+#[test]
+fn expired_orders_return_the_coupon() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let service = Service::with_store(store.clone());
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let coupon = add_coupon(&client, &coupon_json(r#""code":"ONCE","kind":"Fixed","value":100"#));
+    let trip = publish_with(&client, &driver, "start=East&end=Airport");
+
+    let (status, body) = try_apply_coupon(&client, &passenger, &trip.id, 1, "ONCE");
+    assert_eq!(status, Status::Ok);
+    service.expire_order(body["_id"].as_str().unwrap()).unwrap();
+    assert!(store.get_redemptions(&coupon.id).unwrap().is_empty());
+
+    assert_eq!(try_apply_coupon(&client, &passenger, &trip.id, 1, "ONCE").0, Status::Ok);
+    let (status, body) = try_apply_coupon(&client, &passenger, &trip.id, 1, "ONCE");
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("COUPON_USED_UP")));
+    assert_eq!(store.get_redemptions(&coupon.id).unwrap().len(), 1);
+}
+
+#[test]
+fn only_admin_adds_valid_coupons() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    add_coupon(&client, &coupon_json(r#""code":"ONCE","kind":"Fixed","value":100"#));
+
+    let response = client
+        .post("/admin/addCoupon")
+        .header(ContentType::JSON)
+        .header(login(&client, "passenger"))
+        .body(coupon_json(r#""code":"MINE","kind":"Fixed","value":100"#))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let mut response = client
+        .post("/admin/addCoupon")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(coupon_json(r#""code":"ONCE","kind":"Percent","value":120,"per_user_limit":0"#))
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = response.body_string().unwrap();
+    assert!(body.contains("value") && body.contains("per_user_limit"), "{}", body);
+    assert_eq!(store.get_coupons().unwrap().len(), 1);
+}
@@ -0,0 +1,54 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{Header, Status};
+use rocket::local::LocalResponse;
+use pin_che::memory::MemoryStore;
+use common::{client, login};
+
+fn body(response: &mut LocalResponse) -> serde_json::Value {
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+#[test]
+fn business_errors_have_stable_codes() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let passenger = login(&client, "passenger");
+
+    let mut response = client.get("/prepay/unknown").header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let json = body(&mut response);
+    assert_eq!(json["status"], "error");
+    assert_eq!(json["code"], "NOT_FOUND");
+    assert_eq!(json["reason"], "数据不存在");
+
+    let mut response = client
+        .get("/prepay/unknown")
+        .header(passenger)
+        .header(Header::new("Accept-Language", "en-US,en;q=0.9"))
+        .dispatch();
+    assert_eq!(body(&mut response)["reason"], "resource was not found");
+
+    let mut response = client.get("/wallet").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(body(&mut response)["code"], "NO_AUTH");
+}
+
+#[test]
+fn unknown_route_is_json() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let mut response = client.get("/no/such/route").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let json = body(&mut response);
+    assert_eq!((json["status"].as_str(), json["code"].as_str()), (Some("error"), Some("NOT_FOUND")));
+    assert!(json.get("errors").is_none());
+}
@@ -0,0 +1,156 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use rocket::http::Status;
+use pin_che::setting;
+use pin_che::event::{Event, EventBus, Subscriber, TripFinishChecker};
+use pin_che::memory::MemoryStore;
+use pin_che::service::{Result, Service, ServiceError};
+use pin_che::store::EventStore;
+use common::{apply, client, login, notify, publish};
+
+//记录收到的事件，前fail次处理失败
+struct Recorder {
+    name: &'static str,
+    fail: usize,
+    calls: Arc<Mutex<usize>>,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Recorder {
+    fn new(name: &'static str, fail: usize) -> Self {
+        Recorder {
+            name,
+            fail,
+            calls: Arc::new(Mutex::new(0)),
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl Subscriber for Recorder {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn handle(&self, _service: &Service, event: &Event) -> Result<()> {
+        let mut calls = self.calls.lock().unwrap();
+        *calls += 1;
+        if *calls <= self.fail {
+            return Err(ServiceError::String("try again".to_owned()));
+        }
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
+
+#[test]
+fn booking_emits_events() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let service = Service::with_store(store.clone());
+    let recorder = Recorder::new("recorder", 0);
+    let events = recorder.events.clone();
+    let bus = EventBus::new().subscribe(TripFinishChecker).subscribe(recorder);
+    bus.init(&service).unwrap();
+
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish(&client, &driver);
+    let order = apply(&client, &passenger, &trip.id, 1);
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    let response = client.get(format!("/submit/{}", order.id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    while bus.poll(&service) > 0 {}
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            Event::TripPublished {
+                trip_id: trip.id.clone(),
+                openid: "driver".to_owned(),
+            },
+            Event::OrderCreated {
+                order_id: order.id.clone(),
+                trip_id: trip.id.clone(),
+                openid: "passenger".to_owned(),
+            },
+            Event::OrderPaid {
+                order_id: order.id.clone(),
+                transaction_id: format!("wx_{}", order.id),
+            },
+            Event::OrderSubmitted {
+                order_id: order.id.clone(),
+                trip_id: trip.id.clone(),
+            },
+            Event::TripFinished { trip_id: trip.id.clone() },
+        ]
+    );
+}
+
+#[test]
+fn failed_handlers_are_retried_independently() {
+    let store = MemoryStore::new();
+    client(&store);
+    env::set_var("PINCHE_EVENT__RETRY_DELAY", "50");
+    env::set_var("PINCHE_EVENT__MAX_ATTEMPTS", "3");
+    setting::reload().unwrap();
+    let service = Service::with_store(store.clone());
+    let flaky = Recorder::new("flaky", 2);
+    let broken = Recorder::new("broken", 100);
+    let (flaky_events, broken_calls) = (flaky.events.clone(), broken.calls.clone());
+    let bus = EventBus::new().subscribe(flaky).subscribe(broken);
+    bus.init(&service).unwrap();
+
+    let event = Event::OrderExpired {
+        order_id: "order".to_owned(),
+        trip_id: "trip".to_owned(),
+    };
+    store.publish_event(&event).unwrap();
+
+    //失败的事件留在待处理中，等待到期后再投递，互不影响
+    assert_eq!(bus.poll(&service), 2);
+    assert_eq!(bus.poll(&service), 0);
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(bus.poll(&service), 2);
+    //第二次失败后等待时间加倍
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(bus.poll(&service), 0);
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(bus.poll(&service), 2);
+    assert_eq!(*flaky_events.lock().unwrap(), vec![event.clone()]);
+    //超过最大次数后移入死信
+    assert_eq!(*broken_calls.lock().unwrap(), 3);
+    assert_eq!(bus.poll(&service), 0);
+    assert_eq!(store.get_dead_events(10).unwrap(), vec![("broken".to_owned(), event)]);
+}
+
+#[test]
+fn waiting_events_do_not_block_new_ones() {
+    let store = MemoryStore::new();
+    client(&store);
+    let service = Service::with_store(store.clone());
+    let broken = Recorder::new("blocked", 1);
+    let events = broken.events.clone();
+    let bus = EventBus::new().subscribe(broken);
+    bus.init(&service).unwrap();
+
+    let first = Event::TripFinished { trip_id: "first".to_owned() };
+    let second = Event::TripFinished { trip_id: "second".to_owned() };
+    store.publish_event(&first).unwrap();
+    assert_eq!(bus.poll(&service), 1);
+    store.publish_event(&second).unwrap();
+    assert_eq!(bus.poll(&service), 1);
+    assert_eq!(*events.lock().unwrap(), vec![second]);
+}
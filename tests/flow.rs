@@ -0,0 +1,169 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use std::collections::BTreeMap;
+use rocket::http::{ContentType, Status};
+use pin_che::{entity, external, util};
+use pin_che::memory::MemoryStore;
+use pin_che::store::{OrderStore, TripStore, WalletStore};
+use common::{admin, apply, called, client, drain, login, notify, post_json, publish, try_apply};
+
+#[test]
+fn booking_flow() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+
+    let trip = publish(&client, &driver);
+    assert_eq!(trip.openid, "driver");
+
+    let order = apply(&client, &passenger, &trip.id, 2);
+    assert_eq!(store.get_trip(&trip.id).unwrap().current_seat, 2);
+
+    let response = client
+        .get(format!("/prepay/{}", order.id))
+        .header(passenger.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(called("/pay/unifiedorder"));
+
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    let paid = store.get_order(&order.id).unwrap();
+    assert_eq!(paid.status, entity::OrderStatus::Paid);
+    assert_eq!(paid.transaction_id, Some(format!("wx_{}", order.id)));
+
+    //只有车主可以优惠
+    let response = client
+        .get(format!("/discount/{}/100", order.id))
+        .header(passenger.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::NotAcceptable);
+    let response = client
+        .get(format!("/discount/{}/100", order.id))
+        .header(driver.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(called("/secapi/pay/refund"));
+    let discounted = store.get_order(&order.id).unwrap();
+    assert_eq!((discounted.price, discounted.refunded), (1000, 100));
+
+    //多次优惠累计不超过乘客支付的金额
+    let response = client
+        .get(format!("/discount/{}/1901", order.id))
+        .header(driver.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(store.get_order(&order.id).unwrap().refunded, 100);
+
+    //只有乘客本人可以确认
+    let response = client.get(format!("/submit/{}", order.id)).header(driver.clone()).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(client.get(format!("/submit/{}", order.id)).dispatch().status(), Status::Unauthorized);
+    let response = client.get(format!("/submit/{}", order.id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    drain(&store);
+    assert_eq!(store.get_trip(&trip.id).unwrap().status, entity::TripStatus::Finish);
+    //(2000 - 100) * 0.95
+    assert_eq!(store.get_wallet("driver").unwrap().balance, 1805);
+
+    let (status, body) = post_json(&client, &driver, "/withdraw", r#"{"amount":500}"#);
+    assert_eq!(status, Status::Ok);
+    let withdraw: entity::Withdraw = serde_json::from_value(body).unwrap();
+    let wallet = store.get_wallet("driver").unwrap();
+    assert_eq!((wallet.balance, wallet.frozen), (1305, 500));
+
+    let body = format!(r#"{{"withdraw_id":"{}"}}"#, withdraw.id);
+    let response = client
+        .post("/admin/approveWithdraw")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(body.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(called("/mmpaymkttransfers/promotion/transfers"));
+    let wallet = store.get_wallet("driver").unwrap();
+    assert_eq!((wallet.balance, wallet.frozen), (1305, 0));
+
+    //已完成的提现不能再次审核
+    let response = client
+        .post("/admin/rejectWithdraw")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status(), Status::NotAcceptable);
+    assert_eq!(store.get_wallet("driver").unwrap().balance, 1305);
+}
+
+//打款后记录结果失败时提现停留在Processing，补单和重复处理都只完成一次
+#[test]
+fn processing_withdraw_finishes_once() {
+    let store = MemoryStore::new();
+    client(&store);
+    store.credit_wallet("driver", 1000, "test", "test").unwrap();
+    let withdraw = entity::Withdraw::new("driver".to_owned(), 500);
+    store.add_withdraw(&withdraw, 3, 10000).unwrap();
+    let withdraw = store.claim_withdraw(&withdraw.id).unwrap();
+
+    assert!(store.get_processing_withdraws(util::now() - 600).unwrap().is_empty());
+    assert_eq!(store.get_processing_withdraws(util::now()).unwrap(), vec![withdraw.clone()]);
+    store.finish_withdraw(&withdraw, true).unwrap();
+    assert!(store.get_processing_withdraws(util::now()).unwrap().is_empty());
+
+    assert_eq!(store.finish_withdraw(&withdraw, true).unwrap_err().code(), "WITHDRAW_HANDLED");
+    assert_eq!(store.release_withdraw(&withdraw.id).unwrap_err().code(), "WITHDRAW_HANDLED");
+    assert!(store.get_pending_withdraws(0, -1).unwrap().is_empty());
+    let wallet = store.get_wallet("driver").unwrap();
+    assert_eq!((wallet.balance, wallet.frozen), (500, 0));
+}
+
+#[test]
+fn unpaid_order_expires() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+
+    let trip = publish(&client, &driver);
+    let order = apply(&client, &passenger, &trip.id, 4);
+    let (status, body) = try_apply(&client, &login(&client, "other"), &trip.id, 1);
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("NOT_ENOUGH_SEATS")));
+
+    store.expire_order(&order.id).unwrap();
+    assert_eq!(store.get_trip(&trip.id).unwrap().current_seat, 4);
+    assert!(store.get_order(&order.id).is_err());
+
+    //过期后的支付通知不能让订单复活
+    assert!(notify(&client, &order.id).contains("FAIL"));
+    apply(&client, &passenger, &trip.id, 1);
+}
+
+#[test]
+fn forged_notify_is_rejected() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish(&client, &driver);
+    let order = apply(&client, &passenger, &trip.id, 1);
+
+    let mut params = BTreeMap::new();
+    params.insert("result_code".to_owned(), "SUCCESS".to_owned());
+    params.insert("out_trade_no".to_owned(), order.id.clone());
+    params.insert("transaction_id".to_owned(), "forged".to_owned());
+    params.insert("sign".to_owned(), "BADSIGN".to_owned());
+    let mut response = client
+        .post("/wxNotify")
+        .body(external::to_xml(&params))
+        .dispatch();
+    assert!(response.body_string().unwrap().contains("FAIL"));
+    assert_eq!(store.get_order(&order.id).unwrap().status, entity::OrderStatus::Unpaid);
+}
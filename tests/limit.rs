@@ -0,0 +1,123 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use std::net::SocketAddr;
+use rocket::http::{Header, Status};
+use pin_che::memory::MemoryStore;
+use pin_che::store::LimitStore;
+use common::{client, login, publish_path};
+
+#[test]
+fn publish_is_limited_per_user() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let other = login(&client, "other");
+
+    //默认每分钟5次
+    for _ in 0..5 {
+        assert_eq!(client.get(publish_path("")).header(driver.clone()).dispatch().status(), Status::Ok);
+    }
+    let mut response = client.get(publish_path("")).header(driver.clone()).dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["code"], "TOO_MANY_REQUESTS");
+
+    assert_eq!(client.get(publish_path("")).header(other.clone()).dispatch().status(), Status::Ok);
+}
+
+#[test]
+fn login_is_limited_per_ip() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let proxy: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let ip = || Header::new("X-Real-IP", "10.0.0.1");
+
+    //每个IP为用户上限的5倍，经过可信代理时按X-Real-IP计数
+    for i in 0..50 {
+        let response = client.get(format!("/login/user{}", i)).header(ip()).remote(proxy).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+    let response = client.get("/login/user50").header(ip()).remote(proxy).dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+
+    let response = client
+        .get("/login/user50")
+        .header(Header::new("X-Real-IP", "10.0.0.2"))
+        .remote(proxy)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn spoofed_real_ip_is_ignored() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let remote: SocketAddr = "10.1.0.1:40000".parse().unwrap();
+
+    //不是可信代理，每次换X-Real-IP也按连接的IP计数
+    for i in 0..50 {
+        let response = client
+            .get(format!("/login/user{}", i))
+            .header(Header::new("X-Real-IP", format!("10.9.0.{}", i)))
+            .remote(remote)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+    let response = client
+        .get("/login/user50")
+        .header(Header::new("X-Real-IP", "10.9.1.1"))
+        .remote(remote)
+        .dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+}
+
+#[test]
+fn rejected_requests_do_not_count_for_the_user() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let shared: SocketAddr = "10.2.0.1:40000".parse().unwrap();
+    let publish_from = |user: &Header<'static>, remote: SocketAddr| {
+        client.get(publish_path("")).header(user.clone()).remote(remote).dispatch().status()
+    };
+
+    //同一IP的5个用户用完IP的25次
+    for i in 0..5 {
+        let user = login(&client, &format!("driver{}", i));
+        for _ in 0..5 {
+            assert_eq!(publish_from(&user, shared), Status::Ok);
+        }
+    }
+    let late = login(&client, "late");
+    assert_eq!(publish_from(&late, shared), Status::TooManyRequests);
+
+    //被IP拒绝的那次没有计入用户的次数
+    let own: SocketAddr = "10.2.0.2:40000".parse().unwrap();
+    for _ in 0..5 {
+        assert_eq!(publish_from(&late, own), Status::Ok);
+    }
+    assert_eq!(publish_from(&late, own), Status::TooManyRequests);
+}
+
+//撤销只删除本次计入的成员，重复撤销不影响其他请求
+#[test]
+fn unhit_removes_only_its_own_hit() {
+    let store = MemoryStore::new();
+    let first = store.hit("test", 2, 60).unwrap().unwrap();
+    let second = store.hit("test", 2, 60).unwrap().unwrap();
+    assert_eq!(store.hit("test", 2, 60).unwrap(), None);
+
+    store.unhit("test", &first).unwrap();
+    store.unhit("test", &first).unwrap();
+    assert!(store.hit("test", 2, 60).unwrap().is_some());
+    assert_eq!(store.hit("test", 2, 60).unwrap(), None);
+    store.unhit("test", &second).unwrap();
+    assert!(store.hit("test", 2, 60).unwrap().is_some());
+}
@@ -0,0 +1,180 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Status};
+use pin_che::entity;
+use pin_che::event::Event;
+use pin_che::matching;
+use pin_che::memory::MemoryStore;
+use pin_che::store::EventStore;
+use pin_che::util;
+use common::{client, drain, login, notifications};
+
+fn request() -> entity::RideRequest {
+    entity::RideRequest {
+        id: "request".to_owned(),
+        openid: "passenger".to_owned(),
+        start: "East Gate".to_owned(),
+        end: "Airport".to_owned(),
+        earliest: 1000,
+        latest: 3000,
+        count: 2,
+        max_price: 2000,
+        tel: None,
+        message: None,
+        status: entity::RideRequestStatus::Open,
+        trip_id: None,
+        order_id: None,
+        create_time: 0,
+    }
+}
+
+fn trip(id: &str, start_time: i64, price: i64) -> entity::Trip {
+    entity::Trip {
+        id: id.to_owned(),
+        openid: "driver".to_owned(),
+        seat_count: 4,
+        current_seat: 4,
+        start_time,
+        start: "east gate".to_owned(),
+        end: "Airport T2".to_owned(),
+        price,
+        venue: "station".to_owned(),
+        status: entity::TripStatus::Prepare,
+        message: None,
+        plate_number: "A12345".to_owned(),
+        tel: "13800000000".to_owned(),
+        car_type: "suv".to_owned(),
+        stops: vec![],
+        fares: vec![],
+        free_seats: vec![4],
+    }
+}
+
+#[test]
+fn score_is_deterministic() {
+    assert_eq!(matching::similarity("East Gate", "east gate"), 1.0);
+    assert!((matching::similarity("Airport", "Airport T2") - 12.0 / 14.0).abs() < 1e-9);
+    assert_eq!(matching::similarity("Airport", "Station"), 0.0);
+
+    //起点30 + 终点25.7 + 时间20 + 价格5 + 座位5
+    assert_eq!(matching::score(&request(), &trip("a", 2000, 1000)), Some(86));
+    //出发时间在范围边缘，时间得分为0
+    assert_eq!(matching::score(&request(), &trip("a", 3000, 1000)), Some(66));
+
+    let mut other = trip("a", 2000, 1000);
+    other.end = "Railway Station".to_owned();
+    let mut full = trip("a", 2000, 1000);
+    full.current_seat = 1;
+    full.free_seats = vec![1];
+    let mut own = trip("a", 2000, 1000);
+    own.openid = "passenger".to_owned();
+    for candidate in &[trip("a", 3001, 1000), trip("a", 2000, 2001), other, full, own] {
+        assert_eq!(matching::score(&request(), candidate), None);
+    }
+}
+
+#[test]
+fn multi_stop_trip_is_scored_by_best_segment() {
+    //全程起点不顺路、总价超出，但从途经站East Gate上车到终点的一段满足需求
+    let mut multi = trip("a", 2000, 2500);
+    multi.start = "North Park".to_owned();
+    multi.end = "Airport".to_owned();
+    multi.stops = vec!["East Gate".to_owned(), "Center".to_owned()];
+    multi.fares = vec![1500, 600, 400];
+    multi.free_seats = vec![4, 4, 4];
+    //起点30 + 终点30 + 时间20 + 价格5 + 座位5
+    assert_eq!(matching::score(&request(), &multi), Some(90));
+
+    //该段中有一段座位不够
+    multi.free_seats = vec![4, 4, 1];
+    assert_eq!(matching::score(&request(), &multi), None);
+}
+
+#[test]
+fn top_keeps_best_and_breaks_ties_by_id() {
+    let candidates = vec![(50, "d"), (80, "c"), (80, "a"), (90, "b"), (10, "e")];
+    let top = matching::top(candidates, |id| *id);
+    assert_eq!(top, vec![(90, "b"), (80, "a"), (80, "c")]);
+}
+
+#[test]
+fn both_sides_are_notified_once() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    store.add_event_group("probe").unwrap();
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let now = util::now();
+
+    let mut response = client
+        .get(format!(
+            "/postRideRequest?start=East%20Gate&end=Airport&earliest={}&latest={}&count=2&max_price=1500",
+            now + 3600,
+            now + 7200
+        ))
+        .header(passenger.clone())
+        .dispatch();
+    let request: entity::RideRequest = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let mut response = client
+        .post("/publishTrip")
+        .header(ContentType::JSON)
+        .header(driver.clone())
+        .body(format!(
+            r#"{{"seat_count":4,"start_time":{},"start":"East Gate","end":"Airport","price":1200,
+                "venue":"station","message":null,"plate_number":"A12345","car_type":"suv","tel":"13800000000"}}"#,
+            now + 5400
+        ))
+        .dispatch();
+    let trip: entity::Trip = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    drain(&store);
+    drain(&store);
+
+    let matched: Vec<Event> = store
+        .read_events("probe", 100)
+        .unwrap()
+        .into_iter()
+        .map(|(_, event)| event)
+        .filter(|event| match *event {
+            Event::RideMatched { .. } => true,
+            _ => false,
+        })
+        .collect();
+    assert_eq!(matched.len(), 1);
+    match matched[0] {
+        Event::RideMatched { ref request_id, ref trip_id, ref passenger, ref driver, .. } => {
+            assert_eq!(
+                (request_id.as_str(), trip_id.as_str(), passenger.as_str(), driver.as_str()),
+                (request.id.as_str(), trip.id.as_str(), "passenger", "driver")
+            );
+        }
+        _ => unreachable!(),
+    }
+
+    let mut response = client.get(format!("/rideRequestMatches/{}", request.id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let trips: Vec<entity::TripMatch> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(trips.len(), 1);
+    assert_eq!(trips[0].trip.id, trip.id);
+    let mut response = client.get(format!("/tripMatches/{}", trip.id)).header(driver.clone()).dispatch();
+    let requests: Vec<entity::RequestMatch> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(requests[0].request.id, request.id);
+    assert_eq!(requests[0].score, trips[0].score);
+    let response = client.get(format!("/tripMatches/{}", trip.id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::NotAcceptable);
+
+    //双方都收到站内通知
+    for user in &[passenger, driver] {
+        let received = notifications(&client, user);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].kind, entity::NotificationKind::RideMatched);
+        assert_eq!((received[0].trip_id.as_str(), received[0].request_id.as_ref()), (trip.id.as_str(), Some(&request.id)));
+    }
+}
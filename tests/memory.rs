@@ -0,0 +1,70 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use pin_che::entity::{ApplyForm, TripForm, TripStatus};
+use pin_che::event;
+use pin_che::memory::MemoryStore;
+use pin_che::service::{Service, ServiceError};
+use pin_che::store::OrderStore;
+
+fn form(seat_count: i64) -> TripForm {
+    TripForm {
+        seat_count,
+        start_time: 1900000000,
+        start: "A".to_owned(),
+        end: "B".to_owned(),
+        price: 1000,
+        venue: "station".to_owned(),
+        message: None,
+        plate_number: "A12345".to_owned(),
+        car_type: "suv".to_owned(),
+        tel: "13800000000".to_owned(),
+        stops: None,
+        fares: None,
+    }
+}
+
+fn apply_form(trip_id: &str, count: i64) -> ApplyForm {
+    ApplyForm {
+        trip_id: trip_id.to_owned(),
+        count,
+        tel: None,
+        from: None,
+        to: None,
+        coupon: None,
+    }
+}
+
+//不连接mongodb和redis，在进程内跑通下单、支付和确认
+#[test]
+fn booking_flow_without_servers() {
+    //加载测试配置
+    let _ = &*common::WEIXIN;
+    let store = MemoryStore::new();
+    let service = Service::with_store(store.clone());
+    event::bus().init(&service).unwrap();
+    let trip = service.publish_trip("driver".to_owned(), form(4)).unwrap();
+
+    let order = service.apply_trip("passenger".to_owned(), apply_form(&trip.id, 3)).unwrap();
+    match service.apply_trip("other".to_owned(), apply_form(&trip.id, 2)) {
+        Err(ServiceError::DontHaveEnoughSeats) => (),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(service.get_trips(1, None).unwrap()[0].current_seat, 1);
+
+    //未支付的订单不能确认
+    assert!(service.submit(order.id.clone(), "passenger").is_err());
+    //支付结果由微信通知，这里直接写入存储
+    store.pay_order(&order.id, "wx_transaction", &[]).unwrap();
+    service.submit(order.id.clone(), "passenger").unwrap();
+    //行程是否结束由事件处理
+    common::drain(&store);
+    assert_eq!(service.get_trips(1, None).unwrap()[0].status, TripStatus::Finish);
+}
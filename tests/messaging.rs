@@ -0,0 +1,135 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::Client;
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use pin_che::service::Service;
+use pin_che::store::MessageStore;
+use pin_che::{util, validate};
+use common::{apply, client, drain, login, notify, publish, publish_path};
+
+//返回(状态, 响应内容)
+fn send(client: &Client, user: &Header<'static>, trip_id: &str, passenger: Option<&str>, content: &str) -> (Status, serde_json::Value) {
+    let passenger = passenger.map_or("null".to_owned(), |p| format!(r#""{}""#, p));
+    let mut response = client
+        .post("/sendMessage")
+        .header(ContentType::JSON)
+        .header(user.clone())
+        .body(format!(r#"{{"trip_id":"{}","passenger":{},"content":"{}"}}"#, trip_id, passenger, content))
+        .dispatch();
+    let body = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    (response.status(), body)
+}
+
+fn conversations(client: &Client, user: &Header<'static>) -> Vec<entity::ConversationSummary> {
+    let mut response = client.get("/conversations").header(user.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+#[test]
+fn paid_passengers_chat_with_driver() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish(&client, &driver);
+    let order = apply(&client, &passenger, &trip.id, 1);
+
+    //未支付时不能发起会话
+    let (status, body) = send(&client, &passenger, &trip.id, None, "hello");
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("NOT_PAID")));
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+
+    let mut updates = Service::with_store(store.clone()).subscribe_messages("passenger").unwrap();
+    let (status, first) = send(&client, &passenger, &trip.id, None, "where are you?");
+    assert_eq!(status, Status::Ok);
+    let (status, body) = send(&client, &driver, &trip.id, None, "at the gate");
+    assert_eq!((status, body["code"].as_str()), (Status::BadRequest, Some("INVALID_PARAMS")));
+    let (status, reply) = send(&client, &driver, &trip.id, Some("passenger"), "at the gate");
+    assert_eq!(status, Status::Ok);
+    assert_eq!((first["seq"].as_i64(), reply["seq"].as_i64()), (Some(1), Some(2)));
+    let pushed = updates.next().unwrap().unwrap();
+    assert_eq!((pushed.seq, pushed.sender.as_str()), (2, "driver"));
+
+    //消息和会话中都不包含手机号
+    let summaries = conversations(&client, &passenger);
+    assert_eq!(summaries.len(), 1);
+    let id = summaries[0].conversation.id.clone();
+    let json = serde_json::to_string(&summaries).unwrap();
+    assert!(!json.contains("13800000000") && !json.contains("13900000000"));
+    assert_eq!((summaries[0].last_seq, summaries[0].unread, summaries[0].peer_read), (2, 1, 0));
+
+    let mut response = client.get(format!("/messages/{}/1", id)).header(passenger.clone()).dispatch();
+    let messages: Vec<entity::Message> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].content, "at the gate");
+
+    //已读回执
+    let response = client.get(format!("/readMessages/{}/9", id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(conversations(&client, &passenger)[0].unread, 0);
+    assert_eq!(conversations(&client, &driver)[0].peer_read, 2);
+
+    let outsider = login(&client, "outsider");
+    let response = client.get(format!("/messages/{}/0", id)).header(outsider.clone()).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let (status, body) = send(&client, &outsider, &trip.id, None, "hi");
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("NOT_PAID")));
+}
+
+#[test]
+fn conversations_close_after_trip_finishes() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish(&client, &driver);
+    let order = apply(&client, &passenger, &trip.id, 1);
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    assert_eq!(send(&client, &passenger, &trip.id, None, "thanks").0, Status::Ok);
+
+    assert_eq!(client.get(format!("/submit/{}", order.id)).header(passenger.clone()).dispatch().status(), Status::Ok);
+    drain(&store);
+    let mut conversation = store.get_trip_conversations(&trip.id).unwrap().remove(0);
+    let close_time = conversation.close_time.unwrap();
+    assert!((close_time - util::now() - 24 * 3600).abs() <= 1);
+    //关闭前仍然可以发消息
+    assert_eq!(send(&client, &driver, &trip.id, Some("passenger"), "bye").0, Status::Ok);
+
+    conversation.close_time = Some(util::now() - 1);
+    store.update_conversation(&conversation).unwrap();
+    let (status, body) = send(&client, &driver, &trip.id, Some("passenger"), "bye");
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("CONVERSATION_CLOSED")));
+}
+
+#[test]
+fn messages_are_moderated_like_trip_messages() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let trip = publish(&client, &driver);
+    let long = "x".repeat(501);
+
+    let (status, body) = send(&client, &driver, &trip.id, Some("passenger"), &long);
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(body["errors"][0]["field"].as_str(), Some("content"));
+    let response = client
+        .get(publish_path(&format!("message={}", long)))
+        .header(driver.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let blocked = vec!["WeChat".to_owned()];
+    assert!(!validate::is_clean("add my wechat please", &blocked));
+    assert!(validate::is_clean("see you at the gate", &blocked));
+}
@@ -0,0 +1,54 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::Status;
+use pin_che::memory::MemoryStore;
+use common::{admin, apply_tel, client, login, notify, publish_with};
+
+//指标是进程级的，所有检查放在一个测试里
+#[test]
+fn metrics_count_requests_and_bookings() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish_with(&client, &driver, "tel=13812345678");
+    let order = apply_tel(&client, &passenger, &trip.id, 2, "13987654321");
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    assert_eq!(client.get(format!("/order/{}", order.id)).header(passenger.clone()).dispatch().status(), Status::Ok);
+    assert_eq!(client.get("/nowhere").dispatch().status(), Status::NotFound);
+
+    assert_eq!(client.get("/metrics").dispatch().status(), Status::Unauthorized);
+    assert_eq!(client.get("/metrics").header(passenger.clone()).dispatch().status(), Status::Unauthorized);
+    let mut response = client.get("/metrics").header(admin()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let text = response.body_string().unwrap();
+    for line in &[
+        "# TYPE http_request_duration_seconds histogram",
+        r#"http_request_duration_seconds_count{route="/publishTrip",method="GET",status="200"} 1"#,
+        r#"http_request_duration_seconds_bucket{route="/publishTrip",method="GET",status="200",le="+Inf"} 1"#,
+        r#"http_request_duration_seconds_count{route="/order/<id>",method="GET",status="200"} 1"#,
+        r#"http_request_duration_seconds_count{route="unmatched",method="GET",status="404"} 1"#,
+        r#"trips_published_total{source="publish"} 1"#,
+        "orders_created_total 1",
+        "seats_reserved_total 2",
+        "orders_paid_total 1",
+        "# TYPE payout_failures_total counter",
+    ] {
+        assert!(text.lines().any(|l| l == *line), "missing {}\n{}", line, text);
+    }
+    //桶是累计的，最后一个桶等于总数
+    let buckets: Vec<u64> = text.lines()
+        .filter(|l| l.starts_with(r#"http_request_duration_seconds_bucket{route="/publishTrip""#))
+        .map(|l| l.rsplit(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(buckets.len(), 12);
+    assert!(buckets.windows(2).all(|w| w[0] <= w[1]));
+}
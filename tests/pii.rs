@@ -0,0 +1,108 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use std::env;
+use rocket::http::{ContentType, Status};
+use pin_che::{db, entity, pii, setting, util};
+use pin_che::memory::MemoryStore;
+use pin_che::store::TemplateStore;
+use common::{admin, apply_tel, client, login, publish_with};
+
+#[test]
+fn values_are_sealed_and_keys_rotate() {
+    client(&MemoryStore::new());
+    let sealed = pii::seal("13812345678").unwrap();
+    assert!(sealed.starts_with("pii:k1:"));
+    assert!(!sealed.contains("13812345678"));
+    assert_ne!(sealed, pii::seal("13812345678").unwrap());
+    assert_eq!(pii::open(&sealed).unwrap(), "13812345678");
+    //加密上线前的明文原样返回
+    assert_eq!(pii::open("13812345678").unwrap(), "13812345678");
+    assert!(pii::open("pii:k1:broken").is_err());
+
+    assert_eq!(pii::blind_index("13812345678"), pii::blind_index(" 13812345678 "));
+    assert_ne!(pii::blind_index("13812345678"), pii::blind_index("13812345679"));
+
+    //轮换主密钥后新数据使用新密钥，旧数据仍可解密
+    env::set_var("PINCHE_PII__KEYS__K2", "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=");
+    env::set_var("PINCHE_PII__CURRENT_KID", "k2");
+    setting::reload().unwrap();
+    let rotated = pii::seal("13812345678").unwrap();
+    assert!(rotated.starts_with("pii:k2:"));
+    assert_eq!(pii::open(&rotated).unwrap(), "13812345678");
+    assert_eq!(pii::open(&sealed).unwrap(), "13812345678");
+
+    //旧数据还在使用k1，删除或修改k1的重新加载不生效
+    env::remove_var("PINCHE_PII__KEYS__K1");
+    setting::reload().unwrap();
+    assert_eq!(pii::open(&sealed).unwrap(), "13812345678");
+    env::set_var("PINCHE_PII__KEYS__K1", "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=");
+    setting::reload().unwrap();
+    assert_eq!(pii::open(&sealed).unwrap(), "13812345678");
+    env::set_var("PINCHE_PII__KEYS__K1", "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=");
+}
+
+#[test]
+fn support_finds_orders_by_tel() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish_with(&client, &driver, "tel=13812345678");
+    let order = apply_tel(&client, &passenger, &trip.id, 1, "13987654321");
+
+    let mut response = client.get("/admin/ordersByTel/13987654321").header(admin()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let orders: Vec<entity::Order> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(orders.len(), 1);
+    assert_eq!((orders[0].id.as_str(), orders[0].tel.as_ref().map(|t| t.as_str())), (order.id.as_str(), Some("13987654321")));
+
+    let mut response = client.get("/admin/ordersByTel/13900000000").header(admin()).dispatch();
+    assert_eq!(response.body_string().unwrap(), "[]");
+    let response = client.get("/admin/ordersByTel/123").header(admin()).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = client.get("/admin/ordersByTel/13987654321").header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn templates_are_stored_sealed() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let mut response = client
+        .post("/addTemplate")
+        .header(ContentType::JSON)
+        .header(driver.clone())
+        .body(format!(
+            r#"{{"start":"A","end":"B","venue":"station","message":null,"plate_number":"A12345",
+                "car_type":"suv","tel":"13812345678","seat_count":4,"price":1000,"depart_time":28800,
+                "weekdays":[1,2,3,4,5,6,7],"start_date":"{}","end_date":"{}","skip_dates":[]}}"#,
+            util::format_date(util::today() + 1),
+            util::format_date(util::today() + 7)
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let template: entity::TripTemplate = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+    //写入mongodb的文档不含手机号明文，通过盲索引查找
+    let doc = db::template_document(&store.get_template(&template.id).unwrap()).unwrap();
+    assert!(!doc.to_string().contains("13812345678"), "{}", doc);
+    assert_eq!(pii::open(doc.get_str("tel").unwrap()).unwrap(), "13812345678");
+    assert_eq!(doc.get_str("tel_index").unwrap(), pii::blind_index("13812345678"));
+
+    let mut response = client.get("/admin/templatesByTel/13812345678").header(admin()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let templates: Vec<entity::TripTemplate> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(templates.len(), 1);
+    assert_eq!((templates[0].id.as_str(), templates[0].tel.as_str()), (template.id.as_str(), "13812345678"));
+    let mut response = client.get("/admin/templatesByTel/13987654321").header(admin()).dispatch();
+    assert_eq!(response.body_string().unwrap(), "[]");
+}
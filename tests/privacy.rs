@@ -0,0 +1,108 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::Client;
+use pin_che::{entity, util};
+use pin_che::memory::MemoryStore;
+use common::{apply_tel, client, drain, login, notify, publish_with};
+
+//未登录时不带Authorization
+fn trip_tel(client: &Client, user: Option<&Header<'static>>, trip_id: &str) -> String {
+    let mut request = client.get("/getTrips/1");
+    if let Some(user) = user {
+        request = request.header(user.clone());
+    }
+    let mut response = request.dispatch();
+    let trips: Vec<entity::Trip> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    trips.into_iter().find(|trip| trip.id == trip_id).unwrap().tel
+}
+
+fn order_tel(client: &Client, user: &Header<'static>, order_id: &str) -> Option<String> {
+    let mut response = client.get(format!("/order/{}", order_id)).header(user.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let order: entity::Order = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    order.tel
+}
+
+#[test]
+fn numbers_are_shown_only_between_paid_participants() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let stranger = login(&client, "stranger");
+    let trip = publish_with(&client, &driver, "tel=13812345678");
+    assert_eq!(trip.tel, "13812345678");
+    assert_eq!(trip_tel(&client, None, &trip.id), "138****5678");
+    assert_eq!(trip_tel(&client, Some(&driver), &trip.id), "13812345678");
+
+    let order = apply_tel(&client, &passenger, &trip.id, 1, "13987654321");
+    assert_eq!(trip_tel(&client, Some(&passenger), &trip.id), "138****5678");
+    assert_eq!(order_tel(&client, &driver, &order.id), Some("139****4321".to_owned()));
+    let response = client.get(format!("/order/{}", order.id)).header(stranger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    //支付后双方可以看到对方的手机号
+    assert!(notify(&client, &order.id).contains("SUCCESS"));
+    assert_eq!(trip_tel(&client, Some(&passenger), &trip.id), "13812345678");
+    assert_eq!(trip_tel(&client, Some(&stranger), &trip.id), "138****5678");
+    assert_eq!(order_tel(&client, &driver, &order.id), Some("13987654321".to_owned()));
+    let mut response = client.get(format!("/tripOrders/{}", trip.id)).header(driver.clone()).dispatch();
+    let orders: Vec<entity::Order> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(orders[0].tel, Some("13987654321".to_owned()));
+    let response = client.get(format!("/tripOrders/{}", trip.id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::NotAcceptable);
+
+    //行程完成后不再显示，本人仍然可以看到自己的手机号
+    assert_eq!(client.get(format!("/submit/{}", order.id)).header(passenger.clone()).dispatch().status(), Status::Ok);
+    drain(&store);
+    assert_eq!(trip_tel(&client, Some(&passenger), &trip.id), "138****5678");
+    assert_eq!(order_tel(&client, &driver, &order.id), Some("139****4321".to_owned()));
+    assert_eq!(order_tel(&client, &passenger, &order.id), Some("13987654321".to_owned()));
+}
+
+#[test]
+fn drivers_see_masked_ride_requests() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let now = util::now();
+    let mut response = client
+        .get(format!(
+            "/postRideRequest?start=East&end=Airport&earliest={}&latest={}&count=1&max_price=1500&tel=13987654321",
+            now + 3600,
+            now + 7200
+        ))
+        .header(passenger.clone())
+        .dispatch();
+    let request: entity::RideRequest = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(request.tel, Some("13987654321".to_owned()));
+
+    let mut response = client.get("/rideRequests?page=1").header(driver.clone()).dispatch();
+    let requests: Vec<entity::RideRequest> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(requests[0].tel, Some("139****4321".to_owned()));
+
+    let mut response = client
+        .post(format!("/acceptRideRequest/{}", request.id))
+        .header(ContentType::JSON)
+        .header(driver.clone())
+        .body(format!(
+            r#"{{"trip":{{"seat_count":4,"start_time":{},"start":"East","end":"Airport","price":1000,
+                "venue":"station","message":null,"plate_number":"A12345","car_type":"suv","tel":"13812345678"}}}}"#,
+            now + 5400
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let order: entity::Order = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(order.tel, Some("139****4321".to_owned()));
+    assert_eq!(util::mask_tel("12345"), "*****");
+}
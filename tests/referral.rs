@@ -0,0 +1,127 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::Client;
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use pin_che::store::ReferralStore;
+use common::{admin, apply_tel, client, drain, login, notify, publish, try_apply_coupon};
+
+//带邀请码和设备标识登录
+fn login_with(client: &Client, code: &str, referral_code: &str, device: &str) -> Header<'static> {
+    let mut response = client
+        .post("/login")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{"code":"{}","referral_code":"{}","device":"{}"}}"#, code, referral_code, device))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    Header::new("Authorization", format!("Bearer {}", body["token"].as_str().unwrap()))
+}
+
+fn referral_code(client: &Client, user: &Header<'static>) -> String {
+    let mut response = client.get("/referralCode").header(user.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    body["code"].as_str().unwrap().to_owned()
+}
+
+//下单、支付并确认，返回订单id
+fn ride(client: &Client, store: &MemoryStore, passenger: &Header<'static>, tel: &str) -> String {
+    let trip = publish(client, &login(client, "driver"));
+    let order = apply_tel(client, passenger, &trip.id, 1, tel);
+    assert!(notify(client, &order.id).contains("SUCCESS"));
+    assert_eq!(client.get(format!("/submit/{}", order.id)).header(passenger.clone()).dispatch().status(), Status::Ok);
+    drain(store);
+    order.id
+}
+
+fn my_coupons(client: &Client, user: &Header<'static>) -> Vec<entity::Coupon> {
+    let mut response = client.get("/myCoupons").header(user.clone()).dispatch();
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+#[test]
+fn both_sides_are_rewarded_once() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let alice = login(&client, "alice");
+    let code = referral_code(&client, &alice);
+    assert_eq!(referral_code(&client, &alice), code);
+
+    let bob = login_with(&client, "bob", &code, "device-bob");
+    assert_eq!(store.get_referral("bob").unwrap().unwrap().status, entity::ReferralStatus::Pending);
+    ride(&client, &store, &bob, "13900000001");
+    ride(&client, &store, &bob, "13900000001");
+
+    let mut response = client.get("/myReferrals").header(alice.clone()).dispatch();
+    let referrals: Vec<entity::Referral> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(referrals.len(), 1);
+    assert_eq!((referrals[0].id.as_str(), referrals[0].status.clone()), ("bob", entity::ReferralStatus::Rewarded));
+
+    //默认奖励为5元的个人优惠券，只能本人使用
+    let coupons = my_coupons(&client, &alice);
+    assert_eq!(coupons.len(), 1);
+    assert_eq!(my_coupons(&client, &bob).len(), 1);
+    assert_eq!((coupons[0].value, coupons[0].per_user_limit), (500, 1));
+    let trip = publish(&client, &login(&client, "driver"));
+    for &(user, status) in &[(&bob, Status::NotAcceptable), (&alice, Status::Ok)] {
+        assert_eq!(try_apply_coupon(&client, user, &trip.id, 1, &coupons[0].code).0, status);
+    }
+}
+
+#[test]
+fn fraud_is_rejected_and_reported() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let alice = login(&client, "alice");
+    let code = referral_code(&client, &alice);
+    //再次登录不会被邀请，但会记录设备
+    login_with(&client, "alice", &code, "device-alice");
+    assert!(store.get_referral("alice").unwrap().is_none());
+
+    login_with(&client, "bob", &code, "device-bob");
+    login_with(&client, "carol", &code, "device-bob");
+    login_with(&client, "dave", &code, "device-alice");
+    let erin = login_with(&client, "erin", &code, "device-erin");
+    //没有设备标识的邀请不发放奖励
+    let response = client
+        .post("/login")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{"code":"frank","referral_code":"{}"}}"#, code))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    ride(&client, &store, &alice, "13900000000");
+    ride(&client, &store, &erin, "13900000000");
+
+    let mut response = client.get("/admin/referrals/0").header(admin()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let referrals: Vec<entity::Referral> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let reasons: Vec<(&str, Option<&str>)> = referrals
+        .iter()
+        .map(|r| (r.id.as_str(), r.reason.as_ref().map(|reason| reason.as_str())))
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![
+            ("frank", Some("missing device")),
+            ("erin", Some("same tel as referrer")),
+            ("dave", Some("same device as referrer")),
+            ("carol", Some("device already referred")),
+            ("bob", None),
+        ]
+    );
+    assert!(my_coupons(&client, &alice).is_empty());
+    assert!(my_coupons(&client, &erin).is_empty());
+
+    let response = client.get("/admin/referrals/0").header(alice).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
@@ -0,0 +1,143 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::{Client, LocalResponse};
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use pin_che::store::TripStore;
+use pin_che::util;
+use common::{client, drain, login, notifications};
+
+//一小时后到两小时后出发，2个座位，每座最多15元
+fn post(client: &Client, passenger: &Header<'static>) -> entity::RideRequest {
+    let now = util::now();
+    let mut response = client
+        .get(format!(
+            "/postRideRequest?start=East%20Gate&end=Airport&earliest={}&latest={}&count=2&max_price=1500&tel=13900000000",
+            now + 3600,
+            now + 7200
+        ))
+        .header(passenger.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+fn trip_json(start_time: i64, price: i64) -> String {
+    format!(
+        r#"{{"seat_count":4,"start_time":{},"start":"East Gate","end":"Airport","price":{},
+            "venue":"station","message":null,"plate_number":"A12345","car_type":"suv","tel":"13800000000"}}"#,
+        start_time, price
+    )
+}
+
+fn accept<'c>(client: &'c Client, driver: &Header<'static>, id: &str, body: String) -> LocalResponse<'c> {
+    client
+        .post(format!("/acceptRideRequest/{}", id))
+        .header(ContentType::JSON)
+        .header(driver.clone())
+        .body(body)
+        .dispatch()
+}
+
+fn list(client: &Client, driver: &Header<'static>, query: &str) -> Vec<entity::RideRequest> {
+    let mut response = client.get(format!("/rideRequests?{}", query)).header(driver.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+#[test]
+fn driver_accepts_with_new_trip() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let request = post(&client, &passenger);
+    assert_eq!(list(&client, &driver, "start=East&end=Air").len(), 1);
+
+    let mut response = accept(&client, &driver, &request.id, format!(r#"{{"trip":{}}}"#, trip_json(util::now() + 5400, 1200)));
+    assert_eq!(response.status(), Status::Ok);
+    let order: entity::Order = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!((order.openid.as_str(), order.trip_owner.as_str()), ("passenger", "driver"));
+    assert_eq!((order.count, order.price, order.status), (2, 1200, entity::OrderStatus::Unpaid));
+    assert_eq!(store.get_trip(&order.trip_id).unwrap().current_seat, 2);
+
+    let mut response = client.get("/myRideRequests").header(passenger.clone()).dispatch();
+    let requests: Vec<entity::RideRequest> = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(requests[0].status, entity::RideRequestStatus::Accepted);
+    assert_eq!(requests[0].order_id, Some(order.id.clone()));
+    assert!(list(&client, &driver, "page=1").is_empty());
+
+    //乘客收到支付提醒，车主收到接单确认
+    drain(&store);
+    for user in &[&passenger, &driver] {
+        let received: Vec<entity::Notification> = notifications(&client, user)
+            .into_iter()
+            .filter(|n| n.kind == entity::NotificationKind::RideRequestAccepted)
+            .collect();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].order_id, Some(order.id.clone()));
+    }
+
+    let mut response = accept(&client, &login(&client, "other"), &request.id, format!(r#"{{"trip":{}}}"#, trip_json(util::now() + 5400, 1200)));
+    assert_eq!(response.status(), Status::NotAcceptable);
+    assert!(response.body_string().unwrap().contains("REQUEST_CLOSED"));
+}
+
+#[test]
+fn existing_trip_must_match_request() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let request = post(&client, &passenger);
+
+    let mut response = client
+        .post("/publishTrip")
+        .header(ContentType::JSON)
+        .header(driver.clone())
+        .body(trip_json(util::now() + 9000, 2000))
+        .dispatch();
+    let trip: entity::Trip = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let mut response = accept(&client, &driver, &request.id, format!(r#"{{"trip_id":"{}"}}"#, trip.id));
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = response.body_string().unwrap();
+    assert!(body.contains("start_time") && body.contains("price"), "{}", body);
+
+    //只能使用自己的行程，trip_id和trip二选一
+    let response = accept(&client, &login(&client, "other"), &request.id, format!(r#"{{"trip_id":"{}"}}"#, trip.id));
+    assert_eq!(response.status(), Status::NotAcceptable);
+    let response = accept(&client, &driver, &request.id, "{}".to_owned());
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(list(&client, &driver, "page=1").len(), 1);
+}
+
+#[test]
+fn requests_are_filtered_and_cancellable() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let request = post(&client, &passenger);
+
+    assert!(list(&client, &driver, "start=Station").is_empty());
+    assert!(list(&client, &driver, &format!("from={}", util::now() + 9000)).is_empty());
+    assert!(list(&client, &driver, &format!("to={}", util::now() + 1800)).is_empty());
+    assert_eq!(list(&client, &driver, &format!("from={}&to={}", util::now(), util::now() + 4000)).len(), 1);
+
+    let response = client.get(format!("/cancelRideRequest/{}", request.id)).header(driver.clone()).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let response = client.get(format!("/cancelRideRequest/{}", request.id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(list(&client, &driver, "page=1").is_empty());
+    let response = accept(&client, &driver, &request.id, format!(r#"{{"trip":{}}}"#, trip_json(util::now() + 5400, 1200)));
+    assert_eq!(response.status(), Status::NotAcceptable);
+}
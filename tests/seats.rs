@@ -0,0 +1,83 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use std::io::Read;
+use rocket::http::{ContentType, Status};
+use pin_che::entity::{SeatUpdate, TripStatus};
+use pin_che::memory::MemoryStore;
+use pin_che::service::Service;
+use common::{apply, client, login, publish};
+
+fn seats(trip_id: &str, current_seat: i64) -> SeatUpdate {
+    SeatUpdate {
+        trip_id: trip_id.to_owned(),
+        current_seat,
+        status: TripStatus::Prepare,
+    }
+}
+
+#[test]
+fn seat_changes_are_pushed() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish(&client, &driver);
+    let service = Service::with_store(store.clone());
+    let (current, mut updates) = service.subscribe_seats(&trip.id).unwrap();
+    assert_eq!(current, vec![seats(&trip.id, 4)]);
+
+    let order = apply(&client, &passenger, &trip.id, 3);
+    assert_eq!(updates.next(), Some(Some(seats(&trip.id, 1))));
+
+    service.expire_order(&order.id).unwrap();
+    assert_eq!(updates.next(), Some(Some(seats(&trip.id, 4))));
+}
+
+#[test]
+fn stream_starts_with_current_seats() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let trip = publish(&client, &driver);
+
+    let mut response = client.get(format!("/seatStream?ids={},unknown", trip.id)).header(driver.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::new("text", "event-stream")));
+    let mut buf = [0; 1024];
+    let n = response.body().unwrap().into_inner().read(&mut buf).unwrap();
+    let text = String::from_utf8_lossy(&buf[..n]).into_owned();
+    assert!(text.starts_with("event: seats\ndata: "), "{}", text);
+    assert!(text.contains(r#""current_seat":4"#), "{}", text);
+
+    let response = client.get("/seatStream?ids=,").header(driver.clone()).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = client.get(format!("/seatStream?ids={}", trip.id)).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn streams_per_user_are_capped() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "streamer");
+    let trip = publish(&client, &driver);
+    let open = || client.get(format!("/seatStream?ids={}", trip.id)).header(driver.clone()).dispatch();
+
+    //默认每个用户同时2个连接，断开后释放
+    let first = open();
+    let second = open();
+    assert_eq!((first.status(), second.status()), (Status::Ok, Status::Ok));
+    assert_eq!(open().status(), Status::TooManyRequests);
+    let response = client.get("/messageStream").header(driver.clone()).dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+    drop(first);
+    assert_eq!(open().status(), Status::Ok);
+}
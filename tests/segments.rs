@@ -0,0 +1,91 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::Status;
+use pin_che::memory::MemoryStore;
+use pin_che::service::Service;
+use pin_che::store::TripStore;
+use common::{apply, client, login, publish_path, publish_with, try_apply_segment};
+
+//A经过B、C到D，2个座位，三段价格合计10元
+const ROUTE: &str = "seat_count=2&end=D&stops=B,C&fares=300,400,300";
+
+#[test]
+fn seats_are_reused_after_drop_off() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let service = Service::with_store(store.clone());
+    let driver = login(&client, "driver");
+    let trip = publish_with(&client, &driver, ROUTE);
+    assert_eq!(trip.stops, vec!["B", "C"]);
+    assert_eq!(trip.free_seats, vec![2, 2, 2]);
+
+    //A到B的乘客下车后，B到D的座位可以再卖
+    let (status, first) = try_apply_segment(&client, &login(&client, "first"), &trip.id, 0, 1, 2);
+    assert_eq!(status, Status::Ok);
+    assert_eq!((first["price"].as_i64(), first["from"].as_i64(), first["to"].as_i64()), (Some(300), Some(0), Some(1)));
+    let (status, second) = try_apply_segment(&client, &login(&client, "second"), &trip.id, 1, 3, 2);
+    assert_eq!(status, Status::Ok);
+    assert_eq!(second["price"].as_i64(), Some(700));
+    let stored = store.get_trip(&trip.id).unwrap();
+    assert_eq!((stored.free_seats, stored.current_seat), (vec![0, 0, 0], 0));
+
+    let third = login(&client, "third");
+    let (status, body) = try_apply_segment(&client, &third, &trip.id, 0, 2, 1);
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("NOT_ENOUGH_SEATS")));
+
+    //过期订单只归还所乘路段的座位
+    service.expire_order(first["_id"].as_str().unwrap()).unwrap();
+    assert_eq!(store.get_trip(&trip.id).unwrap().free_seats, vec![2, 0, 0]);
+    assert_eq!(try_apply_segment(&client, &third, &trip.id, 0, 1, 1).0, Status::Ok);
+    let stored = store.get_trip(&trip.id).unwrap();
+    assert_eq!((stored.free_seats, stored.current_seat), (vec![1, 0, 0], 0));
+}
+
+#[test]
+fn full_route_and_invalid_segments() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish_with(&client, &driver, ROUTE);
+
+    for &(from, to) in &[(2, 1), (0, 4), (-1, 2), (3, 3)] {
+        let (status, body) = try_apply_segment(&client, &passenger, &trip.id, from, to, 1);
+        assert_eq!((status, body["code"].as_str()), (Status::BadRequest, Some("INVALID_PARAMS")));
+    }
+
+    let order = apply(&client, &passenger, &trip.id, 1);
+    assert_eq!((order.price, order.from, order.to), (1000, 0, 3));
+    assert_eq!(store.get_trip(&trip.id).unwrap().free_seats, vec![1, 1, 1]);
+}
+
+#[test]
+fn fares_must_match_stops_and_price() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let cases = [
+        ("stops=B,C", "fares"),
+        ("stops=B,C&fares=300,700", "fares"),
+        ("stops=B,C&fares=300,400,400", "fares"),
+        ("stops=B,&fares=500,500,0", "stops"),
+    ];
+    for &(query, field) in &cases {
+        let mut response = client
+            .get(publish_path(&format!("seat_count=2&end=D&{}", query)))
+            .header(driver.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", query);
+        let body = response.body_string().unwrap();
+        assert!(body.contains(&format!("\"{}\"", field)), "{}", body);
+    }
+    assert!(store.get_trips(0, -1).unwrap().is_empty());
+}
@@ -0,0 +1,82 @@
+extern crate pin_che;
+
+use std::env;
+use pin_che::setting;
+
+//环境变量是进程级的，所有情况放在一个测试里顺序执行
+#[test]
+fn load_reports_every_problem() {
+    env::set_var("PINCHE_PROFILE", "test");
+    env::set_var("PINCHE_APP__DBURL", "127.0.0.1");
+    env::set_var("PINCHE_APP__DBPORT", "not-a-port");
+
+    let errors = setting::load().unwrap_err().0;
+    assert!(errors.iter().any(|e| e.starts_with("app.dbport is invalid")));
+    for key in &["app.redis", "weixin.appid", "weixin.secret", "weixin.mchid", "weixin.key", "jwt.current_kid", "jwt.keys", "pii.current_kid", "pii.keys", "pii.index_key"] {
+        assert!(errors.contains(&format!("{} is missing", key)), "{:?}", errors);
+    }
+    assert!(!errors.iter().any(|e| e.starts_with("app.dburl")));
+
+    env::set_var("PINCHE_APP__DBPORT", "27017");
+    env::set_var("PINCHE_APP__REDIS", "redis://127.0.0.1/");
+    env::set_var("PINCHE_WEIXIN__APPID", "wx_appid");
+    env::set_var("PINCHE_WEIXIN__SECRET", "wx_secret");
+    env::set_var("PINCHE_WEIXIN__MCHID", "wx_mchid");
+    env::set_var("PINCHE_WEIXIN__KEY", "wx_key");
+    env::set_var("PINCHE_WEIXIN__IP", "127.0.0.1");
+    env::set_var("PINCHE_WEIXIN__NOTIFY_URL", "http://127.0.0.1/wxNotify");
+    env::set_var("PINCHE_JWT__CURRENT_KID", "k1");
+    env::set_var("PINCHE_JWT__KEYS__K1", "test_jwt_secret_k1");
+    env::set_var("PINCHE_PII__CURRENT_KID", "k1");
+    env::set_var("PINCHE_PII__KEYS__K1", "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=");
+    env::set_var("PINCHE_PII__INDEX_KEY", "short");
+    env::set_var("PINCHE_BUSINESS__COMMISSION", "1.5");
+    env::set_var("PINCHE_WALLET__MIN_WITHDRAW", "10");
+
+    let errors = setting::load().unwrap_err().0;
+    assert_eq!(
+        errors,
+        vec![
+            "wallet.min_withdraw must be at least 100".to_owned(),
+            "business.commission must be in [0, 1)".to_owned(),
+            "pii.index_key must be at least 16 characters".to_owned(),
+        ]
+    );
+
+    env::set_var("PINCHE_PII__INDEX_KEY", "test_pii_index_key");
+    env::remove_var("PINCHE_BUSINESS__COMMISSION");
+    env::remove_var("PINCHE_WALLET__MIN_WITHDRAW");
+    let config = setting::load().unwrap();
+    assert_eq!(config.profile, "test");
+    assert_eq!(config.app.dbport, 27017);
+    assert_eq!(config.weixin.notify_url, "http://127.0.0.1/wxNotify");
+    assert_eq!(config.business.pay_timeout, 180);
+
+    //prod要求https回调地址
+    env::set_var("PINCHE_PROFILE", "prod");
+    let errors = setting::load().unwrap_err().0;
+    assert_eq!(errors, vec!["weixin.notify_url must use https in prod".to_owned()]);
+
+    //运行时重新加载，校验失败时保留原配置
+    env::set_var("PINCHE_PROFILE", "test");
+    setting::init().unwrap();
+    env::set_var("PINCHE_BUSINESS__COMMISSION", "0.1");
+    env::set_var("PINCHE_FEATURES__APPLY", "false");
+    env::set_var("PINCHE_APP__DBPORT", "27018");
+    let changes = setting::reload().unwrap();
+    assert_eq!(
+        changes,
+        vec![
+            "business.commission: 0.05 -> 0.1".to_owned(),
+            "features.apply: true -> false".to_owned(),
+        ]
+    );
+    assert_eq!(setting::get().app.dbport, 27017);
+
+    env::set_var("PINCHE_BUSINESS__PAGE_SIZE", "0");
+    assert!(setting::reload().is_err());
+    let config = setting::get();
+    assert_eq!(config.business.commission, 0.1);
+    assert_eq!(config.business.page_size, 10);
+    assert!(!config.features.apply);
+}
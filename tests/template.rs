@@ -0,0 +1,141 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::Client;
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use pin_che::service::Service;
+use pin_che::store::TripStore;
+use pin_che::util;
+use common::{apply, client, login};
+
+const DEPART: i64 = 8 * 3600;
+
+fn date(offset: i64) -> String {
+    util::format_date(util::today() + offset)
+}
+
+//从明天开始每天出发，跳过后天
+fn add_template(client: &Client, driver: &Header<'static>) -> entity::TripTemplate {
+    let mut response = client
+        .post("/addTemplate")
+        .header(ContentType::JSON)
+        .header(driver.clone())
+        .body(format!(
+            r#"{{"start":"A","end":"B","venue":"station","message":null,"plate_number":"A12345",
+                "car_type":"suv","tel":"13800000000","seat_count":4,"price":1000,"depart_time":{},
+                "weekdays":[1,2,3,4,5,6,7],"start_date":"{}","end_date":"{}","skip_dates":["{}"]}}"#,
+            DEPART,
+            date(1),
+            date(30),
+            date(2)
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+//某天生成的行程
+fn trip_on(store: &MemoryStore, offset: i64) -> Option<entity::Trip> {
+    let start = util::day_start(util::today() + offset);
+    store
+        .get_trips(0, -1)
+        .unwrap()
+        .into_iter()
+        .find(|trip| trip.start_time >= start && trip.start_time < start + 86400)
+}
+
+#[test]
+fn template_generates_trips_ahead() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    add_template(&client, &driver);
+
+    //默认提前7天，跳过后天
+    assert_eq!(store.get_trips(0, -1).unwrap().len(), 6);
+    assert!(trip_on(&store, 0).is_none());
+    assert!(trip_on(&store, 2).is_none());
+    let trip = trip_on(&store, 1).unwrap();
+    assert_eq!(trip.start_time, util::day_start(util::today() + 1) + DEPART);
+    assert_eq!((trip.openid.as_str(), trip.seat_count, trip.price), ("driver", 4, 1000));
+
+    //重复生成不会产生新的行程
+    assert_eq!(Service::with_store(store.clone()).generate_trips().unwrap(), 0);
+    assert_eq!(store.get_trips(0, -1).unwrap().len(), 6);
+}
+
+#[test]
+fn skip_and_edit_single_occurrence() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let template = add_template(&client, &driver);
+
+    let response = client
+        .get(format!("/skipOccurrence/{}/{}", template.id, date(3)))
+        .header(driver.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(trip_on(&store, 3).is_none());
+
+    let old = trip_on(&store, 4).unwrap();
+    let response = client
+        .post(format!("/editOccurrence/{}", template.id))
+        .header(ContentType::JSON)
+        .header(driver.clone())
+        .body(format!(r#"{{"date":"{}","price":2000,"depart_time":{}}}"#, date(4), DEPART + 1800))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let edited = trip_on(&store, 4).unwrap();
+    assert_ne!(edited.id, old.id);
+    assert_eq!(edited.price, 2000);
+    assert_eq!(edited.start_time, old.start_time + 1800);
+    assert_eq!(store.get_trips(0, -1).unwrap().len(), 5);
+
+    //其他司机不能修改，已有订单的行程不能跳过
+    let response = client
+        .get(format!("/skipOccurrence/{}/{}", template.id, date(5)))
+        .header(login(&client, "other"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotAcceptable);
+    let booked = trip_on(&store, 5).unwrap();
+    apply(&client, &login(&client, "passenger"), &booked.id, 1);
+    let mut response = client
+        .get(format!("/skipOccurrence/{}/{}", template.id, date(5)))
+        .header(driver.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::NotAcceptable);
+    assert!(response.body_string().unwrap().contains("TRIP_BOOKED"));
+    assert!(trip_on(&store, 5).is_some());
+}
+
+#[test]
+fn invalid_template_is_rejected() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let mut response = client
+        .post("/addTemplate")
+        .header(ContentType::JSON)
+        .header(login(&client, "driver"))
+        .body(
+            r#"{"start":"A","end":"B","venue":"station","message":null,"plate_number":"A12345",
+                "car_type":"suv","tel":"13800000000","seat_count":4,"price":1000,"depart_time":90000,
+                "weekdays":[0,8],"start_date":"2018-02-30","end_date":"2018-13-01","skip_dates":null}"#,
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = response.body_string().unwrap();
+    for field in &["depart_time", "weekdays", "start_date", "end_date"] {
+        assert!(body.contains(field), "{}", body);
+    }
+    assert!(store.get_trips(0, -1).unwrap().is_empty());
+}
@@ -0,0 +1,124 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{ContentType, Status};
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use common::{client, login};
+
+fn errors(body: &str) -> Vec<(String, String)> {
+    let body: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(body["code"], "INVALID_PARAMS");
+    body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| {
+            (
+                e["field"].as_str().unwrap().to_owned(),
+                e["message"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn publish_reports_every_invalid_field() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+
+    let mut response = client
+        .post("/publishTrip")
+        .header(ContentType::JSON)
+        .header(driver)
+        .body(
+            r#"{"seat_count":0,"start_time":1,"start":" ","end":"","price":-1,
+                "venue":"station","message":null,"plate_number":"A12345",
+                "car_type":"suv","tel":"12345"}"#,
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let fields: Vec<String> = errors(&response.body_string().unwrap())
+        .into_iter()
+        .map(|(field, _)| field)
+        .collect();
+    assert_eq!(fields, vec!["seat_count", "start_time", "price", "start", "end", "tel"]);
+}
+
+#[test]
+fn publish_and_apply_with_json() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+
+    let mut response = client
+        .post("/publishTrip")
+        .header(ContentType::JSON)
+        .header(driver)
+        .body(
+            r#"{"seat_count":3,"start_time":1900000000,"start":"A","end":"B","price":1000,
+                "venue":"station","message":"no smoking","plate_number":"A12345",
+                "car_type":"suv","tel":"13800000000"}"#,
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let trip: entity::Trip = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+    //超过座位数和手机号错误同时返回
+    let mut response = client
+        .post("/applyTrip")
+        .header(ContentType::JSON)
+        .header(passenger.clone())
+        .body(format!(r#"{{"trip_id":"{}","count":4,"tel":"abc"}}"#, trip.id))
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(
+        errors(&response.body_string().unwrap()),
+        vec![
+            ("count".to_owned(), "must not exceed the trip's seats".to_owned()),
+            ("tel".to_owned(), "must be a valid mobile number".to_owned()),
+        ]
+    );
+
+    let response = client
+        .post("/applyTrip")
+        .header(ContentType::JSON)
+        .header(passenger.clone())
+        .body(format!(r#"{{"trip_id":"{}","count":0}}"#, trip.id))
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = client
+        .post("/applyTrip")
+        .header(ContentType::JSON)
+        .header(passenger)
+        .body(format!(r#"{{"trip_id":"{}","count":2,"tel":"13900000000"}}"#, trip.id))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn malformed_json_is_a_bad_request() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let mut response = client
+        .post("/publishTrip")
+        .header(ContentType::JSON)
+        .header(driver)
+        .body("{not json")
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["code"], "BAD_REQUEST");
+}
@@ -0,0 +1,124 @@
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate pin_che;
+extern crate rocket;
+extern crate serde_json;
+
+mod common;
+
+use rocket::http::{Header, Status};
+use rocket::local::Client;
+use pin_che::entity;
+use pin_che::memory::MemoryStore;
+use pin_che::service::Service;
+use pin_che::store::{OrderStore, TripStore};
+use common::{apply, client, drain, login, notifications, notify, publish};
+
+//返回(状态, 响应内容)
+fn join(client: &Client, user: &Header<'static>, trip_id: &str, count: i64) -> (Status, serde_json::Value) {
+    let mut response = client
+        .get(format!("/joinWaitlist/{}/{}/13900000000", trip_id, count))
+        .header(user.clone())
+        .dispatch();
+    let body = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    (response.status(), body)
+}
+
+fn waitlist(client: &Client, user: &Header<'static>) -> Vec<entity::Waiter> {
+    let mut response = client.get("/waitlist").header(user.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+#[test]
+fn join_only_when_seats_are_short() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let driver = login(&client, "driver");
+    let passenger = login(&client, "passenger");
+    let trip = publish(&client, &driver);
+
+    let (status, body) = join(&client, &passenger, &trip.id, 2);
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("SEATS_AVAILABLE")));
+
+    apply(&client, &login(&client, "other"), &trip.id, 3);
+    let (status, body) = join(&client, &passenger, &trip.id, 2);
+    assert_eq!(status, Status::Ok);
+    let id = body["_id"].as_str().unwrap().to_owned();
+    let (status, body) = join(&client, &passenger, &trip.id, 2);
+    assert_eq!((status, body["code"].as_str()), (Status::NotAcceptable, Some("ALREADY_WAITING")));
+
+    let response = client.get(format!("/leaveWaitlist/{}", id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(waitlist(&client, &passenger)[0].status, entity::WaitStatus::Removed);
+    let mut response = client.get(format!("/leaveWaitlist/{}", id)).header(passenger.clone()).dispatch();
+    assert_eq!(response.status(), Status::NotAcceptable);
+    assert!(response.body_string().unwrap().contains("NOT_WAITING"));
+}
+
+#[test]
+fn freed_seats_are_offered_in_order() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let service = Service::with_store(store.clone());
+    let driver = login(&client, "driver");
+    let first = login(&client, "first");
+    let second = login(&client, "second");
+    let trip = publish(&client, &driver);
+
+    let order_id = apply(&client, &login(&client, "other"), &trip.id, 3).id;
+    apply(&client, &login(&client, "another"), &trip.id, 1);
+    assert_eq!(join(&client, &first, &trip.id, 3).0, Status::Ok);
+    assert_eq!(join(&client, &second, &trip.id, 3).0, Status::Ok);
+
+    //座位归还后预留给第一位候补，不会被直接下单抢走
+    service.expire_order(&order_id).unwrap();
+    let offered = waitlist(&client, &first).remove(0);
+    assert_eq!(offered.status, entity::WaitStatus::Offered);
+    let offer = store.get_order(offered.order_id.as_ref().unwrap()).unwrap();
+    assert_eq!((offer.openid.as_str(), offer.count), ("first", 3));
+    assert_eq!(store.get_trip(&trip.id).unwrap().current_seat, 0);
+    assert_eq!(waitlist(&client, &second)[0].status, entity::WaitStatus::Waiting);
+
+    //通知候补支付，事件重试时不会重复通知
+    drain(&store);
+    service.notify_seat_offered(&offer.id, &trip.id, "first").unwrap();
+    let received = notifications(&client, &first);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].kind, entity::NotificationKind::SeatOffered);
+    assert_eq!(received[0].order_id.as_ref(), Some(&offer.id));
+    assert!(notifications(&client, &second).is_empty());
+
+    //预留订单未支付，座位转给下一位候补
+    service.expire_order(&offer.id).unwrap();
+    assert_eq!(waitlist(&client, &first)[0].status, entity::WaitStatus::Expired);
+    let offered = waitlist(&client, &second).remove(0);
+    assert_eq!(offered.status, entity::WaitStatus::Offered);
+
+    let order_id = offered.order_id.unwrap();
+    assert!(notify(&client, &order_id).contains("SUCCESS"));
+    assert_eq!(waitlist(&client, &second)[0].status, entity::WaitStatus::Accepted);
+    assert_eq!(store.get_trip(&trip.id).unwrap().current_seat, 0);
+}
+
+#[test]
+fn waiters_needing_more_seats_keep_their_place() {
+    let store = MemoryStore::new();
+    let client = client(&store);
+    let service = Service::with_store(store.clone());
+    let driver = login(&client, "driver");
+    let first = login(&client, "first");
+    let second = login(&client, "second");
+    let trip = publish(&client, &driver);
+
+    let order_id = apply(&client, &login(&client, "other"), &trip.id, 1).id;
+    apply(&client, &login(&client, "another"), &trip.id, 3);
+    assert_eq!(join(&client, &first, &trip.id, 2).0, Status::Ok);
+    assert_eq!(join(&client, &second, &trip.id, 1).0, Status::Ok);
+
+    service.expire_order(&order_id).unwrap();
+    assert_eq!(waitlist(&client, &first)[0].status, entity::WaitStatus::Waiting);
+    assert_eq!(waitlist(&client, &second)[0].status, entity::WaitStatus::Offered);
+}